@@ -0,0 +1,302 @@
+//! Access log, one line per handled connection.
+//!
+//! Written independently of `tracing` subscriber, so it is not affected by log level.
+use rpx::{resolver::ServiceName, DropReason, ForwardOutcome};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// File to append lines to, stdout when omitted
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    format: Format,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Single JSON object per line
+    #[default]
+    Json,
+    /// Space separated `key=value` pairs
+    Text,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Traffic was forwarded to destination
+    Forwarded,
+    /// Resolver did not come up with destination
+    Unresolved,
+    /// Service name was not read in time
+    Timeout,
+    /// Reading service name failed
+    Unreadable,
+    /// No parser recognized traffic while name is required
+    NameRequired,
+    /// Service name took more reads than allowed
+    TooFragmented,
+    /// Every candidate destination points back at the proxy
+    LoopsBack,
+    /// Forwarding failed with error
+    Failed,
+}
+
+/// Single access log line.
+#[derive(Serialize, Debug, PartialEq)]
+struct Entry<'a> {
+    /// Seconds since unix epoch
+    timestamp: f64,
+    client: IpAddr,
+    port: u16,
+    service_name: Option<&'a str>,
+    destination: Option<SocketAddr>,
+    resolver: Option<&'static str>,
+    /// Only present for listeners taking one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<&'a str>,
+    bytes_in: u64,
+    bytes_out: u64,
+    duration_ms: u128,
+    outcome: Outcome,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Forwarded => "forwarded",
+            Outcome::Unresolved => "unresolved",
+            Outcome::Timeout => "timeout",
+            Outcome::Unreadable => "unreadable",
+            Outcome::NameRequired => "name_required",
+            Outcome::TooFragmented => "too_fragmented",
+            Outcome::LoopsBack => "loops_back",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+impl<'a> Entry<'a> {
+    fn new(client: SocketAddr, port: u16, outcome: Option<&'a ForwardOutcome>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs_f64())
+            .unwrap_or_default();
+        let stats = outcome.map(ForwardOutcome::stats);
+        let outcome = match outcome {
+            None => Outcome::Failed,
+            Some(ForwardOutcome::Dropped { reason, .. }) => match reason {
+                DropReason::ParseTimeout => Outcome::Timeout,
+                DropReason::Unreadable => Outcome::Unreadable,
+                DropReason::NameRequired => Outcome::NameRequired,
+                DropReason::Unresolved => Outcome::Unresolved,
+                DropReason::LoopsBack => Outcome::LoopsBack,
+                DropReason::TooFragmented => Outcome::TooFragmented,
+            },
+            Some(ForwardOutcome::Forwarded(_)) => Outcome::Forwarded,
+        };
+
+        Self {
+            timestamp,
+            client: client.ip(),
+            port,
+            // Absent if no parser recognized the traffic, empty if parser read an empty name
+            service_name: stats.and_then(|stats| match stats.service_name.as_ref()? {
+                ServiceName::Parsed(name) => Some(name.as_str()),
+                ServiceName::NotParsed => None,
+            }),
+            destination: stats.and_then(|stats| stats.resolved),
+            resolver: stats.and_then(|stats| stats.resolver),
+            fingerprint: stats.and_then(|stats| stats.fingerprint.as_deref()),
+            bytes_in: stats.map(|stats| stats.bytes_in).unwrap_or_default(),
+            bytes_out: stats.map(|stats| stats.bytes_out).unwrap_or_default(),
+            duration_ms: stats
+                .map(|stats| stats.duration.as_millis())
+                .unwrap_or_default(),
+            outcome,
+        }
+    }
+
+    fn format(&self, format: Format) -> String {
+        match format {
+            Format::Json => serde_json::to_string(self).expect("Entry is always serializable"),
+            Format::Text => {
+                let mut line = format!(
+                    "timestamp={:.3} client={} port={}",
+                    self.timestamp, self.client, self.port
+                );
+                if let Some(service_name) = self.service_name {
+                    line.push_str(&format!(" service_name={service_name:?}"));
+                }
+                if let Some(destination) = self.destination {
+                    line.push_str(&format!(" destination={destination}"));
+                }
+                if let Some(resolver) = self.resolver {
+                    line.push_str(&format!(" resolver={resolver}"));
+                }
+                if let Some(fingerprint) = self.fingerprint {
+                    line.push_str(&format!(" fingerprint={fingerprint}"));
+                }
+                line.push_str(&format!(
+                    " bytes_in={} bytes_out={} duration_ms={} outcome={}",
+                    self.bytes_in,
+                    self.bytes_out,
+                    self.duration_ms,
+                    self.outcome.as_str()
+                ));
+                line
+            }
+        }
+    }
+}
+
+/// Shared handle to access log destination.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: Format,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl AccessLog {
+    pub fn open(config: &Config) -> Result<Self, io::Error> {
+        let out: Box<dyn Write + Send> = match config.path.as_ref() {
+            None => Box::new(io::stdout()),
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+
+        Ok(Self {
+            format: config.format,
+            out: Arc::new(Mutex::new(out)),
+        })
+    }
+
+    /// Records connection from `client` to listener on `port`, `outcome` is `None` if forwarding
+    /// failed.
+    pub fn record(&self, client: SocketAddr, port: u16, outcome: Option<&ForwardOutcome>) {
+        let mut line = Entry::new(client, port, outcome).format(self.format);
+        line.push('\n');
+
+        let mut out = self.out.lock().expect("Access log lock poisoned");
+        if let Err(err) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            warn!("Failed to write access log: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Entry, Format, Outcome};
+    use rpx::{resolver::ServiceName, DropReason, ForwardOutcome, ForwardStats};
+    use std::time::Duration;
+    use test_case::test_case;
+
+    fn entry(outcome: Option<&ForwardOutcome>) -> Entry<'_> {
+        Entry {
+            timestamp: 1700000000.5,
+            ..Entry::new(([10, 0, 0, 1], 50000).into(), 443, outcome)
+        }
+    }
+
+    fn dropped(reason: DropReason, stats: ForwardStats) -> ForwardOutcome {
+        ForwardOutcome::Dropped { reason, stats }
+    }
+
+    #[test]
+    fn formats_forwarded_connection() {
+        let stats = ForwardStats {
+            bytes_in: 100,
+            bytes_out: 2000,
+            resolved: Some(([192, 168, 0, 1], 8443).into()),
+            resolver: Some("constant"),
+            service_name: Some(ServiceName::Parsed("example.com".to_owned())),
+            duration: Duration::from_millis(1500),
+            ..ForwardStats::default()
+        };
+        let forwarded = ForwardOutcome::Forwarded(stats);
+        let entry = entry(Some(&forwarded));
+
+        assert_eq!(entry.outcome, Outcome::Forwarded);
+        assert_eq!(
+            entry.format(Format::Json),
+            r#"{"timestamp":1700000000.5,"client":"10.0.0.1","port":443,"service_name":"example.com","destination":"192.168.0.1:8443","resolver":"constant","bytes_in":100,"bytes_out":2000,"duration_ms":1500,"outcome":"forwarded"}"#
+        );
+        assert_eq!(
+            entry.format(Format::Text),
+            r#"timestamp=1700000000.500 client=10.0.0.1 port=443 service_name="example.com" destination=192.168.0.1:8443 resolver=constant bytes_in=100 bytes_out=2000 duration_ms=1500 outcome=forwarded"#
+        );
+    }
+
+    #[test_case(DropReason::ParseTimeout, Outcome::Timeout, "timeout"; "timeout")]
+    #[test_case(DropReason::Unreadable, Outcome::Unreadable, "unreadable"; "unreadable")]
+    #[test_case(DropReason::NameRequired, Outcome::NameRequired, "name_required"; "name required")]
+    #[test_case(DropReason::Unresolved, Outcome::Unresolved, "unresolved"; "unresolved")]
+    #[test_case(DropReason::LoopsBack, Outcome::LoopsBack, "loops_back"; "loops back")]
+    #[test_case(DropReason::TooFragmented, Outcome::TooFragmented, "too_fragmented"; "too fragmented")]
+    fn reports_outcome(reason: DropReason, expected: Outcome, name: &str) {
+        let outcome = dropped(reason, ForwardStats::default());
+        let entry = entry(Some(&outcome));
+
+        assert_eq!(entry.outcome, expected);
+        assert!(entry
+            .format(Format::Json)
+            .contains(&format!(r#""outcome":"{name}""#)));
+        assert!(entry
+            .format(Format::Text)
+            .ends_with(&format!(" outcome={name}")));
+    }
+
+    #[test]
+    fn reports_failure() {
+        assert_eq!(entry(None).outcome, Outcome::Failed);
+    }
+
+    #[test]
+    fn tells_empty_name_from_unparsed() {
+        let with = |service_name| {
+            let stats = ForwardStats {
+                service_name: Some(service_name),
+                ..ForwardStats::default()
+            };
+            dropped(DropReason::Unresolved, stats)
+        };
+        let empty = with(ServiceName::Parsed(String::new()));
+        let unparsed = with(ServiceName::NotParsed);
+
+        assert!(entry(Some(&empty))
+            .format(Format::Text)
+            .contains(r#"service_name="""#));
+        assert!(!entry(Some(&unparsed))
+            .format(Format::Text)
+            .contains("service_name"));
+    }
+
+    #[test]
+    fn includes_fingerprint_only_if_taken() {
+        let fingerprinted = ForwardOutcome::Forwarded(ForwardStats {
+            fingerprint: Some("ada70206e40642a3e4461f35503241d5".to_owned()),
+            ..ForwardStats::default()
+        });
+        let plain = ForwardOutcome::Forwarded(ForwardStats::default());
+
+        assert!(entry(Some(&fingerprinted))
+            .format(Format::Json)
+            .contains(r#""fingerprint":"ada70206e40642a3e4461f35503241d5""#));
+        assert!(entry(Some(&fingerprinted))
+            .format(Format::Text)
+            .contains(" fingerprint=ada70206e40642a3e4461f35503241d5 "));
+        assert!(!entry(Some(&plain))
+            .format(Format::Json)
+            .contains("fingerprint"));
+    }
+}
@@ -0,0 +1,200 @@
+//! Control socket taking one command per line, answering each with a single line.
+//!
+//! - `reload` re-reads config file and swaps resolver stacks of running TCP listeners;
+//! - `stats` reports connection counts.
+//!
+//! Every TCP listener reads its stack from an [`ArcSwap`] once per accepted connection,
+//! connections already being forwarded keep the stack they started with until they close.
+//! Reload builds all stacks from a fully validated config before swapping any, so a broken
+//! config leaves every listener as it was. Listener options, UDP listeners and listeners
+//! added or removed in the file only change on restart.
+use crate::{config, metrics, resolver_stack};
+use arc_swap::ArcSwap;
+use rpx::resolver::stack::Resolver;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{info, warn};
+
+/// Resolver stack of a TCP listener, swapped on reload.
+pub type Stack = Arc<ArcSwap<Resolver>>;
+
+pub struct Admin {
+    /// Config file to re-read on reload
+    path: PathBuf,
+    /// Stacks keyed by address of listener using them
+    stacks: Vec<(SocketAddr, Stack)>,
+}
+
+impl Admin {
+    pub fn new(path: PathBuf, stacks: Vec<(SocketAddr, Stack)>) -> Self {
+        Self { path, stacks }
+    }
+
+    /// Takes connections on unix socket at `path`, replacing a stale socket left by previous run.
+    #[cfg(unix)]
+    pub async fn serve(self, path: &Path) -> Result<(), anyhow::Error> {
+        let listener = crate::bind::unix(path)?;
+        info!("Serving admin socket on {path:?}");
+
+        let admin = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let admin = admin.clone();
+            tokio::spawn(async move {
+                if let Err(err) = admin.session(stream).await {
+                    warn!("Admin session failed: {err}");
+                }
+            });
+        }
+    }
+
+    async fn session<S>(&self, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (read, mut write) = io::split(stream);
+        let mut lines = BufReader::new(read).lines();
+        while let Some(line) = lines.next_line().await? {
+            let mut reply = self.command(line.trim());
+            reply.push('\n');
+            write.write_all(reply.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    fn command(&self, command: &str) -> String {
+        match command {
+            "reload" => match self.reload() {
+                Ok(swapped) => format!("ok reloaded {swapped} listeners"),
+                Err(err) => {
+                    warn!("Reload failed, keeping previous rules: {err:#}");
+                    format!("error {err:#}")
+                }
+            },
+            "stats" => {
+                let (accepted, active) = metrics::connections();
+                format!("ok active_connections={active} accepted_connections={accepted}")
+            }
+            unknown => format!("error unknown command {unknown:?}, expected `reload` or `stats`"),
+        }
+    }
+
+    /// Swaps stacks of listeners still present in config, returns how many were swapped.
+    fn reload(&self) -> Result<usize, anyhow::Error> {
+        if self.path == Path::new(config::STDIN) {
+            anyhow::bail!("config was read from standard input, which can't be read again");
+        }
+        let config = config::load(&self.path)?;
+        let rebuilt: Vec<_> = self
+            .stacks
+            .iter()
+            .filter_map(|(address, stack)| {
+                match config
+                    .listen
+                    .iter()
+                    .find(|listener| listener.address == *address)
+                {
+                    Some(listener) => Some((
+                        stack,
+                        resolver_stack(config.layers(listener), config.resolver_buffer),
+                    )),
+                    None => {
+                        warn!("Listener {address} is gone from config, keeping its rules until restart");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        let swapped = rebuilt.len();
+        for (stack, resolver) in rebuilt {
+            stack.store(Arc::new(resolver));
+        }
+        info!("Reloaded rules of {swapped} listeners");
+
+        Ok(swapped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Admin;
+    use arc_swap::ArcSwap;
+    use indoc::indoc;
+    use rpx::{resolver::ResolveRequest, ResolverStackBuilder};
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tower::{Service, ServiceExt};
+
+    #[tokio::test]
+    async fn reloads_rules_of_running_listener() {
+        let dir = std::env::temp_dir().join(format!("ormos-admin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Creates dir");
+        let path = dir.join("ormos.yaml");
+        let config = |ip: &str| {
+            format!(
+                indoc! {"
+                listen:
+                  - address: '127.0.0.1:1234'
+                rules:
+                  - type: constant
+                    name: example.com
+                    ips: ['{}']
+                "},
+                ip
+            )
+        };
+        std::fs::write(&path, config("10.0.0.1")).expect("Writes config");
+
+        let stack = Arc::new(ArcSwap::from_pointee(ResolverStackBuilder::new().build()));
+        let admin = Admin::new(
+            path.clone(),
+            vec![(([127, 0, 0, 1], 1234).into(), stack.clone())],
+        );
+        let resolve = || async {
+            let request = ResolveRequest::new("example.com", 443, ([10, 0, 0, 9], 50000).into());
+            let mut resolver = stack.load().as_ref().clone();
+            let resolved = resolver
+                .ready()
+                .await
+                .expect("Ready")
+                .call(request)
+                .await
+                .expect("Resolves");
+            resolved.and_then(|resolved| resolved.address())
+        };
+
+        assert_eq!(resolve().await, None);
+        assert_eq!(admin.command("reload"), "ok reloaded 1 listeners");
+        assert_eq!(resolve().await, Some(([10, 0, 0, 1], 443).into()));
+
+        // Broken config keeps rules in place
+        std::fs::write(&path, "rules: [").expect("Writes config");
+        assert!(admin.command("reload").starts_with("error"));
+        assert_eq!(resolve().await, Some(([10, 0, 0, 1], 443).into()));
+    }
+
+    #[tokio::test]
+    async fn answers_every_command() {
+        let admin = Admin::new("/nonexistent/ormos.yaml".into(), Vec::new());
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move { admin.session(server).await });
+
+        let (read, mut write) = tokio::io::split(client);
+        write
+            .write_all(b"stats\nshutdown\n")
+            .await
+            .expect("Writes commands");
+        let mut lines = BufReader::new(read).lines();
+
+        let stats = lines.next_line().await.expect("Reads").expect("Answer");
+        assert!(stats.starts_with("ok active_connections="), "{stats}");
+        let unknown = lines.next_line().await.expect("Reads").expect("Answer");
+        assert!(unknown.starts_with("error unknown command"), "{unknown}");
+    }
+}
@@ -0,0 +1,168 @@
+//! Listening sockets with options tuned for restarts and high accept rates.
+use crate::config::Listener;
+use socket2::SockRef;
+use std::{io, path::Path, sync::Arc, thread};
+use tokio::net::{TcpListener, TcpSocket};
+use tracing::warn;
+
+/// Binds TCP listener, `SO_REUSEADDR` is always set so restart doesn't wait out `TIME_WAIT`.
+pub fn tcp(listener: &Listener) -> io::Result<TcpListener> {
+    let socket = socket(listener)?;
+    if listener.reuse_port {
+        reuse_port(&socket)?;
+    }
+
+    socket.bind(listener.address)?;
+    socket.listen(listener.listen_backlog)
+}
+
+/// Binds a listener for every accept worker.
+///
+/// Each worker gets its own socket bound with `SO_REUSEPORT`, so kernel spreads connections
+/// between them. Where it is unavailable, workers share a single listener.
+pub fn tcp_workers(listener: &Listener) -> io::Result<Vec<Arc<TcpListener>>> {
+    let workers = listener
+        .accept_workers
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |cpus| cpus.get()))
+        .max(1);
+    if workers == 1 {
+        return Ok(vec![Arc::new(tcp(listener)?)]);
+    }
+
+    let first = socket(listener)?;
+    if let Err(err) = reuse_port(&first) {
+        warn!(
+            "Listener {} can't use SO_REUSEPORT, {workers} workers share a socket: {err}",
+            listener.address
+        );
+        let shared = Arc::new(tcp(listener)?);
+        return Ok((0..workers).map(|_| shared.clone()).collect());
+    }
+    first.bind(listener.address)?;
+    let first = first.listen(listener.listen_backlog)?;
+
+    // Ephemeral port is only known once the first socket is bound
+    let worker = Listener {
+        address: first.local_addr()?,
+        reuse_port: true,
+        ..listener.clone()
+    };
+    let mut acceptors = vec![Arc::new(first)];
+    for _ in 1..workers {
+        acceptors.push(Arc::new(tcp(&worker)?));
+    }
+
+    Ok(acceptors)
+}
+
+/// Binds unix socket at `path`, replacing a stale socket left behind by previous run.
+///
+/// Refuses to replace anything that is not a socket.
+#[cfg(unix)]
+pub fn unix(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+fn socket(listener: &Listener) -> io::Result<TcpSocket> {
+    let socket = if listener.address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    // Some platforms let `[::]` take IPv4 traffic as well, clashing with `0.0.0.0` on the same port
+    if listener.dual_stack && listener.address.is_ipv6() {
+        SockRef::from(&socket).set_only_v6(true)?;
+    }
+    Ok(socket)
+}
+
+#[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+fn reuse_port(socket: &TcpSocket) -> io::Result<()> {
+    socket.set_reuseport(true)
+}
+
+#[cfg(not(all(unix, not(target_os = "solaris"), not(target_os = "illumos"))))]
+fn reuse_port(_: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tcp, tcp_workers};
+    use crate::config::Listener;
+
+    #[tokio::test]
+    async fn binds_with_requested_options() {
+        let listener = Listener {
+            address: "127.0.0.1:0".parse().unwrap(),
+            listen_backlog: 16,
+            ..Listener::default()
+        };
+        let bound = tcp(&listener).expect("Binds");
+        assert!(bound.local_addr().expect("Bound address").port() != 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn given_reuse_port_shares_address() {
+        let mut listener = Listener {
+            address: "127.0.0.1:0".parse().unwrap(),
+            reuse_port: true,
+            ..Listener::default()
+        };
+        let first = tcp(&listener).expect("Binds");
+        listener.address = first.local_addr().expect("Bound address");
+
+        assert!(tcp(&listener).is_ok());
+        listener.reuse_port = false;
+        assert!(tcp(&listener).is_err());
+    }
+
+    #[tokio::test]
+    async fn binds_socket_per_worker() {
+        let listener = Listener {
+            address: "127.0.0.1:0".parse().unwrap(),
+            accept_workers: Some(3),
+            ..Listener::default()
+        };
+        let acceptors = tcp_workers(&listener).expect("Binds");
+
+        assert_eq!(acceptors.len(), 3);
+        let address = acceptors[0].local_addr().expect("Bound address");
+        for acceptor in acceptors.iter() {
+            assert_eq!(acceptor.local_addr().expect("Bound address"), address);
+        }
+    }
+
+    #[tokio::test]
+    async fn given_dual_stack_binds_both_families_on_same_port() {
+        let v4 = Listener {
+            address: "0.0.0.0:0".parse().unwrap(),
+            dual_stack: true,
+            ..Listener::default()
+        };
+        let bound = tcp(&v4).expect("Binds IPv4");
+        let port = bound.local_addr().expect("Bound address").port();
+
+        let v6 = Listener {
+            address: ([0u16; 8], port).into(),
+            ..v4
+        };
+        assert!(tcp(&v6).is_ok());
+    }
+}
@@ -0,0 +1,66 @@
+use std::{fmt, io, net::SocketAddr, path::PathBuf};
+
+/// Why config could not be [loaded][super::load_from_path].
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Config file, or a file it includes, doesn't exist
+    #[error("Config file {} not found", .0.display())]
+    NotFound(PathBuf),
+    /// File exists, but could not be read
+    #[error("Failed to read config file {}: {source}", .path.display())]
+    Unreadable { path: PathBuf, source: io::Error },
+    /// File is not valid YAML, JSON or TOML, or doesn't match config structure
+    #[error("Failed to parse config file {}: {message}", .path.display())]
+    Parse {
+        path: PathBuf,
+        /// Where parser gave up, when it can tell
+        location: Option<Location>,
+        message: String,
+    },
+    /// Config is well formed, but its settings can't be used as they are
+    #[error("{origin}: {message}")]
+    Invalid { origin: Origin, message: String },
+}
+
+impl Error {
+    pub(super) fn invalid(origin: Origin, message: impl fmt::Display) -> Self {
+        Error::Invalid {
+            origin,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Position in config file, both counted from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Part of config an [invalid][Error::Invalid] setting belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// Top-level settings
+    Config,
+    /// Top-level `rules`
+    Rules,
+    /// Named collection of rules
+    RuleSet(String),
+    /// Listener bound to the address
+    Listener(SocketAddr),
+    /// File listed in `include`
+    Include(PathBuf),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Config => write!(f, "Config"),
+            Origin::Rules => write!(f, "Rules"),
+            Origin::RuleSet(name) => write!(f, "Rule set `{name}`"),
+            Origin::Listener(address) => write!(f, "Listener {address}"),
+            Origin::Include(path) => write!(f, "Included file {}", path.display()),
+        }
+    }
+}
@@ -1,36 +1,253 @@
-use super::Kind;
-use serde::Deserialize;
-use std::net::SocketAddr;
+use super::{parser_kind::BoxedParser, Kind};
+use rpx::{
+    connect::Source, pool::Pool, sample::Sampler, throttle::Direction, tls, ForwardOptions,
+    ForwardPolicy,
+};
+use serde::{Deserialize, Deserializer};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 const DEFAULT_BIND: &str = "127.0.0.1:8314";
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+/// Every connection holds two copy buffers, larger ones add up quickly.
+pub const MAX_COPY_BUFFER_BYTES: usize = 1024 * 1024;
 
 /// Configuration for a single listener.
 ///
-/// Listeners consist of bind address and collection of
-/// incoming traffic parsers to apply.
-#[derive(Deserialize, Debug, PartialEq)]
+/// Listeners consist of bind address, collection of
+/// incoming traffic parsers to apply and optional reference
+/// to the rule set used for resolving destinations.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Listener {
+    /// Address to bind. For listeners on unix socket it only stands for connections on it,
+    /// e.g. its port is the one rules see, and must not be a destination itself, as
+    /// connections are never forwarded to the listener address. Must stay unique either way
     pub address: SocketAddr,
-    // Listener with empty list of parsers can only send all traffic to default destination
-    #[serde(default = "default_parsers")]
+    /// Accept TCP connections on this unix socket instead of binding `address`
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    // Listener with empty list of parsers can only send all traffic to default destination.
+    // Each parser looks at every read until it gives up, so cost grows with parsers × reads,
+    // repeated ones are dropped
+    #[serde(default = "default_parsers", deserialize_with = "unique_parsers")]
     pub parsers: Vec<Kind>,
+    /// Name of the rule set to build resolver stack from.
+    /// Listener without one uses top-level rules.
+    #[serde(default)]
+    pub rules: Option<String>,
+    /// Transport protocol to accept traffic over
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Pending connections kernel queues before they are accepted
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    /// Set `SO_REUSEPORT`, letting other sockets bind the same address
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// Tasks accepting connections, each with its own socket. Defaults to number of CPUs
+    #[serde(default)]
+    pub accept_workers: Option<usize>,
+    /// Bind both IPv4 and IPv6 wildcard on the port of `address`
+    #[serde(default)]
+    pub dual_stack: bool,
+    /// Prepend PROXY protocol v2 header to upstream traffic
+    #[serde(default)]
+    pub send_proxy_protocol: bool,
+    /// Drop new connections from client IP exceeding this rate
+    #[serde(default)]
+    pub max_conns_per_ip_per_sec: Option<u32>,
+    /// Close connections open for longer than this, even if they are active
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+    /// Give up connecting to upstream after this long, rules may set their own
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    /// Close connections nothing was read from either side of for this long, rules may set
+    /// their own
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Reuse upstream connections after client half-closes, only safe for some protocols
+    #[serde(default)]
+    pub upstream_pool: Option<UpstreamPool>,
+    /// Cap on throughput of every connection
+    #[serde(default)]
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    /// Traffic the cap applies to
+    #[serde(default)]
+    pub rate_limit_direction: Direction,
+    /// Buffer for each direction of relayed traffic, 8KiB by default
+    #[serde(default)]
+    pub copy_buffer_bytes: Option<usize>,
+    /// Drop clients taking more reads than this to send service name
+    #[serde(default)]
+    pub max_parse_reads: Option<usize>,
+    /// Log summary of one in this many forwarded connections, all of them when unset
+    #[serde(default)]
+    pub log_sample_rate: Option<u64>,
+    /// Drop TLS connections without SNI, along with any others no parser read a name from,
+    /// instead of resolving an empty name
+    #[serde(default)]
+    pub require_sni: bool,
+    /// Take JA3 fingerprint of TLS clients, written to access log
+    #[serde(default)]
+    pub ja3: bool,
+    /// Terminate TLS with these certificates and forward cleartext
+    #[serde(default)]
+    pub tls: Option<tls::Config>,
+    /// Source address of connections to upstream
+    #[serde(default)]
+    pub bind_source: Option<IpAddr>,
+    /// Interface connections to upstream leave through, Linux only
+    #[cfg(feature = "bind-device")]
+    #[serde(default)]
+    pub bind_device: Option<String>,
+}
+
+/// Caps on idle upstream connections kept for reuse.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamPool {
+    /// Drop idle connections after this long
+    pub max_idle_secs: u64,
+    /// Keep at most this many idle connections per destination
+    pub max_per_host: usize,
+}
+
+/// Transport protocol of a listener.
+///
+/// For `udp` listeners parsers are offered the first datagram of every flow. None of the
+/// parsers reads datagrams yet, so they are listed with `parsers: []` and a `fallback` rule.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
 }
 
 impl Default for Listener {
     fn default() -> Self {
         Self {
             address: DEFAULT_BIND.parse().expect("Failed to parse valid address"),
+            path: None,
             parsers: default_parsers(),
+            rules: None,
+            protocol: Protocol::default(),
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            reuse_port: false,
+            accept_workers: None,
+            dual_stack: false,
+            send_proxy_protocol: false,
+            max_conns_per_ip_per_sec: None,
+            max_lifetime_secs: None,
+            connect_timeout_secs: None,
+            idle_timeout_secs: None,
+            upstream_pool: None,
+            rate_limit_bytes_per_sec: None,
+            rate_limit_direction: Direction::default(),
+            copy_buffer_bytes: None,
+            max_parse_reads: None,
+            log_sample_rate: None,
+            require_sni: false,
+            ja3: false,
+            tls: None,
+            bind_source: None,
+            #[cfg(feature = "bind-device")]
+            bind_device: None,
         }
     }
 }
 
 impl Listener {
-    pub fn parsers(&self) -> &[Kind] {
-        self.parsers.as_ref()
+    /// Addresses to bind, with `dual_stack` one per IP family.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        if !self.dual_stack {
+            return vec![self.address];
+        }
+
+        let port = self.address.port();
+        vec![
+            (Ipv4Addr::UNSPECIFIED, port).into(),
+            (Ipv6Addr::UNSPECIFIED, port).into(),
+        ]
+    }
+
+    /// Fresh parsers for a single connection or flow.
+    pub fn build_parsers(&self) -> Vec<BoxedParser> {
+        self.parsers
+            .iter()
+            .map(|kind| -> BoxedParser {
+                match kind {
+                    Kind::Tls if self.require_sni || self.ja3 => {
+                        let mut parser = if self.require_sni {
+                            rpx::parser::tls::ServiceName::requiring_sni()
+                        } else {
+                            rpx::parser::tls::ServiceName::default()
+                        };
+                        if self.ja3 {
+                            parser = parser.with_ja3();
+                        }
+                        Box::new(parser)
+                    }
+                    kind => kind.into(),
+                }
+            })
+            .collect()
     }
+
+    /// Options for forwarding traffic, fails if TLS certificates could not be loaded.
+    pub fn forward_options(
+        &self,
+        self_addresses: &[SocketAddr],
+    ) -> Result<ForwardOptions, tls::Error> {
+        Ok(ForwardOptions {
+            send_proxy_protocol: self.send_proxy_protocol,
+            max_lifetime: self.max_lifetime_secs.map(Duration::from_secs),
+            pool: self
+                .upstream_pool
+                .map(|pool| Pool::new(Duration::from_secs(pool.max_idle_secs), pool.max_per_host)),
+            rate_limit_bytes_per_sec: self.rate_limit_bytes_per_sec,
+            rate_limit_direction: self.rate_limit_direction,
+            require_name: self.require_sni,
+            self_addresses: self_addresses.to_vec(),
+            tls: self.tls.as_ref().map(tls::Terminator::new).transpose()?,
+            source: Source {
+                address: self.bind_source,
+                #[cfg(feature = "bind-device")]
+                device: self.bind_device.clone(),
+            },
+            copy_buffer_size: self.copy_buffer_bytes,
+            max_parse_reads: self.max_parse_reads,
+            log_sample: self.log_sample_rate.map(Sampler::new),
+            policy: ForwardPolicy {
+                connect_timeout: self.connect_timeout_secs.map(Duration::from_secs),
+                idle_timeout: self.idle_timeout_secs.map(Duration::from_secs),
+            },
+        })
+    }
+}
+
+fn default_listen_backlog() -> u32 {
+    DEFAULT_LISTEN_BACKLOG
 }
 
 fn default_parsers() -> Vec<Kind> {
     vec![Kind::H1, Kind::Tls]
 }
+
+/// Keeps the first of repeated parser kinds, e.g. `h1` listed along with `http/1`.
+fn unique_parsers<'de, D>(deserializer: D) -> Result<Vec<Kind>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let kinds = Vec::<Kind>::deserialize(deserializer)?;
+    let mut unique = Vec::with_capacity(kinds.len());
+    for kind in kinds {
+        if !unique.contains(&kind) {
+            unique.push(kind);
+        }
+    }
+    Ok(unique)
+}
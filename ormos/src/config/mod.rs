@@ -5,62 +5,187 @@
 #![doc = include_str!("../../../sample_config.yml")]
 //! ```
 
+use crate::{access_log, health};
 use clap::Parser;
 use rpx::resolver;
-use serde::Deserialize;
-use std::{fs::File, marker::PhantomData, net::SocketAddr, path::PathBuf};
-use tracing::debug;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, warn};
 
+mod error;
 mod listener;
 mod parser_kind;
+mod routes;
 
-use listener::Listener;
+pub use error::{Error, Location, Origin};
+pub use listener::{Listener, Protocol};
 use parser_kind::Kind;
+pub use routes::RouteEntry;
 
+/// Command line arguments.
 #[derive(Parser, Debug)]
 #[clap(version)]
-struct CliConfig {
-    /// Path to config file.
-    /// Defaults to `~/.config/ormos.yaml`
+pub struct Cli {
+    /// Path to config file, format is picked by extension: `.json`, `.toml` or YAML otherwise.
+    /// `-` reads YAML from standard input, which can't be reloaded. Defaults to
+    /// `~/.config/ormos.yaml`
     #[clap(short, long)]
     file: Option<String>,
+    /// Print names the config routes and where to, then exit
+    #[clap(long)]
+    pub dump_routes: bool,
+    /// Validate config along with files it includes, then exit, non-zero if it is invalid.
+    /// Nothing is bound
+    #[clap(long, alias = "check")]
+    pub check_config: bool,
 }
 
 #[derive(Deserialize)]
 // Parsed config file contents
 struct ConfigFile {
+    /// Files to merge `listen` and `rules` from, see [`ConfigFile::load`]
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
     listen: Vec<Listener>,
+    #[serde(default)]
     rules: Vec<Rule>,
+    /// Named collections of rules listeners could opt into
+    #[serde(default)]
+    rule_sets: HashMap<String, Vec<Rule>>,
+    /// Address to serve prometheus metrics on
+    #[serde(default)]
+    metrics_address: Option<SocketAddr>,
+    /// Cap on connections handled at the same time across all listeners
+    #[serde(default)]
+    max_connections: Option<usize>,
+    /// What to do with connections over the cap
+    #[serde(default)]
+    on_overflow: Overflow,
+    /// Resolve requests queued per resolver stack, ones over it are dropped
+    #[serde(default)]
+    resolver_buffer: Option<usize>,
+    /// Convert international names to punycode before resolving
+    #[serde(default)]
+    idna: bool,
+    /// Write a line per handled connection
+    #[serde(default)]
+    access_log: Option<access_log::Config>,
+    /// Refuse suspicious rule combinations instead of warning about them
+    #[serde(default)]
+    strict: bool,
+    /// Addresses the proxy is reachable at besides its listeners, e.g. behind NAT
+    #[serde(default)]
+    self_addresses: Vec<SocketAddr>,
+    /// Unix socket taking control commands, e.g. to reload rules
+    #[serde(default)]
+    admin_socket: Option<PathBuf>,
+    /// Serve `/healthz` reporting stopped listeners and overloaded resolvers
+    #[serde(default)]
+    health: Option<health::Config>,
+}
+
+/// Contents of a file listed in [`ConfigFile::include`].
+///
+/// Other keys are rejected rather than ignored, they would have no effect.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Included {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    listen: Vec<Listener>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+/// Behavior when [`Config::max_connections`] is reached.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Stop accepting until some connection completes, new ones wait in listen queue
+    #[default]
+    Wait,
+    /// Accept and immediately close the connection
+    Reject,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "lowercase")]
 enum Rule {
     Constant(resolver::constant::Config),
+    #[cfg(feature = "consul")]
+    Consul(resolver::consul::Config),
     Dns(resolver::dns::Config),
+    Env(resolver::env::Config),
     Fallback(resolver::fallback::Config),
     Filter(resolver::filter::Config),
+    Geo(resolver::geo::Config),
+    Hostsfile(resolver::hostsfile::Config),
+    #[cfg(feature = "http-discovery")]
+    #[serde(rename = "http_discovery")]
+    HttpDiscovery(resolver::http_discovery::Config),
+    #[cfg(feature = "kubernetes")]
+    Kubernetes(resolver::kubernetes::Config),
+    Literal(resolver::literal::Config),
     Rewrite(resolver::rewrite::Config),
+    Void(resolver::void::Config),
 }
 
-pub fn load_config() -> Result<Config, anyhow::Error> {
-    let cli = CliConfig::parse();
+/// Reads command line arguments, exits printing usage if they are invalid.
+pub fn cli() -> Cli {
+    Cli::parse()
+}
 
-    let reader = cli
+/// Locates config file, either passed on command line or in home directory.
+pub fn path(cli: &Cli) -> Result<PathBuf, anyhow::Error> {
+    let path = cli
         .file
+        .clone()
         .or_else(|| {
             std::env::var("HOME")
                 .map(|home| format!("{home}/.config/ormos.yaml"))
                 .ok()
         })
-        .map(PathBuf::from)
-        .and_then(|path| File::open(path).ok());
+        .map(PathBuf::from);
+
+    match path {
+        Some(path) => Ok(path),
+        None => anyhow::bail!("Failed to locate config file"),
+    }
+}
+
+/// Reads and validates config file, along with files it includes.
+pub fn load(path: &Path) -> Result<Config, anyhow::Error> {
+    Ok(load_from_path(path)?)
+}
 
-    if reader.is_none() {
-        anyhow::bail!("Failed to open config file");
+/// Summary of [loading][load_from_path] config at `path`, listing warnings, `Err` if it could
+/// not be used.
+pub fn check(path: &Path) -> Result<String, String> {
+    match load_from_path(path) {
+        Ok(config) => {
+            let mut summary = format!("Config {} is valid", path.display());
+            if !config.warnings.is_empty() {
+                summary.push_str(", with warnings:");
+                for warning in config.warnings.iter() {
+                    summary.push_str(&format!("\n  {warning}"));
+                }
+            }
+            Ok(summary)
+        }
+        Err(err) => Err(format!("Config {} is invalid: {err}", path.display())),
     }
+}
 
-    let config_file: ConfigFile = serde_yaml::from_reader(reader.unwrap())?;
+/// Same as [`load`], telling missing, malformed and invalid config apart.
+pub fn load_from_path(path: &Path) -> Result<Config, Error> {
+    let config_file = ConfigFile::load(path)?;
     let config = config_file.validate()?;
 
     debug!("Generated config: {config:?}");
@@ -68,10 +193,241 @@ pub fn load_config() -> Result<Config, anyhow::Error> {
     Ok(config)
 }
 
+/// Config file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// Picks format by file extension, YAML unless it is `.json` or `.toml`.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Format::Json,
+            Some("toml") => Format::Toml,
+            _ => Format::Yaml,
+        }
+    }
+
+    /// Parses `raw` contents of file at `path`.
+    fn parse<T: DeserializeOwned>(self, path: &Path, raw: &str) -> Result<T, Error> {
+        let malformed = |location: Option<Location>, message: String| Error::Parse {
+            path: path.to_owned(),
+            location,
+            message,
+        };
+        match self {
+            Format::Yaml => serde_yaml::from_str(raw).map_err(|err| {
+                let location = err.location().map(|location| Location {
+                    line: location.line(),
+                    column: location.column(),
+                });
+                malformed(location, err.to_string())
+            }),
+            Format::Json => serde_json::from_str(raw).map_err(|err| {
+                // Zero when error is not about a particular spot, e.g. a missing field
+                let location = (err.line() > 0).then(|| Location {
+                    line: err.line(),
+                    column: err.column(),
+                });
+                malformed(location, err.to_string())
+            }),
+            #[cfg(feature = "toml")]
+            Format::Toml => toml::from_str(raw).map_err(|err| {
+                let location = err.line_col().map(|(line, column)| Location {
+                    line: line + 1,
+                    column: column + 1,
+                });
+                malformed(location, err.to_string())
+            }),
+            #[cfg(not(feature = "toml"))]
+            Format::Toml => Err(malformed(
+                None,
+                "TOML config requires ormos built with `toml` feature".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Path standing for standard input.
+pub const STDIN: &str = "-";
+
+/// Reads file in the format picked by its extension, expanding environment variables.
+fn read<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    if path == Path::new(STDIN) {
+        read_from(std::io::stdin().lock(), path)
+    } else {
+        let file = std::fs::File::open(path).map_err(|err| unreadable(path, err))?;
+        read_from(file, path)
+    }
+}
+
+/// Same as [`read`], taking contents from `reader` rather than `path` itself.
+fn read_from<T: DeserializeOwned>(mut reader: impl std::io::Read, path: &Path) -> Result<T, Error> {
+    let mut raw = String::new();
+    reader
+        .read_to_string(&mut raw)
+        .map_err(|err| unreadable(path, err))?;
+    let raw = interpolate(&raw, |var| std::env::var(var).ok()).map_err(|err| Error::Parse {
+        path: path.to_owned(),
+        location: None,
+        message: err.to_string(),
+    })?;
+    Format::from_path(path).parse(path, &raw)
+}
+
+fn unreadable(path: &Path, err: std::io::Error) -> Error {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => Error::NotFound(path.to_owned()),
+        _ => Error::Unreadable {
+            path: path.to_owned(),
+            source: err,
+        },
+    }
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` in raw config text with values from `lookup`.
+///
+/// Comment lines are left as they are, `$${` stands for a literal `${`. References to
+/// anything but a variable name, e.g. `${1}` of a regex replacer, are kept as well.
+fn interpolate(
+    raw: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, anyhow::Error> {
+    let mut expanded = String::with_capacity(raw.len());
+    for line in raw.split_inclusive('\n') {
+        if line.trim_start().starts_with('#') {
+            expanded.push_str(line);
+        } else {
+            interpolate_line(line, &lookup, &mut expanded)?;
+        }
+    }
+    Ok(expanded)
+}
+
+fn interpolate_line(
+    line: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+    expanded: &mut String,
+) -> Result<(), anyhow::Error> {
+    let mut rest = line;
+
+    while let Some(start) = rest.find("${") {
+        let reference = &rest[start + 2..];
+        if let Some(before) = rest[..start].strip_suffix('$') {
+            expanded.push_str(before);
+            expanded.push_str("${");
+            rest = reference;
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let end = match reference.find('}') {
+            Some(end) => end,
+            None => anyhow::bail!("Unterminated `${{` in config"),
+        };
+
+        let (var, default) = match reference[..end].split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (&reference[..end], None),
+        };
+        if !is_var_name(var) {
+            expanded.push_str(&rest[start..start + 2 + end + 1]);
+        } else {
+            match lookup(var).or_else(|| default.map(str::to_owned)) {
+                Some(value) => expanded.push_str(&value),
+                None => anyhow::bail!("Config references unset environment variable `{var}`"),
+            }
+        }
+
+        rest = &reference[end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(())
+}
+
+fn is_var_name(var: &str) -> bool {
+    let mut chars = var.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 impl ConfigFile {
-    fn validate(self) -> Result<Config, anyhow::Error> {
+    /// Reads config file along with every file it includes.
+    ///
+    /// Included files could only have `include`, `listen` and `rules` keys. Their rules are
+    /// appended after rules of the including file, depth first in the order files are listed,
+    /// listeners are appended the same way, but must not reuse an address of another listener.
+    /// Relative paths are resolved against directory of the including file, and a file can't
+    /// include itself, directly or not.
+    ///
+    /// Config read from standard input includes files relative to working directory.
+    fn load(path: &Path) -> Result<Self, Error> {
+        let mut config_file: ConfigFile = read(path)?;
+        let include = std::mem::take(&mut config_file.include);
+        let mut chain = if path == Path::new(STDIN) {
+            Vec::new()
+        } else {
+            vec![canonical(path)?]
+        };
+        config_file.merge(&include, path, &mut chain)?;
+        Ok(config_file)
+    }
+
+    /// Merges files included by `origin`, `chain` holds files currently being included.
+    fn merge(
+        &mut self,
+        include: &[PathBuf],
+        origin: &Path,
+        chain: &mut Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        let dir = origin.parent().unwrap_or_else(|| Path::new(""));
+        for path in include.iter().map(|path| dir.join(path)) {
+            let canonical = canonical(&path)?;
+            if chain.contains(&canonical) {
+                return Err(Error::invalid(
+                    Origin::Include(path),
+                    "includes itself, directly or not",
+                ));
+            }
+
+            let included: Included = read(&path)?;
+            self.rules.extend(included.rules);
+            for listener in included.listen {
+                if self.listen.iter().any(|l| l.address == listener.address) {
+                    return Err(Error::invalid(
+                        Origin::Listener(listener.address),
+                        format!("is already defined, found again in {}", path.display()),
+                    ));
+                }
+                self.listen.push(listener);
+            }
+
+            chain.push(canonical);
+            self.merge(&included.include, &path, chain)?;
+            chain.pop();
+        }
+
+        Ok(())
+    }
+
+    fn validate(self) -> Result<Config, Error> {
         if self.rules.is_empty() {
-            return Err(anyhow::anyhow!("Config must include at least one rule"));
+            return Err(Error::invalid(
+                Origin::Rules,
+                "must include at least one rule",
+            ));
+        }
+
+        if self.resolver_buffer == Some(0) {
+            return Err(Error::invalid(
+                Origin::Config,
+                "resolver buffer must fit at least one request",
+            ));
         }
 
         let listen = if self.listen.is_empty() {
@@ -80,9 +436,350 @@ impl ConfigFile {
             self.listen
         };
 
+        if self.idna && !cfg!(feature = "idna") {
+            return Err(Error::invalid(
+                Origin::Config,
+                "`idna` requires ormos built with `idna` feature",
+            ));
+        }
+
+        if self.admin_socket.is_some() && !cfg!(unix) {
+            return Err(Error::invalid(
+                Origin::Config,
+                "`admin_socket` is only supported on unix",
+            ));
+        }
+
+        if matches!(&self.health, Some(health) if health.window_secs == 0) {
+            return Err(Error::invalid(
+                Origin::Config,
+                "health window must span at least one second",
+            ));
+        }
+
+        let rule_sets = self
+            .rule_sets
+            .iter()
+            .map(|(name, rules)| (Origin::RuleSet(name.clone()), rules));
+        let mut warnings = Vec::new();
+        for (origin, rules) in std::iter::once((Origin::Rules, &self.rules)).chain(rule_sets) {
+            for suspicious in lint(rules) {
+                if self.strict {
+                    return Err(Error::invalid(origin, suspicious));
+                }
+                warn!("{origin}: {suspicious}");
+                warnings.push(format!("{origin}: {suspicious}"));
+            }
+        }
+
+        let layers = Layers::new(&self.rules, self.idna)
+            .map_err(|err| Error::invalid(Origin::Rules, err))?;
+
+        let mut names: Vec<_> = self.rule_sets.keys().collect();
+        names.sort();
+        let mut routes = routes::routes(None, &self.rules);
+        for name in names {
+            routes.extend(routes::routes(Some(name), &self.rule_sets[name]));
+        }
+
+        let mut rule_sets = HashMap::new();
+        for (name, rules) in self.rule_sets.iter() {
+            let origin = || Origin::RuleSet(name.clone());
+            if rules.is_empty() {
+                return Err(Error::invalid(origin(), "must include at least one rule"));
+            }
+            let layers =
+                Layers::new(rules, self.idna).map_err(|err| Error::invalid(origin(), err))?;
+            rule_sets.insert(name.clone(), layers);
+        }
+
+        // Second bind would fail with an OS error naming neither listener
+        let mut bound = HashSet::new();
+        let mut paths = HashSet::new();
+        for listener in listen.iter() {
+            for address in listener.addresses() {
+                if !bound.insert((listener.protocol, address)) {
+                    return Err(Error::invalid(
+                        Origin::Listener(listener.address),
+                        format!("binds {address} taken by another listener"),
+                    ));
+                }
+            }
+            if let Some(path) = listener.path.as_ref() {
+                if !paths.insert(path) {
+                    return Err(Error::invalid(
+                        Origin::Listener(listener.address),
+                        format!("binds {} taken by another listener", path.display()),
+                    ));
+                }
+            }
+        }
+
+        for listener in listen.iter() {
+            if listener.protocol == Protocol::Udp {
+                if let Some(kind) = listener.parsers.iter().find(|kind| !kind.reads_datagrams()) {
+                    return Err(Error::invalid(
+                        Origin::Listener(listener.address),
+                        format!(
+                            "accepts UDP, but `{}` parser only reads TCP traffic",
+                            kind.name()
+                        ),
+                    ));
+                }
+            }
+            if listener.upstream_pool.is_some() && listener.send_proxy_protocol {
+                return Err(Error::invalid(
+                    Origin::Listener(listener.address),
+                    "can't reuse upstream connections while sending PROXY protocol",
+                ));
+            }
+            if listener.tls.is_some()
+                && (listener.protocol == Protocol::Udp
+                    || listener.parsers.iter().any(|kind| *kind != Kind::Tls))
+            {
+                return Err(Error::invalid(
+                    Origin::Listener(listener.address),
+                    "terminates TLS, so it only accepts TCP with `tls` parser",
+                ));
+            }
+            if let Some(size) = listener.copy_buffer_bytes {
+                if size == 0 || size > listener::MAX_COPY_BUFFER_BYTES {
+                    return Err(Error::invalid(
+                        Origin::Listener(listener.address),
+                        format!(
+                            "copy buffer must be between 1 and {} bytes, got {size}",
+                            listener::MAX_COPY_BUFFER_BYTES
+                        ),
+                    ));
+                }
+            }
+            if listener.path.is_some()
+                && (!cfg!(unix)
+                    || listener.protocol == Protocol::Udp
+                    || listener.dual_stack
+                    || listener.reuse_port)
+            {
+                return Err(Error::invalid(
+                    Origin::Listener(listener.address),
+                    "on unix socket only accepts TCP on a single socket, on unix",
+                ));
+            }
+            if listener.max_parse_reads == Some(0) {
+                return Err(Error::invalid(
+                    Origin::Listener(listener.address),
+                    "must allow at least one read of service name",
+                ));
+            }
+            if listener.log_sample_rate == Some(0) {
+                return Err(Error::invalid(
+                    Origin::Listener(listener.address),
+                    "must log one in at least one connection",
+                ));
+            }
+            if listener.max_conns_per_ip_per_sec == Some(0) {
+                return Err(Error::invalid(
+                    Origin::Listener(listener.address),
+                    "must allow at least one connection per client a second",
+                ));
+            }
+            if listener.dual_stack
+                && (!listener.address.ip().is_unspecified() || listener.protocol == Protocol::Udp)
+            {
+                return Err(Error::invalid(
+                    Origin::Listener(listener.address),
+                    "binds both IP families, so it only accepts TCP on wildcard address",
+                ));
+            }
+            if let Some(name) = listener.rules.as_ref() {
+                if !rule_sets.contains_key(name) {
+                    return Err(Error::invalid(
+                        Origin::Listener(listener.address),
+                        format!("references unknown rule set `{name}`"),
+                    ));
+                }
+            }
+            // Without parsers every connection resolves an empty name, only `fallback` takes it
+            if listener.parsers.is_empty() {
+                let rules = listener
+                    .rules
+                    .as_ref()
+                    .and_then(|name| self.rule_sets.get(name))
+                    .unwrap_or(&self.rules);
+                if !rules.iter().any(|rule| matches!(rule, Rule::Fallback(_))) {
+                    return Err(Error::invalid(
+                        Origin::Listener(listener.address),
+                        "has no parsers, so its rules need a `fallback` destination",
+                    ));
+                }
+            }
+        }
+
+        let self_addresses = self
+            .self_addresses
+            .into_iter()
+            .chain(
+                listen
+                    .iter()
+                    // Nothing is bound there, so it is no loop
+                    .filter(|listener| listener.path.is_none())
+                    .flat_map(|listener| listener.addresses()),
+            )
+            .collect();
+
+        Ok(Config {
+            layers,
+            rule_sets,
+            listen,
+            self_addresses,
+            metrics_address: self.metrics_address,
+            max_connections: self.max_connections,
+            on_overflow: self.on_overflow,
+            resolver_buffer: self.resolver_buffer,
+            access_log: self.access_log,
+            admin_socket: self.admin_socket,
+            health: self.health,
+            routes,
+            warnings,
+            _empty: PhantomData,
+        })
+    }
+}
+
+fn canonical(path: &Path) -> Result<PathBuf, Error> {
+    path.canonicalize().map_err(|err| unreadable(path, err))
+}
+
+/// Combinations of rules which are valid, but unlikely to do what was meant.
+fn lint(rules: &[Rule]) -> Vec<String> {
+    let has = |kind: fn(&Rule) -> bool| rules.iter().any(kind);
+    let mut suspicious = Vec::new();
+
+    if has(|rule| matches!(rule, Rule::Fallback(_))) && has(|rule| matches!(rule, Rule::Void(_))) {
+        suspicious.push(
+            "`void` is never reached, `fallback` resolves every name left unresolved".to_owned(),
+        );
+    }
+
+    if has(|rule| matches!(rule, Rule::Fallback(_))) && !has(|rule| matches!(rule, Rule::Filter(_)))
+    {
+        suspicious.push(
+            "`fallback` without `filter` forwards every name, including unknown ones".to_owned(),
+        );
+    }
+
+    if has(|rule| matches!(rule, Rule::Literal(_))) && !has(|rule| matches!(rule, Rule::Filter(_)))
+    {
+        suspicious
+            .push("`literal` without `filter` forwards to any address clients name".to_owned());
+    }
+
+    let network = |rule: &Rule| match rule {
+        Rule::Dns(_) => true,
+        #[cfg(feature = "consul")]
+        Rule::Consul(_) => true,
+        #[cfg(feature = "http-discovery")]
+        Rule::HttpDiscovery(_) => true,
+        #[cfg(feature = "kubernetes")]
+        Rule::Kubernetes(_) => true,
+        _ => false,
+    };
+    if has(|rule| matches!(rule, Rule::Rewrite(_))) && !has(network) {
+        suspicious.push(
+            "`rewrite` output only reaches `kubernetes`, `dns`, `consul` and `http_discovery`, none is configured, so it has no effect"
+                .to_owned(),
+        );
+    } else {
+        suspicious.extend(unroutable_rewrites(rules));
+    }
+
+    suspicious
+}
+
+/// Rewrites into domains no `dns` srv domain or `consul` domain covers, which are then left to
+/// plain DNS lookups. Heuristic, as such domains could well be resolvable.
+fn unroutable_rewrites(rules: &[Rule]) -> Vec<String> {
+    let under = |name: &str, domain: &str| {
+        let domain = domain.trim_matches('.');
+        name == domain || name.ends_with(&format!(".{domain}"))
+    };
+    let routable = |domain: &str| {
+        rules.iter().any(|rule| match rule {
+            Rule::Dns(dns) => dns.srv().iter().any(|srv| under(domain, srv.domain())),
+            #[cfg(feature = "consul")]
+            Rule::Consul(consul) => consul.domain().is_none_or(|suffix| under(domain, suffix)),
+            // Registry is asked for every name
+            #[cfg(feature = "http-discovery")]
+            Rule::HttpDiscovery(_) => true,
+            #[cfg(feature = "kubernetes")]
+            Rule::Kubernetes(kubernetes) => under(kubernetes.name(), domain),
+            _ => false,
+        })
+    };
+
+    rules
+        .iter()
+        .filter_map(|rule| match rule {
+            Rule::Rewrite(rewrite) => rewrite.output_domain(),
+            _ => None,
+        })
+        .filter(|domain| !routable(domain))
+        .map(|domain| {
+            let shadowed = rules.iter().any(|rule| match rule {
+                Rule::Constant(constant) => under(constant.name(), domain),
+                _ => false,
+            });
+            let mut warning = format!(
+                "`rewrite` into `{domain}` matches no `dns` srv domain or `consul` domain, only plain DNS lookups could resolve it"
+            );
+            if shadowed {
+                warning.push_str(", `constant` rules for it see names before `rewrite`");
+            }
+            warning
+        })
+        .collect()
+}
+
+/// Optional layers used to compose a single Resolver stack
+#[derive(Debug, Clone)]
+pub struct Layers {
+    /// Convert names to punycode
+    #[cfg(feature = "idna")]
+    pub idna: Option<resolver::idna::Layer>,
+    /// Consult dns servers  
+    pub dns: Option<resolver::dns::Layer>,
+    /// Consult consul for healthy service instances
+    #[cfg(feature = "consul")]
+    pub consul: Option<resolver::consul::Layer>,
+    /// Ask HTTP service registries
+    #[cfg(feature = "http-discovery")]
+    pub http_discovery: Option<resolver::http_discovery::Layer>,
+    /// Resolve names to ready endpoints of Kubernetes Services
+    #[cfg(feature = "kubernetes")]
+    pub kubernetes: Option<resolver::kubernetes::Layer>,
+    /// Resolve names which are IP addresses to themselves
+    pub literal: Option<resolver::literal::Layer>,
+    /// Translate ports and explicitly specify destination
+    pub override_rules: Option<resolver::constant::Layer>,
+    /// Route clients from configured subnets to preferred addresses
+    pub geo: Option<resolver::geo::Layer>,
+    /// Look names up in hosts files
+    pub hostsfile: Option<resolver::hostsfile::Layer>,
+    /// Read destinations from environment variables
+    pub env: Option<resolver::env::Layer>,
+    /// Patch requested domain name
+    pub rewrite: Option<resolver::rewrite::Layer>,
+    /// Fallback if all else fails
+    pub fallback: Option<resolver::fallback::Layer<resolver::Resolved>>,
+    /// Only allow domains from the explicit list
+    pub filter: Option<resolver::filter::Layer>,
+    /// Log names nothing resolved
+    pub void: Option<resolver::void::Config>,
+}
+
+impl Layers {
+    fn new(rules: &[Rule], idna: bool) -> Result<Self, anyhow::Error> {
         let dns = {
-            let mut dns_rules = self
-                .rules
+            let mut dns_rules = rules
                 .iter()
                 .filter_map(|rule| match rule {
                     Rule::Dns(config) => Some(config),
@@ -97,10 +794,60 @@ impl ConfigFile {
             }
         };
 
+        #[cfg(feature = "consul")]
+        let consul = {
+            let mut consul_rules = rules
+                .iter()
+                .filter_map(|rule| match rule {
+                    Rule::Consul(config) => Some(config),
+                    _ => None,
+                })
+                .peekable();
+
+            if consul_rules.peek().is_none() {
+                None
+            } else {
+                Some(resolver::consul::Layer::new(consul_rules)?)
+            }
+        };
+
+        #[cfg(feature = "http-discovery")]
+        let http_discovery = {
+            let mut http_discovery_rules = rules
+                .iter()
+                .filter_map(|rule| match rule {
+                    Rule::HttpDiscovery(config) => Some(config),
+                    _ => None,
+                })
+                .peekable();
+
+            if http_discovery_rules.peek().is_none() {
+                None
+            } else {
+                Some(resolver::http_discovery::Layer::new(http_discovery_rules)?)
+            }
+        };
+
+        #[cfg(feature = "kubernetes")]
+        let kubernetes = {
+            let mut kubernetes_rules = rules
+                .iter()
+                .filter_map(|rule| match rule {
+                    Rule::Kubernetes(config) => Some(config),
+                    _ => None,
+                })
+                .peekable();
+
+            if kubernetes_rules.peek().is_none() {
+                None
+            } else {
+                Some(resolver::kubernetes::Layer::new(kubernetes_rules)?)
+            }
+        };
+
         // override is a keyword :(
         let override_rules = {
-            let mut override_rules = self
-                .rules
+            let mut override_rules = rules
                 .iter()
                 .filter_map(|rule| match rule {
                     Rule::Constant(config) => Some(config),
@@ -115,9 +862,56 @@ impl ConfigFile {
             }
         };
 
+        let geo = {
+            let mut geo_rules = rules
+                .iter()
+                .filter_map(|rule| match rule {
+                    Rule::Geo(config) => Some(config),
+                    _ => None,
+                })
+                .peekable();
+
+            if geo_rules.peek().is_none() {
+                None
+            } else {
+                Some(resolver::geo::Layer::new(geo_rules))
+            }
+        };
+
+        let hostsfile = {
+            let mut hostsfile_rules = rules
+                .iter()
+                .filter_map(|rule| match rule {
+                    Rule::Hostsfile(config) => Some(config),
+                    _ => None,
+                })
+                .peekable();
+
+            if hostsfile_rules.peek().is_none() {
+                None
+            } else {
+                Some(resolver::hostsfile::Layer::new(hostsfile_rules)?)
+            }
+        };
+
+        let env = {
+            let mut env_rules = rules
+                .iter()
+                .filter_map(|rule| match rule {
+                    Rule::Env(config) => Some(config),
+                    _ => None,
+                })
+                .peekable();
+
+            if env_rules.peek().is_none() {
+                None
+            } else {
+                Some(resolver::env::Layer::new(env_rules))
+            }
+        };
+
         let rewrite = {
-            let mut rewrite_rules = self
-                .rules
+            let mut rewrite_rules = rules
                 .iter()
                 .filter_map(|rule| match rule {
                     Rule::Rewrite(config) => Some(config),
@@ -132,19 +926,28 @@ impl ConfigFile {
             }
         };
 
-        let fallback = self
-            .rules
-            .iter()
-            // One fallback is good enough
-            .find_map(|rule| match rule {
-                Rule::Fallback(config) => Some(config.address()),
+        let fallback = {
+            let mut fallback_rules = rules.iter().filter_map(|rule| match rule {
+                Rule::Fallback(config) => Some(config),
                 _ => None,
-            })
-            .map(resolver::fallback::Layer::new);
+            });
+
+            let fallback = fallback_rules.next();
+            // Only one address could be handed out, extra rules would be silently ignored
+            if let Some(extra) = fallback_rules.next() {
+                anyhow::bail!(
+                    "Only one `fallback` rule is allowed, found another one with address {}",
+                    extra.address()
+                );
+            }
+
+            fallback
+                .map(|config| resolver::Resolved::new("fallback", config.address()))
+                .map(resolver::fallback::Layer::new)
+        };
 
         let filter = {
-            let mut filter_rules = self
-                .rules
+            let mut filter_rules = rules
                 .iter()
                 .filter_map(|rule| match rule {
                     Rule::Filter(config) => Some(config),
@@ -152,6 +955,22 @@ impl ConfigFile {
                 })
                 .peekable();
 
+            let mut sinks = HashSet::new();
+            for config in filter_rules.clone() {
+                if config.on_reject() == resolver::filter::OnReject::Sink {
+                    let Some(sink) = config.sink_address() else {
+                        anyhow::bail!(
+                            "`filter` sending rejected names to sink needs `sink_address`"
+                        );
+                    };
+                    sinks.insert(sink);
+                }
+            }
+            // Rejected names can't tell which rule they were rejected by
+            if sinks.len() > 1 {
+                anyhow::bail!("`filter` rules must agree on a single sink, got {sinks:?}");
+            }
+
             if filter_rules.peek().is_none() {
                 None
             } else {
@@ -159,43 +978,437 @@ impl ConfigFile {
             }
         };
 
-        Ok(Config {
+        let literal = rules.iter().find_map(|rule| match rule {
+            Rule::Literal(config) => Some(resolver::literal::Layer::new(config)),
+            _ => None,
+        });
+
+        let void = {
+            let mut void_rules = rules.iter().filter_map(|rule| match rule {
+                Rule::Void(config) => Some(config),
+                _ => None,
+            });
+
+            let void = void_rules.next().cloned();
+            if void_rules.next().is_some() {
+                anyhow::bail!("Only one `void` rule is allowed");
+            }
+            void
+        };
+
+        #[cfg(not(feature = "idna"))]
+        let _ = idna;
+
+        Ok(Self {
+            #[cfg(feature = "idna")]
+            idna: idna.then(resolver::idna::Layer::default),
             dns,
+            #[cfg(feature = "consul")]
+            consul,
+            #[cfg(feature = "http-discovery")]
+            http_discovery,
+            #[cfg(feature = "kubernetes")]
+            kubernetes,
+            literal,
             override_rules,
+            geo,
+            hostsfile,
+            env,
             rewrite,
             fallback,
             filter,
-            listen,
-            _empty: PhantomData,
+            void,
         })
     }
 }
 
 /// Parsed and initialized configuration of the app.
 ///
-/// Includes optional layers used to compose the Resolver stack
+/// Includes layers used to compose the Resolver stack of every listener.
 #[derive(Debug)]
 pub struct Config {
-    /// Consult dns servers  
-    pub dns: Option<resolver::dns::Layer>,
-    /// Translate ports and explicitly specify destination
-    pub override_rules: Option<resolver::constant::Layer>,
-    /// Patch requested domain name
-    pub rewrite: Option<resolver::rewrite::Layer>,
-    /// Fallback if all else fails
-    pub fallback: Option<resolver::fallback::Layer<SocketAddr>>,
-    /// Only allow domains from the explicit list
-    pub filter: Option<resolver::filter::Layer>,
+    /// Layers built from top-level `rules`, used by listeners without explicit rule set
+    pub layers: Layers,
+    /// Layers built from named `rule_sets`
+    pub rule_sets: HashMap<String, Layers>,
     /// Addresses to bind to
     pub listen: Vec<Listener>,
+    /// Serve prometheus metrics on this address when set
+    pub metrics_address: Option<SocketAddr>,
+    /// Cap on connections handled at the same time
+    pub max_connections: Option<usize>,
+    /// Behavior once the cap is reached
+    pub on_overflow: Overflow,
+    /// Resolve requests queued per resolver stack, default when not set
+    pub resolver_buffer: Option<usize>,
+    /// Access log settings, disabled when not set
+    pub access_log: Option<access_log::Config>,
+    /// Destinations refused to avoid traffic looping back, includes listener addresses
+    pub self_addresses: Vec<SocketAddr>,
+    /// Path of control socket, not served when not set
+    pub admin_socket: Option<PathBuf>,
+    /// Health endpoint settings, not served when not set
+    pub health: Option<health::Config>,
+    /// Names rules route statically, top-level rules first
+    routes: Vec<RouteEntry>,
+    /// Suspicious rule combinations tolerated as config isn't `strict`
+    warnings: Vec<String>,
     // Ensure config could only be generated via [`ConfigFile::validate`]
     _empty: PhantomData<()>,
 }
 
+impl Config {
+    /// Layers to compose the Resolver stack for given listener.
+    pub fn layers(&self, listener: &Listener) -> &Layers {
+        listener
+            .rules
+            .as_ref()
+            .and_then(|name| self.rule_sets.get(name))
+            .unwrap_or(&self.layers)
+    }
+
+    /// Names `constant`, `filter` and `rewrite` rules route, with their static destinations,
+    /// top-level rules first, then rule sets by name.
+    pub fn routing_table(&self) -> &[RouteEntry] {
+        &self.routes
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Kind, Listener};
+    use super::{
+        check, interpolate, lint, load_from_path, read_from, ConfigFile, Error, Format, Kind,
+        Listener, Location, Origin, Protocol, Rule, STDIN,
+    };
     use indoc::indoc;
+    use std::path::{Path, PathBuf};
+    use test_case::test_case;
+
+    const YAML: &str = indoc! {"
+    listen:
+      - address: '127.0.0.1:1234'
+        parsers: [tls]
+    rules:
+      - type: constant
+        name: example.com
+        ips: ['10.0.0.1']
+      - type: constant
+        name: example.com
+        ports: ['8443:443']
+    "};
+
+    fn assert_same_as_yaml(parsed: ConfigFile) {
+        let yaml: ConfigFile = Format::Yaml
+            .parse(Path::new("ormos.yaml"), YAML)
+            .expect("valid yaml");
+        assert_eq!(parsed.listen, yaml.listen);
+        assert_eq!(format!("{:?}", parsed.rules), format!("{:?}", yaml.rules));
+    }
+
+    #[test_case("ormos.yaml", Format::Yaml; "yaml")]
+    #[test_case("ormos.yml", Format::Yaml; "yml")]
+    #[test_case("ormos.json", Format::Json; "json")]
+    #[test_case("ormos.toml", Format::Toml; "toml")]
+    #[test_case("ormos", Format::Yaml; "no extension")]
+    #[test_case("ormos.conf", Format::Yaml; "unknown extension")]
+    fn picks_format_by_extension(path: &str, expected: Format) {
+        assert_eq!(Format::from_path(Path::new(path)), expected);
+    }
+
+    #[test]
+    fn parses_json() {
+        let json = r#"{
+            "listen": [{"address": "127.0.0.1:1234", "parsers": ["tls"]}],
+            "rules": [
+                {"type": "constant", "name": "example.com", "ips": ["10.0.0.1"]},
+                {"type": "constant", "name": "example.com", "ports": ["8443:443"]}
+            ]
+        }"#;
+
+        assert_same_as_yaml(
+            Format::Json
+                .parse(Path::new("ormos.json"), json)
+                .expect("valid json"),
+        );
+    }
+
+    #[test]
+    fn reads_yaml_from_stdin() {
+        assert_eq!(Format::from_path(Path::new(STDIN)), Format::Yaml);
+        assert_same_as_yaml(read_from(YAML.as_bytes(), Path::new(STDIN)).expect("valid yaml"));
+        assert!(matches!(
+            read_from::<ConfigFile>("listen: [".as_bytes(), Path::new(STDIN)),
+            Err(Error::Parse { .. })
+        ));
+    }
+
+    /// Writes `files` into a fresh directory named after `test`, returns path to the first one.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ormos-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("rules")).expect("Creates dir");
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).expect("Writes file");
+        }
+        dir.join(files[0].0)
+    }
+
+    #[test]
+    fn merges_included_files() {
+        let path = write_files(
+            "merges-included",
+            &[
+                (
+                    "ormos.yaml",
+                    indoc! {"
+                    include: [rules/constant.yaml]
+                    listen:
+                      - address: '127.0.0.1:1234'
+                    rules:
+                      - type: filter
+                        names: [example.com]
+                    "},
+                ),
+                (
+                    "rules/constant.yaml",
+                    indoc! {"
+                    include: [env.json]
+                    rules:
+                      - type: constant
+                        name: example.com
+                        ips: ['10.0.0.1']
+                    "},
+                ),
+                (
+                    "rules/env.json",
+                    r#"{
+                        "listen": [{"address": "127.0.0.1:4321"}],
+                        "rules": [{"type": "env", "name": "api.example.com", "var": "API_ADDR"}]
+                    }"#,
+                ),
+            ],
+        );
+
+        let config_file = ConfigFile::load(&path).expect("Loads");
+        let addresses: Vec<_> = config_file.listen.iter().map(|l| l.address).collect();
+        assert_eq!(
+            addresses,
+            vec![
+                "127.0.0.1:1234".parse().unwrap(),
+                "127.0.0.1:4321".parse().unwrap()
+            ]
+        );
+        assert!(matches!(
+            config_file.rules.as_slice(),
+            [Rule::Filter(_), Rule::Constant(_), Rule::Env(_)]
+        ));
+    }
+
+    #[test]
+    fn rejects_include_cycle() {
+        let path = write_files(
+            "include-cycle",
+            &[
+                ("ormos.yaml", "include: [rules/a.yaml]"),
+                ("rules/a.yaml", "include: [b.yaml]"),
+                ("rules/b.yaml", "include: [../ormos.yaml]"),
+            ],
+        );
+
+        assert!(ConfigFile::load(&path).is_err());
+    }
+
+    #[test_case("dns", "dns: []"; "top level only key")]
+    #[test_case("typo", "rule: []"; "misspelled key")]
+    fn rejects_unknown_included_keys(name: &str, contents: &str) {
+        let path = write_files(
+            &format!("include-unknown-{name}"),
+            &[
+                ("ormos.yaml", "include: [rules/extra.yaml]"),
+                ("rules/extra.yaml", contents),
+            ],
+        );
+
+        match load_from_path(&path).expect_err("Included file is invalid") {
+            Error::Parse {
+                path: malformed, ..
+            } => {
+                assert_eq!(malformed, path.with_file_name("rules/extra.yaml"))
+            }
+            err => panic!("Unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn rejects_included_listener_with_same_address() {
+        let path = write_files(
+            "include-listener",
+            &[
+                (
+                    "ormos.yaml",
+                    "{include: [rules/listen.yaml], listen: [{address: '127.0.0.1:1234'}]}",
+                ),
+                ("rules/listen.yaml", "listen: [{address: '127.0.0.1:1234'}]"),
+            ],
+        );
+
+        assert!(ConfigFile::load(&path).is_err());
+    }
+
+    #[test]
+    fn tells_missing_file() {
+        let path = write_files("missing", &[("ormos.yaml", "include: [rules/gone.yaml]")]);
+
+        match load_from_path(&path).expect_err("Include is missing") {
+            Error::NotFound(missing) => assert!(missing.ends_with("rules/gone.yaml")),
+            err => panic!("Unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn tells_unreadable_file() {
+        let path = write_files("unreadable", &[("ormos.yaml", "")]);
+        let dir = path.parent().expect("Has dir");
+
+        let err = load_from_path(dir).expect_err("Directory is no config");
+        assert!(matches!(err, Error::Unreadable { .. }), "{err}");
+    }
+
+    #[test_case("ormos.yaml", "listen:\n  - address: '127.0.0.1:1234'\n    parsers: [nope]\n", 3; "yaml")]
+    #[test_case("ormos.json", "{\n  \"rules\": [\n    {\"type\": \"nope\"}\n  ]\n}", 3; "json")]
+    fn locates_parse_error(name: &str, contents: &str, line: usize) {
+        let path = write_files(&format!("parse-{name}"), &[(name, contents)]);
+
+        match load_from_path(&path).expect_err("Config is malformed") {
+            Error::Parse {
+                path: malformed,
+                location: Some(Location { line: at, .. }),
+                ..
+            } => {
+                assert_eq!(malformed, path);
+                assert_eq!(at, line);
+            }
+            err => panic!("Unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn tells_which_part_is_invalid() {
+        let path = write_files(
+            "invalid",
+            &[(
+                "ormos.yaml",
+                indoc! {"
+                listen:
+                  - address: '127.0.0.1:1234'
+                    max_parse_reads: 0
+                rules:
+                  - type: fallback
+                    address: '127.0.0.1:6666'
+                "},
+            )],
+        );
+
+        match load_from_path(&path).expect_err("Listener is invalid") {
+            Error::Invalid { origin, .. } => {
+                assert_eq!(origin, Origin::Listener("127.0.0.1:1234".parse().unwrap()))
+            }
+            err => panic!("Unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn loads_sample_config() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../sample_config.yml");
+        load_from_path(&path).expect("Sample config is valid");
+    }
+
+    #[test]
+    fn checks_config() {
+        let good = write_files(
+            "check-good",
+            &[(
+                "ormos.yaml",
+                indoc! {"
+                listen:
+                  - address: '127.0.0.1:1234'
+                rules:
+                  - type: fallback
+                    address: '127.0.0.1:6666'
+                "},
+            )],
+        );
+        let bad = write_files(
+            "check-bad",
+            &[(
+                "ormos.yaml",
+                indoc! {"
+                listen:
+                  - address: '127.0.0.1:1234'
+                    rules: missing
+                rules:
+                  - type: fallback
+                    address: '127.0.0.1:6666'
+                "},
+            )],
+        );
+
+        let summary = check(&good).expect("Config is valid");
+        assert!(summary.contains("is valid, with warnings:"), "{summary}");
+        assert!(summary.contains("`fallback` without `filter`"), "{summary}");
+
+        let summary = check(&bad).expect_err("Config is invalid");
+        assert!(summary.contains("is invalid"), "{summary}");
+        assert!(summary.contains("unknown rule set `missing`"), "{summary}");
+    }
+
+    fn lookup(var: &str) -> Option<String> {
+        (var == "BIND_ADDR").then(|| "0.0.0.0:443".to_owned())
+    }
+
+    #[test_case("address: ${BIND_ADDR}", "address: 0.0.0.0:443"; "set")]
+    #[test_case("dns: ${DNS_SERVER:-8.8.8.8:53}", "dns: 8.8.8.8:53"; "unset with default")]
+    #[test_case("address: ${BIND_ADDR:-127.0.0.1:8314}", "address: 0.0.0.0:443"; "set with default")]
+    #[test_case("${BIND_ADDR}, ${BIND_ADDR}", "0.0.0.0:443, 0.0.0.0:443"; "repeated")]
+    #[test_case("price: $5", "price: $5"; "no reference")]
+    #[test_case("# set ${DNS_SERVER}\nx: 1", "# set ${DNS_SERVER}\nx: 1"; "comment")]
+    #[test_case("replacer: '$${svc}.consul'", "replacer: '${svc}.consul'"; "escaped")]
+    #[test_case("replacer: '${1}.consul'", "replacer: '${1}.consul'"; "not a variable")]
+    fn interpolates_env_vars(raw: &str, expected: &str) {
+        assert_eq!(interpolate(raw, lookup).expect("Expands"), expected);
+    }
+
+    #[test_case("dns: ${DNS_SERVER}"; "unset")]
+    #[test_case("address: ${BIND_ADDR"; "unterminated")]
+    fn rejects_unexpandable_references(raw: &str) {
+        assert!(interpolate(raw, lookup).is_err());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn parses_toml() {
+        let toml = indoc! {r#"
+        [[listen]]
+        address = "127.0.0.1:1234"
+        parsers = ["tls"]
+
+        [[rules]]
+        type = "constant"
+        name = "example.com"
+        ips = ["10.0.0.1"]
+
+        [[rules]]
+        type = "constant"
+        name = "example.com"
+        ports = ["8443:443"]
+        "#};
+
+        assert_same_as_yaml(
+            Format::Toml
+                .parse(Path::new("ormos.toml"), toml)
+                .expect("valid toml"),
+        );
+    }
 
     #[test]
     fn listener_deserializes() {
@@ -215,8 +1428,443 @@ mod test {
             parsed[0],
             Listener {
                 address: "127.0.0.1:1234".parse().expect("valid address"),
-                parsers: vec![Kind::H1, Kind::Tls]
+                parsers: vec![Kind::H1, Kind::Tls],
+                ..Listener::default()
             }
         )
     }
+
+    #[test]
+    fn listener_uses_named_rule_set() {
+        let yaml = indoc! {"
+        ---
+        listen:
+          - address: '127.0.0.1:1234'
+          - address: '127.0.0.1:3333'
+            rules: public
+        rules:
+          - type: fallback
+            address: '127.0.0.1:6666'
+        rule_sets:
+          public:
+            - type: constant
+              name: example.com
+              ips: ['127.0.0.1']
+        "};
+
+        let parsed: ConfigFile = serde_yaml::from_str(yaml).expect("valid config");
+        let config = parsed.validate().expect("valid rules");
+
+        let internal = config.layers(&config.listen[0]);
+        assert!(internal.fallback.is_some());
+        assert!(internal.override_rules.is_none());
+
+        let public = config.layers(&config.listen[1]);
+        assert!(public.fallback.is_none());
+        assert!(public.override_rules.is_some());
+    }
+
+    #[test]
+    fn rejects_unknown_rule_set() {
+        let yaml = indoc! {"
+        ---
+        listen:
+          - address: '127.0.0.1:1234'
+            rules: missing
+        rules:
+          - type: fallback
+            address: '127.0.0.1:6666'
+        "};
+
+        let parsed: ConfigFile = serde_yaml::from_str(yaml).expect("valid config");
+        assert!(parsed.validate().is_err());
+    }
+
+    #[test]
+    fn given_strict_rejects_fallback_without_filter() {
+        let yaml = indoc! {"
+        ---
+        listen: []
+        strict: true
+        rules:
+          - type: fallback
+            address: '127.0.0.1:6666'
+        "};
+
+        let parsed: ConfigFile = serde_yaml::from_str(yaml).expect("valid config");
+        assert!(parsed.validate().is_err());
+    }
+
+    #[test]
+    fn lints_suspicious_rules() {
+        let rules = |yaml: &str| -> Vec<Rule> { serde_yaml::from_str(yaml).expect("valid rules") };
+
+        let fallback_only = rules(indoc! {"
+        - type: fallback
+          address: '127.0.0.1:6666'
+        "});
+        assert_eq!(lint(&fallback_only).len(), 1);
+
+        let filtered_fallback = rules(indoc! {"
+        - type: fallback
+          address: '127.0.0.1:6666'
+        - type: filter
+          names: ['example.com']
+        "});
+        assert!(lint(&filtered_fallback).is_empty());
+
+        let unreachable_void = rules(indoc! {"
+        - type: fallback
+          address: '127.0.0.1:6666'
+        - type: filter
+          names: ['example.com']
+        - type: void
+          level: info
+        "});
+        assert_eq!(lint(&unreachable_void).len(), 1);
+
+        let rewrite_without_dns = rules(indoc! {"
+        - type: rewrite
+          matcher: '^(.*)\\.internal$'
+          replacer: '$1.example.com'
+        - type: constant
+          name: api.example.com
+          ips: ['10.0.0.1']
+        "});
+        assert_eq!(lint(&rewrite_without_dns).len(), 1);
+
+        let unfiltered_literal = rules(indoc! {"
+        - type: literal
+        "});
+        assert_eq!(lint(&unfiltered_literal).len(), 1);
+    }
+
+    #[test_case("$svc.service.consul", 0; "routed by srv lookups")]
+    #[test_case("$svc.internal", 1; "left to plain lookups")]
+    #[test_case("$svc.example.com", 1; "shadowed by constant rule")]
+    #[test_case("$svc", 0; "unknown domain")]
+    fn warns_about_unroutable_rewrites(replacer: &str, warnings: usize) {
+        let yaml = format!(
+            indoc! {"
+            - type: rewrite
+              matcher: '^(?P<svc>[a-z]+)\\.com$'
+              replacer: '{}'
+            - type: constant
+              name: api.example.com
+              ips: ['10.0.0.1']
+            - type: dns
+              address: '8.8.8.8:53'
+              srv: [consul]
+            "},
+            replacer
+        );
+        let rules: Vec<Rule> = serde_yaml::from_str(&yaml).expect("valid rules");
+
+        let lints = lint(&rules);
+        assert_eq!(lints.len(), warnings, "{lints:?}");
+        if replacer.ends_with("example.com") {
+            assert!(lints[0].contains("`constant`"));
+        }
+    }
+
+    #[test_case("[tls]", "tcp", true; "tls parser")]
+    #[test_case("[]", "tcp", true; "without parsers")]
+    #[test_case("[tls, http/1]", "tcp", false; "non tls parser")]
+    #[test_case("[tls]", "udp", false; "over udp")]
+    fn validates_tls_termination(parsers: &str, protocol: &str, valid: bool) {
+        let yaml = format!(
+            indoc! {"
+            listen:
+              - address: '127.0.0.1:8443'
+                parsers: {}
+                protocol: {}
+                tls:
+                  certs:
+                    - sni: example.com
+                      cert: /etc/ormos/example.com.pem
+                      key: /etc/ormos/example.com.key
+            rules:
+              - type: fallback
+                address: '127.0.0.1:8080'
+            "},
+            parsers, protocol
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test_case("0.0.0.0:443", "tcp", true; "ipv4 wildcard")]
+    #[test_case("'[::]:443'", "tcp", true; "ipv6 wildcard")]
+    #[test_case("127.0.0.1:443", "tcp", false; "specific address")]
+    #[test_case("0.0.0.0:443", "udp", false; "over udp")]
+    fn validates_dual_stack(address: &str, protocol: &str, valid: bool) {
+        let yaml = format!(
+            indoc! {"
+            listen:
+              - address: {}
+                protocol: {}
+                dual_stack: true
+            rules:
+              - type: fallback
+                address: '127.0.0.1:8080'
+            "},
+            address, protocol
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        match config.validate() {
+            Ok(config) => {
+                assert!(valid);
+                assert!(config.self_addresses.contains(&"[::]:443".parse().unwrap()));
+            }
+            Err(_) => assert!(!valid),
+        }
+    }
+
+    #[test_case("127.0.0.1:443", "tcp", "127.0.0.1:443", "tcp", false; "same address")]
+    #[test_case("127.0.0.1:443", "tcp", "127.0.0.1:443", "udp", true; "other protocol")]
+    #[test_case("127.0.0.1:443", "tcp", "127.0.0.1:8443", "tcp", true; "other port")]
+    #[test_case("'[::]:443'", "tcp", "0.0.0.0:443", "tcp", false; "covered by dual stack")]
+    fn validates_listener_addresses(
+        first: &str,
+        first_protocol: &str,
+        second: &str,
+        second_protocol: &str,
+        valid: bool,
+    ) {
+        let yaml = format!(
+            indoc! {"
+            listen:
+              - address: {}
+                protocol: {}
+                dual_stack: {}
+                parsers: []
+              - address: {}
+                protocol: {}
+                parsers: []
+            rules:
+              - type: fallback
+                address: '127.0.0.1:8080'
+            "},
+            first,
+            first_protocol,
+            first.contains("::"),
+            second,
+            second_protocol
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test_case("[]", true; "without parsers")]
+    #[test_case("[tls]", false; "tls parser")]
+    #[test_case("[ssh]", false; "ssh parser")]
+    fn validates_udp_parsers(parsers: &str, valid: bool) {
+        let yaml = format!(
+            indoc! {"
+            listen:
+              - address: '127.0.0.1:5353'
+                protocol: udp
+                parsers: {}
+            rules:
+              - type: fallback
+                address: '127.0.0.1:53'
+            "},
+            parsers
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test_case(65536, true; "reasonable")]
+    #[test_case(0, false; "empty")]
+    #[test_case(64 * 1024 * 1024, false; "too large")]
+    fn validates_copy_buffer(size: usize, valid: bool) {
+        let yaml = format!(
+            indoc! {"
+            listen:
+              - address: '127.0.0.1:1234'
+                copy_buffer_bytes: {}
+            rules:
+              - type: fallback
+                address: '127.0.0.1:8080'
+            "},
+            size
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test_case("[h1, h1]", vec![Kind::H1]; "repeated")]
+    #[test_case("[tls, h1, http/1, tls]", vec![Kind::Tls, Kind::H1]; "repeated under alias")]
+    fn deduplicates_parsers(parsers: &str, expected: Vec<Kind>) {
+        let yaml = format!("{{address: '127.0.0.1:1234', parsers: {parsers}}}");
+        let listener: Listener = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert_eq!(listener.parsers, expected);
+    }
+
+    #[test_case("[]", "fallback", true; "with fallback")]
+    #[test_case("[]", "dns", false; "without fallback")]
+    #[test_case("[h1]", "dns", true; "with parsers")]
+    fn validates_listener_without_parsers(parsers: &str, rule: &str, valid: bool) {
+        let yaml = format!(
+            indoc! {"
+            listen:
+              - address: '127.0.0.1:1234'
+                parsers: {}
+                rules: internal
+            rules:
+              - type: fallback
+                address: '127.0.0.1:8080'
+            rule_sets:
+              internal:
+                - type: {}
+                  address: '127.0.0.1:53'
+            "},
+            parsers, rule
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test]
+    fn deserializes_unix_listener() {
+        let yaml = indoc! {"
+        listen:
+          - address: '127.0.0.1:8080'
+            path: /run/ormos/app.sock
+          - address: '127.0.0.1:8443'
+        rules:
+          - type: fallback
+            address: '127.0.0.1:8080'
+        "};
+        let config = serde_yaml::from_str::<ConfigFile>(yaml)
+            .expect("valid yaml")
+            .validate()
+            .expect("valid config");
+
+        assert_eq!(
+            config.listen[0].path,
+            Some(PathBuf::from("/run/ormos/app.sock"))
+        );
+        assert_eq!(config.listen[1].path, None);
+        // Upstream may well sit at the address standing for unix socket
+        assert!(!config
+            .self_addresses
+            .contains(&"127.0.0.1:8080".parse().unwrap()));
+    }
+
+    #[test_case("protocol: udp"; "over udp")]
+    #[test_case("dual_stack: true"; "dual stack")]
+    #[test_case("reuse_port: true"; "reuse port")]
+    fn rejects_unix_listener_options(option: &str) {
+        let yaml = format!(
+            indoc! {"
+            listen:
+              - address: '0.0.0.0:8080'
+                path: /run/ormos/app.sock
+                {}
+            rules:
+              - type: fallback
+                address: '127.0.0.1:8080'
+            "},
+            option
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test_case(0, false; "zero")]
+    #[test_case(10, true; "some")]
+    fn validates_per_client_rate(rate: u32, valid: bool) {
+        let yaml = format!(
+            indoc! {"
+            listen:
+              - address: '127.0.0.1:1234'
+                max_conns_per_ip_per_sec: {}
+            rules:
+              - type: fallback
+                address: '127.0.0.1:6666'
+            "},
+            rate
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test]
+    fn rejects_multiple_fallbacks() {
+        let yaml = indoc! {"
+        listen: []
+        rules:
+          - type: fallback
+            address: '127.0.0.1:6666'
+          - type: fallback
+            address: '127.0.0.1:7777'
+        "};
+        let config: ConfigFile = serde_yaml::from_str(yaml).expect("valid yaml");
+
+        let error = config.validate().expect_err("Two fallbacks are rejected");
+        assert!(error.to_string().contains("127.0.0.1:7777"), "{error}");
+    }
+
+    #[test_case("on_reject: sink", false ; "sink without address")]
+    #[test_case("on_reject: sink, sink_address: '127.0.0.1:6667'", true ; "sink")]
+    #[test_case("sink_address: '127.0.0.1:6667'", true ; "address without sink")]
+    fn validates_filter_sink(options: &str, valid: bool) {
+        let yaml = format!(
+            indoc! {"
+            listen: []
+            rules:
+              - type: filter
+                names: [example.com]
+              - {{type: filter, names: [example.org], {}}}
+            "},
+            options
+        );
+        let config: ConfigFile = serde_yaml::from_str(&yaml).expect("valid yaml");
+
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test]
+    fn rejects_upstream_pool_with_proxy_protocol() {
+        let yaml = indoc! {"
+        ---
+        listen:
+          - address: '127.0.0.1:1234'
+            send_proxy_protocol: true
+            upstream_pool:
+              max_idle_secs: 30
+              max_per_host: 8
+        rules:
+          - type: fallback
+            address: '127.0.0.1:6666'
+        "};
+
+        let parsed: ConfigFile = serde_yaml::from_str(yaml).expect("valid config");
+        assert!(parsed.validate().is_err());
+    }
+
+    #[test]
+    fn listener_protocol_deserializes() {
+        let yaml = indoc! {"
+        ---
+        - address: '127.0.0.1:1234'
+        - address: '127.0.0.1:5353'
+          protocol: udp
+        "};
+
+        let parsed: Vec<Listener> = serde_yaml::from_str(yaml).expect("valid listeners");
+        assert_eq!(parsed[0].protocol, Protocol::Tcp);
+        assert_eq!(parsed[1].protocol, Protocol::Udp);
+    }
 }
@@ -6,6 +6,28 @@ use serde::Deserialize;
 pub enum Kind {
     H1,
     Tls,
+    Smtp,
+    Ssh,
+}
+
+impl Kind {
+    /// Name the parser is configured by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::H1 => "h1",
+            Kind::Tls => "tls",
+            Kind::Smtp => "smtp",
+            Kind::Ssh => "ssh",
+        }
+    }
+
+    /// Whether parser could read a name from a datagram, so far every one reads a handshake
+    /// of a TCP session.
+    pub fn reads_datagrams(&self) -> bool {
+        match self {
+            Kind::H1 | Kind::Tls | Kind::Smtp | Kind::Ssh => false,
+        }
+    }
 }
 
 struct Visitor;
@@ -47,22 +69,25 @@ impl FromStr for Kind {
         match s {
             "h1" | "http/1" => Ok(Kind::H1),
             "tls" => Ok(Kind::Tls),
+            "smtp" => Ok(Kind::Smtp),
+            "ssh" => Ok(Kind::Ssh),
             _ => anyhow::bail!("Invalid parser kind"),
         }
     }
 }
 
-impl From<&Kind>
-    for Box<
-        dyn rpx::parser::Parser<String, Box<dyn std::error::Error + Send + 'static>>
-            + Send
-            + 'static,
-    >
-{
+/// Parser as [`rpx::forward`] expects it.
+pub type BoxedParser = Box<
+    dyn rpx::parser::Parser<String, Box<dyn std::error::Error + Send + 'static>> + Send + 'static,
+>;
+
+impl From<&Kind> for BoxedParser {
     fn from(kind: &Kind) -> Self {
         match kind {
             Kind::H1 => Box::<rpx::parser::http::Hostname>::default(),
             Kind::Tls => Box::<rpx::parser::tls::ServiceName>::default(),
+            Kind::Smtp => Box::<rpx::parser::smtp::Hostname>::default(),
+            Kind::Ssh => Box::<rpx::parser::ssh::SoftwareVersion>::default(),
         }
     }
 }
@@ -0,0 +1,205 @@
+use super::Rule;
+use rpx::resolver::constant;
+use std::{fmt, net::IpAddr, path::PathBuf};
+
+/// Name config routes, as far as `constant`, `filter` and `rewrite` rules tell without
+/// resolving anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    /// Named rule set the route belongs to, top-level `rules` when `None`
+    pub rule_set: Option<String>,
+    /// Name as rules spell it: exact or wildcard name, domain `filter` allows names under,
+    /// or expression of `filter` and `rewrite`
+    pub name: String,
+    pub destination: Destination,
+    /// Listener ports remapped to other destination ports, the rest are kept
+    pub ports: Vec<(u16, u16)>,
+}
+
+/// Where a [route][RouteEntry] leads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    /// Fixed addresses of `constant` rule
+    Addresses(Vec<IpAddr>),
+    /// Unix socket of `constant` rule
+    Socket(PathBuf),
+    /// Renamed by `rewrite` into the replacer, then routed as the new name
+    Rewrite(String),
+    /// Looked up by `dns` rules
+    Dns,
+    /// Left to rules resolving names as they come, e.g. `consul` or `fallback`
+    Dynamic,
+}
+
+impl fmt::Display for RouteEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(rule_set) = self.rule_set.as_ref() {
+            write!(f, "[{rule_set}] ")?;
+        }
+        write!(f, "{} -> {}", self.name, self.destination)?;
+        if !self.ports.is_empty() {
+            let ports: Vec<_> = self
+                .ports
+                .iter()
+                .map(|(from, to)| format!("{from}:{to}"))
+                .collect();
+            write!(f, " (ports {})", ports.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Addresses(ips) => {
+                let ips: Vec<_> = ips.iter().map(ToString::to_string).collect();
+                write!(f, "{}", ips.join(", "))
+            }
+            Destination::Socket(path) => write!(f, "unix:{}", path.display()),
+            Destination::Rewrite(replacer) => write!(f, "rewritten to `{replacer}`"),
+            Destination::Dns => write!(f, "via DNS"),
+            Destination::Dynamic => write!(f, "resolved at runtime"),
+        }
+    }
+}
+
+/// Routes of `rules` in the order rules first mention names.
+///
+/// Follows precedence of the `constant` layer: socket wins over addresses of the same name,
+/// later addresses replace earlier ones.
+pub(super) fn routes(rule_set: Option<&str>, rules: &[Rule]) -> Vec<RouteEntry> {
+    let mut routes: Vec<Route> = Vec::new();
+    for rule in rules {
+        match rule {
+            Rule::Constant(constant::Config::Ip { name, ips, .. }) => {
+                let (_, destination, _) = route(&mut routes, name);
+                if !matches!(destination, Some(Destination::Socket(_))) {
+                    *destination = Some(Destination::Addresses(ips.clone()));
+                }
+            }
+            Rule::Constant(constant::Config::Path { name, path, .. }) => {
+                route(&mut routes, name).1 = Some(Destination::Socket(path.clone()));
+            }
+            Rule::Constant(constant::Config::Port { name, ports, .. }) => {
+                let ports = ports.iter().map(|binding| (binding.0, binding.1));
+                route(&mut routes, name).2.extend(ports);
+            }
+            Rule::Filter(filter) => {
+                let patterns = filter.patterns().iter().map(|pattern| pattern.as_str());
+                for name in filter.names().iter().map(String::as_str).chain(patterns) {
+                    route(&mut routes, name);
+                }
+            }
+            Rule::Rewrite(rewrite) => {
+                let replacer = rewrite.replacer().to_owned();
+                route(&mut routes, rewrite.matcher().as_str()).1 =
+                    Some(Destination::Rewrite(replacer));
+            }
+            _ => {}
+        }
+    }
+
+    let dns = rules.iter().any(|rule| matches!(rule, Rule::Dns(_)));
+    routes
+        .into_iter()
+        .map(|(name, destination, ports)| RouteEntry {
+            rule_set: rule_set.map(str::to_owned),
+            name,
+            destination: destination.unwrap_or(if dns {
+                Destination::Dns
+            } else {
+                Destination::Dynamic
+            }),
+            ports,
+        })
+        .collect()
+}
+
+type Route = (String, Option<Destination>, Vec<(u16, u16)>);
+
+/// Route of `name`, added with nothing known about it on first mention.
+fn route<'r>(routes: &'r mut Vec<Route>, name: &str) -> &'r mut Route {
+    let index = match routes.iter().position(|(known, ..)| known == name) {
+        Some(index) => index,
+        None => {
+            routes.push((name.to_owned(), None, Vec::new()));
+            routes.len() - 1
+        }
+    };
+    &mut routes[index]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{routes, Destination, RouteEntry};
+    use crate::config::Rule;
+    use indoc::indoc;
+
+    #[test]
+    fn lists_static_routes() {
+        let rules: Vec<Rule> = serde_yaml::from_str(indoc! {r#"
+        - type: filter
+          names: [example.com, internal]
+          patterns: ['^api-[0-9]+\.example\.org$']
+        - type: rewrite
+          matcher: '^(?P<svc>[a-z]+)\.internal$'
+          replacer: '$svc.consul'
+        - type: constant
+          name: example.com
+          ips: ['10.0.0.1']
+        - type: constant
+          name: example.com
+          ports: ['8443:443']
+        - type: constant
+          name: app.example.com
+          path: /run/app.sock
+        - type: constant
+          name: app.example.com
+          ips: ['10.0.0.2']
+        - type: dns
+          address: '10.0.0.53:53'
+        "#})
+        .expect("valid rules");
+
+        let routes = routes(Some("public"), &rules);
+        let table: Vec<_> = routes.iter().map(ToString::to_string).collect();
+
+        assert_eq!(
+            table,
+            [
+                "[public] example.com -> 10.0.0.1 (ports 8443:443)",
+                "[public] internal -> via DNS",
+                r"[public] ^api-[0-9]+\.example\.org$ -> via DNS",
+                r"[public] ^(?P<svc>[a-z]+)\.internal$ -> rewritten to `$svc.consul`",
+                "[public] app.example.com -> unix:/run/app.sock",
+            ]
+        );
+        assert_eq!(
+            routes[0],
+            RouteEntry {
+                rule_set: Some("public".to_owned()),
+                name: "example.com".to_owned(),
+                destination: Destination::Addresses(vec![[10, 0, 0, 1].into()]),
+                ports: vec![(8443, 443)],
+            }
+        );
+    }
+
+    #[test]
+    fn leaves_names_without_dns_to_runtime() {
+        let rules: Vec<Rule> = serde_yaml::from_str(indoc! {"
+        - type: filter
+          names: [example.com]
+        - type: fallback
+          address: '127.0.0.1:6666'
+        "})
+        .expect("valid rules");
+
+        let routes = routes(None, &rules);
+
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].destination, Destination::Dynamic);
+        assert_eq!(routes[0].to_string(), "example.com -> resolved at runtime");
+    }
+}
@@ -0,0 +1,110 @@
+//! Global cap on connections handled at the same time.
+use crate::config::Overflow;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Shared between listeners, permit is held by `forward` task until it completes.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimit {
+    semaphore: Arc<Semaphore>,
+    on_overflow: Overflow,
+}
+
+impl ConnectionLimit {
+    pub fn new(max_connections: usize, on_overflow: Overflow) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_connections)),
+            on_overflow,
+        }
+    }
+
+    /// Returns permit for a new connection or `None` if it should be rejected.
+    ///
+    /// Permit is released when dropped, which includes unwinding of panicking task.
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match self.on_overflow {
+            Overflow::Wait => self.semaphore.clone().acquire_owned().await.ok(),
+            Overflow::Reject => self.semaphore.clone().try_acquire_owned().ok(),
+        }
+    }
+
+    /// Waits until a permit is available ahead of accepting a connection, if connections over
+    /// the limit are to wait. Returns right away if they are rejected instead, as telling them
+    /// so takes accepting them first.
+    ///
+    /// Permit is not kept, accept loops idling in `accept` would hold slots of connections
+    /// otherwise. Several loops could see the same permit available, connections they accept
+    /// then wait in [`acquire`][ConnectionLimit::acquire].
+    ///
+    /// Waiting task is woken by semaphore once some connection completes, accept loop holding
+    /// off this way neither spins nor pulls connections off the listen queue meanwhile.
+    pub async fn available(&self) {
+        if self.on_overflow == Overflow::Wait {
+            let _ = self.semaphore.acquire().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConnectionLimit;
+    use crate::config::Overflow;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn rejects_over_limit() {
+        let limit = ConnectionLimit::new(1, Overflow::Reject);
+
+        let permit = limit.acquire().await;
+        assert!(permit.is_some());
+        assert!(limit.acquire().await.is_none());
+
+        drop(permit);
+        assert!(limit.acquire().await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_available_permit() {
+        let limit = ConnectionLimit::new(1, Overflow::Wait);
+        let permit = limit.acquire().await.expect("available permit");
+        let available = tokio::time::timeout(Duration::from_secs(1), limit.available());
+        assert!(available.await.is_err(), "Available over the limit");
+
+        drop(permit);
+        limit.available().await;
+        assert!(limit.acquire().await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_accept_loops_hold_no_permits() {
+        let limit = ConnectionLimit::new(1, Overflow::Wait);
+        // More accept loops than permits, all of them idle in `accept`
+        let (_connection, accepted) = tokio::sync::watch::channel(());
+        for _ in 0..4 {
+            let limit = limit.clone();
+            let mut accepted = accepted.clone();
+            tokio::spawn(async move {
+                limit.available().await;
+                let _ = accepted.changed().await;
+            });
+        }
+        tokio::task::yield_now().await;
+
+        let acquire = tokio::time::timeout(Duration::from_secs(1), limit.acquire());
+        assert!(acquire.await.expect("Permit held by idle loop").is_some());
+    }
+
+    #[tokio::test]
+    async fn panicking_task_releases_permit() {
+        let limit = ConnectionLimit::new(1, Overflow::Reject);
+
+        let permit = limit.acquire().await.expect("available permit");
+        let task = tokio::spawn(async move {
+            let _permit = permit;
+            panic!("forward panicked");
+        });
+        assert!(task.await.is_err());
+
+        assert!(limit.acquire().await.is_some());
+    }
+}
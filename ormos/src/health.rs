@@ -0,0 +1,248 @@
+//! Health endpoint over plain HTTP, e.g. for liveness and readiness probes.
+//!
+//! `GET /healthz` answers `200` while every listener is accepting and resolver stacks keep up,
+//! `503` otherwise, with the reason in the body.
+//!
+//! Resolver stacks queue requests in a bounded buffer, with load shedding in front of it, so
+//! asking a stack whether it is ready always succeeds and occupancy of the buffer is private to
+//! tower. Pressure is sampled from outcomes instead: every request shed as
+//! [`Overloaded`][rpx::Error::Overloaded] is [counted][shed], the count is sampled once a second
+//! and the stack is considered saturated while more than `max_shed` requests were shed within
+//! the last `window_secs`.
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+use tracing::{info, warn};
+
+/// Longest request line read, the rest of the request is ignored.
+const MAX_REQUEST_LINE: u64 = 1024;
+
+static SHED: AtomicU64 = AtomicU64::new(0);
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Address to serve `/healthz` on
+    pub address: SocketAddr,
+    /// Shed resolve requests tolerated within the window before reporting unhealthy
+    #[serde(default)]
+    pub max_shed: u64,
+    /// Length of the window shed requests are counted in
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+}
+
+fn default_window_secs() -> u64 {
+    10
+}
+
+/// Records resolve request shed because resolver buffer was full.
+pub fn shed() {
+    SHED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records accept loop of a listener that stopped taking connections.
+pub fn stopped() {
+    STOPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Totals of shed requests taken once a second, oldest first.
+#[derive(Debug)]
+struct Pressure {
+    samples: VecDeque<u64>,
+    window: usize,
+}
+
+impl Pressure {
+    fn new(window_secs: u64) -> Self {
+        // One more sample than seconds, so differences span the whole window
+        let window = window_secs as usize + 1;
+        Self {
+            samples: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    fn sample(&mut self, total: u64) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(total);
+    }
+
+    /// Requests shed within the window.
+    fn shed(&self) -> u64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(oldest), Some(latest)) => latest - oldest,
+            _ => 0,
+        }
+    }
+}
+
+pub struct Health {
+    config: Config,
+    pressure: Arc<Mutex<Pressure>>,
+}
+
+impl Health {
+    pub fn new(config: Config) -> Self {
+        let pressure = Arc::new(Mutex::new(Pressure::new(config.window_secs)));
+        Self { config, pressure }
+    }
+
+    /// Samples shed requests and answers probes on configured address.
+    pub async fn serve(self) -> Result<(), anyhow::Error> {
+        let listener = TcpListener::bind(self.config.address).await?;
+        info!("Serving health on {}", self.config.address);
+
+        tokio::spawn({
+            let pressure = Arc::downgrade(&self.pressure);
+            async move {
+                let mut ticks = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    ticks.tick().await;
+                    match pressure.upgrade() {
+                        Some(pressure) => pressure
+                            .lock()
+                            .expect("Pressure lock poisoned")
+                            .sample(SHED.load(Ordering::Relaxed)),
+                        None => return,
+                    }
+                }
+            }
+        });
+
+        let health = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let health = health.clone();
+            tokio::spawn(async move {
+                if let Err(err) = health.answer(stream).await {
+                    warn!("Failed to answer health probe: {err}");
+                }
+            });
+        }
+    }
+
+    async fn answer<S>(&self, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (read, mut write) = io::split(stream);
+        let mut line = String::new();
+        BufReader::new(read.take(MAX_REQUEST_LINE))
+            .read_line(&mut line)
+            .await?;
+
+        let (status, body) = match line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+            ["GET", "/healthz"] => match self.status() {
+                Ok(()) => ("200 OK", "ok".to_owned()),
+                Err(reason) => ("503 Service Unavailable", reason),
+            },
+            ["GET", _] => ("404 Not Found", "not found".to_owned()),
+            _ => ("405 Method Not Allowed", "only GET is supported".to_owned()),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}\n",
+            body.len() + 1
+        );
+        write.write_all(response.as_bytes()).await?;
+        write.shutdown().await
+    }
+
+    /// `Err` with the reason when unhealthy.
+    fn status(&self) -> Result<(), String> {
+        let stopped = STOPPED.load(Ordering::Relaxed);
+        if stopped > 0 {
+            return Err(format!("{stopped} listeners stopped accepting"));
+        }
+
+        let shed = self.pressure.lock().expect("Pressure lock poisoned").shed();
+        if shed > self.config.max_shed {
+            return Err(format!(
+                "resolver overloaded, {shed} requests shed in the last {}s",
+                self.config.window_secs
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Health, Pressure};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn counts_shed_within_window() {
+        let mut pressure = Pressure::new(2);
+        assert_eq!(pressure.shed(), 0);
+
+        for total in [0, 5, 7] {
+            pressure.sample(total);
+        }
+        assert_eq!(pressure.shed(), 7);
+
+        // First burst is out of the window
+        pressure.sample(7);
+        assert_eq!(pressure.shed(), 2);
+        pressure.sample(7);
+        assert_eq!(pressure.shed(), 0);
+    }
+
+    async fn probe(health: &Health, request: &str) -> String {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(request.as_bytes())
+            .await
+            .expect("Writes request");
+        health.answer(server).await.expect("Answers");
+        let mut response = String::new();
+        client
+            .read_to_string(&mut response)
+            .await
+            .expect("Reads response");
+        response
+    }
+
+    #[tokio::test]
+    async fn reports_saturated_resolver() {
+        let health = Health::new(Config {
+            address: ([127, 0, 0, 1], 0).into(),
+            max_shed: 1,
+            window_secs: 10,
+        });
+        let request = "GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        let response = probe(&health, request).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nok\n"), "{response}");
+
+        health.pressure.lock().expect("Lock").sample(0);
+        health.pressure.lock().expect("Lock").sample(3);
+        let response = probe(&health, request).await;
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("3 requests shed"), "{response}");
+
+        let response = probe(&health, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+    }
+}
@@ -1,75 +1,369 @@
-use config::Config;
-use rpx::forward;
-use std::net::SocketAddr;
-use tokio::net::TcpListener;
-use tower::{util::BoxCloneService, ServiceBuilder};
-use tracing::{debug, error, info, info_span, Instrument, Span};
+use access_log::AccessLog;
+use admin::{Admin, Stack};
+use arc_swap::ArcSwap;
+use config::{Layers, Listener, Protocol};
+use connection_limit::ConnectionLimit;
+use health::Health;
+use rate_limit::RateLimiter;
+use rpx::{
+    forward_stream, forward_udp, resolver::stack::Resolver, ForwardOptions, ForwardOutcome,
+    ResolverStackBuilder,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+    sync::OwnedSemaphorePermit,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
+mod access_log;
+mod admin;
+mod bind;
 mod config;
+mod connection_limit;
+mod health;
+mod metrics;
+mod rate_limit;
+mod telemetry;
+
+/// Datagram flows without traffic in either direction for this long are dropped.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    tracing_subscriber::fmt::init();
-    let config = config::load_config()?;
-    let resolver = resolver_stack(&config);
+    telemetry::init()?;
+    let cli = config::cli();
+    let path = config::path(&cli)?;
+    if cli.check_config {
+        match config::check(&path) {
+            Ok(summary) => println!("{summary}"),
+            Err(summary) => {
+                eprintln!("{summary}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    let config = config::load(&path)?;
+    if cli.dump_routes {
+        for route in config.routing_table() {
+            println!("{route}");
+        }
+        return Ok(());
+    }
+    if let Some(address) = config.metrics_address {
+        metrics::install(address)?;
+        info!("Serving metrics on {address}");
+    }
+
+    let access_log = config
+        .access_log
+        .as_ref()
+        .map(AccessLog::open)
+        .transpose()?;
+
+    let connection_limit = config
+        .max_connections
+        .map(|max| ConnectionLimit::new(max, config.on_overflow));
 
     let _ = info_span!("main");
     let mut listener_handles = Vec::new();
-    for listener in config.listen {
-        let acceptor = TcpListener::bind(listener.address).await?;
-        info!("Started listener {listener:?}");
+    let mut stacks = Vec::new();
+    for listener in config.listen.iter() {
+        let resolver = resolver_stack(config.layers(listener), config.resolver_buffer);
+        let handle = match listener.protocol {
+            Protocol::Tcp => {
+                let resolver: Stack = Arc::new(ArcSwap::from_pointee(resolver));
+                stacks.push((listener.address, resolver.clone()));
+                let serve = Serve {
+                    resolver,
+                    listener: listener.clone(),
+                    options: listener.forward_options(&config.self_addresses)?,
+                    connection_limit: connection_limit.clone(),
+                    access_log: access_log.clone(),
+                };
+
+                #[cfg(unix)]
+                if let Some(path) = listener.path.as_ref() {
+                    let acceptor = bind::unix(path)?;
+                    info!("Started listener {listener:?}");
+                    listener_handles.push(tokio::spawn(
+                        serve_unix(acceptor, serve).instrument(info_span!("listener")),
+                    ));
+                    continue;
+                }
+
+                let mut acceptors = Vec::new();
+                for address in listener.addresses() {
+                    let family = Listener {
+                        address,
+                        ..listener.clone()
+                    };
+                    acceptors.extend(bind::tcp_workers(&family)?);
+                }
+                info!("Started listener {listener:?}");
+
+                // Shared by workers, so the limit holds regardless of which one accepted
+                let rate_limiter = listener
+                    .max_conns_per_ip_per_sec
+                    .map(|rate| Arc::new(Mutex::new(RateLimiter::new(rate))));
+                let workers: Vec<_> = acceptors
+                    .into_iter()
+                    .map(|acceptor| {
+                        tokio::spawn(
+                            serve_tcp(acceptor, serve.clone(), rate_limiter.clone())
+                                .instrument(info_span!("listener")),
+                        )
+                    })
+                    .collect();
+                tokio::spawn(async move {
+                    futures::future::join_all(workers).await;
+                })
+            }
+            Protocol::Udp => {
+                let socket = UdpSocket::bind(listener.address).await?;
+                info!("Started listener {listener:?}");
+                let listener = listener.clone();
+
+                tokio::spawn({
+                    let listener_span = info_span!("listener");
+                    async move {
+                        let parsers = move || listener.build_parsers().into_iter();
+                        if let Err(err) =
+                            forward_udp(socket, resolver, parsers, UDP_IDLE_TIMEOUT).await
+                        {
+                            error!("Failed to relay datagrams -> {err}");
+                        }
+                        health::stopped();
+                    }
+                    .instrument(listener_span)
+                })
+            }
+        };
+
+        listener_handles.push(handle);
+    }
+
+    #[cfg(unix)]
+    if let Some(socket) = config.admin_socket.clone() {
+        let admin = Admin::new(path, stacks);
+        listener_handles.push(tokio::spawn(async move {
+            if let Err(err) = admin.serve(&socket).await {
+                error!("Admin socket stopped -> {err}");
+            }
+        }));
+    }
+
+    if let Some(health) = config.health.clone() {
+        listener_handles.push(tokio::spawn(async move {
+            if let Err(err) = Health::new(health).serve().await {
+                error!("Health endpoint stopped -> {err}");
+            }
+        }));
+    }
+
+    futures::future::join_all(listener_handles).await;
+    telemetry::shutdown();
+    Ok(())
+}
+
+/// Shared by accept loops of a single listener.
+#[derive(Clone)]
+struct Serve {
+    resolver: Stack,
+    listener: Listener,
+    options: ForwardOptions,
+    connection_limit: Option<ConnectionLimit>,
+    access_log: Option<AccessLog>,
+}
+
+impl Serve {
+    /// Waits for a slot under connection limit before accepting, see
+    /// [`ConnectionLimit::available`].
+    ///
+    /// While limit is reached accept loop waits here, leaving new connections in the listen
+    /// queue, so kernel pushes back on clients once it fills up, instead of them being accepted
+    /// only to wait as tasks.
+    async fn available(&self) {
+        if let Some(limit) = self.connection_limit.as_ref() {
+            limit.available().await;
+        }
+    }
+
+    /// Waits for a slot under connection limit, `Err` if connection is to be dropped instead.
+    async fn admit(&self) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        match self.connection_limit.as_ref() {
+            Some(limit) => limit.acquire().await.map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
 
-        let resolver = resolver.clone();
-        let handle = tokio::spawn({
-            let listener_span = info_span!("listener");
+    /// Forwards accepted connection in a task of its own, `local` is listener address it
+    /// arrived at.
+    fn spawn<S>(
+        &self,
+        mut incoming: S,
+        local: SocketAddr,
+        client: SocketAddr,
+        permit: Option<OwnedSemaphorePermit>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let port = local.port();
+        let connection = metrics::Connection::accepted(port);
+        // Connection keeps stack it started with, even if it is swapped by reload
+        let resolver = self.resolver.load().as_ref().clone();
+        let parsers = self.listener.build_parsers();
+        let options = self.options.clone();
+        let access_log = self.access_log.clone();
+        tokio::spawn({
+            // Root of a trace of its own, listener span outlives every connection
+            let forwarder_span = info_span!(parent: None, "forwarder");
+            forwarder_span.follows_from(Span::current());
             async move {
-                while let Ok((mut incoming, _)) = acceptor.accept().await {
-                    debug!("Incoming connection {:?}", incoming);
-                    let resolver = resolver.clone();
-                    let parsers = listener
-                        .parsers()
-                        .iter()
-                        .map(Into::into)
-                        .collect::<Vec<_>>();
-                    tokio::spawn({
-                        let forwarder_span = info_span!("forwarder");
-                        forwarder_span.follows_from(Span::current());
-                        async move {
-                            if let Err(err) =
-                                forward(&mut incoming, resolver, parsers.into_iter()).await
-                            {
-                                error!("Failed to forward traffic for {incoming:?} -> {err}");
+                // Released once connection is handled
+                let _permit = permit;
+                let forwarded = forward_stream(
+                    &mut incoming,
+                    local,
+                    client,
+                    resolver,
+                    parsers.into_iter(),
+                    &options,
+                )
+                .await;
+                match forwarded {
+                    Ok(outcome) => {
+                        // Summary of forwarded ones is logged by `forward_stream`, if sampled
+                        if let ForwardOutcome::Dropped { reason, stats } = &outcome {
+                            debug!(?reason, ?stats, "Connection dropped")
+                        }
+                        connection.closed(&outcome);
+                        if let Some(access_log) = access_log {
+                            access_log.record(client, port, Some(&outcome));
+                        }
+                    }
+                    Err(err) => {
+                        match err {
+                            // Capacity problem rather than a broken connection or name
+                            rpx::Error::Overloaded => {
+                                health::shed();
+                                warn!("Resolver is overloaded, dropping {client}")
                             }
+                            err => error!(
+                                stage = err.stage(),
+                                "Failed to forward traffic for {client} -> {err}"
+                            ),
                         }
-                        .instrument(forwarder_span)
-                    });
+                        connection.failed();
+                        if let Some(access_log) = access_log {
+                            access_log.record(client, port, None);
+                        }
+                    }
                 }
             }
-            .instrument(listener_span)
+            .instrument(forwarder_span)
         });
+    }
+}
 
-        listener_handles.push(handle);
+async fn serve_tcp(
+    acceptor: Arc<TcpListener>,
+    serve: Serve,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+) {
+    loop {
+        serve.available().await;
+        let Ok((incoming, client)) = acceptor.accept().await else {
+            break;
+        };
+        if let Some(rate_limiter) = rate_limiter.as_ref() {
+            let allowed = rate_limiter
+                .lock()
+                .expect("Rate limiter lock poisoned")
+                .check(client.ip());
+            if !allowed {
+                continue;
+            }
+        }
+
+        let Ok(permit) = serve.admit().await else {
+            debug!("Connection limit reached, dropping {incoming:?}");
+            continue;
+        };
+
+        debug!("Incoming connection {:?}", incoming);
+        let local = match incoming.local_addr() {
+            Ok(local) => local,
+            Err(err) => {
+                debug!("Dropping {client}, connection is already gone -> {err}");
+                continue;
+            }
+        };
+        serve.spawn(incoming, local, client, permit);
     }
+    health::stopped();
+}
 
-    futures::future::join_all(listener_handles).await;
-    Ok(())
+/// Accepts connections on unix socket, forwarded as if they arrived at listener `address`.
+#[cfg(unix)]
+async fn serve_unix(acceptor: UnixListener, serve: Serve) {
+    // Peers of unix sockets have no address of their own, they count as local clients
+    let client = SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0));
+    loop {
+        serve.available().await;
+        let Ok((incoming, _)) = acceptor.accept().await else {
+            break;
+        };
+        let Ok(permit) = serve.admit().await else {
+            debug!("Connection limit reached, dropping {incoming:?}");
+            continue;
+        };
+
+        debug!("Incoming connection {:?}", incoming);
+        serve.spawn(incoming, serve.listener.address, client, permit);
+    }
+    health::stopped();
 }
 
-fn resolver_stack(
-    config: &Config,
-) -> BoxCloneService<
-    (String, u16),
-    Option<SocketAddr>,
-    Box<dyn std::error::Error + Send + Sync + 'static>,
-> {
-    let service = ServiceBuilder::new()
-        .buffer(1024)
-        .option_layer(config.fallback.clone())
-        .option_layer(config.filter.clone())
-        .option_layer(config.override_rules.clone())
-        .option_layer(config.rewrite.clone())
-        .option_layer(config.dns.clone())
-        .service(rpx::resolver::void::Service);
-
-    BoxCloneService::new(service)
+fn resolver_stack(layers: &Layers, buffer: Option<usize>) -> Resolver {
+    let builder = ResolverStackBuilder::new();
+    let builder = match buffer {
+        Some(size) => builder.buffer(size),
+        None => builder,
+    };
+
+    #[cfg(feature = "idna")]
+    let builder = builder.idna(layers.idna.clone());
+
+    let builder = builder
+        .fallback(layers.fallback.clone())
+        .filter(layers.filter.clone())
+        .literal(layers.literal.clone())
+        .constant(layers.override_rules.clone())
+        .geo(layers.geo.clone())
+        .hostsfile(layers.hostsfile.clone())
+        .env(layers.env.clone())
+        .rewrite(layers.rewrite.clone());
+
+    #[cfg(feature = "kubernetes")]
+    let builder = builder.kubernetes(layers.kubernetes.clone());
+
+    #[cfg(feature = "consul")]
+    let builder = builder.consul(layers.consul.clone());
+
+    #[cfg(feature = "http-discovery")]
+    let builder = builder.http_discovery(layers.http_discovery.clone());
+
+    builder
+        .dns(layers.dns.clone())
+        .void(layers.void.clone())
+        .build()
 }
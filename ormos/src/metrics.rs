@@ -0,0 +1,103 @@
+//! Prometheus metrics.
+//!
+//! Metrics are only recorded once exporter is [installed][install], until then
+//! every call in this module is a no-op, except for [connection counts][connections]
+//! reported by the admin socket.
+use metrics_exporter_prometheus::PrometheusBuilder;
+use rpx::{DropReason, ForwardOutcome};
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+const CONNECTIONS_ACCEPTED: &str = "ormos_connections_accepted_total";
+const CONNECTIONS_ACTIVE: &str = "ormos_connections_active";
+const CONNECTIONS_RESOLVED: &str = "ormos_connections_resolved_total";
+const CONNECTIONS_UNRESOLVED: &str = "ormos_connections_unresolved_total";
+const CONNECTIONS_FAILED: &str = "ormos_connections_failed_total";
+const PARSE_TIMEOUTS: &str = "ormos_parse_timeouts_total";
+const BYTES_IN: &str = "ormos_bytes_in_total";
+const BYTES_OUT: &str = "ormos_bytes_out_total";
+
+static ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Connections accepted since start and ones being handled right now, across all listeners.
+pub fn connections() -> (u64, usize) {
+    (
+        ACCEPTED.load(Ordering::Relaxed),
+        ACTIVE.load(Ordering::Relaxed),
+    )
+}
+
+/// Starts exporter serving `/metrics` on the given address.
+pub fn install(address: SocketAddr) -> Result<(), anyhow::Error> {
+    PrometheusBuilder::new()
+        .with_http_listener(address)
+        .install()?;
+
+    ::metrics::describe_counter!(CONNECTIONS_ACCEPTED, "Connections accepted by listener");
+    ::metrics::describe_gauge!(CONNECTIONS_ACTIVE, "Connections currently being handled");
+    ::metrics::describe_counter!(CONNECTIONS_RESOLVED, "Connections forwarded upstream");
+    ::metrics::describe_counter!(
+        CONNECTIONS_UNRESOLVED,
+        "Connections dropped without destination"
+    );
+    ::metrics::describe_counter!(CONNECTIONS_FAILED, "Connections which failed to forward");
+    ::metrics::describe_counter!(PARSE_TIMEOUTS, "Service name was not read in time");
+    ::metrics::describe_counter!(BYTES_IN, "Bytes sent from clients to upstream");
+    ::metrics::describe_counter!(BYTES_OUT, "Bytes sent from upstream to clients");
+
+    Ok(())
+}
+
+/// Tracks connection from the moment it is accepted until it is dropped.
+///
+/// Decrements active connections gauge on drop, so panicking task is accounted for.
+pub struct Connection {
+    port: String,
+}
+
+impl Connection {
+    pub fn accepted(port: u16) -> Self {
+        let port = port.to_string();
+        ACCEPTED.fetch_add(1, Ordering::Relaxed);
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        ::metrics::increment_counter!(CONNECTIONS_ACCEPTED, "port" => port.clone());
+        ::metrics::increment_gauge!(CONNECTIONS_ACTIVE, 1.0, "port" => port.clone());
+
+        Self { port }
+    }
+
+    pub fn closed(&self, outcome: &ForwardOutcome) {
+        let port = self.port.clone();
+        match outcome {
+            ForwardOutcome::Forwarded(stats) => {
+                let resolver = stats.resolver.unwrap_or("unknown");
+                ::metrics::increment_counter!(CONNECTIONS_RESOLVED, "port" => port.clone(), "resolver" => resolver);
+            }
+            ForwardOutcome::Dropped { reason, .. } => {
+                if *reason == DropReason::ParseTimeout {
+                    ::metrics::increment_counter!(PARSE_TIMEOUTS, "port" => port.clone());
+                }
+                ::metrics::increment_counter!(CONNECTIONS_UNRESOLVED, "port" => port.clone());
+            }
+        }
+
+        let stats = outcome.stats();
+
+        ::metrics::counter!(BYTES_IN, stats.bytes_in, "port" => port.clone());
+        ::metrics::counter!(BYTES_OUT, stats.bytes_out, "port" => port);
+    }
+
+    pub fn failed(&self) {
+        ::metrics::increment_counter!(CONNECTIONS_FAILED, "port" => self.port.clone());
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        ::metrics::decrement_gauge!(CONNECTIONS_ACTIVE, 1.0, "port" => self.port.clone());
+    }
+}
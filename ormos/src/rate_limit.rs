@@ -0,0 +1,172 @@
+//! Per client IP limit of new connections.
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    time::Instant,
+};
+use tracing::warn;
+
+/// Number of tracked client IPs, keeps memory bounded under scans from many sources.
+const MAX_TRACKED: usize = 10_000;
+/// Throttling is logged on the first rejected connection and every this many after.
+const LOG_EVERY: u64 = 1_000;
+
+/// Token bucket for every client IP.
+///
+/// Bucket holds up to a second worth of tokens, so short bursts are allowed.
+pub struct RateLimiter {
+    rate: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Client IPs in the order their buckets were updated, with the update number. Entries
+    /// whose bucket was updated again since are stale and skipped
+    updates: VecDeque<(IpAddr, u64)>,
+    update: u64,
+    capacity: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Number of the most recent update, matches the live entry in `updates`
+    update: u64,
+    rejected: u64,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self::with_capacity(per_second, MAX_TRACKED)
+    }
+
+    fn with_capacity(per_second: u32, capacity: usize) -> Self {
+        Self {
+            rate: f64::from(per_second),
+            buckets: HashMap::new(),
+            updates: VecDeque::new(),
+            update: 0,
+            capacity,
+        }
+    }
+
+    /// Takes a token from the client bucket, returns `false` if connection should be dropped.
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= self.capacity {
+            self.evict();
+        }
+        // Stale entries pile up while the same clients keep connecting, dropping them once there
+        // are more than live ones keeps updates amortized constant time
+        if self.updates.len() >= 2 * self.capacity {
+            let buckets = &self.buckets;
+            self.updates.retain(|(ip, update)| {
+                buckets
+                    .get(ip)
+                    .is_some_and(|bucket| bucket.update == *update)
+            });
+        }
+
+        let rate = self.rate;
+        let update = self.update;
+        self.update += 1;
+        self.updates.push_back((ip, update));
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+            update,
+            rejected: 0,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+        bucket.update = update;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.rejected = 0;
+            true
+        } else {
+            if bucket.rejected.is_multiple_of(LOG_EVERY) {
+                warn!(%ip, rejected = bucket.rejected + 1, "Throttling client");
+            }
+            bucket.rejected += 1;
+            false
+        }
+    }
+
+    /// Forgets the least recently updated bucket, in amortized constant time.
+    fn evict(&mut self) {
+        while let Some((ip, update)) = self.updates.pop_front() {
+            if self
+                .buckets
+                .get(&ip)
+                .is_some_and(|bucket| bucket.update == update)
+            {
+                self.buckets.remove(&ip);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RateLimiter;
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn throttles_after_burst() {
+        let mut limiter = RateLimiter::new(2);
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(ip, now));
+        assert!(limiter.check_at(ip, now));
+        assert!(!limiter.check_at(ip, now));
+        // Other clients are not affected
+        assert!(limiter.check_at([10, 0, 0, 2].into(), now));
+        // Refills over time
+        assert!(limiter.check_at(ip, now + Duration::from_millis(500)));
+        assert!(!limiter.check_at(ip, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn stays_bounded() {
+        let mut limiter = RateLimiter::with_capacity(1, 2);
+        let now = Instant::now();
+
+        assert!(limiter.check_at([10, 0, 0, 1].into(), now));
+        assert!(limiter.check_at([10, 0, 0, 2].into(), now));
+        assert!(limiter.check_at([10, 0, 0, 3].into(), now));
+        assert_eq!(limiter.buckets.len(), 2);
+    }
+
+    #[test]
+    fn evicts_least_recently_updated() {
+        let mut limiter = RateLimiter::with_capacity(1, 2);
+        let first: IpAddr = [10, 0, 0, 1].into();
+        let second: IpAddr = [10, 0, 0, 2].into();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(first, now));
+        assert!(limiter.check_at(second, now));
+        // Updated again, so `second` is the one to go
+        assert!(!limiter.check_at(first, now));
+        for _ in 0..10 {
+            assert!(!limiter.check_at(first, now));
+        }
+        assert!(limiter.check_at([10, 0, 0, 3].into(), now));
+
+        assert!(limiter.buckets.contains_key(&first));
+        assert!(!limiter.buckets.contains_key(&second));
+        // Stale entries of `first` were dropped along the way
+        assert!(limiter.updates.len() <= 4);
+        assert!(!limiter.check_at(first, now));
+    }
+}
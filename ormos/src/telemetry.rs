@@ -0,0 +1,77 @@
+//! Log output, optionally exporting spans to OpenTelemetry collector.
+//!
+//! With `otel` feature, spans are exported over OTLP/gRPC once `OTEL_EXPORTER_OTLP_ENDPOINT`
+//! or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set, e.g. to `http://127.0.0.1:4317`.
+//! Service is reported as `OTEL_SERVICE_NAME`, `ormos` when not set. `RUST_LOG` picks spans
+//! to export the same way it picks lines to log.
+//!
+//! Every forwarded connection is a trace of its own, rooted at `forwarder` span and linked to
+//! `listener` which accepted it.
+
+/// Installs global subscriber, logging to stdout.
+pub fn init() -> Result<(), anyhow::Error> {
+    #[cfg(feature = "otel")]
+    if let Some(layer) = otel::layer()? {
+        use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
+
+        // Matches `tracing_subscriber::fmt::init`, so exported spans follow `RUST_LOG` too
+        let targets = std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|targets| targets.parse().ok())
+            .unwrap_or_else(|| Targets::new().with_default(tracing::Level::INFO));
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .finish()
+            .with(targets)
+            .with(layer)
+            .try_init()?;
+        return Ok(());
+    }
+
+    tracing_subscriber::fmt::init();
+    Ok(())
+}
+
+/// Flushes spans not yet exported.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::{sdk::trace, sdk::Resource, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    const ENDPOINT_VARS: [&str; 2] = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ];
+
+    /// Layer exporting spans to collector, `None` unless its endpoint is set.
+    pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, trace::Tracer>>, anyhow::Error>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        if !ENDPOINT_VARS
+            .iter()
+            .any(|var| std::env::var_os(var).is_some())
+        {
+            return Ok(None);
+        }
+
+        let service = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "ormos".to_owned());
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+            .with_trace_config(
+                trace::config()
+                    .with_resource(Resource::new([KeyValue::new("service.name", service)])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)?;
+
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+}
@@ -0,0 +1,62 @@
+//! Parsers are fed growing buffer one read at a time, the way `forward` does.
+use bytes::{BufMut, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rpx::parser::{http::Hostname, tls::ServiceName, Parser};
+
+/// Request with many headers before `Host`, delivered in small reads.
+fn request(headers: usize) -> Vec<u8> {
+    let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+    for ix in 0..headers {
+        request.extend_from_slice(format!("X-Header-{ix}: some value\r\n").as_bytes());
+    }
+    request.extend_from_slice(b"Host: example.com\r\n\r\n");
+    request
+}
+
+fn http_hostname(c: &mut Criterion) {
+    let mut group = c.benchmark_group("http_hostname");
+    for headers in [16, 256, 1024] {
+        let request = request(headers);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(headers),
+            &request,
+            |b, request| {
+                b.iter(|| {
+                    let mut parser = Hostname::default();
+                    for end in (64..request.len()).step_by(64).chain([request.len()]) {
+                        if let Ok(Some(hostname)) = parser.parse(&request[..end]) {
+                            return hostname;
+                        }
+                    }
+                    unreachable!("Request carries host")
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Cost of growing read buffer while 2KiB ClientHello arrives in 128 byte reads.
+fn read_buffer(c: &mut Criterion) {
+    let chunk = [0u8; 128];
+    let mut group = c.benchmark_group("read_buffer");
+    for capacity in [256, ServiceName::default().size_hint()] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(capacity),
+            &capacity,
+            |b, &capacity| {
+                b.iter(|| {
+                    let mut buf = BytesMut::with_capacity(capacity);
+                    for _ in 0..16 {
+                        buf.put_slice(&chunk);
+                    }
+                    buf
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, http_hostname, read_buffer);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+//! Bulk transfer through `forward` over loopback, at default and larger copy buffer.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rpx::{forward, resolver::Resolved, ForwardOptions, DEFAULT_COPY_BUFFER_SIZE};
+use std::future::ready;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+};
+use tower::{service_fn, BoxError};
+
+const PAYLOAD: usize = 64 * 1024 * 1024;
+
+/// Sends [`PAYLOAD`] from client to upstream through a single forwarded connection.
+async fn transfer(options: &ForwardOptions, payload: &[u8]) {
+    let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+    let upstream = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind upstream");
+    let destination = upstream.local_addr().expect("upstream address");
+    let resolver =
+        service_fn(move |_| ready(Ok::<_, BoxError>(Some(Resolved::new("bench", destination)))));
+
+    let sink = tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.expect("accept");
+        let mut buf = vec![0; 256 * 1024];
+        while stream.read(&mut buf).await.expect("read") > 0 {}
+    });
+    let mut client = TcpStream::connect(proxy.local_addr().expect("proxy address"))
+        .await
+        .expect("connect");
+    let (mut incoming, _) = proxy.accept().await.expect("accept");
+
+    let send = async {
+        client.write_all(payload).await.expect("write");
+        client.shutdown().await.expect("shutdown");
+    };
+    let relay = forward(&mut incoming, resolver, std::iter::empty(), options);
+    let (_, relayed) = tokio::join!(send, relay);
+    relayed.expect("forward succeeds");
+    sink.await.expect("sink");
+}
+
+fn relay(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("runtime");
+    let payload = vec![0xab; PAYLOAD];
+    let mut group = c.benchmark_group("relay");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes(PAYLOAD as u64));
+    for size in [DEFAULT_COPY_BUFFER_SIZE, 256 * 1024] {
+        let options = ForwardOptions {
+            copy_buffer_size: Some(size),
+            ..ForwardOptions::default()
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(size), &options, |b, options| {
+            b.iter(|| runtime.block_on(transfer(options, &payload)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, relay);
+criterion_main!(benches);
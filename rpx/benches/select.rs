@@ -0,0 +1,21 @@
+//! Cost of a single pick among candidate addresses, the way resolvers do on every request.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rpx::resolver::select::{Selector, Strategy};
+use std::{hint::black_box, net::IpAddr};
+
+fn pick(c: &mut Criterion) {
+    let candidates: Vec<IpAddr> = (1..=8).map(|ix| [10, 0, 0, ix].into()).collect();
+    let mut group = c.benchmark_group("pick");
+    for strategy in [Strategy::Random, Strategy::RoundRobin, Strategy::First] {
+        let selector = Selector::new(strategy);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{strategy:?}")),
+            &selector,
+            |b, selector| b.iter(|| *selector.pick(black_box(&candidates)).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pick);
+criterion_main!(benches);
@@ -0,0 +1,289 @@
+//! Connecting to destinations with multiple candidate addresses, see [Happy Eyeballs].
+//!
+//! Candidates are reordered so address families alternate, then connection attempts are
+//! started one after another, each one [delayed][CONNECTION_ATTEMPT_DELAY] unless previous attempt
+//! had already failed. First established connection wins, the rest are dropped.
+//!
+//! [Happy Eyeballs]: https://www.rfc-editor.org/rfc/rfc8305
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpSocket, TcpStream},
+};
+use tracing::{debug, instrument};
+
+/// Delay between starting connection attempts, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Local end of outgoing connections, e.g. to pick egress interface.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Source {
+    /// Address to bind before connecting, port is picked by OS. Candidates of the other
+    /// address family fail to connect.
+    pub address: Option<IpAddr>,
+    /// Interface to send traffic through via `SO_BINDTODEVICE`, Linux only.
+    ///
+    /// Requires `CAP_NET_RAW`, unless kernel is 5.7 or newer and socket isn't already bound
+    /// to another interface.
+    #[cfg(feature = "bind-device")]
+    pub device: Option<String>,
+}
+
+/// Connects to the first candidate that accepts connection.
+///
+/// Returns error of the last failed attempt if none does.
+#[instrument(skip_all, fields(candidates = addresses.len()))]
+pub(crate) async fn connect(addresses: &[SocketAddr], source: &Source) -> io::Result<TcpStream> {
+    if let [address] = addresses {
+        return connect_from(*address, source).await;
+    }
+
+    let mut candidates = interleave(addresses).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match candidates.next() {
+                Some(address) => attempts.push(attempt(address, source)),
+                None => break,
+            }
+        }
+
+        tokio::select! {
+            Some((address, outcome)) = attempts.next() => match outcome {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    debug!(?address, "Connection attempt failed: {err}");
+                    last_error = Some(err);
+                    // Don't wait for the delay to pass, start next attempt right away
+                    if let Some(address) = candidates.next() {
+                        attempts.push(attempt(address, source));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if candidates.len() > 0 => {
+                if let Some(address) = candidates.next() {
+                    attempts.push(attempt(address, source));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No addresses")))
+}
+
+async fn attempt(address: SocketAddr, source: &Source) -> (SocketAddr, io::Result<TcpStream>) {
+    (address, connect_from(address, source).await)
+}
+
+async fn connect_from(address: SocketAddr, source: &Source) -> io::Result<TcpStream> {
+    if *source == Source::default() {
+        return TcpStream::connect(address).await;
+    }
+
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    #[cfg(feature = "bind-device")]
+    if let Some(device) = source.device.as_deref() {
+        bind_device(&socket, device)?;
+    }
+    if let Some(ip) = source.address {
+        socket.bind((ip, 0).into())?;
+    }
+
+    socket.connect(address).await
+}
+
+/// Connects to unix socket at `path`, failing with `Unsupported` on platforms without them.
+pub(crate) async fn connect_unix(path: &Path) -> io::Result<Upstream> {
+    #[cfg(unix)]
+    return tokio::net::UnixStream::connect(path)
+        .await
+        .map(Upstream::Unix);
+
+    #[cfg(not(unix))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Unix socket {} is not supported on this platform",
+            path.display()
+        ),
+    ))
+}
+
+/// Connection to destination, whichever kind of socket it listens on.
+#[derive(Debug)]
+pub(crate) enum Upstream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl AsyncRead for Upstream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Upstream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Upstream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Upstream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Upstream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Upstream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upstream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Upstream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(all(
+    feature = "bind-device",
+    any(target_os = "linux", target_os = "android")
+))]
+fn bind_device(socket: &TcpSocket, device: &str) -> io::Result<()> {
+    socket2::SockRef::from(socket).bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(all(
+    feature = "bind-device",
+    not(any(target_os = "linux", target_os = "android"))
+))]
+fn bind_device(_: &TcpSocket, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Binding to interface is only supported on Linux",
+    ))
+}
+
+/// Reorders addresses so families alternate, starting with the family of the first one.
+///
+/// Relative order of addresses within the same family is preserved.
+fn interleave(addresses: &[SocketAddr]) -> Vec<SocketAddr> {
+    let preferred_v4 = match addresses.first() {
+        Some(address) => address.is_ipv4(),
+        None => return Vec::new(),
+    };
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+        .iter()
+        .partition(|address| address.is_ipv4() == preferred_v4);
+    preferred.reverse();
+    other.reverse();
+
+    let mut interleaved = Vec::with_capacity(addresses.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        interleaved.extend(preferred.pop());
+        interleaved.extend(other.pop());
+    }
+
+    interleaved
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect, interleave, Source};
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    fn addresses(addresses: &[&str]) -> Vec<SocketAddr> {
+        addresses
+            .iter()
+            .map(|address| address.parse().expect("valid address"))
+            .collect()
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let input = addresses(&[
+            "[2001:db8::1]:443",
+            "[2001:db8::2]:443",
+            "[2001:db8::3]:443",
+            "10.0.0.1:443",
+            "10.0.0.2:443",
+        ]);
+        let expected = addresses(&[
+            "[2001:db8::1]:443",
+            "10.0.0.1:443",
+            "[2001:db8::2]:443",
+            "10.0.0.2:443",
+            "[2001:db8::3]:443",
+        ]);
+
+        assert_eq!(interleave(&input), expected);
+    }
+
+    #[tokio::test]
+    async fn falls_over_to_next_candidate() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let listening = listener.local_addr().expect("listener address");
+
+        // Grab a free port and release it, so nothing is listening there
+        let refusing = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind")
+            .local_addr()
+            .expect("listener address");
+
+        let stream = connect(&[refusing, listening], &Source::default())
+            .await
+            .expect("connect");
+        assert_eq!(stream.peer_addr().expect("peer address"), listening);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn binds_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let listening = listener.local_addr().expect("listener address");
+        // Whole 127.0.0.0/8 is routed to loopback on Linux
+        let source = Source {
+            address: Some([127, 0, 0, 2].into()),
+            #[cfg(feature = "bind-device")]
+            device: None,
+        };
+
+        let stream = connect(&[listening], &source).await.expect("connect");
+        assert_eq!(
+            stream.local_addr().expect("local address").ip(),
+            source.address.unwrap()
+        );
+    }
+}
@@ -1,26 +1,273 @@
 #![doc = include_str!("../../Readme.md")]
+pub mod connect;
+mod counted;
+mod idle;
 pub mod parser;
+pub mod pool;
+pub mod proxy_protocol;
 pub mod resolver;
+pub mod sample;
+pub mod throttle;
+pub mod tls;
+mod udp;
 
+use counted::Counted;
 use parser::Parser;
-use std::{future::poll_fn, net::SocketAddr, ops::Deref, time::Duration};
+pub use resolver::stack::ResolverStackBuilder;
+use std::{
+    future::poll_fn,
+    net::SocketAddr,
+    ops::Deref,
+    time::{Duration, Instant},
+};
+use throttle::Throttled;
+pub use udp::forward_udp;
 
 use bytes::{BufMut, BytesMut};
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
-use tracing::{debug, instrument, trace, warn};
+use tracing::{debug, error, field, instrument, trace, warn, Span};
+
+/// Upper bound for buffer preallocated while parsing service name.
+const MAX_INITIAL_BUFFER_CAPACITY: usize = 16 * 1024;
+
+/// Time client has to complete TLS handshake when it is [terminated][ForwardOptions::tls].
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Buffer size of relaying traffic in each direction, same as the one [`io::copy`] uses.
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 8 * 1024;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error("Resolver is overloaded, its queue is full")]
+    Overloaded,
+
+    #[error("Service name was not read within {0} reads")]
+    TooFragmented(usize),
+
+    /// Resolver stack can't take requests at all, e.g. its buffer worker is gone
+    #[error("Resolver is not ready: {0}")]
+    ResolverNotReady(#[source] tower::BoxError),
+
+    /// None of the candidates accepted connection within connect timeout
+    #[error("Failed to connect to {destination}: {source}")]
+    Connect {
+        destination: resolver::Destination,
+        /// Failure of the last attempt
+        source: std::io::Error,
+    },
+
+    /// Client did not complete [terminated][ForwardOptions::tls] TLS handshake in time
+    #[error("TLS handshake did not complete within {0:?}")]
+    HandshakeTimeout(Duration),
+
+    /// Relaying traffic failed once upstream was connected, e.g. either peer reset connection
+    #[error(
+        "Relaying to {} failed after {} bytes in, {} bytes out: {source}",
+        .stats.resolved.map_or("unix socket".to_owned(), |resolved| resolved.to_string()),
+        .stats.bytes_in,
+        .stats.bytes_out
+    )]
+    Relay {
+        /// Traffic relayed until the failure
+        stats: Box<ForwardStats>,
+        source: std::io::Error,
+    },
+
+    /// Resolver failed, as opposed to finding no destination
+    #[error("Failed to resolve destination: {source}")]
+    Resolve {
+        /// Whether resolving could succeed if tried again, see [`resolver::Retry`]
+        retryable: bool,
+        source: tower::BoxError,
+    },
+
     #[error("Unexpected error occurred: `{0}`")]
     Other(Box<dyn std::error::Error + Sync + Send + 'static>),
 }
 
+impl Error {
+    /// Tells resolver running out of capacity apart from failing to resolve.
+    fn from_resolver(err: tower::BoxError) -> Self {
+        if err.is::<tower::load_shed::error::Overloaded>() {
+            Error::Overloaded
+        } else {
+            Error::Resolve {
+                retryable: resolver::is_retryable(err.as_ref()),
+                source: err,
+            }
+        }
+    }
+
+    /// Whether connection could be forwarded if tried again, e.g. once resolver catches up or
+    /// DNS server answers.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Overloaded => true,
+            Error::Resolve { retryable, .. } => *retryable,
+            _ => false,
+        }
+    }
+
+    /// Stage of forwarding which failed, e.g. to label logs or metrics.
+    pub fn stage(&self) -> &'static str {
+        match self {
+            Error::Io(_) | Error::Other(_) => "other",
+            Error::Overloaded | Error::ResolverNotReady(_) | Error::Resolve { .. } => "resolve",
+            Error::TooFragmented(_) => "parse",
+            Error::HandshakeTimeout(_) => "handshake",
+            Error::Connect { .. } => "connect",
+            Error::Relay { .. } => "relay",
+        }
+    }
+}
+
+/// Tunables for [`forward`].
+#[derive(Debug, Clone, Default)]
+pub struct ForwardOptions {
+    /// Send [PROXY protocol][proxy_protocol] v2 header describing the client
+    /// before any other data is sent upstream.
+    pub send_proxy_protocol: bool,
+    /// Close connection once it has been open for this long, even if traffic is still flowing.
+    ///
+    /// Applies on top of any idle timeout, whichever fires first wins. `None` means unlimited.
+    pub max_lifetime: Option<Duration>,
+    /// Reuse upstream connections once client is done, see [`forward`] for when this is safe.
+    ///
+    /// Ignored when [PROXY protocol][ForwardOptions::send_proxy_protocol] is sent, as header
+    /// describes a single client.
+    pub pool: Option<pool::Pool>,
+    /// Cap on throughput of a single connection, `None` means unlimited.
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    /// Traffic [rate limit][ForwardOptions::rate_limit_bytes_per_sec] applies to.
+    pub rate_limit_direction: throttle::Direction,
+    /// Drop connections none of the parsers could read a name from, instead of resolving an
+    /// empty one.
+    pub require_name: bool,
+    /// Addresses this proxy is reachable at besides the listener, destinations among them are
+    /// refused to avoid traffic looping back.
+    pub self_addresses: Vec<SocketAddr>,
+    /// Terminate TLS, sending cleartext upstream. Every client is expected to speak TLS.
+    pub tls: Option<tls::Terminator>,
+    /// Local end of connections to upstream, picked by OS unless set.
+    pub source: connect::Source,
+    /// Buffer used for each direction of relayed traffic, [8KiB][DEFAULT_COPY_BUFFER_SIZE]
+    /// unless set.
+    ///
+    /// Larger buffers mean fewer syscalls for bulk transfers, at the cost of memory held by
+    /// every connection. Beyond 256KiB gains are rarely noticeable.
+    pub copy_buffer_size: Option<usize>,
+    /// Give up on service name once client took this many reads to send it, `None` means
+    /// unlimited.
+    ///
+    /// Applies on top of parse timeout, bounding work clients trickling handshake a byte at a
+    /// time cause before the timeout fires.
+    pub max_parse_reads: Option<usize>,
+    /// Log summary of only some forwarded connections, e.g. one in a hundred, every one when
+    /// `None`. Dropped connections are always logged.
+    pub log_sample: Option<sample::Sampler>,
+    /// Timeouts applied unless destination comes with a [policy][resolver::Resolved::policy]
+    /// of its own.
+    pub policy: ForwardPolicy,
+}
+
+/// Timeouts of connections to a destination.
+///
+/// Ones set on [resolved destination][resolver::Resolved::policy] take precedence over
+/// [defaults][ForwardOptions::policy] of the listener.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForwardPolicy {
+    /// Give up connecting to upstream after this long, `None` leaves it to OS.
+    pub connect_timeout: Option<Duration>,
+    /// Close connection once nothing was read from either side for this long, `None` means
+    /// unlimited.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl ForwardPolicy {
+    /// Timeouts of `self`, taking ones it doesn't set from `defaults`.
+    pub fn or(self, defaults: ForwardPolicy) -> Self {
+        Self {
+            connect_timeout: self.connect_timeout.or(defaults.connect_timeout),
+            idle_timeout: self.idle_timeout.or(defaults.idle_timeout),
+        }
+    }
+}
+
+/// Summary of a single [`forward`] call.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardStats {
+    /// Bytes sent from client to upstream, including bytes read while parsing
+    pub bytes_in: u64,
+    /// Bytes sent from upstream to client
+    pub bytes_out: u64,
+    /// Bytes of [preamble][resolver::Resolved::preamble] sent to upstream, not counted in
+    /// `bytes_in`
+    pub bytes_preamble: u64,
+    /// Destination traffic was forwarded to, `None` for unix socket
+    pub resolved: Option<SocketAddr>,
+    /// Resolver which came up with destination
+    pub resolver: Option<&'static str>,
+    /// Name fed to resolver, `None` if traffic could not be read in time
+    pub service_name: Option<resolver::ServiceName>,
+    /// Client [fingerprint][parser::Parser::fingerprint], if parser which read the name took one
+    pub fingerprint: Option<String>,
+    /// Connection was closed because it reached [max lifetime][ForwardOptions::max_lifetime]
+    pub lifetime_exceeded: bool,
+    /// Connection was closed because it was [idle][ForwardPolicy::idle_timeout] for too long
+    pub idle_timeout_exceeded: bool,
+    /// Time spent handling connection
+    pub duration: Duration,
+}
+
+/// How [`forward`] handled connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardOutcome {
+    /// Traffic was relayed to destination until either side closed
+    Forwarded(ForwardStats),
+    /// Client was disconnected before any destination was reached
+    Dropped {
+        reason: DropReason,
+        stats: ForwardStats,
+    },
+}
+
+/// Why connection was [dropped][ForwardOutcome::Dropped].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Service name was not read in time
+    ParseTimeout,
+    /// Reading service name failed
+    Unreadable,
+    /// No parser recognized traffic while [name is required][ForwardOptions::require_name]
+    NameRequired,
+    /// Resolver came up with no destination
+    Unresolved,
+    /// Every candidate destination points back at the proxy
+    LoopsBack,
+    /// Service name took more than [allowed reads][ForwardOptions::max_parse_reads]
+    TooFragmented,
+}
+
+impl ForwardOutcome {
+    pub fn stats(&self) -> &ForwardStats {
+        match self {
+            ForwardOutcome::Forwarded(stats) | ForwardOutcome::Dropped { stats, .. } => stats,
+        }
+    }
+
+    pub fn into_stats(self) -> ForwardStats {
+        match self {
+            ForwardOutcome::Forwarded(stats) | ForwardOutcome::Dropped { stats, .. } => stats,
+        }
+    }
+}
+
 /// Forwards traffic from incoming connection to preconfigured destination.
 ///
 /// Forwarding traffic involves following steps:
@@ -37,24 +284,107 @@ pub enum Error {
 ///
 /// ### Resolve
 ///
-/// Resolvers are implementors of [service][tower::Service], which accept `(String, u16)` and
-/// respond with optional socket address. There are couple of resolvers available in [corresponding
-/// module][resolver]
+/// Resolvers are implementors of [service][tower::Service], which accept
+/// [request][resolver::ResolveRequest] carrying service name, listener port and client address,
+/// and respond with optional [destination][resolver::Resolved]. There are couple of resolvers
+/// available in [corresponding module][resolver]
 ///
 /// ### Forward
 ///
+/// Candidates equal to listener address or one of [self addresses][ForwardOptions::self_addresses]
+/// are skipped, connection is dropped if none remain.
+/// When destination has multiple candidate addresses, connection attempts to them are raced
+/// following [Happy Eyeballs](https://www.rfc-editor.org/rfc/rfc8305).
+/// Destination on [unix socket][resolver::Destination::Unix] is connected to directly.
 /// Once connection to remote destination had been established all incoming data collected so far
-/// is forwarded to dst. Task resolves when connection is closed.
-#[instrument(skip_all, fields(incoming = ?incoming.peer_addr(), port = ?incoming.local_addr().map(|a| a.port())))]
-pub async fn forward<'a, R, I>(
+/// is forwarded to dst. If [enabled][ForwardOptions::send_proxy_protocol], PROXY protocol header
+/// precedes the data. Task resolves when connection is closed, or once
+/// [max lifetime][ForwardOptions::max_lifetime] is reached or connection stays
+/// [idle][ForwardPolicy::idle_timeout] for too long, in which case both sides are shut down.
+/// Timeouts come from [policy][resolver::Resolved::policy] of the destination, listener
+/// [defaults][ForwardOptions::policy] fill in ones it doesn't set.
+/// With a [rate limit][ForwardOptions::rate_limit_bytes_per_sec] reads are paced by a token
+/// bucket, sleeping until enough of the budget is available.
+///
+/// With [TLS termination][ForwardOptions::tls] handshake is completed before connecting upstream,
+/// replaying ClientHello read while parsing. Only decrypted traffic reaches destination.
+///
+/// ### Upstream reuse
+///
+/// With a [pool][ForwardOptions::pool] configured, idle connection to destination is used instead
+/// of a fresh one when available. Client's FIN is then not passed upstream: once client half-closes
+/// and upstream has been quiet for a moment after responding, client side is closed and upstream
+/// connection is parked for the next client. A proxy working at L4 can't tell where requests end,
+/// so this is only safe when:
+/// - upstream treats connection as a sequence of independent requests, e.g. plain HTTP/1.1 with
+///   keep-alive. Not for TLS passthrough, nor protocols with per-connection state such as
+///   authentication;
+/// - clients send complete requests and half-close instead of waiting for upstream to close;
+/// - upstream responds without long pauses mid-response, as a pause is taken for the end of it.
+///
+/// Connections which upstream closed, or which have unsolicited data pending, are never reused.
+/// Neither are ones to destinations with a [preamble][resolver::Resolved::preamble], as it
+/// belongs to the connection rather than the request.
+///
+/// ### Outcome
+///
+/// On success [outcome][ForwardOutcome] tells forwarded connections from ones dropped without
+/// reaching destination, e.g. because name could not be resolved, both carry
+/// [stats][ForwardStats] of the connection.
+pub async fn forward<R, I>(
     incoming: &mut TcpStream,
+    resolver: R,
+    parsers: I,
+    options: &ForwardOptions,
+) -> Result<ForwardOutcome, Error>
+where
+    R: tower::Service<
+        resolver::ResolveRequest,
+        Response = Option<resolver::Resolved>,
+        Error = Box<dyn std::error::Error + Send + Sync + 'static>,
+    >,
+    I: Iterator<
+        Item = Box<
+            dyn Parser<String, Box<dyn std::error::Error + Send + 'static>> + Send + 'static,
+        >,
+    >,
+{
+    let local = incoming.local_addr()?;
+    let client = incoming.peer_addr()?;
+    forward_stream(incoming, local, client, resolver, parsers, options).await
+}
+
+/// Same as [`forward`] for any stream, e.g. an in-memory one, addresses of both ends are passed
+/// in explicitly.
+///
+/// `local` is the listener address connection arrived at, `client` is the peer one.
+///
+/// Span of the call is filled in with parsed `service.name`, `destination` and byte counts as
+/// they become known, resolvers run in its children.
+#[instrument(
+    skip_all,
+    fields(
+        incoming = %client,
+        port = local.port(),
+        service.name = field::Empty,
+        destination = field::Empty,
+        bytes_in = field::Empty,
+        bytes_out = field::Empty,
+    )
+)]
+pub async fn forward_stream<S, R, I>(
+    incoming: &mut S,
+    local: SocketAddr,
+    client: SocketAddr,
     mut resolver: R,
     parsers: I,
-) -> Result<(), Error>
+    options: &ForwardOptions,
+) -> Result<ForwardOutcome, Error>
 where
+    S: AsyncRead + AsyncWrite + Unpin,
     R: tower::Service<
-        (String, u16),
-        Response = Option<SocketAddr>,
+        resolver::ResolveRequest,
+        Response = Option<resolver::Resolved>,
         Error = Box<dyn std::error::Error + Send + Sync + 'static>,
     >,
     I: Iterator<
@@ -64,75 +394,408 @@ where
     >,
 {
     debug!("enter");
-    let port = incoming.local_addr()?.port();
-
-    let mut buf = BytesMut::with_capacity(256);
+    let started = Instant::now();
+    let port = local.port();
 
     let mut parsers: Vec<_> = parsers.collect();
+    // Sized up front for the most demanding parser, so buffer is not reallocated
+    // while handshake trickles in. Capped, as parsers are free to ask for more
+    let capacity = parsers
+        .iter()
+        .map(|parser| parser.size_hint())
+        .max()
+        .unwrap_or_default()
+        .min(MAX_INITIAL_BUFFER_CAPACITY);
+    let mut buf = BytesMut::with_capacity(capacity);
+
     let mut parsers: Vec<&mut _> = parsers.iter_mut().map(|boxed| boxed.as_mut()).collect();
 
     let with_deadline = {
         let duration = Duration::from_secs(30);
         tokio::time::timeout(
             duration,
-            parse_service_name(incoming, &mut buf, parsers.as_mut_slice()),
+            parse_service_name(
+                incoming,
+                &mut buf,
+                parsers.as_mut_slice(),
+                options.max_parse_reads,
+            ),
         )
     };
 
+    let mut stats = ForwardStats::default();
+
     // Read the service name from incoming stream
     let service_name = match with_deadline.await {
         Err(_) => {
             debug!("Timeout");
             // Failed to read the service name in time -> abort
-            None
+            Err(DropReason::ParseTimeout)
+        }
+        Ok(Err(Error::TooFragmented(reads))) => {
+            debug!(reads, "Service name is too fragmented");
+            Err(DropReason::TooFragmented)
         }
         Ok(Err(err)) => {
             // Error can only occur in the event of IO issue, abort;
             debug!("Failed to resolve service name: {err}");
-            None
+            Err(DropReason::Unreadable)
         }
         Ok(Ok(None)) => {
             debug!("None of the parsers were able to parse the name");
-            // Use default name (empty string) and feed to resolver -> if it has default destination,
+            // Fed to resolver as an empty name -> if it has default destination,
             // it would resolve regardless, if it doesn't - then it would resolve None with noop
-            Some(String::new())
+            Ok(resolver::ServiceName::NotParsed)
         }
-        Ok(Ok(Some(name))) => {
-            debug!(host = name.as_str(), "resolved service name");
-            Some(name)
+        Ok(Ok(Some(Parsed {
+            name,
+            consumed,
+            fingerprint,
+        }))) => {
+            debug!(host = name.as_str(), consumed, "resolved service name");
+            stats.fingerprint = fingerprint;
+            // Upstream only gets what follows the part parser claimed
+            let _ = buf.split_to(consumed.min(buf.len()));
+            Ok(resolver::ServiceName::Parsed(parser::normalize(name)))
         }
     };
 
+    stats.service_name = service_name.clone().ok();
+    if let Some(name) = stats.service_name.as_ref() {
+        Span::current().record("service.name", name.as_str());
+    }
+
     // Resolve service name to some address
     let outgoing = match service_name {
-        None => None,
-        Some(name) => {
+        Err(reason) => Err(reason),
+        Ok(resolver::ServiceName::NotParsed) if options.require_name => {
+            debug!("Name is required, dropping");
+            Err(DropReason::NameRequired)
+        }
+        Ok(name) => {
             // Ensure resolver is ready
             poll_fn(|cx| resolver.poll_ready(cx))
                 .await
-                .map_err(Error::Other)?;
+                .map_err(Error::ResolverNotReady)?;
+
+            resolver
+                .call(resolver::ResolveRequest::from_service_name(
+                    name, port, client,
+                ))
+                .await
+                .map_err(Error::from_resolver)?
+                .ok_or(DropReason::Unresolved)
+        }
+    };
+
+    // Connecting to self would loop traffic until file descriptors run out
+    let outgoing = outgoing.and_then(|outgoing| {
+        if outgoing.path().is_some() {
+            return Ok(outgoing);
+        }
+        let candidates = outgoing
+            .addresses()
+            .iter()
+            .filter(|address| **address != local && !options.self_addresses.contains(address))
+            .copied()
+            .collect();
+        let guarded = resolver::Resolved::with_candidates(outgoing.source(), candidates)
+            .map(|guarded| match outgoing.preamble() {
+                Some(preamble) => guarded.with_preamble(preamble.clone()),
+                None => guarded,
+            })
+            .map(|guarded| match outgoing.policy() {
+                Some(policy) => guarded.with_policy(policy),
+                None => guarded,
+            });
+        if guarded.is_none() {
+            error!(destination = ?outgoing, "Destination points back at the proxy, refusing to connect");
+        }
+        guarded.ok_or(DropReason::LoopsBack)
+    });
+
+    let outgoing = match outgoing {
+        Ok(outgoing) => outgoing,
+        Err(reason) => {
+            warn!(
+                ?reason,
+                "Failed to resolve destination for {client}, dropping request"
+            );
+            incoming.shutdown().await?;
+            stats.duration = started.elapsed();
+            return Ok(ForwardOutcome::Dropped { reason, stats });
+        }
+    };
+
+    debug!(destination = ?outgoing, "resolved destination");
+    stats.resolver = Some(outgoing.source());
+    // PROXY protocol header describes a single client, connection is not to be shared. Neither
+    // is one which has seen a preamble, pool can't tell it from connections which have not
+    let reuse = options.pool.as_ref().filter(|_| {
+        !options.send_proxy_protocol && outgoing.path().is_none() && outgoing.preamble().is_none()
+    });
+    let decrypted = match options.tls.as_ref() {
+        Some(terminator) => {
+            let handshake = terminator.accept(&buf, &mut *incoming);
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(decrypted) => Some(decrypted?),
+                Err(_) => return Err(Error::HandshakeTimeout(HANDSHAKE_TIMEOUT)),
+            }
+        }
+        None => None,
+    };
 
-            resolver.call((name, port)).await.map_err(Error::Other)?
+    let preamble = outgoing.preamble().cloned();
+    let policy = outgoing
+        .policy()
+        .map_or(options.policy, |policy| policy.or(options.policy));
+    let mut outgoing = match outgoing.destination() {
+        resolver::Destination::Tcp(addresses) => {
+            let outgoing = match reuse.and_then(|pool| pool.take(addresses)) {
+                Some(idle) => idle,
+                None => within(
+                    policy.connect_timeout,
+                    connect::connect(addresses, &options.source),
+                )
+                .await
+                .map_err(|source| Error::Connect {
+                    destination: outgoing.destination().clone(),
+                    source,
+                })?,
+            };
+            let resolved = outgoing.peer_addr()?;
+            Span::current().record("destination", field::display(resolved));
+            stats.resolved = Some(resolved);
+            connect::Upstream::Tcp(outgoing)
+        }
+        resolver::Destination::Unix(path) => {
+            Span::current().record("destination", field::debug(path));
+            within(policy.connect_timeout, connect::connect_unix(path))
+                .await
+                .map_err(|source| Error::Connect {
+                    destination: outgoing.destination().clone(),
+                    source,
+                })?
         }
     };
 
-    if let Some(outgoing) = outgoing {
-        debug!(destination = ?outgoing, "resolved destination");
-        let mut outgoing = TcpStream::connect(outgoing).await?;
+    if options.send_proxy_protocol {
+        let header = proxy_protocol::encode(client, local);
+        outgoing.write_all(&header).await?;
+    }
+    if let Some(preamble) = preamble {
+        outgoing.write_all(&preamble).await?;
+        stats.bytes_preamble = preamble.len() as u64;
+    }
 
+    let relayed = match decrypted {
+        Some(decrypted) => {
+            relay(
+                decrypted,
+                &mut outgoing,
+                reuse,
+                options,
+                policy.idle_timeout,
+                started,
+                &mut stats,
+            )
+            .await
+        }
         // Copy everything read so far
-        outgoing.write_all(&buf).await?;
+        None => match outgoing.write_all(&buf).await {
+            Ok(()) => {
+                stats.bytes_in = buf.len() as u64;
+                relay(
+                    &mut *incoming,
+                    &mut outgoing,
+                    reuse,
+                    options,
+                    policy.idle_timeout,
+                    started,
+                    &mut stats,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        },
+    };
+    // Upstream and traffic so far tell more about the failure than the error alone
+    let reusable = match relayed {
+        Ok(reusable) => reusable,
+        Err(source) => {
+            stats.duration = started.elapsed();
+            return Err(Error::Relay {
+                stats: Box::new(stats),
+                source,
+            });
+        }
+    };
 
-        let (incoming, outgoing) = io::copy_bidirectional(incoming, &mut outgoing).await?;
-        debug!(incoming, outgoing, "After copy_bidirectional");
-    } else {
-        warn!("Failed to resolve destination for {incoming:?}, dropping request");
-        incoming.shutdown().await?;
+    if let (Some(pool), connect::Upstream::Tcp(outgoing)) = (reuse.filter(|_| reusable), outgoing) {
+        pool.park(outgoing);
     }
 
+    stats.duration = started.elapsed();
+    Span::current()
+        .record("bytes_in", stats.bytes_in)
+        .record("bytes_out", stats.bytes_out);
+    if options
+        .log_sample
+        .as_ref()
+        .is_none_or(sample::Sampler::sample)
+    {
+        debug!(?stats, "Connection forwarded");
+    }
+    Ok(ForwardOutcome::Forwarded(stats))
+}
+
+/// Copies traffic between client and upstream, returns whether upstream could be reused.
+///
+/// Bytes copied are counted in `stats` even if copying fails. Copying is cut short once connection
+/// reaches max lifetime, or nothing was read from either side for `idle_timeout`.
+async fn relay<C, U>(
+    client: C,
+    upstream: &mut U,
+    reuse: Option<&pool::Pool>,
+    options: &ForwardOptions,
+    idle_timeout: Option<Duration>,
+    started: Instant,
+    stats: &mut ForwardStats,
+) -> io::Result<bool>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (from_client, from_upstream) = match options.rate_limit_bytes_per_sec {
+        Some(rate) => throttle::Bucket::for_direction(rate, options.rate_limit_direction),
+        None => (None, None),
+    };
+    let activity = idle::Activity::new();
+    let mut client = Counted::new(Throttled::new(
+        idle::Tracked::new(client, activity.clone()),
+        from_client,
+    ));
+    let mut upstream = Counted::new(Throttled::new(
+        idle::Tracked::new(upstream, activity.clone()),
+        from_upstream,
+    ));
+    // Part read while parsing service name has already been sent upstream
+    let request_sent = stats.bytes_in > 0;
+    let copy = async {
+        match reuse {
+            Some(_) => pool::copy_for_reuse(&mut client, &mut upstream, request_sent).await,
+            None => {
+                let buffer_size = options.copy_buffer_size.unwrap_or(DEFAULT_COPY_BUFFER_SIZE);
+                copy_half_closing(&mut client, &mut upstream, buffer_size)
+                    .await
+                    .map(|_| false)
+            }
+        }
+    };
+    let lifetime = async {
+        match options.max_lifetime {
+            Some(max_lifetime) => {
+                tokio::time::sleep(max_lifetime.saturating_sub(started.elapsed())).await
+            }
+            None => futures::future::pending().await,
+        }
+    };
+    let idle = async {
+        match idle_timeout {
+            Some(idle_timeout) => activity.idle_for(idle_timeout).await,
+            None => futures::future::pending().await,
+        }
+    };
+
+    let copied = tokio::select! {
+        copied = copy => Some(copied),
+        _ = lifetime => {
+            debug!(max_lifetime = ?options.max_lifetime, "Connection reached max lifetime, closing");
+            stats.lifetime_exceeded = true;
+            None
+        }
+        _ = idle => {
+            debug!(?idle_timeout, "Connection is idle, closing");
+            stats.idle_timeout_exceeded = true;
+            None
+        }
+    };
+    let reusable = match copied {
+        Some(reusable) => reusable,
+        None => {
+            // Peers may already be gone, nothing to do about failures here
+            let _ = client.shutdown().await;
+            let _ = upstream.shutdown().await;
+            Ok(false)
+        }
+    };
+    stats.bytes_in += client.read();
+    stats.bytes_out = upstream.read();
+
+    reusable
+}
+
+/// Gives up on `connecting` once `timeout`, if any, passes.
+async fn within<T>(
+    timeout: Option<Duration>,
+    connecting: impl std::future::Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connecting)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+        None => connecting.await,
+    }
+}
+
+/// Copies traffic both ways, passing EOF on as soon as either peer half-closes.
+///
+/// Opposite direction keeps flowing until its own EOF, so responses streamed after client is
+/// done sending still get through.
+async fn copy_half_closing<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    buffer_size: usize,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (client_read, mut client_write) = io::split(client);
+    let (upstream_read, mut upstream_write) = io::split(upstream);
+
+    tokio::try_join!(
+        pass_on(client_read, &mut upstream_write, buffer_size),
+        pass_on(upstream_read, &mut client_write, buffer_size),
+    )?;
     Ok(())
 }
 
+/// Copies until EOF through buffer of given size, then shuts writer down.
+async fn pass_on<R, W>(reader: R, writer: &mut W, buffer_size: usize) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = io::BufReader::with_capacity(buffer_size, reader);
+    let copied = io::copy_buf(&mut reader, writer).await?;
+    writer.shutdown().await?;
+    Ok(copied)
+}
+
+/// Outcome of parser which came up with a name.
+#[derive(Debug, PartialEq, Eq)]
+struct Parsed {
+    name: String,
+    /// Length of prefix parser claimed for itself
+    consumed: usize,
+    fingerprint: Option<String>,
+}
+
+/// Reads until some parser comes up with a name.
+///
+/// Every parser still in the running looks at the whole buffer after each read, so the cost is
+/// O(parsers × reads), bounded by `max_reads` when it is set.
 #[instrument(skip_all, fields(parsers = parsers.len()))]
 async fn parse_service_name<'b, 'p, B, R>(
     reader: &mut R,
@@ -140,23 +803,47 @@ async fn parse_service_name<'b, 'p, B, R>(
     parsers: &'p mut [&'p mut (dyn Parser<String, Box<dyn std::error::Error + Send + 'static>>
                           + Send
                           + 'static)],
-) -> Result<Option<String>, Error>
+    max_reads: Option<usize>,
+) -> Result<Option<Parsed>, Error>
 where
     B: BufMut + Deref<Target = [u8]>,
-    R: AsyncReadExt + Unpin + core::fmt::Debug,
+    R: AsyncReadExt + Unpin,
 {
     debug!("enter");
     let mut active: Vec<usize> = (0..parsers.len()).collect();
+    let tried: Vec<&str> = parsers.iter().map(|parser| parser.name()).collect();
+    let mut first_read = true;
+    let mut reads = 0;
 
     loop {
         if active.is_empty() {
+            debug!(?tried, "No parser recognized traffic");
             return Ok(None);
         }
 
-        reader.read_buf(buf).await?;
+        if max_reads.is_some_and(|max| reads >= max) {
+            return Err(Error::TooFragmented(reads));
+        }
+        reads += 1;
+
+        if reader.read_buf(buf).await? == 0 {
+            debug!(?tried, "Client closed before any parser succeeded");
+            return Ok(None);
+        }
 
         trace!("read");
 
+        if first_read {
+            first_read = false;
+            active.retain(|&ix| {
+                let compatible = parsers[ix].sniff(buf);
+                if !compatible {
+                    debug!(parser = parsers[ix].name(), "Ruled out by first bytes");
+                }
+                compatible
+            });
+        }
+
         let mut valid = Vec::new();
 
         for &ix in active.iter() {
@@ -164,12 +851,18 @@ where
 
             match parser.parse(buf) {
                 // Parser successfully parsed the name
-                Ok(Some(name)) => return Ok(Some(name)),
+                Ok(Some(name)) => {
+                    return Ok(Some(Parsed {
+                        name,
+                        consumed: parser.consumed(),
+                        fingerprint: parser.fingerprint(),
+                    }))
+                }
                 // Parser still requires more data
                 Ok(None) => valid.push(ix),
                 // Parser failed to parse - no need to ask it anymore
                 Err(err) => {
-                    debug!("Failed to parse: {err}");
+                    debug!(parser = parser.name(), "Parser dropped: {err}");
                 }
             }
         }
@@ -177,3 +870,729 @@ where
         active = valid;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        copy_half_closing, forward, forward_stream, parse_service_name,
+        parser::{tls, Parser},
+        resolver::{Destination, ResolveRequest, Resolved, ServiceName},
+        DropReason, Error, ForwardOptions, ForwardOutcome, ForwardPolicy,
+    };
+    use std::{
+        future::{ready, Ready},
+        net::SocketAddr,
+        pin::Pin,
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf},
+        net::{TcpListener, TcpStream},
+    };
+
+    struct To(SocketAddr);
+
+    impl tower::Service<ResolveRequest> for To {
+        type Response = Option<Resolved>;
+        type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: ResolveRequest) -> Self::Future {
+            ready(Ok(Some(Resolved::new("test", self.0))))
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_connection_after_max_lifetime() {
+        let upstream = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream");
+        let upstream_address = upstream.local_addr().expect("upstream address");
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+
+        let mut client = TcpStream::connect(proxy.local_addr().expect("proxy address"))
+            .await
+            .expect("connect");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+
+        let options = ForwardOptions {
+            max_lifetime: Some(Duration::from_millis(200)),
+            ..ForwardOptions::default()
+        };
+        let forwarding = tokio::spawn(async move {
+            forward(
+                &mut incoming,
+                To(upstream_address),
+                std::iter::empty(),
+                &options,
+            )
+            .await
+        });
+
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        client.write_all(b"hello").await.expect("write");
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.expect("read");
+
+        // Both sides keep connection open, only lifetime cap ends it
+        let stats = forwarding
+            .await
+            .expect("forward task")
+            .expect("forward succeeds")
+            .into_stats();
+        assert!(stats.lifetime_exceeded);
+        assert_eq!(stats.bytes_in, 5);
+        assert_eq!(client.read(&mut buf).await.expect("read"), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_upstream_and_traffic_of_failed_relay() {
+        let upstream = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream");
+        let upstream_address = upstream.local_addr().expect("upstream address");
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+
+        let mut client = TcpStream::connect(proxy.local_addr().expect("proxy address"))
+            .await
+            .expect("connect");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+
+        let forwarding = tokio::spawn(async move {
+            forward(
+                &mut incoming,
+                To(upstream_address),
+                std::iter::empty(),
+                &ForwardOptions::default(),
+            )
+            .await
+        });
+
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        client.write_all(b"hello").await.expect("write");
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.expect("read");
+        // Closing without lingering resets connection
+        accepted.set_linger(Some(Duration::ZERO)).expect("linger");
+        drop(accepted);
+
+        match forwarding.await.expect("forward task") {
+            Err(Error::Relay { stats, source }) => {
+                assert_eq!(stats.resolved, Some(upstream_address));
+                assert_eq!(stats.bytes_in, 5);
+                assert_eq!(source.kind(), std::io::ErrorKind::ConnectionReset);
+            }
+            other => panic!("Unexpected outcome: {other:?}"),
+        }
+    }
+
+    /// Resolves to the address with policy of its own.
+    struct WithPolicy(SocketAddr, ForwardPolicy);
+
+    impl tower::Service<ResolveRequest> for WithPolicy {
+        type Response = Option<Resolved>;
+        type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: ResolveRequest) -> Self::Future {
+            ready(Ok(Some(Resolved::new("test", self.0).with_policy(self.1))))
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connection_per_destination_policy() {
+        let upstream = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream");
+        let upstream_address = upstream.local_addr().expect("upstream address");
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+
+        let mut client = TcpStream::connect(proxy.local_addr().expect("proxy address"))
+            .await
+            .expect("connect");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+
+        // Destination policy wins over listener default, which would never fire in time
+        let options = ForwardOptions {
+            policy: ForwardPolicy {
+                idle_timeout: Some(Duration::from_secs(3600)),
+                ..ForwardPolicy::default()
+            },
+            ..ForwardOptions::default()
+        };
+        let policy = ForwardPolicy {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..ForwardPolicy::default()
+        };
+        let forwarding = tokio::spawn(async move {
+            forward(
+                &mut incoming,
+                WithPolicy(upstream_address, policy),
+                std::iter::empty(),
+                &options,
+            )
+            .await
+        });
+
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        client.write_all(b"hello").await.expect("write");
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.expect("read");
+
+        let stats = forwarding
+            .await
+            .expect("forward task")
+            .expect("forward succeeds")
+            .into_stats();
+        assert!(stats.idle_timeout_exceeded);
+        assert!(!stats.lifetime_exceeded);
+        assert_eq!(stats.bytes_in, 5);
+        assert_eq!(client.read(&mut buf).await.expect("read"), 0);
+    }
+
+    /// Parser which always wants more data.
+    struct Insatiable;
+
+    impl Parser<String, Box<dyn std::error::Error + Send + 'static>> for Insatiable {
+        fn parse(
+            &mut self,
+            _: &[u8],
+        ) -> Result<Option<String>, Box<dyn std::error::Error + Send + 'static>> {
+            Ok(None)
+        }
+
+        fn name(&self) -> &'static str {
+            "insatiable"
+        }
+    }
+
+    /// Parser taking name from a line of its own, which precedes actual traffic.
+    #[derive(Default)]
+    struct Preamble {
+        consumed: usize,
+    }
+
+    impl Parser<String, Box<dyn std::error::Error + Send + 'static>> for Preamble {
+        fn parse(
+            &mut self,
+            input: &[u8],
+        ) -> Result<Option<String>, Box<dyn std::error::Error + Send + 'static>> {
+            let Some(end) = input.iter().position(|byte| *byte == b'\n') else {
+                return Ok(None);
+            };
+            self.consumed = end + 1;
+            Ok(Some(String::from_utf8_lossy(&input[..end]).into_owned()))
+        }
+
+        fn name(&self) -> &'static str {
+            "preamble"
+        }
+
+        fn consumed(&self) -> usize {
+            self.consumed
+        }
+    }
+
+    #[tokio::test]
+    async fn strips_consumed_prefix() {
+        let upstream = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream");
+        let upstream_address = upstream.local_addr().expect("upstream address");
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+        let mut client = TcpStream::connect(proxy.local_addr().expect("proxy address"))
+            .await
+            .expect("connect");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+
+        client
+            .write_all(b"example.com\npayload")
+            .await
+            .expect("write");
+        client.shutdown().await.expect("half-close");
+        let forwarding = tokio::spawn(async move {
+            let parsers = std::iter::once(Box::new(Preamble::default()) as Box<_>);
+            forward(
+                &mut incoming,
+                To(upstream_address),
+                parsers,
+                &ForwardOptions::default(),
+            )
+            .await
+        });
+
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        let mut forwarded = Vec::new();
+        accepted.read_to_end(&mut forwarded).await.expect("read");
+        drop(accepted);
+
+        let stats = forwarding
+            .await
+            .expect("forward task")
+            .expect("forward succeeds")
+            .into_stats();
+        assert_eq!(forwarded, b"payload");
+        assert_eq!(
+            stats.service_name,
+            Some(ServiceName::Parsed("example.com".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_in_memory_stream() {
+        let upstream = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream");
+        let upstream_address = upstream.local_addr().expect("upstream address");
+        let (mut client, mut incoming) = tokio::io::duplex(64);
+        client
+            .write_all(b"example.com\npayload")
+            .await
+            .expect("write");
+        client.shutdown().await.expect("half-close");
+
+        let forwarding = tokio::spawn(async move {
+            let parsers = std::iter::once(Box::new(Preamble::default()) as Box<_>);
+            forward_stream(
+                &mut incoming,
+                ([127, 0, 0, 1], 8443).into(),
+                ([10, 0, 0, 1], 50000).into(),
+                To(upstream_address),
+                parsers,
+                &ForwardOptions::default(),
+            )
+            .await
+        });
+
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        let mut forwarded = Vec::new();
+        accepted.read_to_end(&mut forwarded).await.expect("read");
+        accepted.write_all(b"response").await.expect("write");
+        drop(accepted);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.expect("read");
+        let outcome = forwarding
+            .await
+            .expect("forward task")
+            .expect("forward succeeds");
+        assert_eq!(forwarded, b"payload");
+        assert_eq!(response, b"response");
+        assert!(matches!(outcome, ForwardOutcome::Forwarded(_)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn forwards_to_unix_socket() {
+        let path = std::env::temp_dir().join(format!("rpx-upstream-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let upstream = tokio::net::UnixListener::bind(&path).expect("bind upstream");
+        let (mut client, mut incoming) = tokio::io::duplex(64);
+        client
+            .write_all(b"example.com\npayload")
+            .await
+            .expect("write");
+        client.shutdown().await.expect("half-close");
+
+        let resolved = Resolved::unix("test", &path);
+        let forwarding = tokio::spawn(async move {
+            let resolver = tower::service_fn(move |_: ResolveRequest| {
+                ready(Ok::<_, tower::BoxError>(Some(resolved.clone())))
+            });
+            let parsers = std::iter::once(Box::new(Preamble::default()) as Box<_>);
+            forward_stream(
+                &mut incoming,
+                ([127, 0, 0, 1], 8443).into(),
+                ([10, 0, 0, 1], 50000).into(),
+                resolver,
+                parsers,
+                &ForwardOptions::default(),
+            )
+            .await
+        });
+
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        let mut forwarded = Vec::new();
+        accepted.read_to_end(&mut forwarded).await.expect("read");
+        accepted.write_all(b"response").await.expect("write");
+        drop(accepted);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.expect("read");
+        let outcome = forwarding
+            .await
+            .expect("forward task")
+            .expect("forward succeeds");
+        std::fs::remove_file(&path).expect("Removes socket");
+        assert_eq!(forwarded, b"payload");
+        assert_eq!(response, b"response");
+        let ForwardOutcome::Forwarded(stats) = outcome else {
+            panic!("Expected forwarded connection, got {outcome:?}");
+        };
+        assert_eq!(stats.resolved, None);
+        assert_eq!(stats.resolver, Some("test"));
+    }
+
+    #[tokio::test]
+    async fn writes_upstream_preamble_ahead_of_client_bytes() {
+        let upstream = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream");
+        let resolved = Resolved::new("test", upstream.local_addr().expect("upstream address"))
+            .with_preamble(bytes::Bytes::from_static(b"HELLO\0"));
+        let (mut client, mut incoming) = tokio::io::duplex(64);
+        client
+            .write_all(b"example.com\npayload")
+            .await
+            .expect("write");
+        client.shutdown().await.expect("half-close");
+
+        let forwarding = tokio::spawn(async move {
+            let resolver = tower::service_fn(move |_: ResolveRequest| {
+                ready(Ok::<_, tower::BoxError>(Some(resolved.clone())))
+            });
+            let parsers = std::iter::once(Box::new(Preamble::default()) as Box<_>);
+            forward_stream(
+                &mut incoming,
+                ([127, 0, 0, 1], 8443).into(),
+                ([10, 0, 0, 1], 50000).into(),
+                resolver,
+                parsers,
+                &ForwardOptions::default(),
+            )
+            .await
+        });
+
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        let mut forwarded = Vec::new();
+        accepted.read_to_end(&mut forwarded).await.expect("read");
+        drop(accepted);
+
+        let stats = forwarding
+            .await
+            .expect("forward task")
+            .expect("forward succeeds")
+            .into_stats();
+        assert_eq!(forwarded, b"HELLO\0payload");
+        assert_eq!(stats.bytes_preamble, 6);
+        assert_eq!(stats.bytes_in, 7);
+    }
+
+    #[tokio::test]
+    async fn does_not_pool_upstream_which_has_seen_preamble() {
+        let upstream = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream");
+        let address = upstream.local_addr().expect("upstream address");
+        let resolved =
+            Resolved::new("test", address).with_preamble(bytes::Bytes::from_static(b"HELLO\0"));
+        let pool = crate::pool::Pool::new(Duration::from_secs(60), 1);
+        let options = ForwardOptions {
+            pool: Some(pool.clone()),
+            ..ForwardOptions::default()
+        };
+        let (mut client, mut incoming) = tokio::io::duplex(64);
+        client.write_all(b"example.com\n").await.expect("write");
+        client.shutdown().await.expect("half-close");
+
+        let forwarding = tokio::spawn(async move {
+            let resolver = tower::service_fn(move |_: ResolveRequest| {
+                ready(Ok::<_, tower::BoxError>(Some(resolved.clone())))
+            });
+            let parsers = std::iter::once(Box::new(Preamble::default()) as Box<_>);
+            forward_stream(
+                &mut incoming,
+                ([127, 0, 0, 1], 8443).into(),
+                ([10, 0, 0, 1], 50000).into(),
+                resolver,
+                parsers,
+                &options,
+            )
+            .await
+        });
+
+        // Client's FIN is passed on, as it would not be for connection kept for reuse
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        let mut forwarded = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), accepted.read_to_end(&mut forwarded))
+            .await
+            .expect("EOF passed upstream")
+            .expect("read");
+        drop(accepted);
+
+        forwarding
+            .await
+            .expect("forward task")
+            .expect("forward succeeds");
+        assert_eq!(forwarded, b"HELLO\0");
+        assert!(pool.take(&[address]).is_none());
+    }
+
+    #[tokio::test]
+    async fn stops_parsing_on_eof() {
+        let mut reader: &[u8] = b"partial";
+        let mut buf = bytes::BytesMut::new();
+        let mut parser = Insatiable;
+
+        let parsed = tokio::time::timeout(
+            Duration::from_secs(1),
+            parse_service_name(&mut reader, &mut buf, &mut [&mut parser], None),
+        )
+        .await
+        .expect("Returns once input is exhausted")
+        .expect("Reads");
+
+        assert_eq!(parsed, None);
+        assert_eq!(&buf[..], b"partial");
+    }
+
+    /// Hands out a single byte per read.
+    struct Trickle(Vec<u8>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if !self.0.is_empty() {
+                let byte = self.0.remove(0);
+                buf.put_slice(&[byte]);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_trickled_name() {
+        // Handshake record header announcing a ClientHello of 512 bytes
+        let mut hello = vec![
+            0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03,
+        ];
+        hello.resize(5 + 512, 0);
+        let mut reader = Trickle(hello);
+        let mut buf = bytes::BytesMut::new();
+        let mut parser = tls::ServiceName::default();
+
+        let parsed = parse_service_name(&mut reader, &mut buf, &mut [&mut parser], Some(100)).await;
+
+        assert!(matches!(parsed, Err(Error::TooFragmented(100))));
+        assert_eq!(buf.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn refuses_destination_pointing_at_itself() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+        let proxy_address = proxy.local_addr().expect("proxy address");
+        let mut client = TcpStream::connect(proxy_address).await.expect("connect");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+
+        let outcome = forward(
+            &mut incoming,
+            To(proxy_address),
+            std::iter::empty(),
+            &ForwardOptions::default(),
+        )
+        .await
+        .expect("forward succeeds");
+
+        assert!(matches!(
+            outcome,
+            ForwardOutcome::Dropped {
+                reason: DropReason::LoopsBack,
+                ..
+            }
+        ));
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await.expect("read"), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_configured_self_address() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+        let _client = TcpStream::connect(proxy.local_addr().expect("proxy address"))
+            .await
+            .expect("connect");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+
+        let other_listener: SocketAddr = "127.0.0.1:1".parse().expect("valid address");
+        let options = ForwardOptions {
+            self_addresses: vec![other_listener],
+            ..ForwardOptions::default()
+        };
+        let outcome = forward(
+            &mut incoming,
+            To(other_listener),
+            std::iter::empty(),
+            &options,
+        )
+        .await
+        .expect("forward succeeds");
+
+        assert_eq!(outcome.stats().resolved, None);
+        assert!(matches!(
+            outcome,
+            ForwardOutcome::Dropped {
+                reason: DropReason::LoopsBack,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn given_name_required_drops_unparsed() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+        let mut client = TcpStream::connect(proxy.local_addr().expect("proxy address"))
+            .await
+            .expect("connect");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+        client.write_all(&[0xff; 64]).await.expect("write");
+
+        let options = ForwardOptions {
+            require_name: true,
+            ..ForwardOptions::default()
+        };
+        // Resolves anything, connection is dropped before asking
+        let unreachable = "127.0.0.1:1".parse().expect("valid address");
+        let parsers = std::iter::once(Box::new(tls::ServiceName::default()) as Box<_>);
+        let outcome = forward(&mut incoming, To(unreachable), parsers, &options)
+            .await
+            .expect("forward succeeds");
+
+        assert_eq!(outcome.stats().service_name, Some(ServiceName::NotParsed));
+        assert!(matches!(
+            outcome,
+            ForwardOutcome::Dropped {
+                reason: DropReason::NameRequired,
+                ..
+            }
+        ));
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await.expect("read"), 0);
+    }
+
+    #[tokio::test]
+    async fn passes_half_close_on_while_response_streams() {
+        let (mut client, mut incoming) = tokio::io::duplex(64);
+        let (mut outgoing, mut upstream) = tokio::io::duplex(64);
+        let relay =
+            tokio::spawn(async move { copy_half_closing(&mut incoming, &mut outgoing, 4).await });
+
+        client.write_all(b"request").await.expect("write");
+        client.shutdown().await.expect("half-close");
+
+        // Upstream sees EOF while its response hasn't even started
+        let mut request = Vec::new();
+        upstream.read_to_end(&mut request).await.expect("read");
+        assert_eq!(request, b"request");
+        for chunk in [&b"stream"[..], b"ed"] {
+            upstream.write_all(chunk).await.expect("write");
+        }
+        upstream.shutdown().await.expect("close");
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.expect("read");
+        assert_eq!(response, b"streamed");
+        relay.await.expect("join").expect("relay succeeds");
+    }
+
+    /// Resolver whose buffer worker is gone.
+    struct Closed;
+
+    impl tower::Service<ResolveRequest> for Closed {
+        type Response = Option<Resolved>;
+        type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Err(std::io::Error::other("buffer worker is gone").into()))
+        }
+
+        fn call(&mut self, _: ResolveRequest) -> Self::Future {
+            unreachable!("Called without being ready")
+        }
+    }
+
+    #[tokio::test]
+    async fn tells_failing_stages_apart() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+        let proxy_address = proxy.local_addr().expect("proxy address");
+        // Nothing listens there once listener is dropped
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream")
+            .local_addr()
+            .expect("upstream address");
+
+        let mut client = TcpStream::connect(proxy_address).await.expect("connect");
+        client.write_all(b"hello").await.expect("write");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+        let refused = forward(
+            &mut incoming,
+            To(closed),
+            std::iter::empty(),
+            &ForwardOptions::default(),
+        )
+        .await;
+        let err = refused.expect_err("Connection refused");
+        assert_eq!(err.stage(), "connect");
+        match err {
+            Error::Connect {
+                destination,
+                source,
+            } => {
+                assert_eq!(destination, Destination::Tcp(vec![closed]));
+                assert_eq!(source.kind(), std::io::ErrorKind::ConnectionRefused);
+            }
+            other => panic!("Unexpected error: {other}"),
+        }
+
+        let mut client = TcpStream::connect(proxy_address).await.expect("connect");
+        client.write_all(b"hello").await.expect("write");
+        let (mut incoming, _) = proxy.accept().await.expect("accept");
+        let not_ready = forward(
+            &mut incoming,
+            Closed,
+            std::iter::empty(),
+            &ForwardOptions::default(),
+        )
+        .await;
+        let err = not_ready.expect_err("Resolver is closed");
+        assert!(matches!(err, Error::ResolverNotReady(_)), "{err}");
+        assert_eq!(err.stage(), "resolve");
+    }
+
+    #[test]
+    fn tells_retryable_resolver_failures() {
+        use crate::resolver::dns;
+        use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+
+        let timeout = || dns::Error::from(ResolveError::from(ResolveErrorKind::Timeout));
+        let failures: Vec<(tower::BoxError, bool)> = vec![
+            (timeout().into(), true),
+            (dns::Error::Unavailable(vec![timeout()]).into(), true),
+            (dns::Error::Other(timeout().into()).into(), true),
+            (
+                dns::Error::from(ResolveError::from("malformed answer")).into(),
+                false,
+            ),
+            (std::io::Error::other("unknown").into(), false),
+            (tower::load_shed::error::Overloaded::new().into(), true),
+        ];
+
+        for (failure, retryable) in failures {
+            let message = failure.to_string();
+            let err = Error::from_resolver(failure);
+            assert_eq!(err.is_retryable(), retryable, "{message}");
+        }
+    }
+}
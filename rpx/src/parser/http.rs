@@ -1,4 +1,4 @@
-use tracing::{debug, error, instrument};
+use tracing::{debug, instrument};
 
 const GET: &[u8] = b"GET";
 const HEAD: &[u8] = b"HEAD";
@@ -10,50 +10,308 @@ const PATCH: &[u8] = b"PATCH";
 const TRACE: &[u8] = b"TRACE";
 const DELETE: &[u8] = b"DELETE";
 const METHODS: [&[u8]; 9] = [GET, HEAD, OPTIONS, CONNECT, POST, PUT, PATCH, TRACE, DELETE];
+/// Largest header section buffered while looking for the host, in line with header limits of
+/// common HTTP servers rather than anything TLS imposes.
+pub const MAX_HEADER_SIZE: usize = 64 * 1024;
 
 /// Parses the hostname from http/1 bytes
+///
+/// Remembers how many bytes of complete lines had been scanned already,
+/// so every call only looks at newly arrived lines.
 #[derive(Default)]
-pub struct Hostname;
+pub struct Hostname {
+    scanned: usize,
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Supplied bytes are not valid http/1")]
     NotHttp1,
+    #[error("Header section exceeded {MAX_HEADER_SIZE} bytes")]
+    MaxSizeExceeded,
+    #[error("Request names no host, neither in request line nor `Host` header")]
+    NoHost,
 }
 
 impl super::Parser<String, Box<dyn std::error::Error + Send + 'static>> for Hostname {
+    fn name(&self) -> &'static str {
+        "http/1"
+    }
+
+    fn sniff(&self, first_bytes: &[u8]) -> bool {
+        is_http(first_bytes) != Detection::NotHttp
+    }
+
     #[instrument(skip_all, fields(input_size = input.len()))]
     fn parse(
         &mut self,
         input: &[u8],
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + 'static>> {
-        if !is_http(input) {
-            Err(Box::new(Error::NotHttp1))
-        } else {
-            Ok(try_read_hostname(input))
+        match is_http(input) {
+            Detection::Http => {}
+            Detection::Insufficient => return Ok(None),
+            Detection::NotHttp => return Err(Box::new(Error::NotHttp1)),
+        }
+
+        match self.scan(input) {
+            Ok(None) if input.len() > MAX_HEADER_SIZE => Err(Box::new(Error::MaxSizeExceeded)),
+            Ok(hostname) => Ok(hostname),
+            Err(err) => Err(Box::new(err)),
         }
     }
 }
 
+impl Hostname {
+    /// Reads host out of complete lines which were not scanned yet, preferring authority
+    /// from request line over `Host` header.
+    ///
+    /// Fails once it is clear no host is coming: header section ended without `Host`, as
+    /// HTTP/1.0 clients may send it, or request line has no version, as in HTTP/0.9, which
+    /// has no headers at all.
+    #[instrument(skip_all, fields(len = input.len(), scanned = self.scanned))]
+    fn scan(&mut self, input: &[u8]) -> Result<Option<String>, Error> {
+        let unscanned = &input[self.scanned.min(input.len())..];
+        // Trailing incomplete line is left for the next call
+        let end = match unscanned.iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => end + 1,
+            None => return Ok(None),
+        };
+
+        for line in unscanned[..end].split_inclusive(|byte| *byte == b'\n') {
+            let hostname = if self.scanned == 0 {
+                match request_target_authority(line) {
+                    Some((host, _)) => Some(host.to_owned()),
+                    None if !has_version(line) => return Err(Error::NoHost),
+                    None => None,
+                }
+            } else if line == b"\r\n" || line == b"\n" {
+                return Err(Error::NoHost);
+            } else {
+                read_host_header(line)
+            };
+            self.scanned += line.len();
+
+            if hostname.is_some() {
+                return Ok(hostname);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Outcome of checking whether bytes look like http/1 request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detection {
+    /// Bytes start with a known method
+    Http,
+    /// Bytes are a prefix of some method, more are needed to tell
+    Insufficient,
+    /// Bytes can't be http/1
+    NotHttp,
+}
+
 #[instrument(skip_all, fields(len = buf.len()))]
-pub fn is_http(buf: &[u8]) -> bool {
+pub fn is_http(buf: &[u8]) -> Detection {
     debug!("Got buf: {}", buf.len());
-    METHODS.iter().any(|&method| {
-        debug!("Method: {method:?}");
-        buf.starts_with(method)
-    })
+    if METHODS.iter().any(|&method| buf.starts_with(method)) {
+        Detection::Http
+    } else if METHODS.iter().any(|&method| method.starts_with(buf)) {
+        Detection::Insufficient
+    } else {
+        Detection::NotHttp
+    }
 }
 
-#[instrument(skip_all, fields(len = buf.len()))]
-fn try_read_hostname(buf: &[u8]) -> Option<String> {
-    buf.as_ref()
-        .split(|byte| *byte == b'\n')
-        .filter_map(|line| std::str::from_utf8(line).ok())
-        .find(|line| {
-            debug!("Got a line: {line}");
-            line.starts_with("Host") || line.starts_with("host")
-        })
-        .and_then(|header| header.split(':').nth(1))
-        .map(|hostname| hostname.trim())
-        .map(ToOwned::to_owned)
+/// Reads host and port from request line of forward-proxy style requests, either
+/// `CONNECT host:port HTTP/1.1` or absolute-form `GET http://host:port/path HTTP/1.1`.
+///
+/// Returns `None` for origin-form requests, e.g. `GET /path HTTP/1.1`, or if request line is
+/// incomplete.
+pub fn request_target_authority(buf: &[u8]) -> Option<(&str, Option<u16>)> {
+    let end = buf.iter().position(|byte| *byte == b'\n')?;
+    let line = std::str::from_utf8(&buf[..end]).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+
+    let authority = if method.as_bytes() == CONNECT {
+        target
+    } else {
+        let (scheme, rest) = target.split_once("://")?;
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return None;
+        }
+        let authority = rest.split(['/', '?', '#']).next()?;
+        // Drop userinfo, if any
+        authority.rsplit('@').next()?
+    };
+
+    let (host, port) = split_authority(authority)?;
+    debug!(host, ?port, "Got authority from request line");
+    Some((host, port))
+}
+
+/// Splits `host:port` authority, port being optional. IPv6 literal is expected in brackets,
+/// e.g. `[2001:db8::1]:8443`, and comes out without them.
+///
+/// Returns `None` if host is empty or brackets are not closed.
+pub fn split_authority(authority: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            // Bare IPv6 literal has no room for a port
+            Some((host, port)) if !host.contains(':') => (host, Some(port)),
+            _ => (authority, None),
+        },
+    };
+
+    if host.is_empty() {
+        None
+    } else {
+        Some((host, port.and_then(|port| port.parse().ok())))
+    }
+}
+
+/// Whether request line ends with protocol version, unlike HTTP/0.9 `GET /path`.
+fn has_version(line: &[u8]) -> bool {
+    line.split(u8::is_ascii_whitespace)
+        .filter(|part| !part.is_empty())
+        .nth(2)
+        .is_some()
+}
+
+fn read_host_header(line: &[u8]) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?;
+    debug!("Got a line: {line}");
+    if !line.starts_with("Host") && !line.starts_with("host") {
+        return None;
+    }
+
+    let (_, authority) = line.split_once(':')?;
+    split_authority(authority.trim()).map(|(host, _)| host.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        is_http, request_target_authority, split_authority, Detection, Error, Hostname,
+        MAX_HEADER_SIZE,
+    };
+    use crate::parser::{tls::MAX_HANDSHAKE_SIZE, Parser};
+    use test_case::test_case;
+
+    #[test_case(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n", Some("example.com"); "origin form")]
+    #[test_case(b"GET http://proxied.com/path HTTP/1.1\r\nHost: example.com\r\n\r\n", Some("proxied.com"); "absolute form wins over header")]
+    #[test_case(b"CONNECT proxied.com:443 HTTP/1.1\r\nHost: proxied.com:443\r\n\r\n", Some("proxied.com"); "connect")]
+    #[test_case(b"GET / HTTP/1.1\r\n", None; "no host")]
+    #[test_case(b"GET / HTTP/1.1\r\nHost: [2001:db8::1]:8443\r\n\r\n", Some("2001:db8::1"); "ipv6 host header")]
+    #[test_case(b"GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n", Some("example.com"); "host header with port")]
+    #[test_case(b"GET http://[::1]:8080/ HTTP/1.1\r\n\r\n", Some("::1"); "ipv6 absolute form")]
+    #[test_case(b"GET http://example.com/path HTTP/1.0\r\n\r\n", Some("example.com"); "http/1.0 absolute form")]
+    #[test_case(b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n", Some("example.com"); "http/1.0 host header")]
+    #[test_case(b"GET / HTTP/1.0\r\nAccept: */*\r\n", None; "http/1.0 headers still coming")]
+    fn reads_hostname(input: &[u8], expected: Option<&str>) {
+        let mut parser = Hostname::default();
+        assert_eq!(
+            parser.parse(input).expect("valid request").as_deref(),
+            expected
+        );
+    }
+
+    #[test_case(b"GET / HTTP/1.0\r\n\r\n"; "http/1.0 without host")]
+    #[test_case(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n"; "http/1.0 headers without host")]
+    #[test_case(b"GET /\r\n"; "no version")]
+    #[test_case(b"GET / HTTP/1.1\r\n\r\n"; "http/1.1 without host")]
+    fn gives_up_without_host(input: &[u8]) {
+        let mut parser = Hostname::default();
+        let err = parser.parse(input).expect_err("no host");
+        assert!(matches!(err.downcast_ref(), Some(Error::NoHost)), "{err}");
+    }
+
+    #[test]
+    fn reads_hostname_split_across_reads() {
+        let input = b"GET / HTTP/1.1\r\nAccept: */*\r\nHost: example.com\r\n\r\n";
+        let mut parser = Hostname::default();
+        for end in 0..input.len() {
+            let hostname = parser.parse(&input[..end]).expect("valid request");
+            // Host header only counts once its line is complete
+            let complete = end >= input.len() - 2;
+            assert_eq!(hostname.is_some(), complete, "read up to {end}");
+            if complete {
+                assert_eq!(hostname.as_deref(), Some("example.com"));
+                break;
+            }
+        }
+    }
+
+    #[test_case(b"CONNECT example.com:443 HTTP/1.1\r\n", Some(("example.com", Some(443))); "connect")]
+    #[test_case(b"GET https://user@example.com:8443/a?b HTTP/1.1\r\n", Some(("example.com", Some(8443))); "absolute with userinfo")]
+    #[test_case(b"GET HTTP://example.com?q HTTP/1.1\r\n", Some(("example.com", None)); "absolute without port")]
+    #[test_case(b"GET /path HTTP/1.1\r\n", None; "origin form")]
+    #[test_case(b"CONNECT example.com:443 HTT", None; "incomplete line")]
+    #[test_case(b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\n", Some(("2001:db8::1", Some(443))); "connect to ipv6")]
+    fn reads_request_target_authority(input: &[u8], expected: Option<(&str, Option<u16>)>) {
+        assert_eq!(request_target_authority(input), expected);
+    }
+
+    #[test_case("[::1]", Some(("::1", None)); "bracketed ipv6")]
+    #[test_case("[::1]:8443", Some(("::1", Some(8443))); "bracketed ipv6 with port")]
+    #[test_case("example.com:8443", Some(("example.com", Some(8443))); "host and port")]
+    #[test_case("example.com", Some(("example.com", None)); "host only")]
+    #[test_case("::1", Some(("::1", None)); "bare ipv6")]
+    #[test_case("[::1", None; "unclosed bracket")]
+    #[test_case(":443", None; "empty host")]
+    fn splits_authority(authority: &str, expected: Option<(&str, Option<u16>)>) {
+        assert_eq!(split_authority(authority), expected);
+    }
+
+    #[test]
+    fn gives_up_on_long_header_section() {
+        let mut parser = Hostname::default();
+        let mut input = b"GET / HTTP/1.1\r\n".to_vec();
+        input.resize(MAX_HEADER_SIZE, b'a');
+        assert!(matches!(parser.parse(&input), Ok(None)));
+
+        input.push(b'a');
+        assert!(parser.parse(&input).is_err());
+    }
+
+    #[test]
+    fn reads_header_section_larger_than_tls_handshake() {
+        let mut parser = Hostname::default();
+        let mut input = b"GET / HTTP/1.1\r\nCookie: ".to_vec();
+        input.resize(MAX_HANDSHAKE_SIZE + 1, b'a');
+        input.extend_from_slice(b"\r\nHost: example.com\r\n\r\n");
+
+        assert!(input.len() < MAX_HEADER_SIZE);
+        assert_eq!(
+            parser.parse(&input).expect("valid request").as_deref(),
+            Some("example.com")
+        );
+    }
+
+    #[test_case(b"GET / HTTP/1.1\r\n", Detection::Http; "full method")]
+    #[test_case(b"GE", Detection::Insufficient; "partial method")]
+    #[test_case(b"", Detection::Insufficient; "empty")]
+    #[test_case(b"GEX", Detection::NotHttp; "unknown method")]
+    #[test_case(b"\x16\x03\x01", Detection::NotHttp; "TLS record")]
+    fn detects_http(input: &[u8], expected: Detection) {
+        assert_eq!(is_http(input), expected);
+    }
+
+    #[test]
+    fn survives_short_first_read() {
+        let mut parser = Hostname::default();
+        assert!(matches!(parser.parse(b"GE"), Ok(None)));
+        assert_eq!(
+            parser
+                .parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .expect("valid request"),
+            Some("example.com".to_owned())
+        );
+    }
 }
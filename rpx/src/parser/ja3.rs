@@ -0,0 +1,292 @@
+//! [JA3] fingerprint of TLS clients, taken from raw ClientHello.
+//!
+//! [`rustls`] hands out parsed ClientHello without extension order, supported groups and point
+//! formats, so the handshake is walked here byte by byte instead.
+//!
+//! [JA3]: https://github.com/salesforce/ja3
+use super::tls::HANDSHAKE_RECORD;
+use md5::{Digest, Md5};
+use std::fmt::Write;
+
+const CLIENT_HELLO: u8 = 0x01;
+const SUPPORTED_GROUPS: u16 = 10;
+const EC_POINT_FORMATS: u16 = 11;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("ClientHello is incomplete")]
+    Incomplete,
+
+    #[error("Not a TLS handshake record")]
+    NotHandshake,
+
+    #[error("Handshake is not a ClientHello")]
+    NotClientHello,
+}
+
+/// Fields of ClientHello making up the fingerprint, GREASE values left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ja3 {
+    pub version: u16,
+    pub ciphers: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub groups: Vec<u16>,
+    pub point_formats: Vec<u8>,
+}
+
+impl Ja3 {
+    /// Reads ClientHello from TLS records in `records`, which may span several of them.
+    pub fn from_records(records: &[u8]) -> Result<Self, Error> {
+        let handshake = handshake(records)?;
+        let mut hello = Reader(&handshake);
+        if hello.u8()? != CLIENT_HELLO {
+            return Err(Error::NotClientHello);
+        }
+        let length = hello.u24()?;
+        let mut hello = Reader(hello.take(length)?);
+
+        let version = hello.u16()?;
+        // Random
+        hello.take(32)?;
+        let session_id = hello.u8()?.into();
+        hello.take(session_id)?;
+        let ciphers = hello.u16s()?;
+        let compression = hello.u8()?.into();
+        hello.take(compression)?;
+
+        let mut ja3 = Self {
+            version,
+            ciphers,
+            extensions: Vec::new(),
+            groups: Vec::new(),
+            point_formats: Vec::new(),
+        };
+        // Extensions are optional altogether
+        if hello.0.is_empty() {
+            return Ok(ja3);
+        }
+
+        let length = hello.u16()?.into();
+        let mut extensions = Reader(hello.take(length)?);
+        while !extensions.0.is_empty() {
+            let kind = extensions.u16()?;
+            let length = extensions.u16()?.into();
+            let mut data = Reader(extensions.take(length)?);
+            if is_grease(kind) {
+                continue;
+            }
+            ja3.extensions.push(kind);
+            match kind {
+                SUPPORTED_GROUPS => ja3.groups = data.u16s()?,
+                EC_POINT_FORMATS => {
+                    let length = data.u8()?.into();
+                    ja3.point_formats = data.take(length)?.to_vec();
+                }
+                _ => {}
+            }
+        }
+
+        Ok(ja3)
+    }
+
+    /// Comma separated fields, each a dash separated list of decimal values, e.g.
+    /// `769,47-53,0-10-11,23-24,0`.
+    pub fn fingerprint(&self) -> String {
+        let mut fingerprint = self.version.to_string();
+        for field in [&self.ciphers, &self.extensions, &self.groups] {
+            fingerprint.push(',');
+            join(&mut fingerprint, field.iter());
+        }
+        fingerprint.push(',');
+        join(&mut fingerprint, self.point_formats.iter());
+        fingerprint
+    }
+
+    /// MD5 of [fingerprint][Self::fingerprint] in hex, the form JA3 is usually shared in.
+    pub fn hash(&self) -> String {
+        Md5::digest(self.fingerprint())
+            .iter()
+            .fold(String::with_capacity(32), |mut hash, byte| {
+                let _ = write!(hash, "{byte:02x}");
+                hash
+            })
+    }
+}
+
+/// Handshake message reassembled from payloads of consecutive handshake records.
+fn handshake(mut records: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut handshake = Vec::new();
+    loop {
+        let mut record = Reader(records);
+        if record.u8()? != HANDSHAKE_RECORD {
+            return Err(Error::NotHandshake);
+        }
+        // Legacy record version
+        record.u16()?;
+        let length = record.u16()?.into();
+        handshake.extend_from_slice(record.take(length)?);
+        records = record.0;
+
+        // Type and 24 bit length precede the message
+        if let [_, a, b, c, ..] = handshake[..] {
+            let length = u32::from_be_bytes([0, a, b, c]) as usize;
+            if handshake.len() >= 4 + length {
+                return Ok(handshake);
+            }
+        }
+    }
+}
+
+/// GREASE values are picked at random by clients, so they are not part of fingerprint,
+/// see RFC 8701.
+fn is_grease(value: u16) -> bool {
+    let [high, low] = value.to_be_bytes();
+    high == low && low & 0x0f == 0x0a
+}
+
+fn join<T: ToString>(fingerprint: &mut String, values: impl Iterator<Item = T>) {
+    for (ix, value) in values.enumerate() {
+        if ix > 0 {
+            fingerprint.push('-');
+        }
+        fingerprint.push_str(&value.to_string());
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < length {
+            return Err(Error::Incomplete);
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, Error> {
+        let bytes = self.take(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    /// List of 16 bit values prefixed with its length in bytes, GREASE values skipped.
+    fn u16s(&mut self) -> Result<Vec<u16>, Error> {
+        let length = self.u16()?.into();
+        let mut list = Reader(self.take(length)?);
+        let mut values = Vec::with_capacity(length / 2);
+        while !list.0.is_empty() {
+            let value = list.u16()?;
+            if !is_grease(value) {
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, Ja3};
+
+    /// Wraps ClientHello body into handshake message and record, split into records of
+    /// `fragment` bytes.
+    fn records(body: &[u8], fragment: usize) -> Vec<u8> {
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(body);
+
+        handshake
+            .chunks(fragment)
+            .flat_map(|chunk| {
+                let mut record = vec![0x16, 0x03, 0x01];
+                record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                record.extend_from_slice(chunk);
+                record
+            })
+            .collect()
+    }
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        let mut list = ((values.len() * 2) as u16).to_be_bytes().to_vec();
+        values
+            .iter()
+            .for_each(|value| list.extend_from_slice(&value.to_be_bytes()));
+        list
+    }
+
+    fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut extension = kind.to_be_bytes().to_vec();
+        extension.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        extension.extend_from_slice(data);
+        extension
+    }
+
+    /// TLS 1.0 ClientHello behind the example in JA3 README.
+    fn hello(grease: bool) -> Vec<u8> {
+        let mut body = vec![0x03, 0x01];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        let mut ciphers = vec![47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4];
+        let mut groups = vec![23, 24, 25];
+        if grease {
+            ciphers.insert(0, 0x0a0a);
+            groups.insert(0, 0x2a2a);
+        }
+        body.extend_from_slice(&u16s(&ciphers));
+        body.extend_from_slice(&[1, 0]);
+
+        let mut extensions = Vec::new();
+        if grease {
+            extensions.extend(extension(0xfafa, &[]));
+        }
+        extensions.extend(extension(0, &[0, 0]));
+        extensions.extend(extension(10, &u16s(&groups)));
+        extensions.extend(extension(11, &[1, 0]));
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        body
+    }
+
+    #[test]
+    fn matches_known_fingerprint() {
+        let ja3 = Ja3::from_records(&records(&hello(false), 1 << 14)).expect("Parses");
+
+        assert_eq!(
+            ja3.fingerprint(),
+            "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0"
+        );
+        assert_eq!(ja3.hash(), "ada70206e40642a3e4461f35503241d5");
+    }
+
+    #[test]
+    fn skips_grease_and_reassembles_fragments() {
+        let plain = Ja3::from_records(&records(&hello(false), 1 << 14)).expect("Parses");
+        let greased = Ja3::from_records(&records(&hello(true), 16)).expect("Parses");
+
+        assert_eq!(greased, plain);
+    }
+
+    #[test]
+    fn rejects_truncated_hello() {
+        let records = records(&hello(false), 1 << 14);
+
+        assert_eq!(
+            Ja3::from_records(&records[..records.len() - 1]),
+            Err(Error::Incomplete)
+        );
+        assert_eq!(
+            Ja3::from_records(b"GET / HTTP/1.1"),
+            Err(Error::NotHandshake)
+        );
+    }
+}
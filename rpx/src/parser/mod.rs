@@ -1,6 +1,117 @@
 pub mod http;
+pub mod ja3;
+pub mod smtp;
+pub mod ssh;
 pub mod tls;
 
 pub trait Parser<O, E> {
     fn parse(&mut self, input: &[u8]) -> Result<Option<O>, E>;
+
+    /// Short name identifying parser in logs, e.g. when it gives up on traffic.
+    fn name(&self) -> &'static str;
+
+    /// Leading bytes of input that belong to parser itself, e.g. a header of its own, and are
+    /// not forwarded upstream. Asked once parser came up with a name.
+    fn consumed(&self) -> usize {
+        0
+    }
+
+    /// Fingerprint of the client taken while parsing, e.g. [JA3][ja3] hash of TLS ClientHello.
+    /// Asked once parser came up with a name.
+    fn fingerprint(&self) -> Option<String> {
+        None
+    }
+
+    /// Cheap check of the first bytes client sent, `false` rules parser out right away so it is
+    /// not asked again on every read.
+    fn sniff(&self, _first_bytes: &[u8]) -> bool {
+        true
+    }
+
+    /// Bytes parser typically needs to see before it succeeds,
+    /// used to size the buffer incoming data is read into.
+    fn size_hint(&self) -> usize {
+        256
+    }
+}
+
+/// Protocol told apart by [`sniff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Starts with TLS handshake record
+    Tls,
+    /// Starts with HTTP/1 request method
+    Http1,
+    /// Neither of the above
+    Unknown,
+    /// Bytes are a prefix of some HTTP method, or there are none yet
+    NeedMoreData,
+}
+
+/// Classifies connection by the first bytes client sent, without running any parser.
+pub fn sniff(first_bytes: &[u8]) -> Protocol {
+    match first_bytes.first() {
+        None => Protocol::NeedMoreData,
+        Some(&tls::HANDSHAKE_RECORD) => Protocol::Tls,
+        Some(_) => match http::is_http(first_bytes) {
+            http::Detection::Http => Protocol::Http1,
+            http::Detection::Insufficient => Protocol::NeedMoreData,
+            http::Detection::NotHttp => Protocol::Unknown,
+        },
+    }
+}
+
+/// Brings parsed name to the form resolvers match against: ASCII lowercase, without trailing dot.
+///
+/// Non-ASCII bytes are left untouched.
+pub fn normalize(mut name: String) -> String {
+    name.make_ascii_lowercase();
+    if name.ends_with('.') {
+        name.pop();
+    }
+    name
+}
+
+#[cfg(test)]
+mod test {
+    use super::{http, normalize, smtp, sniff, ssh, tls, Parser, Protocol};
+    use test_case::test_case;
+
+    type Boxed = Box<dyn Parser<String, Box<dyn std::error::Error + Send + 'static>>>;
+
+    #[test_case(Box::new(http::Hostname::default()), "http/1"; "http")]
+    #[test_case(Box::new(tls::ServiceName::default()), "tls"; "tls")]
+    #[test_case(Box::new(smtp::Hostname), "smtp"; "smtp")]
+    #[test_case(Box::new(ssh::SoftwareVersion), "ssh"; "ssh")]
+    fn names_match_config(parser: Boxed, name: &str) {
+        assert_eq!(parser.name(), name);
+    }
+
+    #[test_case(&[0x16, 0x03, 0x01], true, false; "tls record")]
+    #[test_case(b"GET / HTTP/1.1", false, true; "http request")]
+    #[test_case(b"PO", false, true; "http method prefix")]
+    #[test_case(b"SSH-2.0-OpenSSH", false, false; "neither")]
+    fn sniffs_first_bytes(first_bytes: &[u8], tls: bool, http: bool) {
+        assert_eq!(tls::ServiceName::default().sniff(first_bytes), tls);
+        assert_eq!(http::Hostname::default().sniff(first_bytes), http);
+    }
+
+    #[test_case(&[0x16, 0x03, 0x01], Protocol::Tls; "tls record")]
+    #[test_case(b"GET / HTTP/1.1", Protocol::Http1; "http request")]
+    #[test_case(b"SSH-2.0-OpenSSH", Protocol::Unknown; "other protocol")]
+    #[test_case(b"PO", Protocol::NeedMoreData; "http method prefix")]
+    #[test_case(b"", Protocol::NeedMoreData; "nothing yet")]
+    fn classifies_protocol(first_bytes: &[u8], expected: Protocol) {
+        assert_eq!(sniff(first_bytes), expected);
+    }
+
+    #[test_case("example.com", "example.com"; "unchanged")]
+    #[test_case("Example.COM", "example.com"; "mixed case")]
+    #[test_case("example.com.", "example.com"; "trailing dot")]
+    #[test_case("Example.COM.", "example.com"; "mixed case with trailing dot")]
+    #[test_case("xn--mnchen-3ya.Example", "xn--mnchen-3ya.example"; "punycode")]
+    #[test_case("MÜnchen.example", "mÜnchen.example"; "non-ascii untouched")]
+    fn normalizes(name: &str, expected: &str) {
+        assert_eq!(normalize(name.to_owned()), expected);
+    }
 }
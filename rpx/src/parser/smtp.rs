@@ -0,0 +1,119 @@
+//! Reads the hostname client announces in SMTP `EHLO`/`HELO` command.
+//!
+//! In SMTP server speaks first, but the banner comes from upstream and is of no use for routing.
+//! What is parsed here is the client's command, sent before `STARTTLS` is negotiated:
+//! ```text
+//! EHLO client.example.com\r\n
+//! ```
+//! Since clients normally wait for the banner before sending `EHLO`, this parser only helps
+//! with clients that send it right away.
+use super::Parser;
+use tracing::{debug, instrument};
+
+const EHLO: &[u8] = b"EHLO";
+const HELO: &[u8] = b"HELO";
+/// Input starting with fewer letters than this is not an SMTP command.
+const MIN_VERB_LEN: usize = 3;
+/// Longest command line allowed by RFC 5321, including CRLF.
+const MAX_LINE_LEN: usize = 512;
+
+/// Parses the hostname out of `EHLO` or `HELO` command
+#[derive(Default)]
+pub struct Hostname;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Supplied bytes are not SMTP")]
+    NotSmtp,
+    #[error("First command is neither EHLO nor HELO")]
+    NotHello,
+    #[error("Hello command carries no hostname")]
+    MissingHostname,
+    #[error("Command line exceeds {MAX_LINE_LEN} bytes")]
+    MaxSizeExceeded,
+}
+
+impl Parser<String, Box<dyn std::error::Error + Send + 'static>> for Hostname {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    #[instrument(skip_all, fields(input_size = input.len()))]
+    fn parse(
+        &mut self,
+        input: &[u8],
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + 'static>> {
+        parse(input).map_err(|err| Box::new(err) as Box<dyn std::error::Error + Send + 'static>)
+    }
+}
+
+fn parse(input: &[u8]) -> Result<Option<String>, Error> {
+    let head = &input[..input.len().min(MAX_LINE_LEN)];
+    let line_end = head.windows(2).position(|window| window == b"\r\n");
+    if line_end.is_none() && head.len() == MAX_LINE_LEN {
+        return Err(Error::MaxSizeExceeded);
+    }
+
+    let verb_len = input
+        .iter()
+        .position(|byte| !byte.is_ascii_alphabetic())
+        .unwrap_or(input.len());
+
+    // Verb could still be incomplete
+    if verb_len == input.len() {
+        return Ok(None);
+    }
+    if verb_len < MIN_VERB_LEN {
+        return Err(Error::NotSmtp);
+    }
+
+    let line = match line_end {
+        Some(end) => &input[..end],
+        None => return Ok(None),
+    };
+
+    let verb = &line[..verb_len];
+    if !verb.eq_ignore_ascii_case(EHLO) && !verb.eq_ignore_ascii_case(HELO) {
+        return Err(Error::NotHello);
+    }
+
+    let hostname = std::str::from_utf8(&line[verb_len..])
+        .ok()
+        .and_then(|rest| rest.split_whitespace().next())
+        .ok_or(Error::MissingHostname)?;
+    debug!(hostname, "Got hostname from hello command");
+
+    Ok(Some(hostname.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use test_case::test_case;
+
+    #[test_case(b"EHLO client.example.com\r\n", Some("client.example.com"); "EHLO")]
+    #[test_case(b"helo client.example.com \r\nMAIL", Some("client.example.com"); "lowercase HELO")]
+    #[test_case(b"EHL", None; "partial verb")]
+    #[test_case(b"EHLO client.exa", None; "partial line")]
+    fn parses_hello(input: &[u8], expected: Option<&str>) {
+        assert_eq!(parse(input).expect("valid input").as_deref(), expected);
+    }
+
+    #[test_case(b"\x16\x03\x01\x02\x00"; "TLS record")]
+    #[test_case(b"GET / HTTP/1.1\r\n"; "HTTP request")]
+    #[test_case(b"EHLO \r\n"; "missing hostname")]
+    fn rejects(input: &[u8]) {
+        assert!(parse(input).is_err());
+    }
+
+    #[test_case(b"EHLO "; "endless hostname")]
+    #[test_case(b"EHLO"; "endless verb")]
+    fn rejects_long_line(prefix: &[u8]) {
+        let mut input = prefix.to_vec();
+        input.resize(600, b'a');
+        assert!(parse(&input).is_err());
+
+        input.extend_from_slice(b"\r\n");
+        assert!(parse(&input).is_err());
+    }
+}
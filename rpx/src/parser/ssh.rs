@@ -0,0 +1,101 @@
+//! Reads SSH client identification string, see [RFC 4253].
+//!
+//! Client opens connection with
+//! ```text
+//! SSH-2.0-OpenSSH_9.6 optional comments\r\n
+//! ```
+//! software version (`OpenSSH_9.6`) is used as the service name.
+//!
+//! [RFC 4253]: https://www.rfc-editor.org/rfc/rfc4253#section-4.2
+use super::Parser;
+use tracing::{debug, instrument};
+
+const PREFIX: &[u8] = b"SSH-";
+/// Longest identification string allowed, including CRLF.
+const MAX_LINE_LEN: usize = 255;
+
+/// Parses software version out of identification string
+#[derive(Default)]
+pub struct SoftwareVersion;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Supplied bytes are not SSH")]
+    NotSsh,
+    #[error("Identification string exceeds {MAX_LINE_LEN} bytes")]
+    MaxSizeExceeded,
+    #[error("Identification string is malformed")]
+    Malformed,
+}
+
+impl Parser<String, Box<dyn std::error::Error + Send + 'static>> for SoftwareVersion {
+    fn name(&self) -> &'static str {
+        "ssh"
+    }
+
+    #[instrument(skip_all, fields(input_size = input.len()))]
+    fn parse(
+        &mut self,
+        input: &[u8],
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + 'static>> {
+        parse(input).map_err(|err| Box::new(err) as Box<dyn std::error::Error + Send + 'static>)
+    }
+}
+
+fn parse(input: &[u8]) -> Result<Option<String>, Error> {
+    let prefix_len = input.len().min(PREFIX.len());
+    if input[..prefix_len] != PREFIX[..prefix_len] {
+        return Err(Error::NotSsh);
+    }
+
+    let line = match input.iter().position(|byte| *byte == b'\n') {
+        Some(end) if end < MAX_LINE_LEN => &input[..end],
+        Some(_) => return Err(Error::MaxSizeExceeded),
+        None if input.len() >= MAX_LINE_LEN => return Err(Error::MaxSizeExceeded),
+        None => return Ok(None),
+    };
+
+    // SSH-protoversion-softwareversion SP comments
+    let software_version = std::str::from_utf8(&line[PREFIX.len()..])
+        .ok()
+        .map(|rest| rest.trim_end_matches('\r'))
+        .and_then(|rest| rest.split_once('-'))
+        .and_then(|(_, rest)| rest.split(' ').next())
+        .filter(|software_version| !software_version.is_empty())
+        .ok_or(Error::Malformed)?;
+    debug!(
+        software_version,
+        "Got software version from identification string"
+    );
+
+    Ok(Some(software_version.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use test_case::test_case;
+
+    #[test_case(b"SSH-2.0-OpenSSH_9.6\r\n", Some("OpenSSH_9.6"); "plain")]
+    #[test_case(b"SSH-2.0-OpenSSH_9.6 Ubuntu-3\r\n", Some("OpenSSH_9.6"); "with comments")]
+    #[test_case(b"SSH-2.0-PuTTY\n", Some("PuTTY"); "bare LF")]
+    #[test_case(b"SS", None; "partial prefix")]
+    #[test_case(b"SSH-2.0-Open", None; "partial line")]
+    fn parses_identification(input: &[u8], expected: Option<&str>) {
+        assert_eq!(parse(input).expect("valid input").as_deref(), expected);
+    }
+
+    #[test_case(b"\x16\x03\x01\x02\x00"; "TLS record")]
+    #[test_case(b"GET / HTTP/1.1\r\n"; "HTTP request")]
+    #[test_case(b"SSH-2.0\r\n"; "missing software version")]
+    fn rejects(input: &[u8]) {
+        assert!(parse(input).is_err());
+    }
+
+    #[test]
+    fn rejects_long_line() {
+        let mut input = b"SSH-2.0-".to_vec();
+        input.resize(300, b'a');
+        assert!(parse(&input).is_err());
+    }
+}
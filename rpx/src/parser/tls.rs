@@ -1,18 +1,30 @@
 //! To route by SNI, field needs to be parsed from TLS handshake.
 //! To avoid reinventing wheels - module leverages [`rustls`].
-use super::Parser;
+use super::{ja3::Ja3, Parser};
 use rustls::{internal::msgs::message::OpaqueMessage, server::Acceptor};
 use std::io::Cursor;
 use tracing::{debug, error, instrument};
 
+/// Content type of TLS records carrying handshake messages, ClientHello included.
+pub(super) const HANDSHAKE_RECORD: u8 = 0x16;
+/// Largest handshake buffered while looking for ClientHello, a single TLS record at most.
+pub const MAX_HANDSHAKE_SIZE: usize = OpaqueMessage::MAX_WIRE_SIZE;
+
 /// Parses service name extension
 ///
 /// Stores [acceptor][Acceptor] and bytes accepted so far.
 /// Technically could be stateless, but `Acceptor` already
 /// has internal state.
+///
+/// ClientHello without SNI yields an empty name by default, which resolves only through rules
+/// matching everything, such as `fallback`. [Requiring][ServiceName::requiring_sni] SNI turns it
+/// into an error instead.
 pub struct ServiceName {
     acceptor: Acceptor,
     accepted: usize,
+    require_sni: bool,
+    /// `None` unless [fingerprinting][ServiceName::with_ja3], hash once ClientHello is accepted
+    ja3: Option<Option<String>>,
 }
 
 impl Default for ServiceName {
@@ -21,17 +33,50 @@ impl Default for ServiceName {
         Self {
             acceptor,
             accepted: 0,
+            require_sni: false,
+            ja3: None,
+        }
+    }
+}
+
+impl ServiceName {
+    /// Parser failing on ClientHello without SNI.
+    pub fn requiring_sni() -> Self {
+        Self {
+            require_sni: true,
+            ..Self::default()
+        }
+    }
+
+    /// Also takes [JA3][Ja3] hash of ClientHello, handed out as [fingerprint][Parser::fingerprint].
+    pub fn with_ja3(self) -> Self {
+        Self {
+            ja3: Some(None),
+            ..self
         }
     }
 }
 
 #[derive(Debug, thiserror::Error)]
 enum Error {
-    #[error("Buf exceeded max size")]
+    #[error("Handshake exceeded {MAX_HANDSHAKE_SIZE} bytes")]
     MaxSizeExceeded,
+
+    #[error("ClientHello carries no SNI")]
+    MissingSni,
 }
 
 impl Parser<String, Box<dyn std::error::Error + Send + 'static>> for ServiceName {
+    fn name(&self) -> &'static str {
+        "tls"
+    }
+
+    fn sniff(&self, first_bytes: &[u8]) -> bool {
+        first_bytes
+            .first()
+            .is_none_or(|&byte| byte == HANDSHAKE_RECORD)
+    }
+
     #[instrument(skip_all, fields(input_size = input.len()))]
     fn parse(
         &mut self,
@@ -41,22 +86,107 @@ impl Parser<String, Box<dyn std::error::Error + Send + 'static>> for ServiceName
         self.accepted += self
             .acceptor
             .read_tls(&mut cursor)
-            .expect("Failed to read from in-memory cursor");
+            .map_err(|err| Box::new(err) as Box<dyn std::error::Error + Send + 'static>)?;
 
         match self.acceptor.accept() {
-            Ok(None) if self.accepted > OpaqueMessage::MAX_WIRE_SIZE => {
+            Ok(None) if self.accepted > MAX_HANDSHAKE_SIZE => {
                 error!("Buf exceeded max size: {}", self.accepted);
                 Err(Box::new(Error::MaxSizeExceeded))
             }
             Ok(None) => Ok(None),
             Ok(Some(accepted)) => {
                 let client_hello = accepted.client_hello();
-                let sni = client_hello.server_name().unwrap_or_default().to_owned();
+                let sni = match client_hello.server_name() {
+                    Some(sni) => sni.to_owned(),
+                    None if self.require_sni => return Err(Box::new(Error::MissingSni)),
+                    None => String::new(),
+                };
                 debug!("Got sni from incoming connection: {sni:?}");
+                if let Some(ja3) = self.ja3.as_mut() {
+                    // Fingerprint is a nice to have, client is served regardless
+                    *ja3 = Ja3::from_records(&input[..self.accepted])
+                        .map(|ja3| ja3.hash())
+                        .map_err(|err| debug!("Failed to fingerprint ClientHello: {err}"))
+                        .ok();
+                }
 
                 Ok(Some(sni))
             }
             Err(err) => Err(Box::new(err)),
         }
     }
+
+    fn fingerprint(&self) -> Option<String> {
+        self.ja3.clone().flatten()
+    }
+
+    /// ClientHello with a typical set of extensions takes 512B to 2KiB,
+    /// post-quantum key shares push it over 1KiB on their own.
+    fn size_hint(&self) -> usize {
+        4 * 1024
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServiceName;
+    use crate::parser::{ja3::Ja3, Parser};
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::sync::Arc;
+    use test_case::test_case;
+
+    fn client_hello(sni: bool) -> Vec<u8> {
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        config.enable_sni = sni;
+        let mut connection =
+            ClientConnection::new(Arc::new(config), "example.com".try_into().unwrap())
+                .expect("Valid config");
+
+        let mut hello = Vec::new();
+        connection.write_tls(&mut hello).expect("Writes hello");
+        hello
+    }
+
+    #[test_case(ServiceName::default(), true, Some("example.com"); "with sni")]
+    #[test_case(ServiceName::default(), false, Some(""); "without sni")]
+    #[test_case(ServiceName::requiring_sni(), true, Some("example.com"); "required with sni")]
+    #[test_case(ServiceName::requiring_sni(), false, None; "required without sni")]
+    fn parses_sni(mut parser: ServiceName, sni: bool, expected: Option<&str>) {
+        let parsed = parser.parse(&client_hello(sni));
+        match expected {
+            Some(expected) => assert_eq!(parsed.ok().flatten().as_deref(), Some(expected)),
+            None => assert!(parsed.is_err()),
+        }
+    }
+
+    #[test]
+    fn fingerprints_only_when_asked() {
+        let hello = client_hello(true);
+        let mut plain = ServiceName::default();
+        let mut fingerprinting = ServiceName::default().with_ja3();
+        plain.parse(&hello).expect("Parses");
+        fingerprinting.parse(&hello).expect("Parses");
+
+        let expected = Ja3::from_records(&hello).expect("Fingerprints").hash();
+        assert_eq!(plain.fingerprint(), None);
+        assert_eq!(fingerprinting.fingerprint(), Some(expected));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..64 {
+            let mut input = [0u8; 512];
+            rng.fill(&mut input[..]);
+            // Make sure bytes don't start with handshake record
+            input[0] = 0xff;
+
+            let mut parser = ServiceName::default();
+            assert!(parser.parse(&input).is_err());
+        }
+    }
 }
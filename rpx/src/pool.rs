@@ -0,0 +1,276 @@
+//! Idle upstream connections kept around for reuse, see [`forward`][crate::forward] for when
+//! it is safe to do so.
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{debug, trace};
+
+/// Once client is done sending, upstream going quiet for this long is taken as response being
+/// complete.
+const RESPONSE_QUIET_PERIOD: Duration = Duration::from_millis(200);
+const COPY_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Connections along with the moment they were parked.
+type Idle = HashMap<SocketAddr, Vec<(TcpStream, Instant)>>;
+
+/// Idle upstream connections keyed by destination, shared between connections of a listener.
+#[derive(Debug, Clone)]
+pub struct Pool {
+    max_idle: Duration,
+    max_per_host: usize,
+    idle: Arc<Mutex<Idle>>,
+}
+
+impl Pool {
+    /// Pool keeping at most `max_per_host` connections per destination, for at most `max_idle`.
+    pub fn new(max_idle: Duration, max_per_host: usize) -> Self {
+        Self {
+            max_idle,
+            max_per_host,
+            idle: Arc::default(),
+        }
+    }
+
+    /// Idle connection to any of the `candidates`, if there is one still usable.
+    pub(crate) fn take(&self, candidates: &[SocketAddr]) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+        for candidate in candidates {
+            let connections = match idle.get_mut(candidate) {
+                Some(connections) => connections,
+                None => continue,
+            };
+
+            while let Some((connection, parked_at)) = connections.pop() {
+                if parked_at.elapsed() < self.max_idle && is_quiet(&connection) {
+                    debug!(%candidate, "Reusing idle connection");
+                    return Some(connection);
+                }
+                trace!(%candidate, "Dropping stale idle connection");
+            }
+        }
+
+        None
+    }
+
+    /// Keeps `connection` for reuse, unless the pool for its destination is full.
+    pub(crate) fn park(&self, connection: TcpStream) {
+        let destination = match connection.peer_addr() {
+            Ok(destination) => destination,
+            Err(_) => return,
+        };
+
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+        let connections = idle.entry(destination).or_default();
+        connections.retain(|(_, parked_at)| parked_at.elapsed() < self.max_idle);
+        if connections.len() < self.max_per_host {
+            debug!(%destination, "Parking idle connection");
+            connections.push((connection, Instant::now()));
+        }
+    }
+}
+
+/// Connection is still open and upstream has not sent anything nobody asked for.
+fn is_quiet(connection: &TcpStream) -> bool {
+    let mut probe = [0; 1];
+    matches!(
+        connection.try_read(&mut probe),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock
+    )
+}
+
+/// Copies traffic both ways until client half-closes and upstream finishes responding.
+///
+/// Unlike [`tokio::io::copy_bidirectional`] client's FIN is not passed upstream, so upstream
+/// connection stays usable. Resolves to whether it could be reused: upstream did not close the
+/// connection and had been quiet for [`RESPONSE_QUIET_PERIOD`] after its last response.
+///
+/// `request_sent` tells whether client traffic was written upstream before copying started,
+/// e.g. the part read while parsing service name. Upstream owes a response to it, so it is not
+/// reused before answering, or its answer would reach whichever client takes it next.
+pub(crate) async fn copy_for_reuse<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    request_sent: bool,
+) -> io::Result<bool>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut from_client = vec![0; COPY_BUFFER_SIZE];
+    let mut from_upstream = vec![0; COPY_BUFFER_SIZE];
+    let mut client_done = false;
+    // Whether upstream owes a response to what client sent last
+    let mut awaiting_response = request_sent;
+
+    loop {
+        let read = if !client_done {
+            tokio::select! {
+                read = client.read(&mut from_client) => {
+                    match read? {
+                        0 => client_done = true,
+                        len => {
+                            upstream.write_all(&from_client[..len]).await?;
+                            awaiting_response = true;
+                        }
+                    }
+                    continue;
+                }
+                read = upstream.read(&mut from_upstream) => read?,
+            }
+        } else if awaiting_response {
+            upstream.read(&mut from_upstream).await?
+        } else {
+            match tokio::time::timeout(RESPONSE_QUIET_PERIOD, upstream.read(&mut from_upstream))
+                .await
+            {
+                Ok(read) => read?,
+                Err(_) => {
+                    client.shutdown().await?;
+                    return Ok(true);
+                }
+            }
+        };
+
+        match read {
+            0 => {
+                client.shutdown().await?;
+                return Ok(false);
+            }
+            len => {
+                client.write_all(&from_upstream[..len]).await?;
+                awaiting_response = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{copy_for_reuse, Pool};
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn keeps_upstream_open_after_client_half_close() {
+        let (mut client, mut client_side) = tokio::io::duplex(64);
+        let (mut upstream, mut upstream_side) = tokio::io::duplex(64);
+
+        let copy = tokio::spawn(async move {
+            let reusable = copy_for_reuse(&mut client_side, &mut upstream_side, false).await;
+            (reusable, upstream_side)
+        });
+
+        client.write_all(b"ping").await.expect("write");
+        client.shutdown().await.expect("half-close");
+
+        let mut request = [0; 4];
+        upstream.read_exact(&mut request).await.expect("read");
+        assert_eq!(&request, b"ping");
+        upstream.write_all(b"pong").await.expect("write");
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.expect("read");
+        assert_eq!(response, b"pong");
+        let (reusable, mut upstream_side) = copy.await.expect("join");
+        assert!(reusable.expect("copy"));
+
+        // Upstream never saw EOF, connection could carry another request
+        upstream_side.write_all(b"next").await.expect("write");
+        let mut next = [0; 4];
+        upstream.read_exact(&mut next).await.expect("read");
+        assert_eq!(&next, b"next");
+    }
+
+    #[tokio::test]
+    async fn does_not_reuse_closed_upstream() {
+        let (mut client, mut client_side) = tokio::io::duplex(64);
+        let (upstream, mut upstream_side) = tokio::io::duplex(64);
+        drop(upstream);
+
+        client.write_all(b"ping").await.expect("write");
+        client.shutdown().await.expect("half-close");
+        let reusable = copy_for_reuse(&mut client_side, &mut upstream_side, false).await;
+        assert!(!matches!(reusable, Ok(true)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_response_to_request_sent_before_copying() {
+        let (mut client, mut client_side) = tokio::io::duplex(64);
+        let (mut upstream, mut upstream_side) = tokio::io::duplex(64);
+
+        // Request was written upstream before copying, e.g. while parsing service name
+        client.shutdown().await.expect("half-close");
+        let copy = tokio::spawn(async move {
+            copy_for_reuse(&mut client_side, &mut upstream_side, true).await
+        });
+
+        // Slower than quiet period
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        upstream.write_all(b"pong").await.expect("write");
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.expect("read");
+        assert_eq!(response, b"pong");
+        assert!(copy.await.expect("join").expect("copy"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_reuse_upstream_owing_response() {
+        let (mut client, mut client_side) = tokio::io::duplex(64);
+        let (upstream, mut upstream_side) = tokio::io::duplex(64);
+
+        client.shutdown().await.expect("half-close");
+        let copy = tokio::spawn(async move {
+            copy_for_reuse(&mut client_side, &mut upstream_side, true).await
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // Upstream gives up without answering
+        drop(upstream);
+
+        assert!(!matches!(copy.await.expect("join"), Ok(true)));
+    }
+
+    #[tokio::test]
+    async fn takes_parked_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let address = listener.local_addr().expect("address");
+        let pool = Pool::new(Duration::from_secs(60), 1);
+
+        let first = TcpStream::connect(address).await.expect("connect");
+        let second = TcpStream::connect(address).await.expect("connect");
+        let (_first, _) = listener.accept().await.expect("accept");
+        let (_second, _) = listener.accept().await.expect("accept");
+        pool.park(first);
+        // Over the per host cap
+        pool.park(second);
+
+        assert!(pool.take(&[address]).is_some());
+        assert!(pool.take(&[address]).is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_connection_closed_by_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let address = listener.local_addr().expect("address");
+        let pool = Pool::new(Duration::from_secs(60), 1);
+
+        let connection = TcpStream::connect(address).await.expect("connect");
+        let (accepted, _) = listener.accept().await.expect("accept");
+        pool.park(connection);
+        drop(accepted);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(pool.take(&[address]).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+//! [PROXY protocol] v2 header, used to tell upstream who the original client is.
+//!
+//! Only `PROXY` command over TCP is supported, which is all [`forward`][crate::forward] needs.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+use bytes::{Buf, BufMut, BytesMut};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// Fixed preamble every v2 header starts with.
+pub const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Protocol version 2, `PROXY` command.
+const VERSION_COMMAND: u8 = 0x21;
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+const IPV4_ADDRESSES_LEN: u16 = 12;
+const IPV6_ADDRESSES_LEN: u16 = 36;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Header is incomplete")]
+    Incomplete,
+    #[error("Header signature does not match")]
+    InvalidSignature,
+    #[error("Unsupported version or command `{0:#x}`")]
+    UnsupportedCommand(u8),
+    #[error("Unsupported address family or transport `{0:#x}`")]
+    UnsupportedFamily(u8),
+}
+
+/// Serializes v2 header describing connection from `source` to `destination`.
+///
+/// When address families differ, IPv4 address is represented as IPv4-mapped IPv6 one.
+pub fn encode(source: SocketAddr, destination: SocketAddr) -> BytesMut {
+    let mut header = BytesMut::with_capacity(SIGNATURE.len() + 4 + IPV6_ADDRESSES_LEN as usize);
+    header.put_slice(&SIGNATURE);
+    header.put_u8(VERSION_COMMAND);
+
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.put_u8(TCP_OVER_IPV4);
+            header.put_u16(IPV4_ADDRESSES_LEN);
+            header.put_slice(&src.octets());
+            header.put_slice(&dst.octets());
+        }
+        (src, dst) => {
+            header.put_u8(TCP_OVER_IPV6);
+            header.put_u16(IPV6_ADDRESSES_LEN);
+            header.put_slice(&to_ipv6(src).octets());
+            header.put_slice(&to_ipv6(dst).octets());
+        }
+    }
+
+    header.put_u16(source.port());
+    header.put_u16(destination.port());
+    header
+}
+
+/// Reads source and destination addresses out of v2 header.
+///
+/// Returns addresses along with the header length.
+pub fn decode(mut input: &[u8]) -> Result<(SocketAddr, SocketAddr, usize), Error> {
+    if input.len() < SIGNATURE.len() + 4 {
+        return Err(Error::Incomplete);
+    }
+    if input[..SIGNATURE.len()] != SIGNATURE {
+        return Err(Error::InvalidSignature);
+    }
+    input.advance(SIGNATURE.len());
+
+    let version_command = input.get_u8();
+    if version_command != VERSION_COMMAND {
+        return Err(Error::UnsupportedCommand(version_command));
+    }
+    let family = input.get_u8();
+    let len = input.get_u16();
+    if input.len() < len as usize {
+        return Err(Error::Incomplete);
+    }
+
+    let (source, destination): (IpAddr, IpAddr) = match family {
+        TCP_OVER_IPV4 if len >= IPV4_ADDRESSES_LEN => {
+            let source = input.get_u32().into();
+            let destination = input.get_u32().into();
+            (IpAddr::V4(source), IpAddr::V4(destination))
+        }
+        TCP_OVER_IPV6 if len >= IPV6_ADDRESSES_LEN => {
+            let source = input.get_u128().into();
+            let destination = input.get_u128().into();
+            (IpAddr::V6(source), IpAddr::V6(destination))
+        }
+        _ => return Err(Error::UnsupportedFamily(family)),
+    };
+    let source_port = input.get_u16();
+    let destination_port = input.get_u16();
+
+    Ok((
+        (source, source_port).into(),
+        (destination, destination_port).into(),
+        SIGNATURE.len() + 4 + len as usize,
+    ))
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode};
+    use std::net::SocketAddr;
+    use test_case::test_case;
+
+    #[test_case("10.0.0.1:50000", "192.168.1.1:443", 28; "IPv4")]
+    #[test_case("[2001:db8::1]:50000", "[2001:db8::2]:443", 52; "IPv6")]
+    fn round_trips(source: &str, destination: &str, len: usize) {
+        let source: SocketAddr = source.parse().expect("valid address");
+        let destination: SocketAddr = destination.parse().expect("valid address");
+
+        let header = encode(source, destination);
+        assert_eq!(header.len(), len);
+        assert_eq!(
+            decode(&header).expect("valid header"),
+            (source, destination, len)
+        );
+    }
+
+    #[test]
+    fn maps_mixed_families_to_ipv6() {
+        let source: SocketAddr = "10.0.0.1:50000".parse().expect("valid address");
+        let destination: SocketAddr = "[2001:db8::2]:443".parse().expect("valid address");
+
+        let (decoded_source, decoded_destination, _) =
+            decode(&encode(source, destination)).expect("valid header");
+        assert_eq!(
+            decoded_source,
+            "[::ffff:10.0.0.1]:50000".parse().expect("valid address")
+        );
+        assert_eq!(decoded_destination, destination);
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let source: SocketAddr = "10.0.0.1:50000".parse().expect("valid address");
+        let header = encode(source, source);
+        assert!(decode(&header[..20]).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! Bounded cache of registry answers, shared by resolvers asking registries over HTTP.
+//!
+//! Keys come from names clients request, so the cache has to hold up against floods of bogus
+//! ones: it keeps at most `capacity` entries, evicting expired ones first, then the oldest.
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Answers kept at most by a single registry client.
+pub(crate) const MAX_CACHED: usize = 10_000;
+/// Names registry doesn't know kept at most, apart from found ones so they can't evict them.
+pub(crate) const MAX_CACHED_MISSING: usize = 1_000;
+
+/// Answers with the moment they expire, shared by clones.
+#[derive(Debug, Clone)]
+pub(crate) struct Cache<K, V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: Arc<Mutex<Entries<K, V>>>,
+}
+
+#[derive(Debug)]
+struct Entries<K, V> {
+    /// Values with their insertion number and expiry
+    values: HashMap<K, (u64, Instant, V)>,
+    /// Keys in the order they were inserted, which is the order they expire in, as all of
+    /// them live for the same TTL
+    inserted: VecDeque<(K, u64, Instant)>,
+    insertions: u64,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub(crate) fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Arc::new(Mutex::new(Entries {
+                values: HashMap::new(),
+                inserted: VecDeque::new(),
+                insertions: 0,
+            })),
+        }
+    }
+
+    /// Cached answer for `key`, unless it has expired.
+    pub(crate) fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().expect("Cache lock poisoned");
+        entries
+            .values
+            .get(key)
+            .filter(|(_, expiry, _)| *expiry > Instant::now())
+            .map(|(_, _, value)| value.clone())
+    }
+
+    pub(crate) fn insert(&self, key: K, value: V) {
+        if self.capacity == 0 || self.ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("Cache lock poisoned");
+        while let Some((_, _, expiry)) = entries.inserted.front() {
+            if *expiry > now && entries.inserted.len() < self.capacity {
+                break;
+            }
+            entries.pop_oldest();
+        }
+
+        let expiry = now + self.ttl;
+        let insertion = entries.insertions;
+        entries.insertions += 1;
+        entries.inserted.push_back((key.clone(), insertion, expiry));
+        entries.values.insert(key, (insertion, expiry, value));
+    }
+}
+
+impl<K: Hash + Eq, V> Entries<K, V> {
+    /// Drops the oldest key, unless it was inserted again since.
+    fn pop_oldest(&mut self) {
+        if let Some((key, insertion, _)) = self.inserted.pop_front() {
+            if self
+                .values
+                .get(&key)
+                .is_some_and(|(current, ..)| *current == insertion)
+            {
+                self.values.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cache;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_answers() {
+        let cache = Cache::new(Duration::from_secs(5), 10);
+        cache.insert("api", 1);
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(cache.get(&"api"), Some(1));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(cache.get(&"api"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_oldest_over_capacity() {
+        let cache = Cache::new(Duration::from_secs(5), 2);
+        cache.insert("first", 1);
+        cache.insert("second", 2);
+        // Inserted again, oldest is now `second`
+        cache.insert("first", 3);
+        cache.insert("third", 4);
+
+        assert_eq!(cache.get(&"first"), Some(3));
+        assert_eq!(cache.get(&"second"), None);
+        assert_eq!(cache.get(&"third"), Some(4));
+        let entries = cache.entries.lock().unwrap();
+        assert!(entries.values.len() <= 2 && entries.inserted.len() <= 2);
+    }
+}
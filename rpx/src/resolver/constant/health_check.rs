@@ -0,0 +1,78 @@
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    num::NonZeroU64,
+    sync::{RwLock, Weak},
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+/// Addresses which failed the most recent check.
+pub(super) type Unhealthy = RwLock<HashSet<SocketAddr>>;
+
+/// Periodic TCP connect check of rule's addresses.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Seconds between checks, at least one
+    interval_secs: NonZeroU64,
+    /// How long to wait for connection to be established
+    timeout_ms: u64,
+    /// Ports to check in addition to the ones rule's name is mapped to
+    #[serde(default)]
+    ports: Vec<u16>,
+}
+
+impl Config {
+    pub(super) fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+
+    pub(super) fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.get())
+    }
+
+    pub(super) fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Checks `targets` every `interval` until the layer owning `unhealthy` is dropped.
+pub(super) async fn run(
+    targets: Vec<SocketAddr>,
+    unhealthy: Weak<Unhealthy>,
+    interval: Duration,
+    timeout: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let checks = targets.iter().map(|target| async move {
+            let reachable = matches!(
+                tokio::time::timeout(timeout, TcpStream::connect(target)).await,
+                Ok(Ok(_))
+            );
+            (*target, reachable)
+        });
+        let results = futures::future::join_all(checks).await;
+
+        let unhealthy = match unhealthy.upgrade() {
+            Some(unhealthy) => unhealthy,
+            // Layer is gone, nothing to update
+            None => return,
+        };
+
+        let mut unhealthy = unhealthy.write().expect("Health lock poisoned");
+        for (target, reachable) in results {
+            if reachable {
+                if unhealthy.remove(&target) {
+                    info!(%target, "Backend recovered");
+                }
+            } else if unhealthy.insert(target) {
+                warn!(%target, "Backend failed health check");
+            }
+        }
+    }
+}
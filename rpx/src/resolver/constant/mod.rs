@@ -1,17 +1,29 @@
+use bytes::Bytes;
 use futures::future::Either;
-use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
 use serde::Deserialize;
 use std::{
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     future::{ready, Ready},
+    hash::{Hash, Hasher},
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
-use tracing::{debug, instrument, trace, warn};
+use tracing::{debug, field, instrument, trace, warn, Span};
 
+mod health_check;
 mod port_binding;
+mod preamble;
+use super::{
+    select::{Selector, Strategy},
+    ResolveRequest, Resolved,
+};
+use crate::ForwardPolicy;
+use health_check::Unhealthy;
 use port_binding::PortBinding;
+pub use preamble::Preamble;
 
 #[derive(Debug, Deserialize, PartialEq)]
 /// Configuration for a single constant forwarding rule.
@@ -22,41 +34,258 @@ pub enum Config {
     Port {
         name: String,
         ports: Vec<PortBinding>,
+        /// Attached to logs of requests the rule applied to
+        #[serde(default)]
+        label: Option<String>,
+    },
+    /// Override for ip address, to bypass any dns lookups.
+    ///
+    /// Name could be a wildcard: `*.example.com` covers any subdomain not matched exactly,
+    /// `*` covers every name.
+    Ip {
+        name: String,
+        ips: Vec<IpAddr>,
+        /// Skip addresses which fail periodic connection check
+        #[serde(default)]
+        health_check: Option<health_check::Config>,
+        /// Keep sending the same client to the same address instead of picking one at random
+        #[serde(default)]
+        sticky: bool,
+        /// How to pick among `ips` when not `sticky`, `random` unless set
+        #[serde(default)]
+        selection: Strategy,
+        /// Sent to upstream ahead of client traffic
+        #[serde(default)]
+        upstream_preamble: Option<Preamble>,
+        /// Give up connecting to upstream after this long, overriding listener default
+        #[serde(default)]
+        connect_timeout_secs: Option<u64>,
+        /// Close connections idle for this long, overriding listener default
+        #[serde(default)]
+        idle_timeout_secs: Option<u64>,
+        /// Attached to logs of requests the rule applied to
+        #[serde(default)]
+        label: Option<String>,
+    },
+    /// Override for destination listening on unix socket, e.g. an app on the same host.
+    ///
+    /// Takes precedence over `ips` for the same name, port plays no part. Name could be a
+    /// wildcard, same as for `ips`.
+    Path {
+        name: String,
+        path: PathBuf,
+        /// Sent to upstream ahead of client traffic
+        #[serde(default)]
+        upstream_preamble: Option<Preamble>,
+        /// Give up connecting to upstream after this long, overriding listener default
+        #[serde(default)]
+        connect_timeout_secs: Option<u64>,
+        /// Close connections idle for this long, overriding listener default
+        #[serde(default)]
+        idle_timeout_secs: Option<u64>,
+        /// Attached to logs of requests the rule applied to
+        #[serde(default)]
+        label: Option<String>,
     },
-    /// Override for ip address, to bypass any dns lookups
-    Ip { name: String, ips: Vec<IpAddr> },
+}
+
+impl Config {
+    /// Name the rule applies to.
+    pub fn name(&self) -> &str {
+        match self {
+            Config::Port { name, .. } | Config::Ip { name, .. } | Config::Path { name, .. } => name,
+        }
+    }
+
+    /// Human friendly tag of the rule, e.g. `checkout-canary`.
+    pub fn label(&self) -> Option<&str> {
+        match self {
+            Config::Port { label, .. } | Config::Ip { label, .. } | Config::Path { label, .. } => {
+                label.as_deref()
+            }
+        }
+    }
+
+    /// Bytes sent to upstream ahead of client traffic, if the rule has any.
+    pub fn preamble(&self) -> Option<&Preamble> {
+        match self {
+            Config::Port { .. } => None,
+            Config::Ip {
+                upstream_preamble, ..
+            }
+            | Config::Path {
+                upstream_preamble, ..
+            } => upstream_preamble.as_ref(),
+        }
+    }
+
+    /// Timeouts of connections the rule applies to, if it sets any.
+    pub fn policy(&self) -> Option<ForwardPolicy> {
+        match self {
+            Config::Port { .. } => None,
+            Config::Ip {
+                connect_timeout_secs,
+                idle_timeout_secs,
+                ..
+            }
+            | Config::Path {
+                connect_timeout_secs,
+                idle_timeout_secs,
+                ..
+            } => {
+                if connect_timeout_secs.is_none() && idle_timeout_secs.is_none() {
+                    return None;
+                }
+                Some(ForwardPolicy {
+                    connect_timeout: connect_timeout_secs.map(Duration::from_secs),
+                    idle_timeout: idle_timeout_secs.map(Duration::from_secs),
+                })
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Layer {
     ips: Arc<HashMap<String, Vec<IpAddr>>>,
+    wildcards: Arc<HashMap<String, Vec<IpAddr>>>,
     ports: Arc<HashMap<(String, u16), u16>>,
+    paths: Arc<HashMap<String, PathBuf>>,
+    sticky: Arc<HashSet<String>>,
+    selectors: Arc<HashMap<String, Selector>>,
+    labels: Arc<HashMap<String, String>>,
+    preambles: Arc<HashMap<String, Bytes>>,
+    policies: Arc<HashMap<String, ForwardPolicy>>,
+    unhealthy: Arc<Unhealthy>,
 }
 
 impl Layer {
+    /// Collects rules.
+    ///
+    /// Spawns a task per rule asking for health checks,
+    /// which requires running inside tokio runtime.
     pub fn new<'a, I>(rules: I) -> Self
     where
         I: Iterator<Item = &'a Config>,
     {
+        let rules: Vec<&Config> = rules.collect();
         let mut ip_rules: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        let mut wildcard_rules: HashMap<String, Vec<IpAddr>> = HashMap::new();
         let mut port_rules = HashMap::new();
-
-        rules.for_each(|config| match config {
-            Config::Port { name, ports } => {
+        let mut path_rules = HashMap::new();
+        let mut sticky = HashSet::new();
+        let mut selectors = HashMap::new();
+        let mut labels = HashMap::new();
+        let mut preambles = HashMap::new();
+        let mut policies = HashMap::new();
+
+        rules.iter().for_each(|config| {
+            if let Some(label) = config.label() {
+                labels
+                    .entry(config.name().to_owned())
+                    .or_insert_with(|| label.to_owned());
+            }
+            if let Some(Preamble(preamble)) = config.preamble() {
+                preambles
+                    .entry(config.name().to_owned())
+                    .or_insert_with(|| preamble.clone());
+            }
+            if let Some(policy) = config.policy() {
+                policies.entry(config.name().to_owned()).or_insert(policy);
+            }
+        });
+        rules.iter().for_each(|config| match config {
+            Config::Port { name, ports, .. } => {
                 ports.iter().for_each(|PortBinding(from, to)| {
                     if port_rules.insert((name.clone(), *from), *to).is_some() {
                         warn!(name = name, port = from, "Duplicate port mapping detected");
                     }
                 });
             }
-            Config::Ip { name, ips } => {
-                ip_rules.entry(name.clone()).or_default().extend(ips);
+            Config::Ip {
+                name,
+                ips,
+                sticky: is_sticky,
+                selection,
+                ..
+            } => {
+                // Kept apart, so exact names are still found with a single lookup
+                let rules = if name == "*" || name.starts_with("*.") {
+                    &mut wildcard_rules
+                } else {
+                    &mut ip_rules
+                };
+                rules.entry(name.clone()).or_default().extend(ips);
+                if *is_sticky {
+                    sticky.insert(name.clone());
+                }
+                selectors
+                    .entry(name.clone())
+                    .or_insert_with(|| Selector::new(*selection));
+            }
+            Config::Path { name, path, .. } => {
+                if path_rules.contains_key(name) {
+                    warn!(
+                        name = name,
+                        "Duplicate socket path detected, keeping the first one"
+                    );
+                } else {
+                    path_rules.insert(name.clone(), path.clone());
+                }
+            }
+        });
+
+        let unhealthy = Arc::new(Unhealthy::default());
+        rules.iter().for_each(|config| {
+            if let Config::Ip {
+                name,
+                ips,
+                health_check: Some(health_check),
+                ..
+            } = config
+            {
+                // Check ports name is mapped to along with explicitly listed ones
+                let mut ports: Vec<u16> = port_rules
+                    .iter()
+                    .filter(|((mapped, _), _)| mapped == name)
+                    .map(|(_, to)| *to)
+                    .chain(health_check.ports().iter().copied())
+                    .collect();
+                ports.sort_unstable();
+                ports.dedup();
+
+                if ports.is_empty() {
+                    warn!(
+                        name = name,
+                        "No ports to health check, add `ports` to the check"
+                    );
+                    return;
+                }
+
+                let targets = ips
+                    .iter()
+                    .flat_map(|ip| ports.iter().map(|port| SocketAddr::new(*ip, *port)))
+                    .collect();
+                tokio::spawn(health_check::run(
+                    targets,
+                    Arc::downgrade(&unhealthy),
+                    health_check.interval(),
+                    health_check.timeout(),
+                ));
             }
         });
 
         Self {
             ips: Arc::new(ip_rules),
+            wildcards: Arc::new(wildcard_rules),
             ports: Arc::new(port_rules),
+            paths: Arc::new(path_rules),
+            sticky: Arc::new(sticky),
+            selectors: Arc::new(selectors),
+            labels: Arc::new(labels),
+            preambles: Arc::new(preambles),
+            policies: Arc::new(policies),
+            unhealthy,
         }
     }
 }
@@ -65,7 +294,7 @@ impl<S> tower::Layer<S> for Layer {
     type Service = Service<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        Service::new(inner, self.ips.clone(), self.ports.clone())
+        Service::new(inner, self)
     }
 }
 
@@ -73,58 +302,171 @@ impl<S> tower::Layer<S> for Layer {
 pub struct Service<S> {
     inner: S,
     ips: Arc<HashMap<String, Vec<IpAddr>>>,
+    wildcards: Arc<HashMap<String, Vec<IpAddr>>>,
     ports: Arc<HashMap<(String, u16), u16>>,
+    paths: Arc<HashMap<String, PathBuf>>,
+    sticky: Arc<HashSet<String>>,
+    selectors: Arc<HashMap<String, Selector>>,
+    labels: Arc<HashMap<String, String>>,
+    preambles: Arc<HashMap<String, Bytes>>,
+    policies: Arc<HashMap<String, ForwardPolicy>>,
+    unhealthy: Arc<Unhealthy>,
 }
 
 impl<S> Service<S> {
-    pub fn new(
-        inner: S,
-        ips: Arc<HashMap<String, Vec<IpAddr>>>,
-        ports: Arc<HashMap<(String, u16), u16>>,
-    ) -> Self {
-        Self { inner, ips, ports }
+    /// Wraps `inner`, sharing rules collected by `layer`.
+    pub fn new(inner: S, layer: &Layer) -> Self {
+        Self {
+            inner,
+            ips: layer.ips.clone(),
+            wildcards: layer.wildcards.clone(),
+            ports: layer.ports.clone(),
+            paths: layer.paths.clone(),
+            sticky: layer.sticky.clone(),
+            selectors: layer.selectors.clone(),
+            labels: layer.labels.clone(),
+            preambles: layer.preambles.clone(),
+            policies: layer.policies.clone(),
+            unhealthy: layer.unhealthy.clone(),
+        }
+    }
+
+    /// Tags current span with label of the rule which fired, if it has one.
+    fn label(&self, rule: &str) {
+        if let Some(label) = self.labels.get(rule) {
+            Span::current().record("label", label.as_str());
+        }
     }
+
+    /// Attaches preamble and policy of the rule which fired, if it has them.
+    fn attach(&self, rule: &str, resolved: Resolved) -> Resolved {
+        let resolved = match self.preambles.get(rule) {
+            Some(preamble) => resolved.with_preamble(preamble.clone()),
+            None => resolved,
+        };
+        match self.policies.get(rule) {
+            Some(policy) => resolved.with_policy(*policy),
+            None => resolved,
+        }
+    }
+}
+
+/// Wildcard rule names covering `name`, most specific first: for `a.example.com` these are
+/// `*.example.com`, `*.com` and `*`.
+fn wildcards(name: &str) -> impl Iterator<Item = String> + '_ {
+    name.match_indices('.')
+        .map(|(ix, _)| format!("*{}", &name[ix..]))
+        .chain(std::iter::once("*".to_owned()))
+}
+
+/// Picks address for `client` using rendezvous hashing: every address is scored by hash of the
+/// pair, highest score wins. Adding or removing an address only moves clients which pick it.
+fn rendezvous(client: IpAddr, candidates: &[SocketAddr]) -> Option<&SocketAddr> {
+    candidates.iter().max_by_key(|candidate| {
+        let mut hasher = DefaultHasher::new();
+        (client, candidate).hash(&mut hasher);
+        hasher.finish()
+    })
 }
 
-impl<S> tower::Service<(String, u16)> for Service<S>
+impl<S> tower::Service<ResolveRequest> for Service<S>
 where
-    S: tower::Service<(String, u16), Response = Option<SocketAddr>> + Clone,
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>> + Clone,
     S::Future: Send + 'static,
 {
-    type Response = Option<SocketAddr>;
+    type Response = Option<Resolved>;
     type Error = S::Error;
-    type Future = Either<Ready<Result<Option<SocketAddr>, S::Error>>, S::Future>;
+    type Future = Either<Ready<Result<Option<Resolved>, S::Error>>, S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.inner.poll_ready(cx)
     }
 
-    #[instrument(skip(self))]
-    fn call(&mut self, request: (String, u16)) -> Self::Future {
+    #[instrument(skip(self), fields(resolver = "constant", label = field::Empty))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
         debug!("enter");
-        // translate port if any
-        let port: u16 = self.ports.get(&request).copied().unwrap_or(request.1);
+        let ResolveRequest {
+            name,
+            parsed,
+            port,
+            client,
+        } = request;
+        if !self.paths.is_empty() {
+            let rule = match self.paths.get_key_value(&name) {
+                Some((rule, path)) => Some((rule.clone(), path)),
+                None => {
+                    wildcards(&name).find_map(|rule| self.paths.get(&rule).map(|path| (rule, path)))
+                }
+            };
+            if let Some((rule, path)) = rule {
+                self.label(&rule);
+                trace!(?path);
+                let resolved = self.attach(&rule, Resolved::unix("constant", path.clone()));
+                return Either::Left(ready(Ok(Some(resolved))));
+            }
+        }
+
+        let key = (name, port);
+        // translate port if any, exact name beats wildcards
+        let translated = match self.ports.get(&key) {
+            Some(to) => {
+                self.label(&key.0);
+                Some(*to)
+            }
+            None => wildcards(&key.0).find_map(|rule| {
+                let to = self.ports.get(&(rule.clone(), key.1))?;
+                self.label(&rule);
+                Some(*to)
+            }),
+        };
+        let port: u16 = translated.unwrap_or(port);
 
         trace!(port = port);
-        let record = request.0;
-
-        // get the override if any
-        let address: Option<SocketAddr> = self
-            .ips
-            .get(&record)
-            .and_then(|existing| {
-                let mut rng = SmallRng::from_entropy();
-                existing.choose(&mut rng)
-            })
-            .map(ToOwned::to_owned)
-            .map(|ip_addr| (ip_addr, port).into());
+        let record = key.0;
+
+        let rule = match self.ips.get(&record) {
+            Some(ips) => Some((record.clone(), ips)),
+            None if self.wildcards.is_empty() => None,
+            None => {
+                wildcards(&record).find_map(|rule| self.wildcards.get(&rule).map(|ips| (rule, ips)))
+            }
+        };
+
+        // get the override if any, skipping backends known to be down
+        let address: Option<Resolved> = rule.and_then(|(rule, existing)| {
+            self.label(&rule);
+            let unhealthy = self.unhealthy.read().expect("Health lock poisoned");
+            let healthy: Vec<SocketAddr> = existing
+                .iter()
+                .map(|ip_addr| SocketAddr::new(*ip_addr, port))
+                .filter(|address| !unhealthy.contains(address))
+                .collect();
+            if healthy.is_empty() {
+                debug!("All overrides are unhealthy");
+            }
+
+            let chosen = if self.sticky.contains(&rule) {
+                rendezvous(client.ip(), &healthy)
+            } else {
+                // Every rule with `ips` has one
+                self.selectors
+                    .get(&rule)
+                    .and_then(|selector| selector.pick(&healthy))
+            };
+            chosen.map(|address| self.attach(&rule, Resolved::new("constant", *address)))
+        });
 
         trace!(address = ?address);
 
         if address.is_some() {
             Either::Left(ready(Ok(address)))
         } else {
-            let fut = self.inner.call((record, port));
+            let fut = self.inner.call(ResolveRequest {
+                name: record,
+                parsed,
+                port,
+                client,
+            });
             Either::Right(fut)
         }
     }
@@ -132,21 +474,29 @@ where
 
 #[cfg(test)]
 mod test {
-    use super::{port_binding::PortBinding, Config, Layer};
+    use super::{port_binding::PortBinding, rendezvous, Config, Layer, Strategy};
+    use crate::{
+        resolver::{ResolveRequest, Resolved},
+        ForwardPolicy,
+    };
     use indoc::indoc;
     use std::{
         convert::Infallible,
         future::{ready, Ready},
-        net::SocketAddr,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
         task::{Context, Poll},
+        time::Duration,
     };
+    use test_case::test_case;
     use tower::{Layer as _, Service};
 
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 50000);
+
     #[derive(Clone)]
     struct S;
 
-    impl tower::Service<(String, u16)> for S {
-        type Response = Option<SocketAddr>;
+    impl tower::Service<ResolveRequest> for S {
+        type Response = Option<Resolved>;
         type Error = Infallible;
         type Future = Ready<Result<Self::Response, Self::Error>>;
 
@@ -154,8 +504,11 @@ mod test {
             Poll::Ready(Ok(()))
         }
 
-        fn call(&mut self, (_record, port): (String, u16)) -> Self::Future {
-            ready(Ok(Some(([1, 2, 3, 4], port).into())))
+        fn call(&mut self, request: ResolveRequest) -> Self::Future {
+            ready(Ok(Some(Resolved::new(
+                "inner",
+                ([1, 2, 3, 4], request.port).into(),
+            ))))
         }
     }
 
@@ -165,11 +518,14 @@ mod test {
         let mut outer = layer.layer(S);
 
         let outcome = outer
-            .call(("example.com".into(), 1234))
+            .call(ResolveRequest::new("example.com", 1234, CLIENT))
             .await
             .expect("Infallible");
 
-        assert_eq!(outcome, Some(([1, 2, 3, 4], 1234).into()));
+        assert_eq!(
+            outcome,
+            Some(Resolved::new("inner", ([1, 2, 3, 4], 1234).into()))
+        );
     }
 
     #[tokio::test]
@@ -177,16 +533,20 @@ mod test {
         let port_rule = Config::Port {
             name: "example.com".to_string(),
             ports: vec![PortBinding(1234, 222)],
+            label: None,
         };
         let layer = Layer::new(vec![&port_rule].into_iter());
         let mut outer = layer.layer(S);
 
         let outcome = outer
-            .call(("example.com".into(), 1234))
+            .call(ResolveRequest::new("example.com", 1234, CLIENT))
             .await
             .expect("Infallible");
 
-        assert_eq!(outcome, Some(([1, 2, 3, 4], 222).into()));
+        assert_eq!(
+            outcome,
+            Some(Resolved::new("inner", ([1, 2, 3, 4], 222).into()))
+        );
     }
 
     #[tokio::test]
@@ -194,16 +554,78 @@ mod test {
         let ip_rule = Config::Ip {
             name: "example.com".to_string(),
             ips: vec![[1, 1, 1, 1].into()],
+            health_check: None,
+            sticky: false,
+            selection: Strategy::Random,
+            upstream_preamble: None,
+            connect_timeout_secs: None,
+            idle_timeout_secs: None,
+            label: None,
         };
         let layer = Layer::new(vec![&ip_rule].into_iter());
         let mut outer = layer.layer(S);
 
         let outcome = outer
-            .call(("example.com".into(), 1234))
+            .call(ResolveRequest::new("example.com", 1234, CLIENT))
             .await
             .expect("Infallible");
 
-        assert_eq!(outcome, Some(([1, 1, 1, 1], 1234).into()));
+        assert_eq!(
+            outcome,
+            Some(Resolved::new("constant", ([1, 1, 1, 1], 1234).into()))
+        );
+    }
+
+    #[test_case("example.com", Some([1, 1, 1, 1]); "exact beats wildcard")]
+    #[test_case("api.example.com", Some([2, 2, 2, 2]); "subdomain")]
+    #[test_case("v1.api.example.com", Some([2, 2, 2, 2]); "nested subdomain")]
+    #[test_case("api.internal.example.com", Some([3, 3, 3, 3]); "most specific wildcard")]
+    #[test_case("example.org", None; "outside zone")]
+    #[tokio::test]
+    async fn given_wildcard_covers_unmatched_subdomains(name: &str, expected: Option<[u8; 4]>) {
+        let rules: Vec<Config> = serde_yaml::from_str(indoc! {"
+            - name: '*.example.com'
+              ips: [2.2.2.2]
+            - name: example.com
+              ips: [1.1.1.1]
+            - name: '*.internal.example.com'
+              ips: [3.3.3.3]
+        "})
+        .expect("Valid rules");
+        let mut outer = Layer::new(rules.iter()).layer(S);
+
+        let outcome = outer
+            .call(ResolveRequest::new(name, 1234, CLIENT))
+            .await
+            .expect("Infallible");
+
+        let expected = match expected {
+            Some(ip) => Resolved::new("constant", (ip, 1234).into()),
+            None => Resolved::new("inner", ([1, 2, 3, 4], 1234).into()),
+        };
+        assert_eq!(outcome, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn given_catch_all_maps_ports_of_any_name() {
+        let rules: Vec<Config> = serde_yaml::from_str(indoc! {"
+            - name: '*'
+              ips: [2.2.2.2]
+            - name: '*'
+              ports: ['1234:4321']
+        "})
+        .expect("Valid rules");
+        let mut outer = Layer::new(rules.iter()).layer(S);
+
+        let outcome = outer
+            .call(ResolveRequest::new("anything.example", 1234, CLIENT))
+            .await
+            .expect("Infallible");
+
+        assert_eq!(
+            outcome,
+            Some(Resolved::new("constant", ([2, 2, 2, 2], 4321).into()))
+        );
     }
 
     #[tokio::test]
@@ -211,22 +633,250 @@ mod test {
         let port_rule = Config::Port {
             name: "example.com".to_string(),
             ports: vec![PortBinding(1234, 222)],
+            label: None,
         };
 
         let ip_rule = Config::Ip {
             name: "example.com".to_string(),
             ips: vec![[1, 1, 1, 1].into()],
+            health_check: None,
+            sticky: false,
+            selection: Strategy::Random,
+            upstream_preamble: None,
+            connect_timeout_secs: None,
+            idle_timeout_secs: None,
+            label: None,
         };
 
         let layer = Layer::new(vec![&ip_rule, &port_rule].into_iter());
         let mut outer = layer.layer(S);
 
         let outcome = outer
-            .call(("example.com".into(), 1234))
+            .call(ResolveRequest::new("example.com", 1234, CLIENT))
             .await
             .expect("Infallible");
 
-        assert_eq!(outcome, Some(([1, 1, 1, 1], 222).into()));
+        assert_eq!(
+            outcome,
+            Some(Resolved::new("constant", ([1, 1, 1, 1], 222).into()))
+        );
+    }
+
+    #[test_case("app.example.com", "constant"; "socket beats ips")]
+    #[test_case("web.example.com", "constant"; "wildcard")]
+    #[test_case("example.org", "inner"; "no match")]
+    #[tokio::test]
+    async fn given_path_resolves_to_unix_socket(name: &str, source: &str) {
+        let rules: Vec<Config> = serde_yaml::from_str(indoc! {"
+            - name: app.example.com
+              ips: [1.1.1.1]
+            - name: app.example.com
+              path: /run/app.sock
+            - name: '*.example.com'
+              path: /run/web.sock
+        "})
+        .expect("Valid rules");
+        let mut outer = Layer::new(rules.iter()).layer(S);
+
+        let outcome = outer
+            .call(ResolveRequest::new(name, 1234, CLIENT))
+            .await
+            .expect("Infallible")
+            .expect("Resolves");
+
+        assert_eq!(outcome.source(), source);
+        let expected = match name {
+            "app.example.com" => Some("/run/app.sock".as_ref()),
+            "web.example.com" => Some("/run/web.sock".as_ref()),
+            _ => None,
+        };
+        assert_eq!(outcome.path(), expected);
+    }
+
+    #[tokio::test]
+    async fn attaches_preamble_of_matched_rule() {
+        let rules: Vec<Config> = serde_yaml::from_str(indoc! {"
+            - name: legacy.example.com
+              ips: [1.1.1.1]
+              upstream_preamble: {hex: 4f524d}
+            - name: app.example.com
+              ips: [2.2.2.2]
+        "})
+        .expect("Valid rules");
+        let mut outer = Layer::new(rules.iter()).layer(S);
+        let mut preamble = |name| {
+            let resolved = outer.call(ResolveRequest::new(name, 1234, CLIENT));
+            async move {
+                resolved
+                    .await
+                    .expect("Infallible")
+                    .and_then(|resolved| resolved.preamble().cloned())
+            }
+        };
+
+        assert_eq!(
+            preamble("legacy.example.com").await.as_deref(),
+            Some(&b"ORM"[..])
+        );
+        assert_eq!(preamble("app.example.com").await, None);
+    }
+
+    #[tokio::test]
+    async fn attaches_policy_of_matched_rule() {
+        let rules: Vec<Config> = serde_yaml::from_str(indoc! {"
+            - name: slow.example.com
+              ips: [1.1.1.1]
+              connect_timeout_secs: 10
+              idle_timeout_secs: 600
+            - name: app.example.com
+              ips: [2.2.2.2]
+        "})
+        .expect("Valid rules");
+        let mut outer = Layer::new(rules.iter()).layer(S);
+        let mut policy = |name| {
+            let resolved = outer.call(ResolveRequest::new(name, 1234, CLIENT));
+            async move {
+                resolved
+                    .await
+                    .expect("Infallible")
+                    .and_then(|resolved| resolved.policy())
+            }
+        };
+
+        assert_eq!(
+            policy("slow.example.com").await,
+            Some(ForwardPolicy {
+                connect_timeout: Some(Duration::from_secs(10)),
+                idle_timeout: Some(Duration::from_secs(600)),
+            })
+        );
+        assert_eq!(policy("app.example.com").await, None);
+    }
+
+    fn health_checked(ips: &[&str], port: u16) -> Config {
+        let yaml = format!(
+            "{{name: example.com, ips: [{}], health_check: {{interval_secs: 60, timeout_ms: 100, ports: [{port}]}}}}",
+            ips.join(", ")
+        );
+        serde_yaml::from_str(&yaml).expect("Valid config")
+    }
+
+    #[tokio::test]
+    async fn skips_unhealthy_backends() {
+        let alive = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind");
+        let port = alive.local_addr().expect("Bound").port();
+        let rule = health_checked(&["127.0.0.1", "127.0.0.2"], port);
+        let mut outer = Layer::new(vec![&rule].into_iter()).layer(S);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        for _ in 0..10 {
+            let outcome = outer
+                .call(ResolveRequest::new("example.com", port, CLIENT))
+                .await
+                .expect("Infallible");
+            assert_eq!(
+                outcome,
+                Some(Resolved::new("constant", ([127, 0, 0, 1], port).into()))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_zero_health_check_interval() {
+        let yaml = "{name: example.com, ips: [10.0.0.1], health_check: {interval_secs: 0, timeout_ms: 100}}";
+        assert!(serde_yaml::from_str::<Config>(yaml).is_err());
+    }
+
+    #[test_case("first", [1, 1, 1, 1]; "first")]
+    #[test_case("round_robin", [1, 2, 3, 1]; "round robin")]
+    #[tokio::test]
+    async fn given_selection_picks_accordingly(selection: &str, expected: [u8; 4]) {
+        let yaml = format!(
+            "{{name: example.com, ips: [10.0.0.1, 10.0.0.2, 10.0.0.3], selection: {selection}}}"
+        );
+        let rule: Config = serde_yaml::from_str(&yaml).expect("Valid rule");
+        let layer = Layer::new(std::iter::once(&rule));
+
+        let mut picked = Vec::new();
+        for _ in 0..4 {
+            // Stacks built from the same layer share the turn
+            let outcome = layer
+                .layer(S)
+                .call(ResolveRequest::new("example.com", 443, CLIENT))
+                .await
+                .expect("Infallible")
+                .and_then(|resolved| resolved.address());
+            picked.push(match outcome.map(|address| address.ip()) {
+                Some(std::net::IpAddr::V4(ip)) => ip.octets()[3],
+                other => panic!("Expected IPv4 address, got {other:?}"),
+            });
+        }
+
+        assert_eq!(picked, expected);
+    }
+
+    #[tokio::test]
+    async fn given_all_backends_unhealthy_falls_through() {
+        let port = {
+            let dead = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+            dead.local_addr().expect("Bound").port()
+        };
+        let rule = health_checked(&["127.0.0.1"], port);
+        let mut outer = Layer::new(vec![&rule].into_iter()).layer(S);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let outcome = outer
+            .call(ResolveRequest::new("example.com", port, CLIENT))
+            .await
+            .expect("Infallible");
+        assert_eq!(
+            outcome,
+            Some(Resolved::new("inner", ([1, 2, 3, 4], port).into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn given_sticky_rule_keeps_client_on_same_address() {
+        let rule: Config = serde_yaml::from_str(
+            "{name: example.com, ips: [1.1.1.1, 2.2.2.2, 3.3.3.3], sticky: true}",
+        )
+        .expect("Valid config");
+        let mut outer = Layer::new(vec![&rule].into_iter()).layer(S);
+
+        for client in [[10, 0, 0, 1], [10, 0, 0, 2], [192, 168, 1, 1]] {
+            let mut outcomes = Vec::new();
+            // Clients reconnect from different ports
+            for client_port in 50000..50010 {
+                let outcome = outer
+                    .call(ResolveRequest::new(
+                        "example.com",
+                        443,
+                        (client, client_port).into(),
+                    ))
+                    .await
+                    .expect("Infallible");
+                outcomes.push(outcome);
+            }
+            outcomes.dedup();
+            assert_eq!(outcomes.len(), 1);
+        }
+    }
+
+    #[test]
+    fn rendezvous_only_moves_clients_of_removed_address() {
+        let candidates: Vec<SocketAddr> = (1..=4).map(|ix| ([10, 1, 0, ix], 443).into()).collect();
+        let removed = candidates[0];
+
+        for ix in 0..=255 {
+            let client = IpAddr::from([172, 16, 0, ix]);
+            let before = *rendezvous(client, &candidates).expect("Has candidates");
+            let after = *rendezvous(client, &candidates[1..]).expect("Has candidates");
+            if before != removed {
+                assert_eq!(before, after);
+            }
+        }
     }
 
     #[test]
@@ -242,6 +892,13 @@ mod test {
           ips:
             - '1.2.3.4' 
             - '8.8.8.8'
+
+        - name: 'checkout.xyz'
+          ips: ['10.0.0.1']
+          label: checkout-canary
+
+        - name: 'app.xyz'
+          path: /run/app.sock
         "};
 
         let parsed: Result<Vec<Config>, _> = serde_yaml::from_str(yaml);
@@ -251,14 +908,34 @@ mod test {
             parsed[0],
             Config::Port {
                 name: "first.xyz".to_string(),
-                ports: vec![PortBinding(80, 80), PortBinding(3333, 4444)]
+                ports: vec![PortBinding(80, 80), PortBinding(3333, 4444)],
+                label: None,
             }
         );
         assert_eq!(
             parsed[1],
             Config::Ip {
                 name: "first.xyz".to_string(),
-                ips: vec![[1, 2, 3, 4].into(), [8, 8, 8, 8].into()]
+                ips: vec![[1, 2, 3, 4].into(), [8, 8, 8, 8].into()],
+                health_check: None,
+                sticky: false,
+                selection: Strategy::Random,
+                upstream_preamble: None,
+                connect_timeout_secs: None,
+                idle_timeout_secs: None,
+                label: None,
+            }
+        );
+        assert_eq!(parsed[2].label(), Some("checkout-canary"));
+        assert_eq!(
+            parsed[3],
+            Config::Path {
+                name: "app.xyz".to_string(),
+                path: "/run/app.sock".into(),
+                upstream_preamble: None,
+                connect_timeout_secs: None,
+                idle_timeout_secs: None,
+                label: None,
             }
         );
     }
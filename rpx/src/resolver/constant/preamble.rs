@@ -0,0 +1,57 @@
+use base64::Engine;
+use bytes::Bytes;
+use serde::Deserialize;
+
+/// Bytes written to upstream once connected, ahead of anything client sent, e.g. a handshake
+/// legacy backends expect.
+///
+/// Given either as `{hex: "..."}` or `{base64: "..."}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Encoded")]
+pub struct Preamble(pub Bytes);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoded {
+    Hex(String),
+    Base64(String),
+}
+
+impl TryFrom<Encoded> for Preamble {
+    type Error = String;
+
+    fn try_from(encoded: Encoded) -> Result<Self, Self::Error> {
+        let decoded = match encoded {
+            Encoded::Hex(hex) => hex::decode(hex).map_err(|err| format!("Invalid hex: {err}"))?,
+            Encoded::Base64(base64) => base64::engine::general_purpose::STANDARD
+                .decode(base64)
+                .map_err(|err| format!("Invalid base64: {err}"))?,
+        };
+        if decoded.is_empty() {
+            return Err("Preamble is empty".to_owned());
+        }
+
+        Ok(Self(decoded.into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Preamble;
+    use test_case::test_case;
+
+    #[test_case("{hex: 4f524d}" ; "hex")]
+    #[test_case("{base64: T1JN}" ; "base64")]
+    fn decodes_either_encoding(encoded: &str) {
+        let preamble: Preamble = serde_yaml::from_str(encoded).expect("Valid preamble");
+
+        assert_eq!(&preamble.0[..], b"ORM");
+    }
+
+    #[test_case("{hex: 4f5}" ; "odd hex")]
+    #[test_case("{base64: '*'}" ; "bad base64")]
+    #[test_case("{hex: ''}" ; "empty")]
+    fn rejects_malformed(encoded: &str) {
+        assert!(serde_yaml::from_str::<Preamble>(encoded).is_err());
+    }
+}
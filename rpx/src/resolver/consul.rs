@@ -0,0 +1,363 @@
+//! Resolves names by querying [Consul] health API for instances passing health checks.
+//!
+//! Requested name is used as Consul service name. When `domain` is configured, only names
+//! ending with it are looked up, with the suffix stripped, e.g. `api.service.consul` → `api`.
+//!
+//! Healthy instances are cached for `cache_ttl_secs`, services without any in a smaller cache of
+//! their own, so floods of bogus names can't evict known ones.
+//!
+//! [Consul]: https://developer.hashicorp.com/consul/api-docs/health#list-service-instances-for-service
+use super::{
+    cache::{Cache, MAX_CACHED, MAX_CACHED_MISSING},
+    encode, ResolveRequest, Resolved,
+};
+use serde::Deserialize;
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tracing::{debug, instrument, warn};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync + 'static>),
+}
+
+impl super::Retry for Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Error::Http(err) => super::is_http_retryable(err),
+            Error::Other(err) => super::is_retryable(err.as_ref()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    /// Base url of Consul agent, e.g. `http://127.0.0.1:8500`
+    address: String,
+    /// ACL token
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    datacenter: Option<String>,
+    /// Only look up names with this suffix
+    #[serde(default)]
+    domain: Option<String>,
+    /// For how long healthy instances are cached
+    #[serde(default = "default_cache_ttl_secs")]
+    cache_ttl_secs: u64,
+}
+
+impl Config {
+    /// Suffix of names looked up in consul, `None` if all of them are.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+}
+
+const fn default_cache_ttl_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    clients: Arc<Vec<Client>>,
+}
+
+impl Layer {
+    pub fn new<'a, I>(configs: I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a Config>,
+    {
+        let clients = configs
+            .map(Client::new)
+            .collect::<Result<Vec<Client>, _>>()
+            .map(Arc::new)?;
+
+        Ok(Self { clients })
+    }
+}
+
+impl<S> tower::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service::new(inner, self.clients.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    clients: Arc<Vec<Client>>,
+}
+
+impl<S> Service<S> {
+    pub fn new(inner: S, clients: Arc<Vec<Client>>) -> Self {
+        Self { inner, clients }
+    }
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>> + Send + Clone + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    S::Future: Send + 'static,
+{
+    type Response = Option<Resolved>;
+    type Error = Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Option<Resolved>, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[instrument(skip(self), fields(resolver = "consul"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        debug!("enter");
+        let mut this = self.clone();
+
+        Box::pin(async move {
+            for client in this.clients.iter() {
+                let service = match client.service_name(&request.name) {
+                    Some(service) => service,
+                    None => continue,
+                };
+
+                match client.resolve(service).await {
+                    Ok(Some(address)) => return Ok(Some(Resolved::new("consul", address))),
+                    Ok(None) => debug!(service, "No healthy instances"),
+                    Err(err) => warn!(service, "Failed to query consul: {err}"),
+                }
+            }
+
+            this.inner
+                .call(request)
+                .await
+                .map_err(Into::into)
+                .map_err(Error::Other)
+        })
+    }
+}
+
+/// Queries single Consul agent, caching healthy instances per service.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    config: Config,
+    found: Cache<String, Instances>,
+    /// Services without healthy instances
+    missing: Cache<String, ()>,
+}
+
+#[derive(Debug, Clone)]
+struct Instances {
+    addresses: Arc<[SocketAddr]>,
+    cursor: Arc<AtomicUsize>,
+}
+
+impl Client {
+    fn new(config: &Config) -> Result<Self, Error> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+
+        let ttl = Duration::from_secs(config.cache_ttl_secs);
+        Ok(Self {
+            http,
+            config: config.clone(),
+            found: Cache::new(ttl, MAX_CACHED),
+            missing: Cache::new(ttl, MAX_CACHED_MISSING),
+        })
+    }
+
+    /// Consul service for requested name, names outside of `domain` and ones which are not a
+    /// single path segment are not looked up.
+    fn service_name<'r>(&self, record: &'r str) -> Option<&'r str> {
+        let service = match self.config.domain.as_deref() {
+            None => record,
+            Some(domain) => record
+                .strip_suffix(domain.trim_matches('.'))?
+                .strip_suffix('.')?,
+        };
+
+        Some(service).filter(|service| !matches!(*service, "" | "." | ".."))
+    }
+
+    fn url(&self, service: &str) -> String {
+        format!(
+            "{}/v1/health/service/{}",
+            self.config.address.trim_end_matches('/'),
+            encode(service)
+        )
+    }
+
+    /// Picks healthy instance of the service, round-robin.
+    async fn resolve(&self, service: &str) -> Result<Option<SocketAddr>, Error> {
+        let key = service.to_owned();
+        if self.missing.get(&key).is_some() {
+            return Ok(None);
+        }
+
+        let instances = match self.found.get(&key) {
+            Some(instances) => instances,
+            None => {
+                let addresses = self.fetch(service).await?;
+                if addresses.is_empty() {
+                    self.missing.insert(key, ());
+                    return Ok(None);
+                }
+
+                let instances = Instances {
+                    addresses: addresses.into(),
+                    cursor: Arc::default(),
+                };
+                self.found.insert(key, instances.clone());
+                instances
+            }
+        };
+
+        let Instances { addresses, cursor } = instances;
+        let ix = cursor.fetch_add(1, Ordering::Relaxed) % addresses.len();
+        Ok(Some(addresses[ix]))
+    }
+
+    #[instrument(skip(self))]
+    async fn fetch(&self, service: &str) -> Result<Vec<SocketAddr>, Error> {
+        let mut request = self
+            .http
+            .get(self.url(service))
+            .query(&[("passing", "true")]);
+        if let Some(datacenter) = self.config.datacenter.as_ref() {
+            request = request.query(&[("dc", datacenter)]);
+        }
+        if let Some(token) = self.config.token.as_ref() {
+            request = request.header("X-Consul-Token", token);
+        }
+
+        let entries: Vec<Entry> = request.send().await?.error_for_status()?.json().await?;
+        Ok(addresses(entries))
+    }
+}
+
+/// Single entry of `/v1/health/service/:service` response, only fields used for routing.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Entry {
+    node: Node,
+    service: ServiceEntry,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    #[serde(default)]
+    address: String,
+    port: u16,
+}
+
+/// Service address falls back to node address when empty, as Consul does.
+fn addresses(entries: Vec<Entry>) -> Vec<SocketAddr> {
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            let address = if entry.service.address.is_empty() {
+                entry.node.address
+            } else {
+                entry.service.address
+            };
+
+            address
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::from((ip, entry.service.port)))
+                .map_err(|err| warn!(address, "Skipping instance: {err}"))
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{addresses, Client, Config, Entry};
+    use indoc::indoc;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn reads_instance_addresses() {
+        let json = indoc! {r#"
+        [
+          {
+            "Node": { "Node": "one", "Address": "10.0.0.1" },
+            "Service": { "Service": "api", "Address": "10.0.1.1", "Port": 8080 },
+            "Checks": []
+          },
+          {
+            "Node": { "Node": "two", "Address": "10.0.0.2" },
+            "Service": { "Service": "api", "Address": "", "Port": 8081 },
+            "Checks": []
+          }
+        ]
+        "#};
+
+        let entries: Vec<Entry> = serde_json::from_str(json).expect("valid response");
+        let expected: Vec<SocketAddr> =
+            vec![([10, 0, 1, 1], 8080).into(), ([10, 0, 0, 2], 8081).into()];
+        assert_eq!(addresses(entries), expected);
+    }
+
+    #[test]
+    fn strips_domain() {
+        let config: Config = serde_yaml::from_str(indoc! {"
+        ---
+        address: 'http://127.0.0.1:8500'
+        domain: service.consul
+        "})
+        .expect("valid config");
+        let client = Client::new(&config).expect("valid client");
+
+        assert_eq!(client.service_name("api.service.consul"), Some("api"));
+        assert_eq!(client.service_name("service.consul"), None);
+        assert_eq!(client.service_name("example.com"), None);
+        assert_eq!(client.service_name("evilservice.consul"), None);
+        assert_eq!(client.service_name("..service.consul"), None);
+    }
+
+    #[test]
+    fn encodes_service_name() {
+        let config: Config = serde_yaml::from_str(indoc! {"
+        ---
+        address: 'http://127.0.0.1:8500/'
+        "})
+        .expect("valid config");
+        let client = Client::new(&config).expect("valid client");
+
+        assert_eq!(client.service_name(".."), None);
+        assert_eq!(
+            client.url("../../v1/agent/members"),
+            "http://127.0.0.1:8500/v1/health/service/..%2F..%2Fv1%2Fagent%2Fmembers"
+        );
+        assert_eq!(
+            client.url("api?dc=other&"),
+            "http://127.0.0.1:8500/v1/health/service/api%3Fdc%3Dother%26"
+        );
+    }
+}
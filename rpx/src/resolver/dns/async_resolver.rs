@@ -1,19 +1,45 @@
-use super::{Config, Error};
+use super::{Config, Error, Srv};
+use crate::resolver::{select::Selector, Resolved};
 use core::fmt;
-use rand::{prelude::IteratorRandom, rngs::SmallRng, SeedableRng};
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
-use std::sync::Arc;
-use tracing::{info_span, instrument, Instrument, Span};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info_span, instrument, trace, Instrument};
 use trust_dns_resolver::{
-    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
     TokioAsyncResolver,
 };
 
+/// Missing names remembered at most, further ones are looked up every time until some expire.
+const MAX_MISSING: usize = 10_000;
+
+/// Kind of lookup a resolver performs for a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lookup {
+    /// SRV record, then addresses of its target
+    Srv,
+    /// Addresses of the name itself
+    Ip,
+}
+
 #[derive(Clone, Debug)]
 pub struct Resolver {
     inner: TokioAsyncResolver,
-    srv: Arc<Vec<String>>,
+    strategy: LookupIpStrategy,
+    srv: Arc<Vec<Srv>>,
+    domains: Arc<Vec<String>>,
+    /// Domains with their own strategy, most specific first
+    overrides: Arc<Vec<(String, LookupIpStrategy)>>,
+    /// Orders addresses of a name
+    selector: Selector,
+    /// Picks SRV target, cursor of its own so every target gets its turn whatever number of
+    /// addresses were ordered in between
+    targets: Selector,
+    missing: Missing,
 }
 
 impl Resolver {
@@ -24,9 +50,19 @@ impl Resolver {
             address,
             strategy,
             srv,
+            domains,
+            strategy_overrides,
+            selection,
+            max_negative_ttl_secs,
         } = config;
+        let mut overrides: Vec<_> = strategy_overrides
+            .iter()
+            .map(|rule| (rule.domain.clone(), rule.strategy))
+            .collect();
+        overrides.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.len()));
 
-        // Be mindful of recursive calls when A record points to the instance running the forwarder
+        // Be mindful of recursive calls when A record points to the instance running the forwarder,
+        // `forward` refuses destinations equal to its own addresses, but only those it knows about
         resolver_opts.ip_strategy = *strategy;
         let name_server = NameServerConfig {
             socket_addr: *address,
@@ -41,74 +77,484 @@ impl Resolver {
         TokioAsyncResolver::tokio(resolver_config, resolver_opts)
             .map(|inner| Self {
                 inner,
+                strategy: *strategy,
                 srv: Arc::new(srv.to_vec()),
+                domains: Arc::new(domains.to_vec()),
+                overrides: Arc::new(overrides),
+                selector: Selector::new(*selection),
+                targets: Selector::new(*selection),
+                missing: Missing::new(Duration::from_secs(*max_negative_ttl_secs)),
             })
             .map_err(Error::TrustDns)
     }
 
-    pub fn should_lookup_srv(&self, record: &str) -> bool {
-        self.srv.iter().any(|domain| record.ends_with(domain))
+    /// Whether `record` belongs to this resolver, keeping split-horizon names from leaking.
+    pub fn should_lookup(&self, record: &str) -> bool {
+        self.domains.is_empty() || self.domains.iter().any(|domain| in_domain(record, domain))
+    }
+
+    /// Lookup this resolver performs for `record`, `None` if it doesn't belong to it.
+    ///
+    /// Decided by each resolver on its own, so a name under `srv` domains of one resolver is
+    /// still looked up as is by the others.
+    pub fn lookup_for(&self, record: &str) -> Option<Lookup> {
+        if !self.should_lookup(record) {
+            None
+        } else if self.srv_for(record).is_some() {
+            Some(Lookup::Srv)
+        } else {
+            Some(Lookup::Ip)
+        }
+    }
+
+    /// Most specific srv domain `record` falls under.
+    pub fn srv_for(&self, record: &str) -> Option<&Srv> {
+        self.srv
+            .iter()
+            .filter(|srv| in_domain(record, srv.domain()))
+            .max_by_key(|srv| srv.domain().len())
+    }
+
+    /// Strategy of the most specific override `record` falls under, configured one otherwise.
+    pub fn strategy_for(&self, record: &str) -> LookupIpStrategy {
+        self.overrides
+            .iter()
+            .find(|(domain, _)| in_domain(record, domain))
+            .map_or(self.strategy, |(_, strategy)| *strategy)
+    }
+
+    /// Looks up addresses of fully qualified `name` with strategy configured for it.
+    async fn lookup(&self, name: String) -> Result<Vec<IpAddr>, Error> {
+        let dns_span = info_span!("tokio-async-resolver");
+
+        let strategy = self.strategy_for(name.trim_end_matches('.'));
+        let ipv4 = || async {
+            Ok(self
+                .inner
+                .ipv4_lookup(name.as_str())
+                .await?
+                .iter()
+                .map(|ip| IpAddr::V4(*ip))
+                .collect())
+        };
+        let ipv6 = || async {
+            Ok(self
+                .inner
+                .ipv6_lookup(name.as_str())
+                .await?
+                .iter()
+                .map(|ip| IpAddr::V6(*ip))
+                .collect())
+        };
+        // Strategy is fixed when inner resolver is built, overrides query each family themselves
+        let found = async {
+            match strategy {
+                _ if strategy == self.strategy => {
+                    Ok(self.inner.lookup_ip(name.as_str()).await?.iter().collect())
+                }
+                LookupIpStrategy::Ipv4Only => ipv4().await,
+                LookupIpStrategy::Ipv6Only => ipv6().await,
+                LookupIpStrategy::Ipv4AndIpv6 => match futures::join!(ipv4(), ipv6()) {
+                    (Err(err), Err(_)) => Err(err),
+                    (v4, v6) => Ok([v4.unwrap_or_default(), v6.unwrap_or_default()].concat()),
+                },
+                LookupIpStrategy::Ipv4thenIpv6 => or_else(ipv4().await, ipv6).await,
+                LookupIpStrategy::Ipv6thenIpv4 => or_else(ipv6().await, ipv4).await,
+            }
+        };
+
+        let found = with_ipv4_fallback(strategy, found, ipv4).instrument(dns_span);
+        self.missing.lookup(Lookup::Ip, &name, found).await
     }
 
     #[instrument(skip(self))]
     pub async fn resolve_ip<T, D>(
         &self,
         (record, port): (T, u16),
-    ) -> Result<Option<SocketAddr>, Error>
+    ) -> Result<Option<Resolved>, Error>
     where
         T: fmt::Display + fmt::Debug + Clone + Deref<Target = D>,
         D: Deref<Target = str>,
     {
-        let mut rng = SmallRng::from_entropy();
-        let dns_span = info_span!("tokio-async-resolver");
-        dns_span.follows_from(Span::current());
+        // All records are candidates, so connection could be raced across address families
+        let addresses = self.lookup(format!("{}.", record)).await?;
 
-        let address = self
-            .inner
-            .lookup_ip(format!("{}.", record))
-            .instrument(dns_span)
-            .await?
-            .iter()
-            .choose(&mut rng)
-            .map(|ip_addr| SocketAddr::from((ip_addr, port)));
-
-        Ok(address)
+        Ok(candidates(&self.selector, addresses, port))
     }
 
     #[instrument(skip(self))]
-    pub async fn resolve_srv<T, D>(
-        &self,
-        (record, _): (T, u16),
-    ) -> Result<Option<SocketAddr>, Error>
+    pub async fn resolve_srv<T, D>(&self, (record, _): (T, u16)) -> Result<Option<Resolved>, Error>
     where
         T: fmt::Display + fmt::Debug + Clone + Deref<Target = D>,
         D: Deref<Target = str>,
     {
-        let mut rng = SmallRng::from_entropy();
+        let query = match self.srv_for(&record) {
+            Some(srv) => srv.query(&record),
+            None => format!("{}.", record),
+        };
         let dns_span = info_span!("tokio-async-resolver");
-        dns_span.follows_from(Span::current());
-        let response = self
-            .inner
-            .srv_lookup(format!("{}.", record))
-            .instrument(dns_span)
-            .await?;
-
-        if let Some((name, port)) = response
-            .iter()
-            .map(|response| (response.target(), response.port()))
-            .choose(&mut rng)
-        {
-            let address = self
+        let found = async {
+            Ok(self
                 .inner
-                .lookup_ip((*name).clone())
+                .srv_lookup(query.as_str())
                 .await?
                 .iter()
-                .next()
-                .map(|ip| SocketAddr::from((ip, port)));
+                .map(|response| (response.target().clone(), response.port()))
+                .collect())
+        };
+        let targets = self
+            .missing
+            .lookup(Lookup::Srv, &query, found.instrument(dns_span))
+            .await?;
 
-            Ok(address)
+        if let Some((name, port)) = self.targets.pick(&targets) {
+            // Target could have several addresses, `forward` moves on if one is down
+            let addresses = self.lookup(name.to_string()).await?;
+
+            Ok(candidates(&self.selector, addresses, *port))
         } else {
             Ok(None)
         }
     }
 }
+
+/// Names found missing, with when to look them up again.
+///
+/// Keeps floods of connections for bogus names, e.g. from scanners, from turning into floods of
+/// queries, as inner resolver only caches what it found.
+#[derive(Debug, Clone)]
+struct Missing {
+    max_ttl: Duration,
+    expiries: Arc<Mutex<HashMap<(Lookup, String), Instant>>>,
+}
+
+impl Missing {
+    fn new(max_ttl: Duration) -> Self {
+        Self {
+            max_ttl,
+            expiries: Arc::default(),
+        }
+    }
+
+    /// Awaits `found` unless `name` was recently missing, remembering it if it is now.
+    ///
+    /// Names without records are kept for SOA minimum TTL, when the answer carries one,
+    /// never longer than `max_ttl`.
+    async fn lookup<T, F>(&self, kind: Lookup, name: &str, found: F) -> Result<Vec<T>, Error>
+    where
+        F: Future<Output = Result<Vec<T>, Error>>,
+    {
+        if self.max_ttl.is_zero() {
+            return found.await;
+        }
+
+        let key = (kind, name.to_owned());
+        let now = Instant::now();
+        let missing = self
+            .expiries
+            .lock()
+            .expect("Missing names lock poisoned")
+            .get(&key)
+            .is_some_and(|expiry| *expiry > now);
+        if missing {
+            trace!(name, "Known to be missing");
+            return Ok(Vec::new());
+        }
+
+        let found = found.await;
+        let ttl = match &found {
+            Ok(found) if found.is_empty() => self.max_ttl,
+            Err(err) if err.is_no_records() => err
+                .negative_ttl()
+                .map_or(self.max_ttl, |ttl| ttl.min(self.max_ttl)),
+            _ => return found,
+        };
+
+        let now = Instant::now();
+        let mut expiries = self.expiries.lock().expect("Missing names lock poisoned");
+        if expiries.len() >= MAX_MISSING {
+            expiries.retain(|_, expiry| *expiry > now);
+        }
+        if expiries.len() < MAX_MISSING {
+            expiries.insert(key, now + ttl);
+        }
+
+        found
+    }
+}
+
+/// Whether `name` is `domain` or falls under it, `othercorp.example.com` is not under
+/// `corp.example.com`.
+fn in_domain(name: &str, domain: &str) -> bool {
+    name.strip_suffix(domain.trim_matches('.'))
+        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+}
+
+/// Addresses in order `selector` picks, spreading connections across them.
+fn candidates(selector: &Selector, addresses: Vec<IpAddr>, port: u16) -> Option<Resolved> {
+    let mut addresses: Vec<SocketAddr> = addresses
+        .into_iter()
+        .map(|ip_addr| SocketAddr::from((ip_addr, port)))
+        .collect();
+    selector.order(&mut addresses);
+
+    Resolved::with_candidates("dns", addresses)
+}
+
+/// Keeps `found` addresses, looking up `other` family only if there are none.
+async fn or_else<L, R>(found: Result<Vec<IpAddr>, Error>, other: L) -> Result<Vec<IpAddr>, Error>
+where
+    L: FnOnce() -> R,
+    R: Future<Output = Result<Vec<IpAddr>, Error>>,
+{
+    match found {
+        Ok(found) if !found.is_empty() => Ok(found),
+        _ => other().await,
+    }
+}
+
+/// Awaits `found`, retrying with A records from `ipv4` if IPv6 only lookup came up empty.
+///
+/// Hosts without IPv6 egress would otherwise resolve nothing for names with A records only.
+async fn with_ipv4_fallback<F, L, R>(
+    strategy: LookupIpStrategy,
+    found: F,
+    ipv4: L,
+) -> Result<Vec<IpAddr>, Error>
+where
+    F: Future<Output = Result<Vec<IpAddr>, Error>>,
+    L: FnOnce() -> R,
+    R: Future<Output = Result<Vec<IpAddr>, Error>>,
+{
+    let found = found.await;
+    let empty = found.as_ref().map_or(true, Vec::is_empty);
+    if strategy != LookupIpStrategy::Ipv6Only || !empty {
+        return found;
+    }
+
+    debug!("No AAAA records, retrying with A records");
+    ipv4().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{candidates, with_ipv4_fallback, Error, Lookup, Missing, Resolver};
+    use crate::resolver::{
+        dns::Config,
+        select::{Selector, Strategy},
+    };
+    use indoc::indoc;
+    use std::{
+        net::IpAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use test_case::test_case;
+    use trust_dns_resolver::{
+        config::LookupIpStrategy,
+        error::{ResolveError, ResolveErrorKind},
+        proto::op::{Query, ResponseCode},
+    };
+
+    const V4: [u8; 4] = [10, 0, 0, 1];
+    const V6: [u16; 8] = [0xfd00, 0, 0, 0, 0, 0, 0, 1];
+
+    fn no_records() -> Result<Vec<IpAddr>, Error> {
+        Err(ResolveError::from("no records found").into())
+    }
+
+    #[test_case(LookupIpStrategy::Ipv6Only, Ok(vec![]), Some(V4.into()); "empty ipv6")]
+    #[test_case(LookupIpStrategy::Ipv6Only, no_records(), Some(V4.into()); "failed ipv6")]
+    #[test_case(LookupIpStrategy::Ipv6Only, Ok(vec![V6.into()]), Some(V6.into()); "found ipv6")]
+    #[test_case(LookupIpStrategy::Ipv4AndIpv6, Ok(vec![]), None; "other strategy")]
+    #[tokio::test]
+    async fn falls_back_to_ipv4(
+        strategy: LookupIpStrategy,
+        found: Result<Vec<IpAddr>, Error>,
+        expected: Option<IpAddr>,
+    ) {
+        let ipv4 = || async { Ok(vec![V4.into()]) };
+        let addresses = with_ipv4_fallback(strategy, async { found }, ipv4)
+            .await
+            .unwrap_or_default();
+
+        assert_eq!(addresses.first().copied(), expected);
+    }
+
+    #[test_case("api.internal", true, false; "internal name")]
+    #[test_case("example.com", false, true; "public name")]
+    #[test_case("api.notinternal", false, false; "shares suffix but not label")]
+    #[tokio::test]
+    async fn looks_up_own_domains_only(name: &str, internal: bool, public: bool) {
+        let resolver = |yaml| {
+            let config: Config = serde_yaml::from_str(yaml).expect("Valid config");
+            Resolver::new(&config).expect("Resolver")
+        };
+        let internal_resolver =
+            resolver("{address: '10.0.0.53:53', domains: [internal], srv: [internal]}");
+        let public_resolver = resolver("{address: '8.8.8.8:53', domains: [example.com]}");
+        let any_resolver = resolver("{address: '1.1.1.1:53'}");
+
+        assert_eq!(internal_resolver.should_lookup(name), internal);
+        assert_eq!(
+            internal_resolver.lookup_for(name),
+            internal.then_some(Lookup::Srv)
+        );
+        assert_eq!(public_resolver.should_lookup(name), public);
+        assert!(any_resolver.should_lookup(name));
+    }
+
+    #[test_case("legacy.example.com", LookupIpStrategy::Ipv4Only; "overridden domain")]
+    #[test_case("api.legacy.example.com", LookupIpStrategy::Ipv4Only; "under override")]
+    #[test_case("canary.legacy.example.com", LookupIpStrategy::Ipv6thenIpv4; "most specific override")]
+    #[test_case("www.example.com", LookupIpStrategy::Ipv4AndIpv6; "default")]
+    #[test_case("notlegacy.example.com", LookupIpStrategy::Ipv4AndIpv6; "shares suffix but not label")]
+    #[tokio::test]
+    async fn picks_most_specific_strategy(name: &str, expected: LookupIpStrategy) {
+        let yaml = indoc! {"
+        address: '10.0.0.53:53'
+        strategy_overrides:
+          - domain: legacy.example.com
+            strategy: Ipv4Only
+          - domain: canary.legacy.example.com
+            strategy: Ipv6thenIpv4
+        "};
+        let config: Config = serde_yaml::from_str(yaml).expect("Valid config");
+        let resolver = Resolver::new(&config).expect("Resolver");
+
+        assert_eq!(resolver.strategy_for(name), expected);
+    }
+
+    #[test_case("_https._tcp.example.com", Some("_https._tcp.example.com."); "full srv name")]
+    #[test_case("api.internal", Some("_https._tcp.api.internal."); "default proto")]
+    #[test_case("dns.udp.internal", Some("_dns._udp.dns.udp.internal."); "most specific domain")]
+    #[test_case("www.example.org", None; "outside srv domains")]
+    #[test_case("www.notexample.com", None; "shares suffix but not label")]
+    #[tokio::test]
+    async fn forms_srv_query_name(name: &str, expected: Option<&str>) {
+        let yaml = indoc! {"
+        address: '10.0.0.53:53'
+        srv:
+          - example.com
+          - {domain: internal, service: https}
+          - {domain: udp.internal, service: dns, proto: udp}
+        "};
+        let config: Config = serde_yaml::from_str(yaml).expect("Valid config");
+        let resolver = Resolver::new(&config).expect("Resolver");
+
+        let query = resolver.srv_for(name).map(|srv| srv.query(name));
+
+        assert_eq!(query.as_deref(), expected);
+    }
+
+    fn nxdomain(negative_ttl: Option<u32>) -> Result<Vec<IpAddr>, Error> {
+        let kind = ResolveErrorKind::NoRecordsFound {
+            query: Box::new(Query::default()),
+            soa: None,
+            negative_ttl,
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        };
+        Err(ResolveError::from(kind).into())
+    }
+
+    #[test_case(nxdomain(Some(5)), 5; "soa minimum ttl")]
+    #[test_case(nxdomain(Some(300)), 30; "capped soa ttl")]
+    #[test_case(nxdomain(None), 30; "no soa")]
+    #[test_case(Ok(vec![]), 30; "empty answer")]
+    #[tokio::test(start_paused = true)]
+    async fn remembers_missing_names(answer: Result<Vec<IpAddr>, Error>, ttl_secs: u64) {
+        let missing = Missing::new(Duration::from_secs(30));
+        let queries = AtomicUsize::new(0);
+        let mut answer = Some(answer);
+        let mut lookup = || {
+            let answer = answer.take().unwrap_or_else(|| Ok(vec![V4.into()]));
+            let queries = &queries;
+            missing.lookup(Lookup::Ip, "bogus.example.com.", async move {
+                queries.fetch_add(1, Ordering::Relaxed);
+                answer
+            })
+        };
+
+        let _ = lookup().await;
+        tokio::time::advance(Duration::from_secs(ttl_secs - 1)).await;
+        let found = lookup().await.expect("Missing name isn't an error");
+        assert!(found.is_empty());
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let found = lookup().await.expect("Looked up again");
+        assert_eq!(found, vec![IpAddr::from(V4)]);
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn forgets_nothing_found_with_zero_ttl() {
+        let missing = Missing::new(Duration::ZERO);
+        let queries = AtomicUsize::new(0);
+        for _ in 0..2 {
+            let _ = missing
+                .lookup(Lookup::Ip, "bogus.example.com.", async {
+                    queries.fetch_add(1, Ordering::Relaxed);
+                    nxdomain(Some(5))
+                })
+                .await;
+        }
+
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn keeps_every_address_of_srv_target() {
+        let records: Vec<IpAddr> = vec![
+            [10, 0, 0, 1].into(),
+            [10, 0, 0, 2].into(),
+            [10, 0, 0, 3].into(),
+        ];
+        let resolved =
+            candidates(&Selector::seeded(0), records.clone(), 8443).expect("Has candidates");
+
+        let mut addresses: Vec<_> = resolved.addresses().iter().map(|a| a.ip()).collect();
+        addresses.sort();
+        assert_eq!(addresses, records);
+        assert!(resolved
+            .addresses()
+            .iter()
+            .all(|address| address.port() == 8443));
+        assert!(candidates(&Selector::default(), vec![], 8443).is_none());
+    }
+
+    #[test]
+    fn rotates_candidates_in_turn() {
+        let selector = Selector::new(Strategy::RoundRobin);
+        let records: Vec<IpAddr> = vec![[10, 0, 0, 1].into(), [10, 0, 0, 2].into()];
+        let first = |selector: &Selector| {
+            candidates(selector, records.clone(), 443)
+                .and_then(|resolved| resolved.address())
+                .map(|address| address.ip())
+        };
+
+        assert_eq!(first(&selector), Some(records[0]));
+        assert_eq!(first(&selector), Some(records[1]));
+        assert_eq!(first(&selector), Some(records[0]));
+    }
+
+    #[tokio::test]
+    async fn picks_srv_targets_in_turn() {
+        let config: Config =
+            serde_yaml::from_str("{address: '10.0.0.53:53', selection: round_robin}")
+                .expect("Valid config");
+        let resolver = Resolver::new(&config).expect("Resolver");
+        let targets = ["a.internal.", "b.internal."];
+
+        let picked: Vec<_> = (0..4)
+            .map(|_| {
+                let picked = *resolver.targets.pick(&targets).expect("Has targets");
+                // Addresses of the target are ordered in between
+                candidates(&resolver.selector, vec![V4.into()], 443);
+                picked
+            })
+            .collect();
+        assert_eq!(picked, [targets[0], targets[1], targets[0], targets[1]]);
+    }
+}
@@ -1,12 +1,18 @@
+use super::select;
 use serde::Deserialize;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use trust_dns_resolver::{config::LookupIpStrategy, error::ResolveError};
+use std::time::Duration;
+use trust_dns_resolver::{
+    config::LookupIpStrategy,
+    error::{ResolveError, ResolveErrorKind},
+    proto::error::ProtoErrorKind,
+};
 
 mod async_resolver;
 mod service;
 
-use async_resolver::Resolver;
+use async_resolver::{Lookup, Resolver};
 pub use service::Service;
 
 #[derive(Debug, Deserialize)]
@@ -15,7 +21,72 @@ pub struct Config {
     #[serde(default = "default_strategy")]
     strategy: LookupIpStrategy,
     #[serde(default)]
-    srv: Vec<String>,
+    srv: Vec<Srv>,
+    /// Only names ending with one of these are looked up, all names when empty
+    #[serde(default)]
+    domains: Vec<String>,
+    /// Strategies for names under given domains, most specific domain wins over others and
+    /// `strategy`
+    #[serde(default)]
+    strategy_overrides: Vec<StrategyOverride>,
+    /// Order addresses are tried in and SRV target picked by, `random` unless set
+    #[serde(default)]
+    selection: select::Strategy,
+    /// Longest a missing name is remembered for, SOA minimum TTL applies when shorter,
+    /// `0` looks missing names up every time
+    #[serde(default = "default_max_negative_ttl_secs")]
+    max_negative_ttl_secs: u64,
+}
+
+/// Domain names under which are resolved with SRV lookups.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Srv {
+    /// Names are queried as they are, so they have to be full SRV names,
+    /// e.g. `_https._tcp.example.com`
+    Domain(String),
+    /// Names are queried prefixed with `_service._proto`, e.g. `api.example.com` as
+    /// `_https._tcp.api.example.com`
+    Service {
+        domain: String,
+        service: String,
+        /// `tcp` unless set
+        #[serde(default = "default_proto")]
+        proto: String,
+    },
+}
+
+impl Srv {
+    pub fn domain(&self) -> &str {
+        match self {
+            Srv::Domain(domain) | Srv::Service { domain, .. } => domain,
+        }
+    }
+
+    /// Fully qualified name of SRV record for `record`.
+    pub fn query(&self, record: &str) -> String {
+        match self {
+            Srv::Domain(_) => format!("{record}."),
+            Srv::Service { service, proto, .. } => format!("_{service}._{proto}.{record}."),
+        }
+    }
+}
+
+fn default_proto() -> String {
+    "tcp".to_owned()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StrategyOverride {
+    domain: String,
+    strategy: LookupIpStrategy,
+}
+
+impl Config {
+    /// Domains to perform srv lookups for.
+    pub fn srv(&self) -> &[Srv] {
+        &self.srv
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -46,7 +117,11 @@ impl<S> tower::Layer<S> for Layer {
 }
 
 const fn default_strategy() -> LookupIpStrategy {
-    LookupIpStrategy::Ipv6Only
+    LookupIpStrategy::Ipv4AndIpv6
+}
+
+const fn default_max_negative_ttl_secs() -> u64 {
+    30
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -54,6 +129,56 @@ pub enum Error {
     #[error(transparent)]
     TrustDns(#[from] ResolveError),
 
+    #[error(
+        "Every resolver failed: {}",
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+    )]
+    Unavailable(Vec<Error>),
+
     #[error(transparent)]
     Other(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
+
+impl super::Retry for Error {
+    /// Servers timing out or unreachable could answer next time, an answer without records
+    /// would be the same.
+    fn is_retryable(&self) -> bool {
+        match self {
+            Error::TrustDns(err) => match err.kind() {
+                ResolveErrorKind::Timeout
+                | ResolveErrorKind::NoConnections
+                | ResolveErrorKind::Io(_) => true,
+                ResolveErrorKind::Proto(err) => matches!(
+                    err.kind(),
+                    ProtoErrorKind::Timeout | ProtoErrorKind::Io(_) | ProtoErrorKind::Busy
+                ),
+                _ => false,
+            },
+            Error::Unavailable(errors) => errors.iter().any(super::Retry::is_retryable),
+            Error::Other(err) => super::is_retryable(err.as_ref()),
+        }
+    }
+}
+
+impl Error {
+    /// Resolver answered, there just are no such records.
+    fn is_no_records(&self) -> bool {
+        matches!(
+            self,
+            Error::TrustDns(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. })
+        )
+    }
+
+    /// How long missing records may be assumed missing, from SOA of authoritative answer.
+    fn negative_ttl(&self) -> Option<Duration> {
+        match self {
+            Error::TrustDns(err) => match err.kind() {
+                ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => {
+                    negative_ttl.map(|ttl| Duration::from_secs(ttl.into()))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
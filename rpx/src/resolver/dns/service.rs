@@ -1,13 +1,14 @@
-use super::{Error, Resolver};
+use super::{Error, Lookup, Resolver};
+use crate::resolver::{ResolveRequest, Resolved};
 use core::fmt;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use std::future::Future;
 use std::ops::Deref;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::{net::SocketAddr, pin::Pin};
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
 
 /// Looks up records for provided service name
 ///
@@ -24,99 +25,108 @@ impl<S> Service<S> {
         Self { inner, resolvers }
     }
 
-    #[instrument(skip(self), fields(resolvers = self.resolvers.len()))]
-    async fn resolve_srv<T, D>(&self, (record, port): (T, u16)) -> Result<Option<SocketAddr>, Error>
-    where
-        // Some indirection to express deref coercion
-        T: fmt::Display + fmt::Debug + Clone + Deref<Target = D>,
-        D: Deref<Target = str>,
-    {
-        let resolvers = self.resolvers.clone();
-        let mut futures: FuturesUnordered<_> = resolvers
+    /// Resolvers `record` belongs to, along with the lookup each one performs for it.
+    fn lookups(&self, record: &str) -> Vec<(&Resolver, Lookup)> {
+        self.resolvers
             .iter()
-            .filter(|resolver| resolver.should_lookup_srv(&record))
-            .map(|resolver| {
-                let record = record.clone();
-                Box::pin(async move { resolver.resolve_srv((record, port)).await })
-            })
-            .collect();
-
-        loop {
-            match futures.next().await {
-                Some(Ok(Some(address))) => return Ok(Some(address)),
-                None => return Ok(None),
-                _ => {}
-            }
-        }
+            .filter_map(|resolver| Some((resolver, resolver.lookup_for(record)?)))
+            .collect()
     }
 
     #[instrument(skip(self), fields(resolvers = self.resolvers.len()))]
-    async fn resolve_ip<T, D>(&self, (record, port): (T, u16)) -> Result<Option<SocketAddr>, Error>
+    async fn resolve<T, D>(&self, (record, port): (T, u16)) -> Result<Option<Resolved>, Error>
     where
         // Some indirection to express deref coercion
         T: fmt::Display + fmt::Debug + Clone + Deref<Target = D>,
         D: Deref<Target = str>,
     {
-        let resolvers = self.resolvers.clone();
-        let mut futures: FuturesUnordered<_> = resolvers
-            .iter()
-            .map(|resolver| {
+        let futures: FuturesUnordered<_> = self
+            .lookups(&record)
+            .into_iter()
+            .map(|(resolver, lookup)| {
                 let record = record.clone();
-                Box::pin(async move { resolver.resolve_ip((record, port)).await })
+                Box::pin(async move {
+                    match lookup {
+                        Lookup::Srv => resolver.resolve_srv((record, port)).await,
+                        Lookup::Ip => resolver.resolve_ip((record, port)).await,
+                    }
+                })
             })
             .collect();
 
-        loop {
-            match futures.next().await {
-                Some(Ok(Some(address))) => return Ok(Some(address)),
-                // log errors here
-                None => return Ok(None),
-                _ => {}
+        first_found(futures).await
+    }
+}
+
+/// First address any lookup came up with.
+///
+/// Fails if none did and some lookup failed, so all resolvers being down doesn't pass for
+/// the name being unknown.
+async fn first_found<F>(mut lookups: FuturesUnordered<F>) -> Result<Option<Resolved>, Error>
+where
+    F: Future<Output = Result<Option<Resolved>, Error>>,
+{
+    let mut errors = Vec::new();
+    while let Some(found) = lookups.next().await {
+        match found {
+            Ok(Some(address)) => return Ok(Some(address)),
+            Ok(None) => {}
+            Err(err) if err.is_no_records() => {}
+            Err(err) => {
+                debug!("Lookup failed: {err}");
+                errors.push(err);
             }
         }
     }
+
+    if errors.is_empty() {
+        Ok(None)
+    } else {
+        Err(Error::Unavailable(errors))
+    }
 }
 
-impl<S> tower::Service<(String, u16)> for Service<S>
+impl<S> tower::Service<ResolveRequest> for Service<S>
 where
-    S: tower::Service<(String, u16), Response = Option<SocketAddr>> + Send + Sync + Clone + 'static,
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>> + Send + Sync + Clone + 'static,
     S::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
     S::Future: Send + 'static,
 {
-    type Response = Option<SocketAddr>;
+    type Response = Option<Resolved>;
     type Error = Error;
     type Future =
-        Pin<Box<dyn Future<Output = Result<Option<SocketAddr>, Self::Error>> + Send + 'static>>;
+        Pin<Box<dyn Future<Output = Result<Option<Resolved>, Self::Error>> + Send + 'static>>;
 
     fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    #[instrument(skip(self))]
-    fn call(&mut self, (record, port): (String, u16)) -> Self::Future {
+    #[instrument(skip(self), fields(resolver = "dns"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
         debug!("enter");
-        let should_lookup_srv = self
-            .resolvers
-            .iter()
-            .any(|resolver| resolver.should_lookup_srv(&record));
         let mut this = self.clone();
-        let clonable = Arc::new(record.clone());
+        let clonable = Arc::new(request.name.clone());
+        let port = request.port;
 
         Box::pin(async move {
-            let address = if should_lookup_srv {
-                this.resolve_srv((clonable, port)).await
-            } else {
-                this.resolve_ip((clonable, port)).await
-            };
+            let address = this.resolve((clonable, port)).await;
 
             match address {
                 Ok(Some(address)) => Ok(Some(address)),
-                _ => this
+                Ok(None) => this
                     .inner
-                    .call((record, port))
+                    .call(request)
                     .await
                     .map_err(Into::into)
                     .map_err(Error::Other),
+                // Let layers above, e.g. `fallback`, know resolvers are down
+                Err(err) => {
+                    warn!("Failed to look up name: {err}");
+                    match this.inner.call(request).await {
+                        Ok(Some(address)) => Ok(Some(address)),
+                        _ => Err(err),
+                    }
+                }
             }
         })
     }
@@ -131,3 +141,97 @@ impl<S> fmt::Display for Service<S> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{first_found, Error, Lookup, Resolver, Service};
+    use crate::resolver::{dns::Config, Resolved};
+    use futures::{future::ready, stream::FuturesUnordered};
+    use std::sync::Arc;
+    use test_case::test_case;
+    use trust_dns_resolver::{
+        error::{ResolveError, ResolveErrorKind},
+        proto::op::{Query, ResponseCode},
+    };
+
+    fn failed() -> Result<Option<Resolved>, Error> {
+        Err(ResolveError::from("connection refused").into())
+    }
+
+    fn no_records() -> Result<Option<Resolved>, Error> {
+        let kind = ResolveErrorKind::NoRecordsFound {
+            query: Box::new(Query::default()),
+            soa: None,
+            negative_ttl: None,
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        };
+        Err(ResolveError::from(kind).into())
+    }
+
+    fn found() -> Result<Option<Resolved>, Error> {
+        Ok(Some(Resolved::new("dns", ([10, 0, 0, 1], 443).into())))
+    }
+
+    async fn first(
+        lookups: Vec<Result<Option<Resolved>, Error>>,
+    ) -> Result<Option<Resolved>, Error> {
+        first_found(
+            lookups
+                .into_iter()
+                .map(ready)
+                .collect::<FuturesUnordered<_>>(),
+        )
+        .await
+    }
+
+    #[test_case("api.internal", vec![Lookup::Srv, Lookup::Ip]; "srv for one resolver only")]
+    #[test_case("example.com", vec![Lookup::Ip]; "outside internal domain")]
+    #[tokio::test]
+    async fn decides_lookup_per_resolver(name: &str, expected: Vec<Lookup>) {
+        let resolvers = [
+            "{address: '10.0.0.53:53', domains: [internal], srv: [internal]}",
+            "{address: '1.1.1.1:53'}",
+        ]
+        .into_iter()
+        .map(|yaml| {
+            let config: Config = serde_yaml::from_str(yaml).expect("Valid config");
+            Resolver::new(&config).expect("Resolver")
+        })
+        .collect();
+        let service = Service::new((), Arc::new(resolvers));
+
+        let lookups: Vec<Lookup> = service
+            .lookups(name)
+            .into_iter()
+            .map(|(_, lookup)| lookup)
+            .collect();
+
+        assert_eq!(lookups, expected);
+    }
+
+    #[tokio::test]
+    async fn fails_once_every_resolver_failed() {
+        let error = first(vec![failed(), failed()])
+            .await
+            .expect_err("Every lookup failed");
+
+        assert!(
+            matches!(&error, Error::Unavailable(errors) if errors.len() == 2),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn prefers_any_answer_over_failures() {
+        assert!(first(vec![failed(), found()])
+            .await
+            .expect("Found")
+            .is_some());
+        assert!(first(vec![no_records(), Ok(None)])
+            .await
+            .expect("Resolved nothing")
+            .is_none());
+        assert!(first(vec![no_records(), failed()]).await.is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Resolves names to addresses stored in environment variables.
+//!
+//! Variable is read on every request, so updated values are picked up without restart.
+use super::{ResolveRequest, Resolved};
+use futures::future::Either;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tracing::{debug, instrument, warn};
+
+/// Maps service name to environment variable holding `ip:port` of the destination.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Config {
+    name: String,
+    var: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    vars: Arc<HashMap<String, String>>,
+}
+
+impl Layer {
+    pub fn new<'a, I>(rules: I) -> Self
+    where
+        I: Iterator<Item = &'a Config>,
+    {
+        let vars = rules
+            .map(|rule| (rule.name.clone(), rule.var.clone()))
+            .collect();
+
+        Self {
+            vars: Arc::new(vars),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service::new(inner, self.vars.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    vars: Arc<HashMap<String, String>>,
+}
+
+impl<S> Service<S> {
+    pub fn new(inner: S, vars: Arc<HashMap<String, String>>) -> Self {
+        Self { inner, vars }
+    }
+
+    fn lookup(&self, record: &str) -> Option<SocketAddr> {
+        let var = self.vars.get(record)?;
+        match std::env::var(var) {
+            Ok(value) => match value.trim().parse() {
+                Ok(address) => Some(address),
+                Err(err) => {
+                    warn!(var, value, "Failed to parse address: {err}");
+                    None
+                }
+            },
+            Err(err) => {
+                warn!(var, "Failed to read variable: {err}");
+                None
+            }
+        }
+    }
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>>,
+{
+    type Response = Option<Resolved>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Option<Resolved>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    #[instrument(skip(self), fields(resolver = "env"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        debug!("enter");
+        match self.lookup(&request.name) {
+            Some(address) => Either::Left(ready(Ok(Some(Resolved::new("env", address))))),
+            None => Either::Right(self.inner.call(request)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Layer};
+    use crate::resolver::{void, ResolveRequest, Resolved};
+    use tower::{Layer as _, Service as _};
+
+    fn resolver(
+        name: &str,
+        var: &str,
+    ) -> impl tower::Service<ResolveRequest, Response = Option<Resolved>> {
+        let config = Config {
+            name: name.to_owned(),
+            var: var.to_owned(),
+        };
+        Layer::new(std::iter::once(&config)).layer(void::Service)
+    }
+
+    fn request() -> ResolveRequest {
+        ResolveRequest::new("api.example.com", 443, ([127, 0, 0, 1], 50000).into())
+    }
+
+    #[tokio::test]
+    async fn reads_variable_on_every_call() {
+        let var = "RPX_TEST_ENV_RESOLVER_READS";
+        let mut svc = resolver("api.example.com", var);
+
+        std::env::set_var(var, "10.0.1.5:8080");
+        let outcome = svc.call(request()).await.ok();
+        assert_eq!(
+            outcome,
+            Some(Some(Resolved::new("env", ([10, 0, 1, 5], 8080).into())))
+        );
+
+        std::env::set_var(var, "10.0.1.6:8080");
+        let outcome = svc.call(request()).await.ok();
+        assert_eq!(
+            outcome,
+            Some(Some(Resolved::new("env", ([10, 0, 1, 6], 8080).into())))
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_through_when_missing_or_invalid() {
+        let var = "RPX_TEST_ENV_RESOLVER_FALLS_THROUGH";
+        let mut svc = resolver("api.example.com", var);
+
+        std::env::remove_var(var);
+        let outcome = svc.call(request()).await.ok();
+        assert_eq!(outcome, Some(None));
+
+        std::env::set_var(var, "not an address");
+        let outcome = svc.call(request()).await.ok();
+        assert_eq!(outcome, Some(None));
+    }
+}
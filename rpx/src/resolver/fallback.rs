@@ -60,7 +60,7 @@ where
         Poll::Ready(Ok(()))
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(resolver = "fallback"))]
     fn call(&mut self, req: R) -> Self::Future {
         debug!("enter");
 
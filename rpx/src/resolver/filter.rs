@@ -1,6 +1,15 @@
+use super::{ResolveRequest, Resolved};
+use futures::future::{ready, Either, ErrInto, Ready, TryFutureExt};
+use regex::Regex;
 use serde::Deserialize;
-use std::{collections::HashSet, sync::Arc};
-use tower::filter::{Filter, Predicate};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::BoxError;
+use tracing::{debug, instrument};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -8,14 +17,64 @@ pub enum Error {
     NotSupported(String),
 }
 
+impl super::Retry for Error {
+    /// Filter denies the same name every time.
+    fn is_retryable(&self) -> bool {
+        false
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
+    /// Names ending with any of these are allowed
+    #[serde(default)]
     names: Vec<String>,
+    /// Names matching any of these expressions are allowed as well
+    #[serde(default, with = "serde_regex")]
+    patterns: Vec<Regex>,
+    #[serde(default)]
+    on_reject: OnReject,
+    /// Destination of rejected names, required with [`OnReject::Sink`]
+    #[serde(default)]
+    sink_address: Option<SocketAddr>,
+}
+
+impl Config {
+    /// Domains names are allowed under.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Expressions allowing names besides [`names`][Config::names].
+    pub fn patterns(&self) -> &[Regex] {
+        &self.patterns
+    }
+
+    pub fn on_reject(&self) -> OnReject {
+        self.on_reject
+    }
+
+    pub fn sink_address(&self) -> Option<SocketAddr> {
+        self.sink_address
+    }
+}
+
+/// What becomes of names filter rejects.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnReject {
+    /// Fail resolution, connection is dropped unless `fallback` catches it
+    #[default]
+    Drop,
+    /// Resolve to `sink_address`, e.g. a server logging and closing politely
+    Sink,
 }
 
 #[derive(Debug, Clone)]
 pub struct Layer {
     allowed_domains: Arc<Vec<String>>,
+    patterns: Arc<Vec<Regex>>,
+    sink: Option<SocketAddr>,
 }
 
 impl Layer {
@@ -23,7 +82,9 @@ impl Layer {
     where
         I: Iterator<Item = &'a Config>,
     {
+        let rules: Vec<&Config> = rules.collect();
         let allowed_domains = rules
+            .iter()
             .map(|rule| rule.names.clone())
             .fold(HashSet::new(), |mut a, b| {
                 a.extend(b);
@@ -31,33 +92,148 @@ impl Layer {
             })
             .into_iter()
             .collect();
+        let patterns = rules
+            .iter()
+            .flat_map(|rule| rule.patterns.iter().cloned())
+            .collect();
+        // Rules share allowed names, so they share the sink as well, first one wins
+        let sink = rules
+            .iter()
+            .filter(|rule| rule.on_reject == OnReject::Sink)
+            .find_map(|rule| rule.sink_address);
 
         Layer {
             allowed_domains: Arc::new(allowed_domains),
+            patterns: Arc::new(patterns),
+            sink,
         }
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct Check(Arc<Vec<String>>);
-
-impl Predicate<(String, u16)> for Check {
-    type Request = (String, u16);
+pub struct Check {
+    allowed_domains: Arc<Vec<String>>,
+    patterns: Arc<Vec<Regex>>,
+}
 
-    fn check(&mut self, (name, port): Self::Request) -> Result<Self::Request, tower::BoxError> {
-        if self.0.iter().any(|domain| name.ends_with(domain)) {
-            Ok((name, port))
-        } else {
-            Err(Box::new(Error::NotSupported(name)) as tower::BoxError)
-        }
+impl Check {
+    pub fn allows(&self, name: &str) -> bool {
+        self.allowed_domains
+            .iter()
+            .any(|domain| name.ends_with(domain))
+            || self.patterns.iter().any(|pattern| pattern.is_match(name))
     }
 }
 
 impl<S> tower::Layer<S> for Layer {
-    type Service = Filter<S, Check>;
+    type Service = Service<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        let check = Check(self.allowed_domains.clone());
-        Filter::new(inner, check)
+        let check = Check {
+            allowed_domains: self.allowed_domains.clone(),
+            patterns: self.patterns.clone(),
+        };
+        Service {
+            inner,
+            check,
+            sink: self.sink,
+        }
+    }
+}
+
+/// Passes allowed names on to `inner`, rejecting the rest with [`Error::NotSupported`] or
+/// resolving them to the sink, if there is one.
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    check: Check,
+    sink: Option<SocketAddr>,
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>>,
+    S::Error: Into<BoxError>,
+{
+    type Response = Option<Resolved>;
+    type Error = BoxError;
+    type Future = Either<Ready<Result<Option<Resolved>, BoxError>>, ErrInto<S::Future, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    #[instrument(skip_all, fields(resolver = "filter"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        if self.check.allows(&request.name) {
+            return Either::Right(self.inner.call(request).err_into());
+        }
+
+        let rejected = match self.sink {
+            Some(sink) => {
+                debug!(name = request.name.as_str(), %sink, "Sending rejected name to sink");
+                Ok(Some(Resolved::new("filter", sink)))
+            }
+            None => Err(Box::new(Error::NotSupported(request.name)) as BoxError),
+        };
+        Either::Left(ready(rejected))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Check, Config, Layer};
+    use crate::resolver::{is_retryable, void, ResolveRequest, Resolved};
+    use indoc::indoc;
+    use test_case::test_case;
+    use tower::{Layer as _, ServiceExt};
+
+    #[test_case("api.example.com", true ; "suffix")]
+    #[test_case("prod-web.internal.net", true ; "pattern")]
+    #[test_case("stage-db.internal.net", true ; "other alternative of pattern")]
+    #[test_case("dev-web.internal.net", false ; "pattern not matched")]
+    #[test_case("prod-web.internal.net.evil.com", false ; "anchored pattern")]
+    #[test_case("google.com", false ; "neither")]
+    fn allows_names_and_patterns(name: &str, allowed: bool) {
+        let yaml = indoc! {r#"
+        names: [example.com]
+        patterns: ['^(prod|stage)-[a-z]+\.internal\.net$']
+        "#};
+        let config: Config = serde_yaml::from_str(yaml).expect("Valid config");
+        let layer = Layer::new(std::iter::once(&config));
+        let check = Check {
+            allowed_domains: layer.allowed_domains.clone(),
+            patterns: layer.patterns.clone(),
+        };
+
+        assert_eq!(check.allows(name), allowed);
+    }
+
+    #[test_case("names: [example.com]", None ; "drop by default")]
+    #[test_case("{names: [example.com], on_reject: sink, sink_address: '127.0.0.1:6667'}", Some(([127, 0, 0, 1], 6667).into()) ; "sink")]
+    #[tokio::test]
+    async fn handles_rejected_names(yaml: &str, sink: Option<std::net::SocketAddr>) {
+        let config: Config = serde_yaml::from_str(yaml).expect("Valid config");
+        let service = Layer::new(std::iter::once(&config)).layer(void::Service);
+        let request = |name| ResolveRequest::new(name, 443, ([10, 0, 0, 1], 50000).into());
+
+        let allowed = service.clone().oneshot(request("api.example.com")).await;
+        assert_eq!(allowed.expect("Passed on"), None);
+
+        let rejected = service.oneshot(request("google.com")).await;
+        match sink {
+            Some(sink) => assert_eq!(
+                rejected.expect("Resolved to sink"),
+                Some(Resolved::new("filter", sink))
+            ),
+            // Denied again however many times it is retried
+            None => assert!(!is_retryable(rejected.expect_err("Dropped").as_ref())),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_pattern() {
+        let yaml = "patterns: ['(unclosed']";
+        assert!(serde_yaml::from_str::<Config>(yaml).is_err());
     }
 }
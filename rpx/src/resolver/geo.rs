@@ -0,0 +1,257 @@
+//! Routes by client address: clients from configured subnets are sent to preferred addresses.
+//!
+//! When subnets overlap, the most specific one (longest prefix) wins.
+use super::{select::Selector, ResolveRequest, Resolved};
+use futures::future::Either;
+use serde::Deserialize;
+use std::{
+    cmp::Reverse,
+    fmt,
+    future::{ready, Ready},
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tracing::{debug, instrument, trace};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("Expected `address/prefix`, got `{0}`")]
+    Malformed(String),
+    #[error("Prefix length {prefix} is too long for {address}")]
+    PrefixTooLong { address: IpAddr, prefix: u8 },
+}
+
+/// Subnet in `address/prefix` notation, e.g. `10.1.0.0/16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                masked(u32::from(network).into(), self.prefix, 32)
+                    == masked(u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                masked(network.into(), self.prefix, 128) == masked(ip.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Keeps `prefix` most significant bits of `bits` wide address.
+fn masked(address: u128, prefix: u8, bits: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => address >> (bits - prefix),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || Error::Malformed(s.to_owned());
+        let (address, prefix) = s.split_once('/').ok_or_else(malformed)?;
+        let address: IpAddr = address.parse().map_err(|_| malformed())?;
+        let prefix: u8 = prefix.parse().map_err(|_| malformed())?;
+
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(Error::PrefixTooLong { address, prefix });
+        }
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Config {
+    /// Clients this rule applies to
+    cidr: Cidr,
+    /// Addresses to send matching clients to
+    ips: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    /// Most specific subnet first
+    rules: Arc<Vec<Config>>,
+    selector: Selector,
+}
+
+impl Layer {
+    pub fn new<'a, I>(configs: I) -> Self
+    where
+        I: Iterator<Item = &'a Config>,
+    {
+        let mut rules: Vec<Config> = configs.cloned().collect();
+        rules.sort_by_key(|rule| Reverse(rule.cidr.prefix));
+
+        Self {
+            rules: Arc::new(rules),
+            selector: Selector::default(),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service::new(inner, self.rules.clone(), self.selector.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    rules: Arc<Vec<Config>>,
+    selector: Selector,
+}
+
+impl<S> Service<S> {
+    pub fn new(inner: S, rules: Arc<Vec<Config>>, selector: Selector) -> Self {
+        Self {
+            inner,
+            rules,
+            selector,
+        }
+    }
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>>,
+{
+    type Response = Option<Resolved>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Option<Resolved>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    #[instrument(skip(self), fields(resolver = "geo"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        debug!("enter");
+        let client = request.client.ip();
+        let address: Option<Resolved> = self
+            .rules
+            .iter()
+            .find(|rule| rule.cidr.contains(client))
+            .and_then(|rule| {
+                trace!(cidr = %rule.cidr, "matched");
+                self.selector.pick(&rule.ips).copied()
+            })
+            .map(|ip_addr| Resolved::new("geo", (ip_addr, request.port).into()));
+
+        trace!(address = ?address);
+
+        if address.is_some() {
+            Either::Left(ready(Ok(address)))
+        } else {
+            Either::Right(self.inner.call(request))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cidr, Config, Layer};
+    use crate::resolver::{void, ResolveRequest, Resolved};
+    use indoc::indoc;
+    use std::net::IpAddr;
+    use test_case::test_case;
+    use tower::{Layer as _, Service as _};
+
+    #[test_case("10.1.0.0/16", "10.1.200.3", true; "inside v4")]
+    #[test_case("10.1.0.0/16", "10.2.0.1", false; "outside v4")]
+    #[test_case("10.1.2.3/32", "10.1.2.3", true; "host route")]
+    #[test_case("0.0.0.0/0", "192.168.1.1", true; "default route")]
+    #[test_case("2001:db8::/32", "2001:db8:1::1", true; "inside v6")]
+    #[test_case("2001:db8::/32", "2001:db9::1", false; "outside v6")]
+    #[test_case("0.0.0.0/0", "::1", false; "family mismatch")]
+    fn matches_subnet(cidr: &str, ip: &str, expected: bool) {
+        let cidr: Cidr = cidr.parse().expect("Valid cidr");
+        let ip: IpAddr = ip.parse().expect("Valid ip");
+        assert_eq!(cidr.contains(ip), expected);
+    }
+
+    #[test_case("10.1.0.0"; "missing prefix")]
+    #[test_case("10.1.0.0/33"; "prefix too long")]
+    #[test_case("example.com/8"; "not an address")]
+    fn rejects_cidr(cidr: &str) {
+        assert!(cidr.parse::<Cidr>().is_err());
+    }
+
+    async fn resolve(configs: &[Config], client: [u8; 4]) -> Option<Resolved> {
+        Layer::new(configs.iter())
+            .layer(void::Service)
+            .call(ResolveRequest::new(
+                "example.com",
+                443,
+                (client, 50000).into(),
+            ))
+            .await
+            .expect("Infallible")
+    }
+
+    #[tokio::test]
+    async fn prefers_longest_prefix() {
+        let configs: Vec<Config> = serde_yaml::from_str(indoc! {"
+        ---
+        - cidr: 0.0.0.0/0
+          ips: [192.168.0.3]
+        - cidr: 10.1.2.0/24
+          ips: [192.168.0.2]
+        - cidr: 10.1.0.0/16
+          ips: [192.168.0.1]
+        "})
+        .expect("Valid config");
+
+        let expected = |ip: [u8; 4]| Some(Resolved::new("geo", (ip, 443).into()));
+        assert_eq!(
+            resolve(&configs, [10, 1, 2, 3]).await,
+            expected([192, 168, 0, 2])
+        );
+        assert_eq!(
+            resolve(&configs, [10, 1, 3, 3]).await,
+            expected([192, 168, 0, 1])
+        );
+        assert_eq!(
+            resolve(&configs, [172, 16, 0, 1]).await,
+            expected([192, 168, 0, 3])
+        );
+    }
+
+    #[tokio::test]
+    async fn given_no_match_falls_through() {
+        let configs: Vec<Config> =
+            serde_yaml::from_str("[{cidr: 10.1.0.0/16, ips: [192.168.0.1]}]")
+                .expect("Valid config");
+
+        assert_eq!(resolve(&configs, [172, 16, 0, 1]).await, None);
+    }
+}
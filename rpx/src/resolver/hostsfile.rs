@@ -0,0 +1,261 @@
+//! Resolves names from files in `/etc/hosts` format.
+//!
+//! Every non-empty line holds an ip address followed by hostname and optional aliases,
+//! anything after `#` is a comment:
+//! ```text
+//! # ip         hostname            aliases
+//! 10.0.0.5     api.example.com     api
+//! ```
+use super::{select::Selector, ResolveRequest, Resolved};
+use crate::parser::normalize;
+use futures::future::Either;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    net::IpAddr,
+    num::NonZeroU64,
+    path::PathBuf,
+    sync::{Arc, RwLock, Weak},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, info, instrument, trace, warn};
+
+type Hosts = HashMap<String, Vec<IpAddr>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to read hosts file `{path}`: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    /// Path to hosts file
+    path: PathBuf,
+    /// Re-read file when it changes, checking modification time every so many seconds, at
+    /// least one
+    #[serde(default)]
+    watch_interval_secs: Option<NonZeroU64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    hosts: Arc<RwLock<Hosts>>,
+    selector: Selector,
+}
+
+impl Layer {
+    /// Reads all configured files.
+    ///
+    /// If any of the configs asks for it, spawns a task watching files for changes,
+    /// which requires running inside tokio runtime.
+    pub fn new<'a, I>(configs: I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a Config>,
+    {
+        let configs: Vec<&Config> = configs.collect();
+        let paths: Vec<PathBuf> = configs.iter().map(|config| config.path.clone()).collect();
+        let hosts = Arc::new(RwLock::new(load(&paths)?));
+
+        if let Some(interval) = configs
+            .iter()
+            .filter_map(|config| config.watch_interval_secs)
+            .min()
+        {
+            tokio::spawn(watch(
+                paths,
+                Arc::downgrade(&hosts),
+                Duration::from_secs(interval.get()),
+            ));
+        }
+
+        Ok(Self {
+            hosts,
+            selector: Selector::default(),
+        })
+    }
+}
+
+impl<S> tower::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service::new(inner, self.hosts.clone(), self.selector.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    hosts: Arc<RwLock<Hosts>>,
+    selector: Selector,
+}
+
+impl<S> Service<S> {
+    pub fn new(inner: S, hosts: Arc<RwLock<Hosts>>, selector: Selector) -> Self {
+        Self {
+            inner,
+            hosts,
+            selector,
+        }
+    }
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>>,
+{
+    type Response = Option<Resolved>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Option<Resolved>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    #[instrument(skip(self), fields(resolver = "hostsfile"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        debug!("enter");
+        let address: Option<Resolved> = self
+            .hosts
+            .read()
+            .expect("Hosts lock poisoned")
+            .get(&request.name)
+            .and_then(|existing| self.selector.pick(existing).copied())
+            .map(|ip_addr| Resolved::new("hostsfile", (ip_addr, request.port).into()));
+
+        trace!(address = ?address);
+
+        if address.is_some() {
+            Either::Left(ready(Ok(address)))
+        } else {
+            Either::Right(self.inner.call(request))
+        }
+    }
+}
+
+fn load(paths: &[PathBuf]) -> Result<Hosts, Error> {
+    let mut hosts = Hosts::new();
+    for path in paths {
+        let contents = std::fs::read_to_string(path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+
+        for (name, ips) in parse(&contents) {
+            hosts.entry(name).or_default().extend(ips);
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Parses hosts file contents, lines which fail to parse are skipped.
+fn parse(contents: &str) -> Hosts {
+    let mut hosts = Hosts::new();
+
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let ip = match fields.next() {
+            None => continue,
+            Some(ip) => ip,
+        };
+
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => fields.for_each(|name| {
+                // Matched against requested names, which come normalized
+                hosts
+                    .entry(normalize(name.to_owned()))
+                    .or_default()
+                    .push(ip)
+            }),
+            Err(err) => warn!("Skipping hosts file line `{line}`: {err}"),
+        }
+    }
+
+    hosts
+}
+
+async fn watch(paths: Vec<PathBuf>, hosts: Weak<RwLock<Hosts>>, interval: Duration) {
+    let modified = |paths: &[PathBuf]| -> Vec<Option<SystemTime>> {
+        paths
+            .iter()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    };
+
+    let mut last_modified = modified(&paths);
+    loop {
+        if hosts.strong_count() == 0 {
+            return;
+        }
+        tokio::time::sleep(interval).await;
+
+        let current = modified(&paths);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        let hosts = match hosts.upgrade() {
+            Some(hosts) => hosts,
+            // Layer is gone, nothing to update
+            None => return,
+        };
+
+        match load(&paths) {
+            Ok(reloaded) => {
+                info!("Reloaded hosts files");
+                *hosts.write().expect("Hosts lock poisoned") = reloaded;
+            }
+            Err(err) => warn!("Keeping previous hosts: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Config};
+    use indoc::indoc;
+    use std::net::IpAddr;
+
+    #[test]
+    fn parses_comments_and_blank_lines() {
+        let contents = indoc! {"
+        # Comment on its own line
+        127.0.0.1   localhost
+
+        10.0.0.5    api.example.com api   # trailing comment
+
+        2001:db8::1 API.Example.com.
+        10.0.0.6    Admin.Example.com
+        not-an-ip   broken.example.com
+        "};
+
+        let hosts = parse(contents);
+        let v4: IpAddr = [10, 0, 0, 5].into();
+        let v6: IpAddr = "2001:db8::1".parse().expect("valid ip");
+
+        assert_eq!(hosts.len(), 4);
+        assert_eq!(hosts["localhost"], vec![IpAddr::from([127, 0, 0, 1])]);
+        assert_eq!(hosts["api"], vec![v4]);
+        assert_eq!(hosts["api.example.com"], vec![v4, v6]);
+        assert_eq!(
+            hosts["admin.example.com"],
+            vec![IpAddr::from([10, 0, 0, 6])]
+        );
+        assert!(!hosts.contains_key("broken.example.com"));
+    }
+
+    #[test]
+    fn rejects_zero_watch_interval() {
+        let yaml = "{path: /etc/hosts, watch_interval_secs: 0}";
+        assert!(serde_yaml::from_str::<Config>(yaml).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Resolves names by asking an HTTP service registry, e.g. `GET /resolve?name=api&port=443`
+//! answered with `{"address": "10.0.0.5:443"}`.
+//!
+//! Names registry doesn't know (`404`) are cached as such for the same TTL as found ones, in a
+//! smaller cache of their own, so floods of bogus names can't evict found ones. Any other
+//! failure, including responses that could not be understood, is not cached. Either way
+//! request falls through to inner resolver.
+use super::{
+    cache::{Cache, MAX_CACHED, MAX_CACHED_MISSING},
+    encode, ResolveRequest, Resolved,
+};
+use serde::Deserialize;
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tracing::{debug, instrument, warn};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error("Url `{0}` has no `{{name}}` placeholder")]
+    NoNamePlaceholder(String),
+
+    #[error("Response has no address at `{pointer}`: {response}")]
+    Malformed {
+        pointer: String,
+        response: serde_json::Value,
+    },
+
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync + 'static>),
+}
+
+impl super::Retry for Error {
+    /// Registry answering with something else than an address would answer the same again.
+    fn is_retryable(&self) -> bool {
+        match self {
+            Error::Http(err) => super::is_http_retryable(err),
+            Error::NoNamePlaceholder(_) | Error::Malformed { .. } => false,
+            Error::Other(err) => super::is_retryable(err.as_ref()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    /// Url with `{name}` and optionally `{port}` placeholders, e.g.
+    /// `http://127.0.0.1:8080/resolve?name={name}&port={port}`
+    url: String,
+    /// [JSON pointer][pointer] to address in response, either `ip:port` or `ip` alone,
+    /// in which case requested port is kept
+    ///
+    /// [pointer]: https://www.rfc-editor.org/rfc/rfc6901
+    #[serde(default = "default_address_pointer")]
+    address_pointer: String,
+    /// For how long answers are cached
+    #[serde(default = "default_cache_ttl_secs")]
+    cache_ttl_secs: u64,
+}
+
+fn default_address_pointer() -> String {
+    "/address".to_owned()
+}
+
+const fn default_cache_ttl_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    clients: Arc<Vec<Client>>,
+}
+
+impl Layer {
+    /// Registries are asked in order, until one of them knows the name.
+    pub fn new<'a, I>(configs: I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a Config>,
+    {
+        let clients = configs
+            .map(Client::new)
+            .collect::<Result<Vec<Client>, _>>()
+            .map(Arc::new)?;
+
+        Ok(Self { clients })
+    }
+}
+
+impl<S> tower::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service::new(inner, self.clients.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    clients: Arc<Vec<Client>>,
+}
+
+impl<S> Service<S> {
+    pub fn new(inner: S, clients: Arc<Vec<Client>>) -> Self {
+        Self { inner, clients }
+    }
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>> + Send + Clone + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    S::Future: Send + 'static,
+{
+    type Response = Option<Resolved>;
+    type Error = Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Option<Resolved>, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[instrument(skip(self), fields(resolver = "http_discovery"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        debug!("enter");
+        let mut this = self.clone();
+
+        Box::pin(async move {
+            for client in this.clients.iter() {
+                match client.resolve(&request.name, request.port).await {
+                    Ok(Some(address)) => return Ok(Some(Resolved::new("http_discovery", address))),
+                    Ok(None) => debug!("Registry doesn't know the name"),
+                    Err(err) => warn!("Failed to query registry: {err}"),
+                }
+            }
+
+            this.inner
+                .call(request)
+                .await
+                .map_err(Into::into)
+                .map_err(Error::Other)
+        })
+    }
+}
+
+/// Queries single registry, caching answers per name and port.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    config: Config,
+    found: Cache<(String, u16), SocketAddr>,
+    /// Names registry doesn't know
+    missing: Cache<(String, u16), ()>,
+}
+
+impl Client {
+    fn new(config: &Config) -> Result<Self, Error> {
+        if !config.url.contains("{name}") {
+            return Err(Error::NoNamePlaceholder(config.url.clone()));
+        }
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+
+        let ttl = Duration::from_secs(config.cache_ttl_secs);
+        Ok(Self {
+            http,
+            config: config.clone(),
+            found: Cache::new(ttl, MAX_CACHED),
+            missing: Cache::new(ttl, MAX_CACHED_MISSING),
+        })
+    }
+
+    async fn resolve(&self, name: &str, port: u16) -> Result<Option<SocketAddr>, Error> {
+        let key = (name.to_owned(), port);
+        if let Some(address) = self.found.get(&key) {
+            return Ok(Some(address));
+        }
+        if self.missing.get(&key).is_some() {
+            return Ok(None);
+        }
+
+        let address = self.fetch(name, port).await?;
+        match address {
+            Some(address) => self.found.insert(key, address),
+            None => self.missing.insert(key, ()),
+        }
+        Ok(address)
+    }
+
+    #[instrument(skip(self))]
+    async fn fetch(&self, name: &str, port: u16) -> Result<Option<SocketAddr>, Error> {
+        let url = self
+            .config
+            .url
+            .replace("{name}", &encode(name))
+            .replace("{port}", &port.to_string());
+        let response = self.http.get(url).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response: serde_json::Value = response.error_for_status()?.json().await?;
+        let pointer = self.config.address_pointer.as_str();
+        let address = response
+            .pointer(pointer)
+            .and_then(serde_json::Value::as_str)
+            .and_then(|address| {
+                address.parse::<SocketAddr>().ok().or_else(|| {
+                    let ip: IpAddr = address.parse().ok()?;
+                    Some(SocketAddr::new(ip, port))
+                })
+            });
+        match address {
+            Some(address) => Ok(Some(address)),
+            None => Err(Error::Malformed {
+                pointer: pointer.to_owned(),
+                response,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Layer};
+    use crate::resolver::{void, ResolveRequest, Resolved};
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tower::{Layer as _, ServiceExt};
+
+    /// Serves canned responses by name in query, recording request targets it got.
+    async fn registry() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let address = listener.local_addr().expect("address");
+        let requested = Arc::new(Mutex::new(Vec::new()));
+
+        let log = requested.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    if stream.read(&mut byte).await.expect("read") == 0 {
+                        break;
+                    }
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8(head).expect("utf8");
+                let target = head.split(' ').nth(1).expect("target").to_owned();
+                log.lock().unwrap().push(target.clone());
+
+                let (status, body) = match target.split("name=").nth(1) {
+                    Some(query) if query.starts_with("api.example.com&") => {
+                        ("200 OK", r#"{"address": "10.0.0.5:8443"}"#)
+                    }
+                    Some(query) if query.starts_with("bare.example.com&") => {
+                        ("200 OK", r#"{"address": "10.0.0.6"}"#)
+                    }
+                    Some(query) if query.starts_with("broken.example.com&") => {
+                        ("200 OK", r#"{"address": 42"#)
+                    }
+                    _ => ("404 Not Found", "{}"),
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.expect("write");
+            }
+        });
+
+        (address, requested)
+    }
+
+    #[tokio::test]
+    async fn resolves_known_names_and_falls_through_otherwise() {
+        let (address, requested) = registry().await;
+        let config: Config = serde_yaml::from_str(&format!(
+            "url: 'http://{address}/resolve?name={{name}}&port={{port}}'"
+        ))
+        .expect("Valid config");
+        let service = Layer::new(std::iter::once(&config))
+            .expect("Valid layer")
+            .layer(void::Service);
+        let resolve = |name: &str| {
+            service.clone().oneshot(ResolveRequest::new(
+                name,
+                443,
+                ([10, 0, 0, 1], 50000).into(),
+            ))
+        };
+
+        let found = Some(Resolved::new(
+            "http_discovery",
+            ([10, 0, 0, 5], 8443).into(),
+        ));
+        assert_eq!(resolve("api.example.com").await.expect("Resolves"), found);
+        assert_eq!(resolve("api.example.com").await.expect("Resolves"), found);
+        assert_eq!(
+            resolve("bare.example.com").await.expect("Resolves"),
+            Some(Resolved::new("http_discovery", ([10, 0, 0, 6], 443).into()))
+        );
+        assert_eq!(
+            resolve("unknown.example.com").await.expect("Falls through"),
+            None
+        );
+        assert_eq!(
+            resolve("broken.example.com").await.expect("Falls through"),
+            None
+        );
+
+        let requested = requested.lock().unwrap().clone();
+        assert_eq!(requested[0], "/resolve?name=api.example.com&port=443");
+        // Second lookup of the same name is answered from cache
+        assert_eq!(requested.len(), 4, "{requested:?}");
+    }
+
+    #[test]
+    fn requires_name_placeholder() {
+        let config: Config =
+            serde_yaml::from_str("url: 'http://127.0.0.1/resolve'").expect("Valid config");
+
+        assert!(Layer::new(std::iter::once(&config)).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+//! Converts international names to ASCII form (A-labels) before they reach inner resolvers,
+//! so `münchen.example` matches rules written as `xn--mnchen-3ya.example` and vice versa.
+//!
+//! Names which are not valid IDNA are passed through unchanged.
+use super::{ResolveRequest, Resolved};
+use std::task::{Context, Poll};
+use tracing::{instrument, trace, warn};
+
+#[derive(Debug, Clone, Default)]
+pub struct Layer;
+
+impl<S> tower::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service::new(inner)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+}
+
+impl<S> Service<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>>,
+{
+    type Response = Option<Resolved>;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    #[instrument(skip(self))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        let name = to_ascii(request.name);
+        trace!(name);
+        self.inner.call(ResolveRequest { name, ..request })
+    }
+}
+
+fn to_ascii(record: String) -> String {
+    match idna::domain_to_ascii(&record) {
+        Ok(ascii) => ascii,
+        Err(err) => {
+            warn!(record, "Passing name through, not valid IDNA: {err:?}");
+            record
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::to_ascii;
+    use test_case::test_case;
+
+    #[test_case("münchen.example", "xn--mnchen-3ya.example"; "U-label")]
+    #[test_case("xn--mnchen-3ya.example", "xn--mnchen-3ya.example"; "A-label")]
+    #[test_case("example.com", "example.com"; "ascii")]
+    #[test_case("", ""; "empty")]
+    fn converts_to_ascii(record: &str, expected: &str) {
+        assert_eq!(to_ascii(record.to_owned()), expected);
+    }
+}
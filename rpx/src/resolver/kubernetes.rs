@@ -0,0 +1,396 @@
+//! Resolves names to ready endpoints of a Kubernetes Service, kept up to date by watching its
+//! [EndpointSlices].
+//!
+//! Each rule maps a single name to a single `namespace/service`, whose slices are listed once
+//! and then watched, so lookups never leave the process. Requested port is kept, names without
+//! ready endpoints fall through to inner resolver.
+//!
+//! [EndpointSlices]: https://kubernetes.io/docs/concepts/services-networking/endpoint-slices/
+use super::{select::Selector, ResolveRequest, Resolved};
+use futures::{future::Either, TryStreamExt};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::{Api, ListParams, WatchEvent};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    net::IpAddr,
+    sync::{Arc, RwLock, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tracing::{debug, info, instrument, trace, warn};
+
+/// Ready addresses by name.
+type Endpoints = HashMap<String, Vec<IpAddr>>;
+
+/// Pause before listing slices again once watch fails.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Service `{0}` is not in `namespace/service` form")]
+    MalformedService(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    /// Name resolved to endpoints of `service`
+    name: String,
+    /// Watched Service, as `namespace/service`
+    service: String,
+}
+
+impl Config {
+    /// Name resolved to endpoints of the Service.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn namespace_and_service(&self) -> Result<(&str, &str), Error> {
+        match self.service.split_once('/') {
+            Some((namespace, service))
+                if !namespace.is_empty() && !service.is_empty() && !service.contains('/') =>
+            {
+                Ok((namespace, service))
+            }
+            _ => Err(Error::MalformedService(self.service.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    endpoints: Arc<RwLock<Endpoints>>,
+    selector: Selector,
+}
+
+impl Layer {
+    /// Spawns a task watching each configured Service, which requires running inside tokio
+    /// runtime. Tasks stop once the layer and services built from it are dropped.
+    ///
+    /// Cluster access is inferred from environment, i.e. service account of the pod ormos runs
+    /// in, or local kubeconfig.
+    pub fn new<'a, I>(configs: I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'a Config>,
+    {
+        let endpoints = Arc::new(RwLock::new(Endpoints::new()));
+        for config in configs {
+            let (namespace, service) = config.namespace_and_service()?;
+            tokio::spawn(watch(
+                config.name.clone(),
+                namespace.to_owned(),
+                service.to_owned(),
+                Arc::downgrade(&endpoints),
+            ));
+        }
+
+        Ok(Self {
+            endpoints,
+            selector: Selector::default(),
+        })
+    }
+}
+
+impl<S> tower::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service::new(inner, self.endpoints.clone(), self.selector.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    endpoints: Arc<RwLock<Endpoints>>,
+    selector: Selector,
+}
+
+impl<S> Service<S> {
+    pub fn new(inner: S, endpoints: Arc<RwLock<Endpoints>>, selector: Selector) -> Self {
+        Self {
+            inner,
+            endpoints,
+            selector,
+        }
+    }
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>>,
+{
+    type Response = Option<Resolved>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Option<Resolved>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    #[instrument(skip(self), fields(resolver = "kubernetes"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        debug!("enter");
+        let address: Option<Resolved> = self
+            .endpoints
+            .read()
+            .expect("Endpoints lock poisoned")
+            .get(&request.name)
+            .and_then(|ready| self.selector.pick(ready).copied())
+            .map(|ip_addr| Resolved::new("kubernetes", (ip_addr, request.port).into()));
+
+        trace!(address = ?address);
+
+        if address.is_some() {
+            Either::Left(ready(Ok(address)))
+        } else {
+            Either::Right(self.inner.call(request))
+        }
+    }
+}
+
+/// Subset of `discovery.k8s.io/v1` EndpointSlice, `k8s-openapi` compatible with `kube` only
+/// knows `v1beta1`, which is gone since Kubernetes 1.25.
+#[derive(Debug, Clone, Deserialize)]
+struct EndpointSlice {
+    metadata: ObjectMeta,
+    /// Sent as `null` when there are none
+    endpoints: Option<Vec<Endpoint>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Endpoint {
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: Conditions,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Conditions {
+    ready: Option<bool>,
+}
+
+impl k8s_openapi::Resource for EndpointSlice {
+    const API_VERSION: &'static str = "discovery.k8s.io/v1";
+    const GROUP: &'static str = "discovery.k8s.io";
+    const KIND: &'static str = "EndpointSlice";
+    const VERSION: &'static str = "v1";
+}
+
+impl k8s_openapi::Metadata for EndpointSlice {
+    type Ty = ObjectMeta;
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut ObjectMeta {
+        &mut self.metadata
+    }
+}
+
+impl EndpointSlice {
+    fn key(&self) -> String {
+        self.metadata.name.clone().unwrap_or_default()
+    }
+
+    /// Addresses of ready endpoints, unknown readiness counts as ready as API asks clients to.
+    /// FQDN addresses are skipped.
+    fn ready(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.endpoints
+            .iter()
+            .flatten()
+            .filter(|endpoint| endpoint.conditions.ready != Some(false))
+            .flat_map(|endpoint| endpoint.addresses.iter())
+            .filter_map(|address| address.parse().ok())
+    }
+}
+
+/// Ready addresses across all slices of a Service, in stable order with duplicates removed.
+fn ready_addresses(slices: &HashMap<String, EndpointSlice>) -> Vec<IpAddr> {
+    let mut ready: Vec<IpAddr> = slices.values().flat_map(EndpointSlice::ready).collect();
+    ready.sort_unstable();
+    ready.dedup();
+    ready
+}
+
+/// Lists slices of the Service, then follows changes from the version listed, starting over
+/// whenever watch fails.
+async fn watch(
+    name: String,
+    namespace: String,
+    service: String,
+    endpoints: Weak<RwLock<Endpoints>>,
+) {
+    let params = ListParams::default().labels(&format!("kubernetes.io/service-name={service}"));
+    let publish = |slices: &HashMap<String, EndpointSlice>| -> bool {
+        let endpoints = match endpoints.upgrade() {
+            Some(endpoints) => endpoints,
+            // Layer is gone, nothing to update
+            None => return false,
+        };
+        let ready = ready_addresses(slices);
+        debug!("`{name}` has {} ready endpoints", ready.len());
+        endpoints
+            .write()
+            .expect("Endpoints lock poisoned")
+            .insert(name.clone(), ready);
+        true
+    };
+
+    let mut api = None;
+    loop {
+        if endpoints.strong_count() == 0 {
+            return;
+        }
+        let slices: &Api<EndpointSlice> = match api.as_ref() {
+            Some(api) => api,
+            None => match kube::Client::try_default().await {
+                Ok(client) => api.insert(Api::namespaced(client, &namespace)),
+                Err(err) => {
+                    warn!("Failed to connect to Kubernetes API: {err}");
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            },
+        };
+
+        let listed = match slices.list(&params).await {
+            Ok(listed) => listed,
+            Err(err) => {
+                warn!("Failed to list endpoints of `{namespace}/{service}`: {err}");
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        let mut version = listed.metadata.resource_version.unwrap_or_default();
+        let mut current: HashMap<String, EndpointSlice> = listed
+            .items
+            .into_iter()
+            .map(|slice| (slice.key(), slice))
+            .collect();
+        if !publish(&current) {
+            return;
+        }
+        info!("Watching endpoints of `{namespace}/{service}` for `{name}`");
+
+        // Server closes watches every few minutes, those resume from the last version seen
+        'watch: loop {
+            let events = match slices.watch(&params, &version).await {
+                Ok(events) => events,
+                Err(err) => {
+                    warn!("Failed to watch endpoints of `{namespace}/{service}`: {err}");
+                    break 'watch;
+                }
+            };
+            futures::pin_mut!(events);
+            loop {
+                let event = match events.try_next().await {
+                    Ok(Some(event)) => event,
+                    Ok(None) => continue 'watch,
+                    Err(err) => {
+                        warn!("Watch of `{namespace}/{service}` failed: {err}");
+                        break 'watch;
+                    }
+                };
+                match event {
+                    WatchEvent::Added(slice) | WatchEvent::Modified(slice) => {
+                        version = slice.metadata.resource_version.clone().unwrap_or(version);
+                        current.insert(slice.key(), slice);
+                    }
+                    WatchEvent::Deleted(slice) => {
+                        version = slice.metadata.resource_version.clone().unwrap_or(version);
+                        current.remove(&slice.key());
+                    }
+                    WatchEvent::Bookmark(bookmark) => {
+                        version = bookmark.metadata.resource_version;
+                        continue;
+                    }
+                    // Usually version is too old to resume from
+                    WatchEvent::Error(err) => {
+                        debug!("Watch of `{namespace}/{service}` ended: {err:?}");
+                        break 'watch;
+                    }
+                }
+                if !publish(&current) {
+                    return;
+                }
+            }
+        }
+
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ready_addresses, Config, EndpointSlice, Error};
+    use indoc::indoc;
+    use std::{collections::HashMap, net::IpAddr};
+
+    #[test]
+    fn takes_ready_addresses_only() {
+        let slice: EndpointSlice = serde_yaml::from_str(indoc! {"
+        metadata:
+          name: api-abcde
+        addressType: IPv4
+        endpoints:
+          - addresses: ['10.0.0.5']
+            conditions:
+              ready: true
+          - addresses: ['10.0.0.6']
+            conditions:
+              ready: false
+          - addresses: ['10.0.0.7']
+          - addresses: ['pod.example.com']
+        "})
+        .expect("Valid slice");
+        let other: EndpointSlice = serde_yaml::from_str(indoc! {"
+        metadata:
+          name: api-fghij
+        addressType: IPv4
+        endpoints:
+          - addresses: ['10.0.0.7', '10.0.0.1']
+        "})
+        .expect("Valid slice");
+        let empty: EndpointSlice = serde_yaml::from_str(indoc! {"
+        metadata:
+          name: api-klmno
+        addressType: IPv4
+        endpoints: null
+        "})
+        .expect("Valid slice");
+
+        let slices: HashMap<String, EndpointSlice> = [slice, other, empty]
+            .into_iter()
+            .map(|slice| (slice.key(), slice))
+            .collect();
+        let expected: Vec<IpAddr> = vec![
+            [10, 0, 0, 1].into(),
+            [10, 0, 0, 5].into(),
+            [10, 0, 0, 7].into(),
+        ];
+        assert_eq!(ready_addresses(&slices), expected);
+    }
+
+    #[test]
+    fn requires_namespaced_service() {
+        let config = |service: &str| Config {
+            name: "api.example.com".to_owned(),
+            service: service.to_owned(),
+        };
+
+        assert_eq!(
+            config("default/api").namespace_and_service().ok(),
+            Some(("default", "api"))
+        );
+        for service in ["api", "/api", "default/", "a/b/c"] {
+            assert!(matches!(
+                config(service).namespace_and_service(),
+                Err(Error::MalformedService(_))
+            ));
+        }
+    }
+}
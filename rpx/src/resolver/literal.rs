@@ -0,0 +1,101 @@
+//! Resolves names which are IP addresses already, e.g. `Host: 10.0.0.1` or `[2001:db8::1]`,
+//! to that address on the requested port, without asking layers below.
+use super::{ResolveRequest, Resolved};
+use futures::future::Either;
+use serde::Deserialize;
+use std::{
+    future::{ready, Ready},
+    net::{IpAddr, SocketAddr},
+    task::{Context, Poll},
+};
+use tracing::{debug, instrument};
+
+/// Enables [`Layer`], no options so far.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Config {}
+
+#[derive(Debug, Clone, Default)]
+pub struct Layer;
+
+impl Layer {
+    pub fn new(_: &Config) -> Self {
+        Self
+    }
+}
+
+impl<S> tower::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service::new(inner)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+}
+
+impl<S> Service<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S> tower::Service<ResolveRequest> for Service<S>
+where
+    S: tower::Service<ResolveRequest, Response = Option<Resolved>>,
+{
+    type Response = Option<Resolved>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Option<Resolved>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    #[instrument(skip(self), fields(resolver = "literal"))]
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        debug!("enter");
+        match request.name.parse::<IpAddr>() {
+            Ok(ip) => {
+                let address = SocketAddr::new(ip, request.port);
+                Either::Left(ready(Ok(Some(Resolved::new("literal", address)))))
+            }
+            Err(_) => Either::Right(self.inner.call(request)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Layer};
+    use crate::resolver::{void, ResolveRequest, Resolved};
+    use std::net::SocketAddr;
+    use test_case::test_case;
+    use tower::{Layer as _, ServiceExt};
+
+    #[test_case("10.0.0.1", Some("10.0.0.1:8443"); "ipv4")]
+    #[test_case("2001:db8::1", Some("[2001:db8::1]:8443"); "ipv6")]
+    #[test_case("example.com", None; "name")]
+    #[test_case("10.0.0.1.example.com", None; "name starting with address")]
+    #[tokio::test]
+    async fn resolves_address_literals(name: &str, expected: Option<&str>) {
+        let resolver = Layer::new(&Config::default()).layer(void::Service);
+
+        let resolved = resolver
+            .oneshot(ResolveRequest::new(
+                name,
+                8443,
+                ([127, 0, 0, 1], 50000).into(),
+            ))
+            .await
+            .expect("Infallible");
+
+        let expected = expected.map(|address| {
+            let address: SocketAddr = address.parse().expect("Valid address");
+            Resolved::new("literal", address)
+        });
+        assert_eq!(resolved, expected);
+    }
+}
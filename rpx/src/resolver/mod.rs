@@ -1,7 +1,297 @@
+//! Resolvers are [services][tower::Service] which accept [`ResolveRequest`] and respond with
+//! optional [destination][Resolved].
+use crate::ForwardPolicy;
+use bytes::Bytes;
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+#[cfg(any(feature = "consul", feature = "http-discovery"))]
+mod cache;
 pub mod constant;
+#[cfg(feature = "consul")]
+pub mod consul;
 pub mod dns;
+pub mod env;
 pub mod fallback;
 #[cfg(feature = "filter")]
 pub mod filter;
+pub mod geo;
+pub mod hostsfile;
+#[cfg(feature = "http-discovery")]
+pub mod http_discovery;
+#[cfg(feature = "idna")]
+pub mod idna;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+pub mod literal;
 pub mod rewrite;
+pub mod select;
+pub mod stack;
 pub mod void;
+
+/// Service name read from incoming traffic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceName {
+    /// Name one of the parsers read, could be empty, e.g. for ClientHello without SNI
+    Parsed(String),
+    /// None of the parsers recognized the traffic
+    NotParsed,
+}
+
+impl ServiceName {
+    /// Name as resolvers see it, empty when it was not parsed.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ServiceName::Parsed(name) => name,
+            ServiceName::NotParsed => "",
+        }
+    }
+}
+
+/// What resolvers are asked for.
+///
+/// Most resolvers only look at `name` and `port` and pass the request along as is, `client` is
+/// there so resolvers such as [sticky constant overrides][constant] can route by client.
+///
+/// `name` is empty both when parser read an empty name and when none of them recognized the
+/// traffic, so rules matching everything such as [fallback] apply to either. Resolvers telling
+/// them apart look at `parsed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveRequest {
+    /// Service name read from the connection
+    pub name: String,
+    /// Whether `name` was read by a parser, see [`ServiceName`]
+    pub parsed: bool,
+    /// Port of the listener connection arrived at
+    pub port: u16,
+    /// Address of the client
+    pub client: SocketAddr,
+}
+
+impl ResolveRequest {
+    /// Request for a parsed name.
+    pub fn new(name: impl Into<String>, port: u16, client: SocketAddr) -> Self {
+        Self {
+            name: name.into(),
+            parsed: true,
+            port,
+            client,
+        }
+    }
+
+    pub fn from_service_name(name: ServiceName, port: u16, client: SocketAddr) -> Self {
+        match name {
+            ServiceName::Parsed(name) => Self::new(name, port, client),
+            ServiceName::NotParsed => Self {
+                parsed: false,
+                ..Self::new(String::new(), port, client)
+            },
+        }
+    }
+}
+
+/// Where [`forward`][crate::forward] connects to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    /// One or more candidate addresses in order of preference. When there is more than one
+    /// connection attempts to them are raced, see [Happy Eyeballs].
+    ///
+    /// [Happy Eyeballs]: https://www.rfc-editor.org/rfc/rfc8305
+    Tcp(Vec<SocketAddr>),
+    /// Unix domain socket at given path, e.g. an app sharing the host. Only stream
+    /// connections are forwarded there and upstream connections are never pooled.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Tcp(addresses) => {
+                let addresses: Vec<_> = addresses.iter().map(ToString::to_string).collect();
+                write!(f, "{}", addresses.join(", "))
+            }
+            Destination::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Destination produced by resolver, along with the name of resolver which produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    destination: Destination,
+    source: &'static str,
+    preamble: Option<Bytes>,
+    policy: Option<ForwardPolicy>,
+}
+
+impl Resolved {
+    pub fn new(source: &'static str, address: SocketAddr) -> Self {
+        Self {
+            destination: Destination::Tcp(vec![address]),
+            source,
+            preamble: None,
+            policy: None,
+        }
+    }
+
+    /// Destination with multiple candidates, `None` if there are none.
+    pub fn with_candidates(source: &'static str, addresses: Vec<SocketAddr>) -> Option<Self> {
+        if addresses.is_empty() {
+            None
+        } else {
+            Some(Self {
+                destination: Destination::Tcp(addresses),
+                source,
+                preamble: None,
+                policy: None,
+            })
+        }
+    }
+
+    /// Destination listening on unix socket at `path`.
+    pub fn unix(source: &'static str, path: impl Into<PathBuf>) -> Self {
+        Self {
+            destination: Destination::Unix(path.into()),
+            source,
+            preamble: None,
+            policy: None,
+        }
+    }
+
+    /// Sends `preamble` to upstream once connected, ahead of client traffic.
+    pub fn with_preamble(self, preamble: Bytes) -> Self {
+        Self {
+            preamble: Some(preamble),
+            ..self
+        }
+    }
+
+    /// Connects and relays traffic with `policy` instead of listener defaults.
+    pub fn with_policy(self, policy: ForwardPolicy) -> Self {
+        Self {
+            policy: Some(policy),
+            ..self
+        }
+    }
+
+    pub fn destination(&self) -> &Destination {
+        &self.destination
+    }
+
+    /// Most preferred address, `None` for unix socket.
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.addresses().first().copied()
+    }
+
+    /// All candidate addresses, most preferred first. Empty for unix socket.
+    pub fn addresses(&self) -> &[SocketAddr] {
+        match &self.destination {
+            Destination::Tcp(addresses) => addresses,
+            Destination::Unix(_) => &[],
+        }
+    }
+
+    /// Path of unix socket, if that is the destination.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.destination {
+            Destination::Tcp(_) => None,
+            Destination::Unix(path) => Some(path),
+        }
+    }
+
+    /// Resolver which produced the destination, e.g. `constant` or `dns`.
+    pub fn source(&self) -> &'static str {
+        self.source
+    }
+
+    /// Bytes to write to freshly connected upstream before any client traffic.
+    pub fn preamble(&self) -> Option<&Bytes> {
+        self.preamble.as_ref()
+    }
+
+    /// Timeouts overriding [listener ones][crate::ForwardOptions::policy], if destination has
+    /// any.
+    pub fn policy(&self) -> Option<ForwardPolicy> {
+        self.policy
+    }
+}
+
+/// Failure of a resolver, telling transient ones, e.g. DNS server timing out, which could
+/// succeed if tried again, from permanent ones, e.g. name denied by `filter`.
+pub trait Retry {
+    fn is_retryable(&self) -> bool;
+}
+
+/// Whether error returned by a resolver stack is worth retrying, asking the first error along
+/// its [sources][std::error::Error::source] which implements [`Retry`].
+///
+/// Errors of no known resolver are taken as permanent.
+pub fn is_retryable(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(retry) = as_retry(err) {
+            return retry.is_retryable();
+        }
+        current = err.source();
+    }
+    false
+}
+
+fn as_retry<'e>(err: &'e (dyn std::error::Error + 'static)) -> Option<&'e dyn Retry> {
+    if let Some(err) = err.downcast_ref::<dns::Error>() {
+        return Some(err);
+    }
+    #[cfg(feature = "filter")]
+    if let Some(err) = err.downcast_ref::<filter::Error>() {
+        return Some(err);
+    }
+    #[cfg(feature = "consul")]
+    if let Some(err) = err.downcast_ref::<consul::Error>() {
+        return Some(err);
+    }
+    #[cfg(feature = "http-discovery")]
+    if let Some(err) = err.downcast_ref::<http_discovery::Error>() {
+        return Some(err);
+    }
+    None
+}
+
+/// Registry timed out, could not be reached or failed on its side.
+#[cfg(any(feature = "consul", feature = "http-discovery"))]
+fn is_http_retryable(err: &reqwest::Error) -> bool {
+    err.is_timeout()
+        || err.is_connect()
+        || err.status().is_some_and(|status| status.is_server_error())
+}
+
+/// Percent-encodes everything but unreserved characters, names come straight from clients.
+#[cfg(any(feature = "consul", feature = "http-discovery"))]
+fn encode(value: &str) -> String {
+    use std::fmt::Write;
+
+    value.bytes().fold(String::new(), |mut encoded, byte| {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+        encoded
+    })
+}
+
+#[cfg(all(test, any(feature = "consul", feature = "http-discovery")))]
+mod test {
+    use super::encode;
+
+    #[test]
+    fn encodes_names() {
+        assert_eq!(encode("api.example.com"), "api.example.com");
+        assert_eq!(encode("a&b=c d"), "a%26b%3Dc%20d");
+    }
+}
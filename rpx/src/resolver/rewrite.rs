@@ -7,6 +7,8 @@ use std::{
 use regex::Regex;
 use serde::Deserialize;
 
+use super::ResolveRequest;
+
 #[derive(Debug, Clone)]
 pub struct Service<S> {
     rules: Arc<Vec<Config>>,
@@ -41,9 +43,11 @@ impl<S> Service<S> {
         Self { rules, inner }
     }
 
-    pub fn apply_all(&self, input: String) -> String {
+    /// Name rewritten by the first rule that changes it, among rules for `port`.
+    pub fn apply_all(&self, input: String, port: u16) -> String {
         self.rules
             .iter()
+            .filter(|rule| rule.port.is_none_or(|only| only == port))
             .find_map(|rule| match rule.apply(&input) {
                 Cow::Borrowed(_) => None,
                 Cow::Owned(applied) => Some(applied),
@@ -52,9 +56,9 @@ impl<S> Service<S> {
     }
 }
 
-impl<S> tower::Service<(String, u16)> for Service<S>
+impl<S> tower::Service<ResolveRequest> for Service<S>
 where
-    S: tower::Service<(String, u16)>,
+    S: tower::Service<ResolveRequest>,
 {
     type Response = S::Response;
     type Error = S::Error;
@@ -64,9 +68,9 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, (name, port): (String, u16)) -> Self::Future {
-        let name = self.apply_all(name);
-        self.inner.call((name, port))
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        let name = self.apply_all(request.name, request.port);
+        self.inner.call(ResolveRequest { name, ..request })
     }
 }
 
@@ -75,12 +79,49 @@ pub struct Config {
     #[serde(with = "serde_regex")]
     matcher: Regex,
     replacer: String,
+    /// Only rewrite names of connections arriving at this port, names on any port when unset
+    #[serde(default)]
+    port: Option<u16>,
 }
 
 impl Config {
-    pub fn apply<'s, 'a>(&'s self, input: &'a str) -> Cow<'a, str> {
+    /// Expression names are matched against.
+    pub fn matcher(&self) -> &Regex {
+        &self.matcher
+    }
+
+    /// What matching names are rewritten into, with `$group` references.
+    pub fn replacer(&self) -> &str {
+        &self.replacer
+    }
+
+    pub fn apply<'a>(&self, input: &'a str) -> Cow<'a, str> {
         self.matcher.replace(input, &self.replacer)
     }
+
+    /// Domain every rewritten name falls under, if replacer spells it out after the last
+    /// capture group, e.g. `internal` for `$svc.internal`.
+    pub fn output_domain(&self) -> Option<&str> {
+        let literal = match self.replacer.rfind('$') {
+            None => return Some(self.replacer.as_str()).filter(|name| !name.is_empty()),
+            Some(dollar) => {
+                let group = &self.replacer[dollar + 1..];
+                match group.strip_prefix('{') {
+                    Some(braced) => &braced[braced.find('}').map_or(braced.len(), |end| end + 1)..],
+                    None => {
+                        group.trim_start_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')
+                    }
+                }
+            }
+        };
+
+        // Text glued to the group is a part of its label, not of the domain
+        let domain = match literal.strip_prefix('.') {
+            Some(domain) => domain,
+            None => literal.split_once('.').map_or("", |(_, domain)| domain),
+        };
+        Some(domain).filter(|domain| !domain.is_empty())
+    }
 }
 
 #[cfg(test)]
@@ -99,11 +140,28 @@ mod test {
         let rule = Config {
             matcher: Regex::new(r#"^(?P<svc>[a-z]+)\.some\.domain$"#).expect("Valid regex"),
             replacer: "$svc.patched".to_owned(),
+            port: None,
         };
 
         assert_eq!(rule.apply(input), output);
     }
 
+    #[test_case("$svc.internal", Some("internal"); "After named group")]
+    #[test_case("${svc}.example.com", Some("example.com"); "After braced group")]
+    #[test_case("$1-api.example.com", Some("example.com"); "Skips partial label")]
+    #[test_case("api.example.com", Some("api.example.com"); "Without groups")]
+    #[test_case("$svc", None; "Ends with group")]
+    #[test_case("${svc}-api", None; "Ends within label")]
+    fn output_domain(replacer: &str, domain: Option<&str>) {
+        let rule = Config {
+            matcher: Regex::new(".*").expect("Valid regex"),
+            replacer: replacer.to_owned(),
+            port: None,
+        };
+
+        assert_eq!(rule.output_domain(), domain);
+    }
+
     #[test]
     fn deserializes() {
         let input = indoc! {r#"
@@ -134,6 +192,25 @@ mod test {
         let rules = rules.map(Arc::new).unwrap();
         let svc = Service::new(rules, ());
 
-        assert_eq!(&svc.apply_all(input.to_string()), output);
+        assert_eq!(&svc.apply_all(input.to_string(), 443), output);
+    }
+
+    #[test_case(443, "api.tls.internal"; "Rule for port")]
+    #[test_case(8443, "api.alt.internal"; "Rule for other port")]
+    #[test_case(8080, "api.example.com"; "No rule for port")]
+    fn matches_port(port: u16, output: &str) {
+        let config = indoc! {r#"
+        ---
+        - matcher: '^api\.example\.com$'
+          replacer: 'api.tls.internal'
+          port: 443
+        - matcher: '^api\.example\.com$'
+          replacer: 'api.alt.internal'
+          port: 8443
+        "#};
+        let rules: Vec<Config> = serde_yaml::from_str(config).expect("Valid rules");
+        let svc = Service::new(Arc::new(rules), ());
+
+        assert_eq!(&svc.apply_all("api.example.com".to_owned(), port), output);
     }
 }
@@ -0,0 +1,135 @@
+//! Picking among several candidate addresses, shared by resolvers handing out more than one.
+use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+/// How to pick among candidates.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Random candidate on every call
+    #[default]
+    Random,
+    /// Always the first candidate, in order they are listed or were looked up
+    First,
+    /// Candidates in turn, counter is shared by clones of the selector
+    RoundRobin,
+}
+
+/// Picks candidates following [`Strategy`].
+///
+/// Cursor and RNG are set up once and shared by clones, seeding an RNG costs far more
+/// than a pick does.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    strategy: Strategy,
+    next: Arc<AtomicUsize>,
+    rng: Arc<Mutex<SmallRng>>,
+}
+
+impl Default for Selector {
+    fn default() -> Self {
+        Self::new(Strategy::default())
+    }
+}
+
+impl Selector {
+    pub fn new(strategy: Strategy) -> Self {
+        Self::with(strategy, SmallRng::from_entropy())
+    }
+
+    /// Random selector drawing from RNG seeded with `seed`, so picks could be replayed,
+    /// e.g. in tests.
+    pub fn seeded(seed: u64) -> Self {
+        Self::with(Strategy::Random, SmallRng::seed_from_u64(seed))
+    }
+
+    fn with(strategy: Strategy, rng: SmallRng) -> Self {
+        Self {
+            strategy,
+            next: Arc::new(AtomicUsize::new(0)),
+            rng: Arc::new(Mutex::new(rng)),
+        }
+    }
+
+    /// Candidate to use, `None` if there are none.
+    pub fn pick<'a, T>(&self, candidates: &'a [T]) -> Option<&'a T> {
+        match self.strategy {
+            Strategy::Random => self.with_rng(|rng| candidates.choose(rng)),
+            Strategy::First => candidates.first(),
+            Strategy::RoundRobin if candidates.is_empty() => None,
+            Strategy::RoundRobin => candidates.get(self.turn() % candidates.len()),
+        }
+    }
+
+    /// Reorders `candidates` so the one [`pick`][Self::pick] would return comes first,
+    /// e.g. when all of them are handed out to be tried in turn.
+    pub fn order<T>(&self, candidates: &mut [T]) {
+        match self.strategy {
+            Strategy::Random => self.with_rng(|rng| candidates.shuffle(rng)),
+            Strategy::First => {}
+            Strategy::RoundRobin if candidates.is_empty() => {}
+            Strategy::RoundRobin => {
+                let turn = self.turn() % candidates.len();
+                candidates.rotate_left(turn);
+            }
+        }
+    }
+
+    fn turn(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn with_rng<R>(&self, f: impl FnOnce(&mut SmallRng) -> R) -> R {
+        f(&mut self.rng.lock().expect("Selector RNG lock poisoned"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Selector, Strategy};
+
+    const CANDIDATES: [u8; 3] = [1, 2, 3];
+
+    #[test]
+    fn takes_turns_across_clones() {
+        let selector = Selector::new(Strategy::RoundRobin);
+        let clone = selector.clone();
+
+        let picked: Vec<u8> = (0..4)
+            .map(|ix| {
+                *if ix % 2 == 0 { &selector } else { &clone }
+                    .pick(&CANDIDATES)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(picked, [1, 2, 3, 1]);
+
+        let mut ordered = CANDIDATES;
+        selector.order(&mut ordered);
+        assert_eq!(ordered, [2, 3, 1]);
+    }
+
+    #[test]
+    fn picks_first_or_nothing() {
+        let selector = Selector::new(Strategy::First);
+
+        assert_eq!(selector.pick(&CANDIDATES), Some(&1));
+        assert_eq!(selector.pick::<u8>(&[]), None);
+        assert_eq!(Selector::new(Strategy::RoundRobin).pick::<u8>(&[]), None);
+    }
+
+    #[test]
+    fn replays_seeded_picks() {
+        let picks = |selector: Selector| -> Vec<u8> {
+            (0..8)
+                .map(|_| *selector.pick(&CANDIDATES).unwrap())
+                .collect()
+        };
+
+        assert_eq!(picks(Selector::seeded(7)), picks(Selector::seeded(7)));
+    }
+}
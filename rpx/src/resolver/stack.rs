@@ -0,0 +1,296 @@
+//! Composes resolvers into a single service in the canonical order.
+//!
+//! Requests pass through layers outermost first:
+//! 1. `idna` converts names to ASCII, so every other layer sees the same spelling;
+//! 2. `fallback` wraps everything else, catching whatever nothing below resolved
+//!    (including names `filter` rejected, unless it sends them to its sink);
+//! 3. `filter` rejects disallowed names before any lookup, in particular before `dns` or
+//!    `consul` could leak them;
+//! 4. `literal` resolves names which are IP addresses already, as no layer below could do
+//!    better, yet they are still subject to `filter`;
+//! 5. `constant`, `geo`, `hostsfile` and `env` are local and cheap, and are meant to
+//!    override anything discovered over the network;
+//! 6. `rewrite` patches names for the network resolvers below only, local rules match names
+//!    as requested;
+//! 7. `kubernetes`, `consul`, `http_discovery` and `dns` go over the network, `kubernetes`
+//!    only in background, answering from what its watch last saw;
+//! 8. `void` resolves nothing, so unmatched names end up as `None`, optionally logging them.
+use super::{
+    constant, dns, env, fallback, geo, hostsfile, literal, rewrite, void, ResolveRequest, Resolved,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::{
+    buffer::Buffer,
+    load_shed::LoadShed,
+    util::{BoxService, Either},
+    BoxError, ServiceBuilder,
+};
+
+/// How many requests could be queued for the stack by default, shared by all connections using it.
+const BUFFER_SIZE: usize = 1024;
+
+/// Resolver stack produced by [`ResolverStackBuilder`].
+///
+/// Layers run behind a buffer, so handles are cheap to clone and `Sync`,
+/// e.g. to be swapped at runtime.
+#[derive(Clone)]
+pub struct Resolver(LoadShed<Buffer<Layers, ResolveRequest>>);
+
+/// Layers composed by the builder, owned by buffer worker.
+type Layers = BoxService<ResolveRequest, Option<Resolved>, BoxError>;
+
+impl tower::Service<ResolveRequest> for Resolver {
+    type Response = Option<Resolved>;
+    type Error = BoxError;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Option<Resolved>, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        Box::pin(self.0.call(request))
+    }
+}
+
+/// Builds [resolver stack][Resolver] out of optional layers.
+///
+/// Layers are applied in the order described in [module docs][self], regardless of the order
+/// they were supplied in.
+#[derive(Debug, Clone, Default)]
+pub struct ResolverStackBuilder {
+    #[cfg(feature = "idna")]
+    idna: Option<super::idna::Layer>,
+    fallback: Option<fallback::Layer<Resolved>>,
+    #[cfg(feature = "filter")]
+    filter: Option<super::filter::Layer>,
+    literal: Option<literal::Layer>,
+    constant: Option<constant::Layer>,
+    geo: Option<geo::Layer>,
+    hostsfile: Option<hostsfile::Layer>,
+    env: Option<env::Layer>,
+    rewrite: Option<rewrite::Layer>,
+    #[cfg(feature = "kubernetes")]
+    kubernetes: Option<super::kubernetes::Layer>,
+    #[cfg(feature = "consul")]
+    consul: Option<super::consul::Layer>,
+    #[cfg(feature = "http-discovery")]
+    http_discovery: Option<super::http_discovery::Layer>,
+    dns: Option<dns::Layer>,
+    void: Option<void::Config>,
+    buffer: Option<usize>,
+}
+
+impl ResolverStackBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(feature = "idna")]
+    pub fn idna(mut self, layer: Option<super::idna::Layer>) -> Self {
+        self.idna = layer;
+        self
+    }
+
+    pub fn fallback(mut self, layer: Option<fallback::Layer<Resolved>>) -> Self {
+        self.fallback = layer;
+        self
+    }
+
+    #[cfg(feature = "filter")]
+    pub fn filter(mut self, layer: Option<super::filter::Layer>) -> Self {
+        self.filter = layer;
+        self
+    }
+
+    pub fn literal(mut self, layer: Option<literal::Layer>) -> Self {
+        self.literal = layer;
+        self
+    }
+
+    pub fn constant(mut self, layer: Option<constant::Layer>) -> Self {
+        self.constant = layer;
+        self
+    }
+
+    pub fn geo(mut self, layer: Option<geo::Layer>) -> Self {
+        self.geo = layer;
+        self
+    }
+
+    pub fn hostsfile(mut self, layer: Option<hostsfile::Layer>) -> Self {
+        self.hostsfile = layer;
+        self
+    }
+
+    pub fn env(mut self, layer: Option<env::Layer>) -> Self {
+        self.env = layer;
+        self
+    }
+
+    pub fn rewrite(mut self, layer: Option<rewrite::Layer>) -> Self {
+        self.rewrite = layer;
+        self
+    }
+
+    #[cfg(feature = "kubernetes")]
+    pub fn kubernetes(mut self, layer: Option<super::kubernetes::Layer>) -> Self {
+        self.kubernetes = layer;
+        self
+    }
+
+    #[cfg(feature = "consul")]
+    pub fn consul(mut self, layer: Option<super::consul::Layer>) -> Self {
+        self.consul = layer;
+        self
+    }
+
+    #[cfg(feature = "http-discovery")]
+    pub fn http_discovery(mut self, layer: Option<super::http_discovery::Layer>) -> Self {
+        self.http_discovery = layer;
+        self
+    }
+
+    pub fn dns(mut self, layer: Option<dns::Layer>) -> Self {
+        self.dns = layer;
+        self
+    }
+
+    /// Log names nothing resolved, instead of silently resolving them to `None`.
+    pub fn void(mut self, config: Option<void::Config>) -> Self {
+        self.void = config;
+        self
+    }
+
+    /// Requests queued for the stack, once full new ones fail with
+    /// [`Overloaded`][tower::load_shed::error::Overloaded] instead of waiting.
+    pub fn buffer(mut self, size: usize) -> Self {
+        self.buffer = Some(size);
+        self
+    }
+
+    /// Composes the stack, requires running inside tokio runtime.
+    pub fn build(self) -> Resolver {
+        let service = ServiceBuilder::new();
+
+        #[cfg(feature = "idna")]
+        let service = service.option_layer(self.idna);
+
+        let service = service.option_layer(self.fallback);
+
+        #[cfg(feature = "filter")]
+        let service = service.option_layer(self.filter);
+
+        let service = service
+            .option_layer(self.literal)
+            .option_layer(self.constant)
+            .option_layer(self.geo)
+            .option_layer(self.hostsfile)
+            .option_layer(self.env)
+            .option_layer(self.rewrite);
+
+        #[cfg(feature = "kubernetes")]
+        let service = service.option_layer(self.kubernetes);
+
+        #[cfg(feature = "consul")]
+        let service = service.option_layer(self.consul);
+
+        #[cfg(feature = "http-discovery")]
+        let service = service.option_layer(self.http_discovery);
+
+        let leaf = match self.void {
+            Some(config) => Either::B(void::Logged::new(config)),
+            None => Either::A(void::Service),
+        };
+        let service = service.option_layer(self.dns).service(leaf);
+
+        Resolver(
+            ServiceBuilder::new()
+                .load_shed()
+                .buffer(self.buffer.unwrap_or(BUFFER_SIZE))
+                .service(BoxService::new(service)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ResolverStackBuilder;
+    use crate::resolver::{constant, ResolveRequest, Resolved};
+    use tower::{load_shed::error::Overloaded, Service, ServiceExt};
+
+    #[tokio::test]
+    async fn resolves_constant_rule() {
+        let rule: constant::Config =
+            serde_yaml::from_str("{name: example.com, ips: [10.0.0.1]}").expect("Valid config");
+        let resolver = ResolverStackBuilder::new()
+            .constant(Some(constant::Layer::new(std::iter::once(&rule))))
+            .build();
+
+        let request = |name: &str| ResolveRequest::new(name, 443, ([127, 0, 0, 1], 50000).into());
+        let resolved = resolver
+            .clone()
+            .oneshot(request("example.com"))
+            .await
+            .expect("Resolves");
+        assert_eq!(
+            resolved,
+            Some(Resolved::new("constant", ([10, 0, 0, 1], 443).into()))
+        );
+
+        let unmatched = resolver
+            .oneshot(request("other.example.com"))
+            .await
+            .expect("Resolves");
+        assert_eq!(unmatched, None);
+    }
+
+    #[cfg(feature = "filter")]
+    #[tokio::test]
+    async fn filters_address_literals() {
+        use crate::resolver::{filter, literal};
+
+        let rule: filter::Config =
+            serde_yaml::from_str("{names: [10.0.0.1]}").expect("Valid config");
+        let resolver = ResolverStackBuilder::new()
+            .filter(Some(filter::Layer::new(std::iter::once(&rule))))
+            .literal(Some(literal::Layer::new(&literal::Config::default())))
+            .build();
+
+        let request = |name: &str| ResolveRequest::new(name, 443, ([127, 0, 0, 1], 50000).into());
+        let allowed = resolver
+            .clone()
+            .oneshot(request("10.0.0.1"))
+            .await
+            .expect("Resolves");
+        assert_eq!(
+            allowed,
+            Some(Resolved::new("literal", ([10, 0, 0, 1], 443).into()))
+        );
+
+        let denied = resolver.oneshot(request("10.0.0.2")).await;
+        assert!(denied.is_err(), "{denied:?}");
+    }
+
+    #[tokio::test]
+    async fn sheds_load_once_buffer_is_full() {
+        let mut resolver = ResolverStackBuilder::new().buffer(1).build();
+        let request = || ResolveRequest::new("example.com", 443, ([127, 0, 0, 1], 50000).into());
+
+        // Worker doesn't get to run before the test yields, so this one keeps the only slot
+        let queued = resolver.ready().await.expect("Ready").call(request());
+        let error = resolver
+            .clone()
+            .oneshot(request())
+            .await
+            .expect_err("Buffer is full");
+        assert!(error.is::<Overloaded>(), "{error}");
+
+        assert_eq!(queued.await.expect("Resolves"), None);
+    }
+}
@@ -1,9 +1,12 @@
+use super::{ResolveRequest, Resolved};
+use serde::Deserialize;
 use std::{
     convert::Infallible,
     future::{ready, Ready},
-    net::SocketAddr,
+    sync::Arc,
     task::{Context, Poll},
 };
+use tracing::{debug, error, info, warn};
 
 /// Leaf resolver that doesn't resolve anything
 ///
@@ -11,8 +14,8 @@ use std::{
 #[derive(Clone, Debug)]
 pub struct Service;
 
-impl tower::Service<(String, u16)> for Service {
-    type Response = Option<SocketAddr>;
+impl tower::Service<ResolveRequest> for Service {
+    type Response = Option<Resolved>;
     type Error = Infallible;
     type Future = Ready<Result<Self::Response, Self::Error>>;
 
@@ -20,7 +23,94 @@ impl tower::Service<(String, u16)> for Service {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: (String, u16)) -> Self::Future {
+    fn call(&mut self, _: ResolveRequest) -> Self::Future {
         ready(Ok(None))
     }
 }
+
+/// Configuration of [`Logged`], reporting names which reached the end of the stack.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+pub struct Config {
+    #[serde(default)]
+    level: Level,
+    /// Why such names are dropped, e.g. policy they violate
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Level unresolved names are logged at.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Debug,
+    Info,
+    #[default]
+    Warn,
+    Error,
+}
+
+/// Leaf resolver that doesn't resolve anything, logging every name it is asked for.
+///
+/// Tells connections dropped on purpose apart from ones every other resolver failed on.
+#[derive(Clone, Debug)]
+pub struct Logged {
+    config: Arc<Config>,
+}
+
+impl Logged {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl tower::Service<ResolveRequest> for Logged {
+    type Response = Option<Resolved>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: ResolveRequest) -> Self::Future {
+        let name = request.name.as_str();
+        let reason = self
+            .config
+            .reason
+            .as_deref()
+            .unwrap_or("no resolver matched");
+        match self.config.level {
+            Level::Debug => debug!(name, reason, "Dropping unresolved connection"),
+            Level::Info => info!(name, reason, "Dropping unresolved connection"),
+            Level::Warn => warn!(name, reason, "Dropping unresolved connection"),
+            Level::Error => error!(name, reason, "Dropping unresolved connection"),
+        }
+        ready(Ok(None))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Level, Logged};
+    use crate::resolver::ResolveRequest;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn resolves_nothing() {
+        let config: Config =
+            serde_yaml::from_str("{level: info, reason: not on allowlist}").expect("Valid config");
+        assert_eq!(config.level, Level::Info);
+
+        let resolved = Logged::new(config)
+            .oneshot(ResolveRequest::new(
+                "example.com",
+                443,
+                ([127, 0, 0, 1], 50000).into(),
+            ))
+            .await
+            .expect("Infallible");
+        assert_eq!(resolved, None);
+    }
+}
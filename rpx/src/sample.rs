@@ -0,0 +1,47 @@
+//! Counter picking which connections get their summary logged, keeping logs of busy listeners
+//! readable without lowering log level.
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Picks one in every `rate` calls, clones share the count.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    rate: u64,
+    seen: Arc<AtomicU64>,
+}
+
+impl Sampler {
+    /// Sampler picking one in every `rate` calls, `0` is treated as `1`.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            seen: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Whether this call is the one in `rate` to pick, first call always is.
+    pub fn sample(&self) -> bool {
+        self.seen
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.rate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sampler;
+
+    #[test]
+    fn picks_one_in_rate_across_clones() {
+        let sampler = Sampler::new(3);
+        let clone = sampler.clone();
+
+        let picked: Vec<bool> = (0..6)
+            .map(|ix| if ix % 2 == 0 { &sampler } else { &clone }.sample())
+            .collect();
+
+        assert_eq!(picked, [true, false, false, true, false, false]);
+    }
+}
@@ -0,0 +1,214 @@
+//! Stream wrapper pacing reads with a token bucket, so traffic in that direction stays under a
+//! configured rate.
+use serde::Deserialize;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// Which traffic [rate limit][crate::ForwardOptions::rate_limit_bytes_per_sec] applies to.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Each direction is limited separately
+    #[default]
+    Each,
+    /// Both directions share the limit
+    Shared,
+    /// Only traffic from client to upstream
+    Upload,
+    /// Only traffic from upstream to client
+    Download,
+}
+
+/// Bucket which could be shared by both directions.
+pub(crate) type SharedBucket = Arc<Mutex<Bucket>>;
+
+/// Token bucket, holding up to a second worth of traffic.
+#[derive(Debug)]
+pub(crate) struct Bucket {
+    bytes_per_sec: u64,
+    /// Could go negative when shared by concurrent readers
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    /// Bucket starts empty, so transfer never goes faster than the rate.
+    pub(crate) fn new(bytes_per_sec: u64) -> SharedBucket {
+        Arc::new(Mutex::new(Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        }))
+    }
+
+    /// Pair of buckets for reads from client and upstream respectively.
+    pub(crate) fn for_direction(
+        bytes_per_sec: u64,
+        direction: Direction,
+    ) -> (Option<SharedBucket>, Option<SharedBucket>) {
+        match direction {
+            Direction::Each => (
+                Some(Self::new(bytes_per_sec)),
+                Some(Self::new(bytes_per_sec)),
+            ),
+            Direction::Shared => {
+                let bucket = Self::new(bytes_per_sec);
+                (Some(bucket.clone()), Some(bucket))
+            }
+            Direction::Upload => (Some(Self::new(bytes_per_sec)), None),
+            Direction::Download => (None, Some(Self::new(bytes_per_sec))),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        let capacity = self.bytes_per_sec as f64;
+        self.tokens = (self.tokens + elapsed * capacity).min(capacity);
+        self.refilled_at = now;
+    }
+
+    /// How many bytes could be read right away, or how long to wait before at least `wanted`
+    /// become available.
+    fn available(&mut self, wanted: usize) -> Result<usize, Duration> {
+        self.refill();
+        // Not waking up for every byte
+        let wanted = wanted.min((self.bytes_per_sec / 100).max(1) as usize) as f64;
+        if self.tokens >= wanted {
+            Ok(self.tokens as usize)
+        } else {
+            let missing = wanted - self.tokens;
+            Err(Duration::from_secs_f64(missing / self.bytes_per_sec as f64))
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+pub(crate) struct Throttled<S> {
+    inner: S,
+    bucket: Option<SharedBucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// Stream reads from which are paced by `bucket`, passthrough without one.
+    pub(crate) fn new(inner: S, bucket: Option<SharedBucket>) -> Self {
+        Self {
+            inner,
+            bucket,
+            sleep: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let bucket = match this.bucket.as_ref() {
+            Some(bucket) => bucket,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+
+        loop {
+            if let Some(sleep) = this.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.sleep = None;
+            }
+
+            let available = bucket
+                .lock()
+                .expect("Bucket lock poisoned")
+                .available(buf.remaining());
+            match available {
+                Ok(available) => {
+                    let limit = available.min(buf.remaining());
+                    let mut limited = buf.take(limit);
+                    let poll = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+                    let read = limited.filled().len();
+                    // `limited` shares memory with `buf`, which only needs to learn about it
+                    unsafe { buf.assume_init(read) };
+                    buf.advance(read);
+                    bucket.lock().expect("Bucket lock poisoned").consume(read);
+                    return poll;
+                }
+                Err(wait) => this.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bucket, Throttled};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn transfer_takes_at_least_size_over_rate() {
+        const SIZE: usize = 64 * 1024;
+        const RATE: u64 = 128 * 1024;
+
+        let (mut writer, reader) = tokio::io::duplex(SIZE);
+        writer.write_all(&[0; SIZE]).await.expect("write");
+        drop(writer);
+
+        let started = Instant::now();
+        let mut throttled = Throttled::new(reader, Some(Bucket::new(RATE)));
+        let mut received = Vec::new();
+        throttled.read_to_end(&mut received).await.expect("read");
+
+        assert_eq!(received.len(), SIZE);
+        assert!(started.elapsed() >= Duration::from_secs_f64(SIZE as f64 / RATE as f64));
+    }
+
+    #[tokio::test]
+    async fn passes_through_without_bucket() {
+        let (mut writer, reader) = tokio::io::duplex(16);
+        writer.write_all(b"hello").await.expect("write");
+        drop(writer);
+
+        let mut received = Vec::new();
+        Throttled::new(reader, None)
+            .read_to_end(&mut received)
+            .await
+            .expect("read");
+        assert_eq!(received, b"hello");
+    }
+}
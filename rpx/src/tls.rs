@@ -0,0 +1,414 @@
+//! Terminates TLS with configured certificates, so upstream receives cleartext.
+use rustls::{
+    server::{ClientHello, ResolvesServerCert, ResolvesServerCertUsingSni},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+/// Certificates to terminate TLS with.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub certs: Vec<Cert>,
+}
+
+/// Certificate presented to clients asking for `sni`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Cert {
+    /// Exact name, or wildcard such as `*.example.com` matching a single label
+    pub sni: String,
+    /// PEM file with certificate chain, leaf first
+    pub cert: PathBuf,
+    /// PEM file with private key
+    pub key: PathBuf,
+    /// Present to clients asking for unknown or no name, instead of rejecting handshake
+    #[serde(default)]
+    pub default: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to read `{0}`: {1}")]
+    Io(PathBuf, io::Error),
+
+    #[error("No private key found in `{0}`")]
+    NoKey(PathBuf),
+
+    #[error("Unsupported private key in `{0}`")]
+    UnsupportedKey(PathBuf),
+
+    #[error("Certificate for `{0}` is unusable: {1}")]
+    Rustls(String, rustls::Error),
+
+    #[error("Certificate for `{0}` is listed more than once")]
+    Duplicate(String),
+
+    #[error("Only one default certificate is allowed, found another one for `{0}`")]
+    MultipleDefaults(String),
+}
+
+/// Completes TLS handshakes using certificate picked by SNI.
+#[derive(Clone)]
+pub struct Terminator {
+    acceptor: TlsAcceptor,
+    names: Arc<Vec<String>>,
+}
+
+impl fmt::Debug for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Terminator")
+            .field("names", &self.names)
+            .finish()
+    }
+}
+
+impl Terminator {
+    /// Loads certificates, every one of them has to be valid for its SNI.
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let resolver = SniResolver::new(config)?;
+
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            names: Arc::new(config.certs.iter().map(|cert| cert.sni.clone()).collect()),
+        })
+    }
+
+    /// Completes handshake, `read` holds bytes already consumed from `stream`.
+    pub(crate) async fn accept<S>(&self, read: &[u8], stream: S) -> io::Result<TlsStream<Rewind<S>>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.acceptor
+            .accept(Rewind::new(read.to_vec(), stream))
+            .await
+    }
+}
+
+/// Picks certificate by SNI, preferring exact match over wildcard one.
+struct SniResolver {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    /// Keyed by the part after `*.`
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn new(config: &Config) -> Result<Self, Error> {
+        let mut resolver = Self {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+            default: None,
+        };
+
+        for cert in config.certs.iter() {
+            let sni = cert.sni.to_ascii_lowercase();
+            let key = Arc::new(load(cert)?);
+
+            let (names, name) = match sni.strip_prefix("*.") {
+                Some(parent) => (&mut resolver.wildcard, parent.to_owned()),
+                None => (&mut resolver.exact, sni.clone()),
+            };
+            // Wildcard is valid for any single label in its place
+            let sample = sni.replacen('*', "wildcard", 1);
+            ResolvesServerCertUsingSni::new()
+                .add(&sample, CertifiedKey::clone(&key))
+                .map_err(|err| Error::Rustls(cert.sni.clone(), err))?;
+            if names.insert(name, key.clone()).is_some() {
+                return Err(Error::Duplicate(cert.sni.clone()));
+            }
+
+            if cert.default && resolver.default.replace(key).is_some() {
+                return Err(Error::MultipleDefaults(cert.sni.clone()));
+            }
+        }
+
+        Ok(resolver)
+    }
+
+    fn pick(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let matched = sni.and_then(|sni| {
+            let sni = sni.to_ascii_lowercase();
+            self.exact.get(&sni).or_else(|| {
+                sni.split_once('.')
+                    .and_then(|(_, parent)| self.wildcard.get(parent))
+            })
+        });
+
+        matched.or(self.default.as_ref()).cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.pick(client_hello.server_name())
+    }
+}
+
+fn load(cert: &Cert) -> Result<CertifiedKey, Error> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| Error::Io(path.to_owned(), err))
+    };
+
+    let chain = rustls_pemfile::certs(&mut open(&cert.cert)?)
+        .map_err(|err| Error::Io(cert.cert.clone(), err))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let key = rustls_pemfile::read_all(&mut open(&cert.key)?)
+        .map_err(|err| Error::Io(cert.key.clone(), err))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::NoKey(cert.key.clone()))?;
+    let key =
+        sign::any_supported_type(&key).map_err(|_| Error::UnsupportedKey(cert.key.clone()))?;
+
+    Ok(CertifiedKey::new(chain, key))
+}
+
+/// Stream replaying bytes read from it earlier, before reading any further.
+pub(crate) struct Rewind<S> {
+    read: Vec<u8>,
+    replayed: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    fn new(read: Vec<u8>, inner: S) -> Self {
+        Self {
+            read,
+            replayed: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.replayed < this.read.len() {
+            let pending = &this.read[this.replayed..];
+            let len = pending.len().min(buf.remaining());
+            buf.put_slice(&pending[..len]);
+            this.replayed += len;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cert, Config, SniResolver, Terminator};
+    use crate::{
+        forward,
+        parser::tls,
+        resolver::{ResolveRequest, Resolved, ServiceName},
+        ForwardOptions,
+    };
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use std::{path::PathBuf, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsConnector;
+    use tower::BoxError;
+
+    /// Writes self-signed certificate for `sni`, returns its config and DER.
+    fn self_signed(sni: &str) -> (Cert, Vec<u8>) {
+        let generated =
+            rcgen::generate_simple_self_signed(vec![sni.to_owned()]).expect("Generates cert");
+        let dir = std::env::temp_dir().join(format!("rpx-tls-{sni}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Creates dir");
+
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        // Signing is randomized, DER has to come from the very PEM written
+        let pem = generated.serialize_pem().expect("Serializes cert");
+        std::fs::write(&cert, &pem).expect("Writes");
+        std::fs::write(&key, generated.serialize_private_key_pem()).expect("Writes");
+
+        let der = rustls_pemfile::certs(&mut pem.as_bytes()).expect("Valid PEM")[0].clone();
+        (
+            Cert {
+                sni: sni.to_owned(),
+                cert,
+                key,
+                default: false,
+            },
+            der,
+        )
+    }
+
+    fn connector(der: Vec<u8>) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(&rustls::Certificate(der)).expect("Valid root");
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[test]
+    fn rejects_missing_files() {
+        let config = Config {
+            certs: vec![Cert {
+                sni: "example.com".to_owned(),
+                cert: PathBuf::from("/nonexistent/cert.pem"),
+                key: PathBuf::from("/nonexistent/key.pem"),
+                default: false,
+            }],
+        };
+
+        assert!(Terminator::new(&config).is_err());
+    }
+
+    #[test]
+    fn picks_cert_by_sni() {
+        let (wildcard, wildcard_der) = self_signed("*.example.com");
+        let (exact, exact_der) = self_signed("api.other.com");
+        let (overlapping, overlapping_der) = self_signed("api.example.com");
+        let resolver = SniResolver::new(&Config {
+            certs: vec![wildcard, exact, overlapping],
+        })
+        .expect("Loads certs");
+        let picked = |sni| resolver.pick(sni).map(|key| key.cert[0].0.clone());
+
+        assert_eq!(picked(Some("api.other.com")), Some(exact_der));
+        assert_eq!(picked(Some("API.Other.com")), picked(Some("api.other.com")));
+        assert_eq!(picked(Some("www.example.com")), Some(wildcard_der));
+        assert_eq!(picked(Some("api.example.com")), Some(overlapping_der));
+        // Wildcard covers a single label only
+        assert_eq!(picked(Some("a.b.example.com")), None);
+        assert_eq!(picked(Some("example.com")), None);
+        assert_eq!(picked(None), None);
+    }
+
+    #[test]
+    fn falls_back_to_default_cert() {
+        let (mut fallback, fallback_der) = self_signed("default.example.org");
+        fallback.default = true;
+        let (exact, exact_der) = self_signed("api.other.org");
+        let resolver = SniResolver::new(&Config {
+            certs: vec![fallback, exact],
+        })
+        .expect("Loads certs");
+        let picked = |sni| resolver.pick(sni).map(|key| key.cert[0].0.clone());
+
+        assert_eq!(picked(Some("api.other.org")), Some(exact_der));
+        assert_eq!(picked(Some("unknown.org")), Some(fallback_der.clone()));
+        assert_eq!(picked(None), Some(fallback_der));
+    }
+
+    #[test]
+    fn rejects_cert_for_another_name() {
+        let (mut cert, _) = self_signed("mismatch.example.net");
+        cert.sni = "other.example.net".to_owned();
+
+        assert!(Terminator::new(&Config { certs: vec![cert] }).is_err());
+    }
+
+    #[tokio::test]
+    async fn forwards_cleartext() {
+        let (cert, der) = self_signed("terminated.example.com");
+        let options = ForwardOptions {
+            tls: Some(Terminator::new(&Config { certs: vec![cert] }).expect("Loads cert")),
+            ..ForwardOptions::default()
+        };
+
+        let upstream = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind upstream");
+        let upstream_address = upstream.local_addr().expect("upstream address");
+        let proxy = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+        let proxy_address = proxy.local_addr().expect("proxy address");
+
+        let forwarding = tokio::spawn(async move {
+            let (mut incoming, _) = proxy.accept().await.expect("accept");
+            let resolver = tower::service_fn(move |_: ResolveRequest| async move {
+                Ok::<_, BoxError>(Some(Resolved::new("test", upstream_address)))
+            });
+            let parsers = std::iter::once(Box::new(tls::ServiceName::default()) as Box<_>);
+            forward(&mut incoming, resolver, parsers, &options).await
+        });
+
+        let client = TcpStream::connect(proxy_address).await.expect("connect");
+        let name = ServerName::try_from("terminated.example.com").expect("Valid name");
+        let mut client = connector(der)
+            .connect(name, client)
+            .await
+            .expect("Handshake");
+        client.write_all(b"hello").await.expect("write");
+
+        let (mut accepted, _) = upstream.accept().await.expect("accept upstream");
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.expect("read");
+        assert_eq!(&buf, b"hello");
+
+        accepted.write_all(b"world").await.expect("write");
+        drop(accepted);
+        client.read_exact(&mut buf).await.expect("read");
+        assert_eq!(&buf, b"world");
+        client.shutdown().await.expect("shutdown");
+
+        let stats = forwarding
+            .await
+            .expect("forward task")
+            .expect("forward succeeds")
+            .into_stats();
+        assert_eq!(
+            stats.service_name,
+            Some(ServiceName::Parsed("terminated.example.com".to_owned()))
+        );
+        assert_eq!(stats.bytes_in, 5);
+    }
+}
@@ -0,0 +1,281 @@
+//! Datagram relaying.
+//!
+//! UDP has no connections, so every client source address is treated as a separate flow.
+//! Destination for a flow is resolved once, when the first datagram arrives, afterwards
+//! datagrams are relayed both ways until the flow stays idle for the configured duration.
+use crate::{
+    parser::{self, Parser},
+    resolver::{ResolveRequest, Resolved, ServiceName},
+    Error,
+};
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    future::poll_fn,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, error::TrySendError},
+};
+use tracing::{debug, instrument, trace, warn, Instrument};
+
+/// Largest payload a single datagram could carry.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+/// Datagrams queued per flow while destination is being resolved or upstream is busy.
+const FLOW_QUEUE_SIZE: usize = 64;
+
+type Flows = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Bytes>>>>;
+
+/// Relays datagrams received by `socket` to destinations picked by resolver.
+///
+/// Parsers produced by `parsers` are offered the first datagram of every flow,
+/// if none of them is able to parse the name an empty name is fed to resolver.
+/// Datagrams from unresolved flows are dropped.
+///
+/// Function resolves only when reading from `socket` fails.
+#[instrument(skip_all, fields(local = ?socket.local_addr()))]
+pub async fn forward_udp<R, P, I>(
+    socket: UdpSocket,
+    resolver: R,
+    parsers: P,
+    idle_timeout: Duration,
+) -> Result<(), Error>
+where
+    R: tower::Service<
+            ResolveRequest,
+            Response = Option<Resolved>,
+            Error = Box<dyn std::error::Error + Send + Sync + 'static>,
+        > + Clone
+        + Send
+        + 'static,
+    R::Future: Send,
+    P: Fn() -> I,
+    I: Iterator<
+        Item = Box<
+            dyn Parser<String, Box<dyn std::error::Error + Send + 'static>> + Send + 'static,
+        >,
+    >,
+{
+    debug!("enter");
+    let port = socket.local_addr()?.port();
+    let socket = Arc::new(socket);
+    let flows: Flows = Arc::default();
+
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, client) = socket.recv_from(&mut buf).await?;
+        let datagram = Bytes::copy_from_slice(&buf[..len]);
+        trace!(len, ?client, "received datagram");
+
+        let existing = flows
+            .lock()
+            .expect("Flows lock poisoned")
+            .get(&client)
+            .cloned();
+
+        let datagram = match existing {
+            Some(flow) => match flow.try_send(datagram) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    trace!(?client, "flow queue is full, dropping datagram");
+                    continue;
+                }
+                // Flow expired, start over
+                Err(TrySendError::Closed(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+
+        let name = parse_service_name(&datagram, parsers());
+        let (tx, rx) = mpsc::channel(FLOW_QUEUE_SIZE);
+        tx.try_send(datagram)
+            .expect("Fresh channel has capacity for a datagram");
+        flows
+            .lock()
+            .expect("Flows lock poisoned")
+            .insert(client, tx.clone());
+
+        let flow = Flow {
+            client,
+            socket: socket.clone(),
+            idle_timeout,
+        };
+        let resolver = resolver.clone();
+        let flows = flows.clone();
+        tokio::spawn(
+            async move {
+                if let Err(err) = flow
+                    .run(
+                        resolver,
+                        ResolveRequest::from_service_name(name, port, client),
+                        rx,
+                    )
+                    .await
+                {
+                    warn!("Failed to relay datagrams: {err}");
+                }
+
+                // Only forget the flow if it had not been replaced in the meantime
+                let mut flows = flows.lock().expect("Flows lock poisoned");
+                if flows
+                    .get(&client)
+                    .map(|existing| existing.same_channel(&tx))
+                    .unwrap_or_default()
+                {
+                    flows.remove(&client);
+                }
+            }
+            .instrument(tracing::info_span!("udp-flow", ?client)),
+        );
+    }
+}
+
+fn parse_service_name<I>(datagram: &[u8], parsers: I) -> ServiceName
+where
+    I: Iterator<
+        Item = Box<
+            dyn Parser<String, Box<dyn std::error::Error + Send + 'static>> + Send + 'static,
+        >,
+    >,
+{
+    parsers
+        .filter(|parser| parser.sniff(datagram))
+        .find_map(|mut parser| match parser.parse(datagram) {
+            Ok(name) => name.map(parser::normalize).map(ServiceName::Parsed),
+            Err(err) => {
+                debug!(parser = parser.name(), "Parser dropped: {err}");
+                None
+            }
+        })
+        .unwrap_or(ServiceName::NotParsed)
+}
+
+struct Flow {
+    client: SocketAddr,
+    socket: Arc<UdpSocket>,
+    idle_timeout: Duration,
+}
+
+impl Flow {
+    async fn run<R>(
+        self,
+        mut resolver: R,
+        request: ResolveRequest,
+        mut datagrams: mpsc::Receiver<Bytes>,
+    ) -> Result<(), Error>
+    where
+        R: tower::Service<
+            ResolveRequest,
+            Response = Option<Resolved>,
+            Error = Box<dyn std::error::Error + Send + Sync + 'static>,
+        >,
+    {
+        poll_fn(|cx| resolver.poll_ready(cx))
+            .await
+            .map_err(Error::from_resolver)?;
+        let resolved = resolver.call(request).await.map_err(Error::from_resolver)?;
+        // Datagrams can't tell whether destination is reachable, so only the preferred one is used
+        let destination = match resolved.as_ref().map(Resolved::address) {
+            Some(Some(address)) => address,
+            Some(None) => {
+                warn!(destination = ?resolved, "Destination is not reachable over UDP, dropping flow");
+                return Ok(());
+            }
+            None => {
+                warn!("Failed to resolve destination, dropping flow");
+                return Ok(());
+            }
+        };
+        debug!(?destination, "resolved destination");
+
+        let local: SocketAddr = if destination.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let upstream = UdpSocket::bind(local).await?;
+        upstream.connect(destination).await?;
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            tokio::select! {
+                datagram = datagrams.recv() => match datagram {
+                    Some(datagram) => {
+                        upstream.send(&datagram).await?;
+                    }
+                    None => return Ok(()),
+                },
+                received = upstream.recv(&mut buf) => {
+                    let len = received?;
+                    self.socket.send_to(&buf[..len], self.client).await?;
+                }
+                _ = tokio::time::sleep(self.idle_timeout) => {
+                    debug!("Flow is idle, closing");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::forward_udp;
+    use crate::resolver::{ResolveRequest, Resolved};
+    use std::{
+        future::{ready, Ready},
+        net::SocketAddr,
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::net::UdpSocket;
+
+    #[derive(Clone)]
+    struct To(SocketAddr);
+
+    impl tower::Service<ResolveRequest> for To {
+        type Response = Option<Resolved>;
+        type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: ResolveRequest) -> Self::Future {
+            ready(Ok(Some(Resolved::new("test", self.0))))
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_datagrams_both_ways() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.expect("bind upstream");
+        let proxy = UdpSocket::bind("127.0.0.1:0").await.expect("bind proxy");
+        let proxy_address = proxy.local_addr().expect("proxy address");
+
+        tokio::spawn(forward_udp(
+            proxy,
+            To(upstream.local_addr().expect("upstream address")),
+            std::iter::empty,
+            Duration::from_secs(1),
+        ));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.expect("bind client");
+        client.connect(proxy_address).await.expect("connect");
+        client.send(b"ping").await.expect("send");
+
+        let mut buf = [0; 16];
+        let (len, flow) = upstream.recv_from(&mut buf).await.expect("upstream recv");
+        assert_eq!(&buf[..len], b"ping");
+
+        upstream
+            .send_to(b"pong", flow)
+            .await
+            .expect("upstream send");
+        let len = client.recv(&mut buf).await.expect("client recv");
+        assert_eq!(&buf[..len], b"pong");
+    }
+}
@@ -0,0 +1,180 @@
+//! End-to-end coverage of parse -> resolve -> copy pipeline of [`forward_stream`],
+//! with clients on in-memory streams and upstreams echoing whatever they get.
+use rpx::{
+    forward_stream,
+    parser::{http, tls, Parser},
+    resolver::{ResolveRequest, Resolved, ServiceName},
+    DropReason, Error, ForwardOptions, ForwardOutcome,
+};
+use rustls::{ClientConfig, ClientConnection, RootCertStore};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::TcpListener,
+    task::JoinHandle,
+};
+use tower::BoxError;
+
+type Boxed = Box<dyn Parser<String, Box<dyn std::error::Error + Send + 'static>> + Send>;
+
+const LISTENER: ([u8; 4], u16) = ([127, 0, 0, 1], 8443);
+const CLIENT: ([u8; 4], u16) = ([10, 0, 0, 1], 50000);
+
+/// Binds upstream sending every connection's traffic back until client half-closes.
+async fn echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind upstream");
+    let address = listener.local_addr().expect("upstream address");
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                tokio::io::copy(&mut read, &mut write).await.expect("echo");
+                write.shutdown().await.expect("close");
+            });
+        }
+    });
+    address
+}
+
+/// Starts forwarding client end of an in-memory stream, resolving names with `routes`.
+fn start(
+    routes: &[(&str, SocketAddr)],
+) -> (DuplexStream, JoinHandle<Result<ForwardOutcome, Error>>) {
+    let routes: Arc<HashMap<String, SocketAddr>> = Arc::new(
+        routes
+            .iter()
+            .map(|(name, address)| (name.to_string(), *address))
+            .collect(),
+    );
+    let resolver = tower::service_fn(move |request: ResolveRequest| {
+        let resolved = routes
+            .get(&request.name)
+            .map(|address| Resolved::new("test", *address));
+        async move { Ok::<_, BoxError>(resolved) }
+    });
+    let parsers: Vec<Boxed> = vec![
+        Box::new(tls::ServiceName::default()),
+        Box::new(http::Hostname::default()),
+    ];
+
+    let (client, mut incoming) = tokio::io::duplex(64 * 1024);
+    let forwarding = tokio::spawn(async move {
+        forward_stream(
+            &mut incoming,
+            LISTENER.into(),
+            CLIENT.into(),
+            resolver,
+            parsers.into_iter(),
+            &ForwardOptions::default(),
+        )
+        .await
+    });
+    (client, forwarding)
+}
+
+/// Sends `request` and half-closes, returns what came back along with forwarding outcome.
+async fn exchange(routes: &[(&str, SocketAddr)], request: &[u8]) -> (Vec<u8>, ForwardOutcome) {
+    let (mut client, forwarding) = start(routes);
+    client.write_all(request).await.expect("write");
+    client.shutdown().await.expect("half-close");
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.expect("read");
+    let outcome = forwarding
+        .await
+        .expect("forward task")
+        .expect("forward succeeds");
+    (response, outcome)
+}
+
+fn client_hello(sni: &str) -> Vec<u8> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+    let mut connection =
+        ClientConnection::new(Arc::new(config), sni.try_into().expect("Valid name"))
+            .expect("Valid config");
+
+    let mut hello = Vec::new();
+    connection.write_tls(&mut hello).expect("Writes hello");
+    hello
+}
+
+#[tokio::test]
+async fn routes_tls_by_sni() {
+    let (first, second) = (echo().await, echo().await);
+    let routes = [("first.example.com", first), ("second.example.com", second)];
+    let hello = client_hello("second.example.com");
+
+    let (response, outcome) = exchange(&routes, &hello).await;
+
+    assert_eq!(response, hello);
+    let ForwardOutcome::Forwarded(stats) = outcome else {
+        panic!("Expected forwarded connection, got {outcome:?}");
+    };
+    assert_eq!(stats.resolved, Some(second));
+    assert_eq!(
+        stats.service_name,
+        Some(ServiceName::Parsed("second.example.com".to_owned()))
+    );
+}
+
+#[tokio::test]
+async fn routes_http_by_host() {
+    let upstream = echo().await;
+    let request = b"GET / HTTP/1.1\r\nHost: API.example.com\r\n\r\n";
+
+    let (response, outcome) = exchange(&[("api.example.com", upstream)], request).await;
+
+    assert_eq!(response, request);
+    let ForwardOutcome::Forwarded(stats) = outcome else {
+        panic!("Expected forwarded connection, got {outcome:?}");
+    };
+    assert_eq!(stats.resolved, Some(upstream));
+    assert_eq!(stats.bytes_in, request.len() as u64);
+    assert_eq!(stats.bytes_out, request.len() as u64);
+}
+
+#[tokio::test]
+async fn drops_unresolved_name() {
+    let upstream = echo().await;
+    let request = b"GET / HTTP/1.1\r\nHost: unknown.example.com\r\n\r\n";
+
+    let (response, outcome) = exchange(&[("api.example.com", upstream)], request).await;
+
+    assert!(response.is_empty());
+    assert!(matches!(
+        outcome,
+        ForwardOutcome::Dropped {
+            reason: DropReason::Unresolved,
+            ..
+        }
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn drops_client_stalling_mid_handshake() {
+    let upstream = echo().await;
+    let hello = client_hello("api.example.com");
+    let (mut client, forwarding) = start(&[("api.example.com", upstream)]);
+
+    // Record header only, rest of ClientHello never arrives
+    client.write_all(&hello[..5]).await.expect("write");
+    let outcome = forwarding
+        .await
+        .expect("forward task")
+        .expect("forward succeeds");
+
+    assert!(matches!(
+        outcome,
+        ForwardOutcome::Dropped {
+            reason: DropReason::ParseTimeout,
+            ..
+        }
+    ));
+    let mut buf = [0; 1];
+    assert_eq!(client.read(&mut buf).await.expect("read"), 0);
+}